@@ -0,0 +1,35 @@
+//! Embed every catalog in locales/, so adding a language needs no code change.
+
+use std::{env, fs, path::Path};
+
+fn main() {
+    println!("cargo:rerun-if-changed=locales");
+    let dir = Path::new(&env::var("CARGO_MANIFEST_DIR").unwrap()).join("locales");
+    let mut locales = fs::read_dir(&dir)
+        .map(|entries| {
+            entries
+                .filter_map(|entry| entry.ok())
+                .map(|entry| entry.path())
+                .filter(|path| path.extension().is_some_and(|ext| ext == "toml"))
+                .collect::<Vec<_>>()
+        })
+        .unwrap_or_default();
+    locales.sort();
+    let entries = locales
+        .iter()
+        .map(|path| {
+            let code = path.file_stem().unwrap().to_string_lossy();
+            format!(
+                "    ({:?}, include_str!({:?})),\n",
+                code,
+                path.display().to_string()
+            )
+        })
+        .collect::<String>();
+    let out = Path::new(&env::var("OUT_DIR").unwrap()).join("locales.rs");
+    fs::write(
+        out,
+        format!("pub const LOCALES: &[(&str, &str)] = &[\n{}];\n", entries),
+    )
+    .unwrap();
+}
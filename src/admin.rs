@@ -0,0 +1,908 @@
+use std::{collections::HashMap, sync::Arc};
+
+use axum::{
+    async_trait,
+    extract::{Extension, FromRequest, FromRequestParts, Multipart, Path, Query, Request, State},
+    http::{
+        header::{AUTHORIZATION, CONTENT_TYPE, HOST, ORIGIN, WWW_AUTHENTICATE},
+        request::Parts,
+        HeaderMap, HeaderValue, Method, StatusCode,
+    },
+    Json,
+};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use chrono::{DateTime, Utc};
+use maud::{html, Markup};
+
+use ruma::{api::client::membership::invite_user, OwnedRoomId, OwnedUserId, RoomId};
+use tokio::task::JoinSet;
+
+use crate::{
+    discovery::{self, RoomsDiff},
+    email_hash, find_room, links, normalize_user_id, page, queue, reload, room_policy,
+    secret::redact,
+    security::CspNonce,
+    stats, store,
+    webhook::{self, EventKind},
+    AppState, RoomInfo,
+};
+
+/// Extractor rejecting requests without the configured admin token, given either as a bearer
+/// token or, for browsers, as the basic auth password.
+pub struct Admin {
+    /// Who made the request, for the logs: the basic auth user name if given.
+    pub actor: String,
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+/// User name and password of a basic auth header.
+fn basic_credentials(value: &str) -> Option<(String, String)> {
+    let credentials = STANDARD.decode(value.strip_prefix("Basic ")?).ok()?;
+    let credentials = String::from_utf8(credentials).ok()?;
+    let (user, password) = credentials.split_once(':')?;
+    Some((user.to_string(), password.to_string()))
+}
+
+/// Browsers resend basic auth credentials on cross-site form posts, so only accept
+/// state-changing basic auth requests coming from our own pages.
+fn same_origin(parts: &Parts) -> bool {
+    if parts.method == Method::GET || parts.method == Method::HEAD {
+        return true;
+    }
+    if let Some(site) = parts.headers.get("sec-fetch-site") {
+        return site == "same-origin";
+    }
+    match (parts.headers.get(ORIGIN), parts.headers.get(HOST)) {
+        (Some(origin), Some(host)) => origin
+            .to_str()
+            .ok()
+            .and_then(|origin| url::Url::parse(origin).ok())
+            .is_some_and(|origin| {
+                let authority = match origin.port() {
+                    Some(port) => format!("{}:{}", origin.host_str().unwrap_or_default(), port),
+                    None => origin.host_str().unwrap_or_default().to_string(),
+                };
+                host.as_bytes() == authority.as_bytes()
+            }),
+        (None, _) => true,
+        (Some(_), None) => false,
+    }
+}
+
+fn unauthorized(status: StatusCode, message: &str) -> (StatusCode, HeaderMap, String) {
+    let mut headers = HeaderMap::new();
+    headers.insert(
+        WWW_AUTHENTICATE,
+        HeaderValue::from_static("Basic realm=\"bouncer admin\""),
+    );
+    (status, headers, message.to_string())
+}
+
+#[async_trait]
+impl FromRequestParts<Arc<AppState>> for Admin {
+    type Rejection = (StatusCode, HeaderMap, String);
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &Arc<AppState>,
+    ) -> Result<Self, Self::Rejection> {
+        let Some(expected) = &state.admin_token else {
+            return Err((StatusCode::NOT_FOUND, HeaderMap::new(), "".to_string()));
+        };
+        let authorization = parts
+            .headers
+            .get(AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .ok_or_else(|| unauthorized(StatusCode::UNAUTHORIZED, "missing admin token"))?;
+        let (actor, token) = match authorization.strip_prefix("Bearer ") {
+            Some(token) => ("bearer token".to_string(), token.to_string()),
+            None => {
+                let (user, password) = basic_credentials(authorization)
+                    .ok_or_else(|| unauthorized(StatusCode::UNAUTHORIZED, "missing admin token"))?;
+                if !same_origin(parts) {
+                    log::warn!("rejected cross-site admin request");
+                    return Err((
+                        StatusCode::FORBIDDEN,
+                        HeaderMap::new(),
+                        "cross-site admin request".to_string(),
+                    ));
+                }
+                (user, password)
+            }
+        };
+        if !constant_time_eq(token.as_bytes(), expected.expose().as_bytes()) {
+            log::warn!("rejected admin request with invalid token");
+            return Err(unauthorized(StatusCode::FORBIDDEN, "invalid admin token"));
+        }
+        Ok(Admin { actor })
+    }
+}
+
+#[derive(serde::Serialize)]
+pub struct AdminError {
+    error: String,
+}
+
+pub async fn refresh_rooms(
+    _: Admin,
+    State(state): State<Arc<AppState>>,
+) -> Result<Json<RoomsDiff>, (StatusCode, Json<AdminError>)> {
+    reload::refresh_rooms(&state)
+        .await
+        .map(|diff| Json(diff.as_ref().clone()))
+        .map_err(|error| admin_error(StatusCode::SERVICE_UNAVAILABLE, error))
+}
+
+fn admin_error(status: StatusCode, error: String) -> (StatusCode, Json<AdminError>) {
+    (status, Json(AdminError { error }))
+}
+
+fn unavailable(err: anyhow::Error) -> (StatusCode, Json<AdminError>) {
+    log::error!("session store unavailable: {:#}", redact(&err));
+    admin_error(
+        StatusCode::SERVICE_UNAVAILABLE,
+        "session store unavailable".to_string(),
+    )
+}
+
+/// Serve a room again, after checking the bot can still invite users to it.
+pub async fn add_room(
+    _: Admin,
+    State(state): State<Arc<AppState>>,
+    Path(room_id): Path<OwnedRoomId>,
+) -> Result<Json<RoomInfo>, (StatusCode, Json<AdminError>)> {
+    if !state.room_filter.read().await.allows(&room_id) {
+        return Err(admin_error(
+            StatusCode::CONFLICT,
+            "room is excluded by configuration".to_string(),
+        ));
+    }
+    let mut room = discovery::inspect_room(&state.client, &state.user_id, &room_id)
+        .await
+        .map_err(|err| {
+            log::error!("failed to inspect room {}: {:#}", &room_id, redact(&err));
+            admin_error(
+                StatusCode::BAD_GATEWAY,
+                format!("failed to inspect room: {}", redact(&err)),
+            )
+        })?
+        .ok_or_else(|| {
+            admin_error(
+                StatusCode::CONFLICT,
+                "bot does not have invite permission in this room".to_string(),
+            )
+        })?;
+    room_policy::display(&state.rules.load().room_configs, &mut room);
+
+    if state.room_filter.read().await.hides(&room) {
+        return Err(admin_error(
+            StatusCode::CONFLICT,
+            "public rooms are hidden by configuration".to_string(),
+        ));
+    }
+
+    state.hidden_rooms.write().await.remove(&room_id);
+    state
+        .rooms
+        .write()
+        .await
+        .insert(room.room_id.clone(), room.clone());
+    log::warn!("room {} added through admin api", &room_id);
+    Ok(Json(room))
+}
+
+/// Hide a room from the invite page and reject invites to it until it is added again.
+pub async fn remove_room(
+    _: Admin,
+    State(state): State<Arc<AppState>>,
+    Path(room_id): Path<OwnedRoomId>,
+) -> Result<Json<RoomInfo>, (StatusCode, Json<AdminError>)> {
+    state.hidden_rooms.write().await.insert(room_id.clone());
+    let room = state.rooms.write().await.remove(&room_id).ok_or_else(|| {
+        admin_error(
+            StatusCode::NOT_FOUND,
+            "room is not currently served".to_string(),
+        )
+    })?;
+    log::warn!("room {} removed through admin api", &room_id);
+    Ok(Json(room))
+}
+
+/// Largest accepted bulk invite CSV, instead of the much smaller `--max-body-size`.
+pub const BULK_BODY_LIMIT: usize = 1024 * 1024;
+
+struct BulkRow {
+    row: usize,
+    user_id: OwnedUserId,
+    room_id: OwnedRoomId,
+    reason: Option<String>,
+}
+
+#[derive(serde::Serialize)]
+pub struct BulkOutcome {
+    row: usize,
+    user_id: OwnedUserId,
+    room_id: OwnedRoomId,
+    error: Option<String>,
+}
+
+/// Split a CSV line into trimmed fields, honouring double quotes.
+fn csv_fields(line: &str) -> Vec<String> {
+    let mut fields = vec![String::new()];
+    let mut quoted = false;
+    let mut chars = line.chars().peekable();
+    while let Some(c) = chars.next() {
+        let field = fields.last_mut().expect("fields is never empty");
+        match c {
+            '"' if quoted && chars.peek() == Some(&'"') => {
+                chars.next();
+                field.push('"');
+            }
+            '"' => quoted = !quoted,
+            ',' if !quoted => fields.push(String::new()),
+            c => field.push(c),
+        }
+    }
+    fields
+        .into_iter()
+        .map(|field| field.trim().to_string())
+        .collect()
+}
+
+/// Validate every `user_id,room[,reason]` row against the served rooms.
+fn bulk_rows(body: &str, rooms: &discovery::Rooms) -> Result<Vec<BulkRow>, Vec<String>> {
+    let mut rows = vec![];
+    let mut errors = vec![];
+    for (index, line) in body.lines().enumerate() {
+        let row = index + 1;
+        if line.trim().is_empty() || (index == 0 && line.trim_start().starts_with("user_id")) {
+            continue;
+        }
+        let fields = csv_fields(line);
+        let (user, room, reason) = match fields.as_slice() {
+            [user, room] => (user, room, None),
+            [user, room, reason] => (user, room, Some(reason.clone())),
+            _ => {
+                errors.push(format!("row {}: expected user_id,room[,reason]", row));
+                continue;
+            }
+        };
+        let Some(user_id) = normalize_user_id(user) else {
+            errors.push(format!("row {}: invalid user_id {:?}", row, user));
+            continue;
+        };
+        let Some(room) = find_room(rooms, room) else {
+            errors.push(format!("row {}: room {} is not served", row, room));
+            continue;
+        };
+        rows.push(BulkRow {
+            row,
+            user_id,
+            room_id: room.room_id.clone(),
+            reason: reason.filter(|reason| !reason.is_empty()),
+        });
+    }
+    if errors.is_empty() {
+        Ok(rows)
+    } else {
+        Err(errors)
+    }
+}
+
+async fn bulk_invite_row(state: &AppState, actor: &str, row: &BulkRow) -> Result<(), String> {
+    if state.dry_run {
+        log::warn!(
+            "dry run, not inviting {} to room {} for the admin",
+            &row.user_id,
+            &row.room_id
+        );
+        store::record_admin(
+            state,
+            EventKind::InviteDryRun,
+            &row.user_id,
+            &row.room_id,
+            actor,
+            row.reason.clone(),
+        )
+        .await;
+        return Ok(());
+    }
+    let mut request = invite_user::v3::Request::new(
+        row.room_id.clone(),
+        invite_user::v3::InvitationRecipient::UserId {
+            user_id: row.user_id.clone(),
+        },
+    );
+    request.reason = row.reason.clone();
+    if let Err(err) = state.client.send_request(request).await {
+        log::error!(
+            "failed to invite user {} to room {}: {}",
+            &row.user_id,
+            &row.room_id,
+            redact(&err)
+        );
+        store::record_admin(
+            state,
+            EventKind::InviteFailed,
+            &row.user_id,
+            &row.room_id,
+            actor,
+            Some(redact(&err)),
+        )
+        .await;
+        return Err(format!("failed to invite user: {}", redact(&err)));
+    }
+    state.count_invite(&row.room_id).await;
+    log::warn!(
+        "admin-initiated invite of {} to room {} by {}",
+        &row.user_id,
+        &row.room_id,
+        actor
+    );
+    store::record_admin(
+        state,
+        EventKind::InviteSent,
+        &row.user_id,
+        &row.room_id,
+        actor,
+        row.reason.clone(),
+    )
+    .await;
+    Ok(())
+}
+
+/// CSV of a bulk invite: the first file of a `multipart/form-data` upload, or the body itself.
+async fn bulk_csv(
+    state: &Arc<AppState>,
+    request: Request,
+) -> Result<String, (StatusCode, Json<AdminError>)> {
+    let multipart = request
+        .headers()
+        .get(CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| value.starts_with("multipart/form-data"));
+    if !multipart {
+        return String::from_request(request, state)
+            .await
+            .map_err(|err| admin_error(err.status(), err.body_text()));
+    }
+    let mut multipart = Multipart::from_request(request, state)
+        .await
+        .map_err(|err| admin_error(err.status(), err.body_text()))?;
+    let field = multipart
+        .next_field()
+        .await
+        .map_err(|err| admin_error(err.status(), err.body_text()))?
+        .ok_or_else(|| {
+            admin_error(
+                StatusCode::BAD_REQUEST,
+                "multipart upload without a file".to_string(),
+            )
+        })?;
+    field
+        .text()
+        .await
+        .map_err(|err| admin_error(err.status(), err.body_text()))
+}
+
+/// Invite everyone listed in a CSV of `user_id,room[,reason]` rows, after validating all of
+/// them, and report the outcome of each row. Rows are invited as many at a time as the shared
+/// [`crate::throttle`] lets homeserver requests run, which page requests keep competing for.
+pub async fn bulk_invite(
+    admin: Admin,
+    State(state): State<Arc<AppState>>,
+    request: Request,
+) -> Result<Json<Vec<BulkOutcome>>, (StatusCode, Json<AdminError>)> {
+    let body = bulk_csv(&state, request).await?;
+    let rows = bulk_rows(&body, &*state.rooms.read().await)
+        .map_err(|errors| admin_error(StatusCode::BAD_REQUEST, errors.join("; ")))?;
+    if rows.is_empty() {
+        return Err(admin_error(
+            StatusCode::BAD_REQUEST,
+            "no rows to invite".to_string(),
+        ));
+    }
+    log::warn!(
+        "admin bulk invite of {} rows by {}",
+        rows.len(),
+        admin.actor
+    );
+
+    let concurrency = state.throttle.stats().concurrency.max(1);
+    let mut rows = rows.into_iter();
+    let mut tasks = JoinSet::new();
+    let mut outcomes = vec![];
+    loop {
+        while tasks.len() < concurrency {
+            let Some(row) = rows.next() else {
+                break;
+            };
+            let state = state.clone();
+            let actor = admin.actor.clone();
+            tasks.spawn(async move {
+                let error = bulk_invite_row(&state, &actor, &row).await.err();
+                BulkOutcome {
+                    row: row.row,
+                    user_id: row.user_id,
+                    room_id: row.room_id,
+                    error,
+                }
+            });
+        }
+        let Some(outcome) = tasks.join_next().await else {
+            break;
+        };
+        outcomes.push(outcome.map_err(|err| {
+            log::error!("bulk invite task failed: {}", redact(&err));
+            admin_error(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "bulk invite task failed".to_string(),
+            )
+        })?);
+    }
+    outcomes.sort_by_key(|outcome| outcome.row);
+    Ok(Json(outcomes))
+}
+
+/// Shortest token prefix accepted when revoking, so one request cannot clear everything.
+const MIN_TOKEN_PREFIX: usize = 6;
+/// Characters of the csrf token shown when listing pending invites.
+pub const SHOWN_TOKEN_PREFIX: usize = 8;
+
+#[derive(serde::Serialize)]
+pub struct PendingInvite {
+    token_prefix: String,
+    user_ids: Vec<OwnedUserId>,
+    /// Hashes of the email addresses, see [`crate::email_hash`].
+    email_sha256: Vec<String>,
+    room_ids: Vec<OwnedRoomId>,
+    age_seconds: u64,
+}
+
+/// Invites waiting for the GitHub login to complete.
+pub async fn pending(
+    _: Admin,
+    State(state): State<Arc<AppState>>,
+) -> Result<Json<Vec<PendingInvite>>, (StatusCode, Json<AdminError>)> {
+    let mut pending = state
+        .sessions
+        .pending()
+        .await
+        .map_err(unavailable)?
+        .iter()
+        .map(|(token, invite)| PendingInvite {
+            token_prefix: token.chars().take(SHOWN_TOKEN_PREFIX).collect(),
+            user_ids: invite.user_ids.clone(),
+            email_sha256: invite
+                .emails
+                .iter()
+                .map(|email| email_hash(email))
+                .collect(),
+            room_ids: invite.room_ids.clone(),
+            age_seconds: invite.age_seconds(),
+        })
+        .collect::<Vec<_>>();
+    pending.sort_by_key(|invite| invite.age_seconds);
+    Ok(Json(pending))
+}
+
+#[derive(serde::Serialize)]
+pub struct Revoked {
+    revoked: usize,
+}
+
+/// Drop pending invites whose csrf token starts with the given prefix, so the GitHub callback
+/// fails for them.
+pub async fn revoke_pending(
+    admin: Admin,
+    State(state): State<Arc<AppState>>,
+    Path(prefix): Path<String>,
+) -> Result<Json<Revoked>, (StatusCode, Json<AdminError>)> {
+    if prefix.chars().count() < MIN_TOKEN_PREFIX {
+        return Err(admin_error(
+            StatusCode::BAD_REQUEST,
+            format!(
+                "token prefix must be at least {} characters",
+                MIN_TOKEN_PREFIX
+            ),
+        ));
+    }
+    let pending = state.sessions.pending().await.map_err(unavailable)?;
+    let mut revoked = 0;
+    for (token, _) in pending {
+        if token.starts_with(&prefix) {
+            let taken = state
+                .sessions
+                .take_pending(&token)
+                .await
+                .map_err(unavailable)?;
+            revoked += usize::from(taken.is_some());
+        }
+    }
+    log::warn!(
+        "{} revoked {} pending invites with token prefix {}",
+        admin.actor,
+        revoked,
+        prefix
+    );
+    Ok(Json(Revoked { revoked }))
+}
+
+#[derive(serde::Deserialize)]
+pub struct RevokeQuery {
+    user_id: String,
+}
+
+/// Drop every pending invite for one Matrix user.
+pub async fn revoke_pending_user(
+    admin: Admin,
+    State(state): State<Arc<AppState>>,
+    Query(query): Query<RevokeQuery>,
+) -> Result<Json<Revoked>, (StatusCode, Json<AdminError>)> {
+    let user_id = normalize_user_id(&query.user_id)
+        .ok_or_else(|| admin_error(StatusCode::BAD_REQUEST, "invalid user_id".to_string()))?;
+    let pending = state.sessions.pending().await.map_err(unavailable)?;
+    let mut revoked = 0;
+    for (token, invite) in pending {
+        if !invite.user_ids.contains(&user_id) {
+            continue;
+        }
+        let Some(mut invite) = state
+            .sessions
+            .take_pending(&token)
+            .await
+            .map_err(unavailable)?
+        else {
+            continue;
+        };
+        invite.user_ids.retain(|pending| *pending != user_id);
+        revoked += 1;
+        if !invite.user_ids.is_empty() || !invite.emails.is_empty() {
+            state
+                .sessions
+                .insert_pending(&token, &invite, state.pending_limits)
+                .await
+                .map_err(unavailable)?;
+        }
+    }
+    log::warn!(
+        "{} revoked {} pending invites for {}",
+        admin.actor,
+        revoked,
+        user_id
+    );
+    Ok(Json(Revoked { revoked }))
+}
+
+/// Send a synthetic event to the configured webhook and report whether it was accepted.
+pub async fn test_webhook(
+    admin: Admin,
+    State(state): State<Arc<AppState>>,
+) -> Result<Json<webhook::Event>, (StatusCode, Json<AdminError>)> {
+    let webhook = state.webhook.as_ref().ok_or_else(|| {
+        admin_error(
+            StatusCode::NOT_FOUND,
+            "no webhook is configured".to_string(),
+        )
+    })?;
+    let event = webhook::Event::new(
+        webhook::EventKind::Test,
+        &state.user_id,
+        &RoomId::parse("!test:example.com").expect("static room id is valid"),
+        "",
+        Some(format!("test event requested by {}", admin.actor)),
+    );
+    webhook
+        .deliver(&event)
+        .await
+        .map_err(|err| admin_error(StatusCode::BAD_GATEWAY, err))?;
+    Ok(Json(event))
+}
+
+#[derive(serde::Deserialize)]
+pub struct ResetQuery {
+    room_id: Option<OwnedRoomId>,
+}
+
+/// Reset the invite counters of one room, or of every room.
+pub async fn reset_invite_counts(
+    admin: Admin,
+    State(state): State<Arc<AppState>>,
+    Query(query): Query<ResetQuery>,
+) -> Json<HashMap<OwnedRoomId, u64>> {
+    let mut counts = state.invite_counts.lock().await;
+    let previous = match &query.room_id {
+        Some(room_id) => counts.remove_entry(room_id).into_iter().collect(),
+        None => std::mem::take(&mut *counts),
+    };
+    if let Some(store) = &state.store {
+        store
+            .update(|data| data.invite_counts = counts.clone())
+            .await;
+    }
+    log::warn!(
+        "{} reset invite counters of {}",
+        admin.actor,
+        query
+            .room_id
+            .as_ref()
+            .map_or("all rooms".to_string(), ToString::to_string)
+    );
+    Json(previous)
+}
+
+#[derive(serde::Deserialize)]
+pub struct NewLink {
+    room_id: String,
+    #[serde(default)]
+    note: String,
+    expires_at: Option<DateTime<Utc>>,
+    /// Claims the link allows, once by default.
+    max_uses: Option<u32>,
+}
+
+#[derive(serde::Serialize)]
+pub struct CreatedLink {
+    url: String,
+}
+
+/// Create an invite link to a served room, claimed without a GitHub login.
+pub async fn create_link(
+    admin: Admin,
+    State(state): State<Arc<AppState>>,
+    Json(link): Json<NewLink>,
+) -> Result<Json<CreatedLink>, (StatusCode, Json<AdminError>)> {
+    let room_id = find_room(&*state.rooms.read().await, link.room_id.trim())
+        .map(|room| room.room_id.clone())
+        .ok_or_else(|| admin_error(StatusCode::NOT_FOUND, "room is not served".to_string()))?;
+    let max_uses = link.max_uses.unwrap_or(1);
+    if max_uses == 0 {
+        return Err(admin_error(
+            StatusCode::BAD_REQUEST,
+            "max_uses must be at least 1".to_string(),
+        ));
+    }
+    if link
+        .expires_at
+        .is_some_and(|expires_at| expires_at <= Utc::now())
+    {
+        return Err(admin_error(
+            StatusCode::BAD_REQUEST,
+            "expires_at is in the past".to_string(),
+        ));
+    }
+    let token = links::create(
+        &state,
+        room_id,
+        link.note,
+        &admin.actor,
+        link.expires_at,
+        max_uses,
+    )
+    .await;
+    Ok(Json(CreatedLink {
+        url: state.absolute_link(&format!("claim/{}", token)),
+    }))
+}
+
+#[derive(serde::Serialize)]
+pub struct OutstandingLink {
+    token_prefix: String,
+    remaining_uses: u32,
+    #[serde(flatten)]
+    link: links::Link,
+}
+
+/// Invite links with their remaining uses, including expired and used up ones not swept yet.
+pub async fn links(_: Admin, State(state): State<Arc<AppState>>) -> Json<Vec<OutstandingLink>> {
+    let mut links = state
+        .links
+        .lock()
+        .await
+        .iter()
+        .map(|(token, link)| OutstandingLink {
+            token_prefix: token.chars().take(SHOWN_TOKEN_PREFIX).collect(),
+            remaining_uses: link.max_uses.saturating_sub(link.uses),
+            link: link.clone(),
+        })
+        .collect::<Vec<_>>();
+    links.sort_by_key(|link| link.link.created);
+    Json(links)
+}
+
+/// Revoke the invite links whose token starts with the given prefix.
+pub async fn revoke_link(
+    admin: Admin,
+    State(state): State<Arc<AppState>>,
+    Path(prefix): Path<String>,
+) -> Result<Json<Revoked>, (StatusCode, Json<AdminError>)> {
+    if prefix.chars().count() < MIN_TOKEN_PREFIX {
+        return Err(admin_error(
+            StatusCode::BAD_REQUEST,
+            format!(
+                "token prefix must be at least {} characters",
+                MIN_TOKEN_PREFIX
+            ),
+        ));
+    }
+    let revoked = links::revoke(&state, &prefix).await;
+    log::warn!(
+        "{} revoked {} invite links with token prefix {}",
+        admin.actor,
+        revoked,
+        prefix
+    );
+    Ok(Json(Revoked { revoked }))
+}
+
+/// Audit log entries shown on the dashboard.
+const RECENT_ENTRIES: usize = 20;
+
+/// Operator overview of served rooms and pending invites. Never shows tokens.
+pub async fn dashboard(
+    _: Admin,
+    State(state): State<Arc<AppState>>,
+    Extension(CspNonce(nonce)): Extension<CspNonce>,
+) -> Markup {
+    let rooms = state.rooms.read().await;
+    let mut rooms = rooms.values().collect::<Vec<_>>();
+    state.room_order.sort(&mut rooms);
+    let counts = state.invite_counts.lock().await.clone();
+    let knocks = state.knocks.counts().await;
+    let mut pending = state
+        .sessions
+        .pending()
+        .await
+        .map_err(|err| log::error!("failed to list pending invites: {:#}", redact(&err)))
+        .unwrap_or_default()
+        .iter()
+        .map(|(_, invite)| invite.age_seconds())
+        .collect::<Vec<_>>();
+    pending.sort_unstable();
+    let throttle = state.throttle.stats();
+    let room_age = state.refresh.age().await.as_secs();
+    let queued = queue::depth(&state).await;
+    let recent = match &state.store {
+        Some(store) => Some(
+            store
+                .entries(|entries| {
+                    entries
+                        .iter()
+                        .rev()
+                        .take(RECENT_ENTRIES)
+                        .cloned()
+                        .collect::<Vec<_>>()
+                })
+                .await,
+        ),
+        None => None,
+    };
+    let acceptance = match (&state.store, state.track_joins) {
+        (Some(store), true) => Some(
+            store
+                .entries(|entries| {
+                    let sent = entries
+                        .iter()
+                        .filter(|entry| entry.event == EventKind::InviteSent);
+                    let accepted = sent.clone().filter(|entry| entry.accepted.is_some());
+                    (accepted.count(), sent.count())
+                })
+                .await,
+        ),
+        _ => None,
+    };
+    page::layout(
+        &state,
+        &nonce,
+        "Bouncer Admin",
+        html! {
+            h1 { "Bouncer Admin" }
+            h2 { "Served rooms" }
+            table {
+                thead {
+                    tr {
+                        th { "Name" }
+                        th { "ID" }
+                        th { "Join Rule" }
+                        th { "Invites sent" }
+                        @if state.knock_mode {
+                            th { "Pending knocks" }
+                        }
+                    }
+                }
+                tbody {
+                    @for room in &rooms {
+                        tr {
+                            td { (room.name.clone().unwrap_or_default()) }
+                            td { (room.display_id()) }
+                            td { (room.join_rule) }
+                            td class="number" { (counts.get(&room.room_id).copied().unwrap_or(0)) }
+                            @if state.knock_mode {
+                                td class="number" { (knocks.get(&room.room_id).copied().unwrap_or(0)) }
+                            }
+                        }
+                    }
+                }
+            }
+            p {
+                "Rooms discovered " (room_age) "s ago"
+                @if let Some(ttl) = state.room_cache_ttl {
+                    ", refreshed by pages after " (ttl.as_secs()) "s"
+                }
+                "; " (state.refresh.failures()) " refreshes failed since startup"
+            }
+            form method="post" action=(state.absolute_link("admin/refresh-rooms")) {
+                button type="submit" { "Refresh rooms" }
+            }
+            h2 { "Pending GitHub logins" }
+            p { (pending.len()) " pending of at most " (state.pending_limits.total) }
+            @if !pending.is_empty() {
+                ul {
+                    @for age in &pending {
+                        li { "started " (age) "s ago" }
+                    }
+                }
+            }
+            h2 { "Homeserver requests" }
+            p {
+                (throttle.in_flight) " of at most " (throttle.concurrency) " in flight, "
+                (throttle.requests) " since startup, which waited "
+                (format!("{:.1}", throttle.waited.as_secs_f64())) "s for a slot in total; "
+                (throttle.timeouts) " gave up waiting"
+            }
+            @if let Some(queued) = queued {
+                p { (queued) " queued invites waiting to be sent" }
+            }
+            h2 { "Recent invite attempts" }
+            @if let Some((accepted, sent)) = acceptance {
+                p { (accepted) " of " (sent) " invites accepted (" (stats::percent(accepted, sent)) ")" }
+            }
+            @match &recent {
+                None => p { "Audit log not enabled." },
+                Some(entries) => table {
+                    thead {
+                        tr {
+                            th { "Time" }
+                            th { "Outcome" }
+                            th { "User" }
+                            th { "Room" }
+                            th { "GitHub user" }
+                            th { "Reason" }
+                            @if state.track_joins {
+                                th { "Joined" }
+                            }
+                        }
+                    }
+                    tbody {
+                        @for entry in entries {
+                            tr {
+                                td { (entry.timestamp.format("%Y-%m-%d %H:%M:%S")) }
+                                td { (entry.event.label()) }
+                                td {
+                                    @match (&entry.user_id, &entry.email_sha256) {
+                                        (Some(user_id), _) => (user_id),
+                                        (None, Some(hash)) => { "email " code { (hash.chars().take(12).collect::<String>()) } },
+                                        (None, None) => {},
+                                    }
+                                }
+                                td { (entry.room_id) }
+                                td { (entry.github_login) }
+                                td { (entry.reason.clone().unwrap_or_default()) }
+                                @if state.track_joins {
+                                    td {
+                                        @if let Some(accepted) = entry.accepted {
+                                            (accepted.format("%Y-%m-%d %H:%M:%S"))
+                                        }
+                                    }
+                                }
+                            }
+                        }
+                    }
+                },
+            }
+        },
+    )
+}
@@ -0,0 +1,289 @@
+use std::{collections::HashMap, sync::Arc, time::Duration};
+
+use chrono::{DateTime, Utc};
+use maud::html;
+use ruma::{
+    api::client::sync::sync_events,
+    events::{
+        room::message::Relation, AnySyncMessageLikeEvent, AnySyncTimelineEvent,
+        SyncMessageLikeEvent,
+    },
+    EventId, OwnedEventId, OwnedRoomId, OwnedUserId, UserId,
+};
+use tokio::sync::Mutex;
+
+use crate::{audit, commands, invite::invite_user, membership, secret::redact, AppState};
+
+/// How long the sync loop waits for new events before polling again.
+const SYNC_TIMEOUT: Duration = Duration::from_secs(30);
+/// Delay before retrying a failed sync.
+const RETRY_DELAY: Duration = Duration::from_secs(10);
+
+/// Invite waiting for a moderator decision in the admin room.
+#[derive(Clone, serde::Serialize, serde::Deserialize)]
+pub struct Approval {
+    pub user_id: OwnedUserId,
+    pub room_id: OwnedRoomId,
+    pub login: String,
+    pub reason: Option<String>,
+    pub requested: DateTime<Utc>,
+}
+
+/// Pending approvals keyed by the admin room message asking for them, kept in the audit store
+/// so that decisions still apply after a restart.
+#[derive(Default)]
+pub struct Approvals {
+    pending: Mutex<HashMap<OwnedEventId, Approval>>,
+}
+
+async fn persist(state: &AppState, pending: &HashMap<OwnedEventId, Approval>) {
+    if let Some(store) = &state.store {
+        store.update(|data| data.approvals = pending.clone()).await;
+    }
+}
+
+/// Ask the admin room moderators to approve an invite.
+pub async fn request(state: &AppState, approval: Approval, age: &str) -> Result<(), String> {
+    let room = state.room_name(&approval.room_id).await;
+    let event_id = audit::post(
+        state,
+        format!(
+            "{} requests an invite to {} as GitHub user {} (account age {}). \
+             React with 👍 or 👎, or reply !approve or !deny.",
+            approval.user_id, room, approval.login, age
+        ),
+        html! {
+            a href=(format!("https://matrix.to/#/{}", approval.user_id)) { (approval.user_id) }
+            " requests an invite to " (room) " as GitHub user "
+            a href=(format!("https://github.com/{}", approval.login)) { (approval.login) }
+            " (account age " (age) "). React with 👍 or 👎, or reply !approve or !deny."
+        },
+    )
+    .await
+    .ok_or_else(|| t("failed to request moderator approval"))?;
+    log::warn!(
+        "invite of {} to room {} awaits moderator approval",
+        &approval.user_id,
+        &approval.room_id
+    );
+    let mut pending = state.approvals.pending.lock().await;
+    pending.insert(event_id, approval);
+    persist(state, &pending).await;
+    Ok(())
+}
+
+/// Watch the admin room for moderator decisions and `!bouncer` commands.
+pub fn spawn(state: Arc<AppState>) {
+    if state.admin_room.is_none() {
+        return;
+    }
+    tokio::spawn(async move {
+        if let Some(store) = &state.store {
+            let restored = store.read(|data| data.approvals.clone()).await;
+            if !restored.is_empty() {
+                log::warn!("restored {} pending approvals", restored.len());
+            }
+            state.approvals.pending.lock().await.extend(restored);
+        }
+        let mut since = None;
+        loop {
+            let mut request = sync_events::v3::Request::new();
+            request.timeout = since.is_some().then_some(SYNC_TIMEOUT);
+            request.since = since.clone();
+            let response = match state.client.send_request(request).await {
+                Ok(response) => response,
+                Err(err) => {
+                    log::error!("failed to sync admin room: {}", redact(&err));
+                    tokio::time::sleep(RETRY_DELAY).await;
+                    continue;
+                }
+            };
+            // The first sync only establishes where to start, earlier events are not decisions.
+            if since.is_some() {
+                if let Some(room) = state
+                    .admin_room
+                    .as_ref()
+                    .and_then(|room_id| response.rooms.join.get(room_id))
+                {
+                    for event in &room.timeline.events {
+                        match event.deserialize() {
+                            Ok(event) => handle(&state, event).await,
+                            Err(err) => log::debug!("ignoring admin room event: {}", err),
+                        }
+                    }
+                }
+            }
+            since = Some(response.next_batch);
+            expire(&state).await;
+        }
+    });
+}
+
+async fn handle(state: &AppState, event: AnySyncTimelineEvent) {
+    let AnySyncTimelineEvent::MessageLike(event) = event else {
+        return;
+    };
+    match event {
+        AnySyncMessageLikeEvent::Reaction(SyncMessageLikeEvent::Original(reaction)) => {
+            let approve = match reaction.content.relates_to.key.trim_end_matches('\u{fe0f}') {
+                "👍" => true,
+                "👎" => false,
+                _ => return,
+            };
+            decide(
+                state,
+                &reaction.content.relates_to.event_id,
+                &reaction.sender,
+                approve,
+            )
+            .await;
+        }
+        AnySyncMessageLikeEvent::RoomMessage(SyncMessageLikeEvent::Original(message)) => {
+            if message.sender == state.user_id {
+                return;
+            }
+            if let Some(command) = message.content.body().trim().strip_prefix("!bouncer") {
+                commands::handle(state, &message.sender, command).await;
+                return;
+            }
+            let Some(Relation::Reply { in_reply_to }) = &message.content.relates_to else {
+                return;
+            };
+            // Skip the quoted fallback of the message being replied to.
+            let command = message
+                .content
+                .body()
+                .lines()
+                .map(str::trim)
+                .find(|line| !line.is_empty() && !line.starts_with('>'));
+            let approve = match command {
+                Some("!approve") => true,
+                Some("!deny") => false,
+                _ => return,
+            };
+            decide(state, &in_reply_to.event_id, &message.sender, approve).await;
+        }
+        _ => {}
+    }
+}
+
+async fn decide(state: &AppState, event_id: &EventId, moderator: &UserId, approve: bool) {
+    if moderator == &*state.user_id || !state.approvals.pending.lock().await.contains_key(event_id)
+    {
+        return;
+    }
+    let Some(admin_room) = &state.admin_room else {
+        return;
+    };
+    match membership::power_level(&state.client, admin_room, moderator).await {
+        Ok(level) if level >= state.approval_power_level => {}
+        Ok(_) => {
+            log::warn!(
+                "ignoring approval decision of {} without sufficient power level",
+                moderator
+            );
+            return;
+        }
+        Err(err) => {
+            log::error!(
+                "failed to get power level of {}: {:#}",
+                moderator,
+                redact(&err)
+            );
+            return;
+        }
+    }
+    let approval = {
+        let mut pending = state.approvals.pending.lock().await;
+        let Some(approval) = pending.remove(event_id) else {
+            return;
+        };
+        persist(state, &pending).await;
+        approval
+    };
+
+    let room = state.room_name(&approval.room_id).await;
+    let outcome = if approve {
+        log::warn!(
+            "{} approved the invite of {} to room {}",
+            moderator,
+            &approval.user_id,
+            &approval.room_id
+        );
+        match invite_user(
+            state,
+            &approval.room_id,
+            &approval.user_id,
+            &approval.login,
+            approval.reason,
+        )
+        .await
+        {
+            Ok(()) => format!(
+                "{} was invited to {}, approved by {}",
+                approval.user_id, room, moderator
+            ),
+            Err(err) => format!(
+                "{} approved the invite of {} to {}, but it failed: {}",
+                moderator, approval.user_id, room, err
+            ),
+        }
+    } else {
+        log::warn!(
+            "{} denied the invite of {} to room {}",
+            moderator,
+            &approval.user_id,
+            &approval.room_id
+        );
+        format!(
+            "{} was denied an invite to {} by {}",
+            approval.user_id, room, moderator
+        )
+    };
+    audit::edit(state, event_id, outcome).await;
+}
+
+async fn expire(state: &AppState) {
+    let expired = {
+        let mut pending = state.approvals.pending.lock().await;
+        let now = Utc::now();
+        let expired = pending
+            .iter()
+            .filter(|(_, approval)| {
+                (now - approval.requested)
+                    .to_std()
+                    .is_ok_and(|age| age > state.approval_expiry)
+            })
+            .map(|(event_id, _)| event_id.clone())
+            .collect::<Vec<_>>();
+        let expired = expired
+            .into_iter()
+            .filter_map(|event_id| {
+                pending
+                    .remove(&event_id)
+                    .map(|approval| (event_id, approval))
+            })
+            .collect::<Vec<_>>();
+        if !expired.is_empty() {
+            persist(state, &pending).await;
+        }
+        expired
+    };
+    for (event_id, approval) in expired {
+        log::warn!(
+            "approval of the invite of {} to room {} expired",
+            &approval.user_id,
+            &approval.room_id
+        );
+        let room = state.room_name(&approval.room_id).await;
+        audit::edit(
+            state,
+            &event_id,
+            format!(
+                "The invite request of {} to {} expired without a decision",
+                approval.user_id, room
+            ),
+        )
+        .await;
+    }
+}
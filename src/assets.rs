@@ -0,0 +1,226 @@
+use std::{
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::Arc,
+};
+
+use anyhow::Context;
+use axum::{
+    extract::State,
+    http::{header, StatusCode},
+    response::IntoResponse,
+};
+use maud::{html, Markup};
+
+use crate::{hex, AppState};
+
+/// Built-in styles, overridden by `--stylesheet`.
+const BASE_CSS: &str = r#"
+  table, th, td {
+    border: 1px solid;
+  }
+  th, td {
+    padding: 5px;
+  }
+  table {
+    border-collapse: collapse;
+  }
+  .avatar {
+    display: block;
+    width: 32px;
+    height: 32px;
+  }
+  .number {
+    text-align: right;
+  }
+  .controls {
+    display: flex;
+    padding: 5px;
+  }
+  .fields {
+    display: flex;
+    flex-direction: column;
+  }
+  .field, .cf-turnstile {
+    padding: 5px;
+  }
+  .field label {
+    padding-right: 5px;
+  }
+  .field button {
+    width: 100%;
+  }
+  .dry-run {
+    border: 2px dashed;
+    padding: 5px;
+  }
+  .website {
+    position: absolute;
+    left: -10000px;
+  }
+  .client-links a {
+    display: inline-block;
+    border: 1px solid;
+    padding: 5px;
+    margin: 2px;
+  }
+  .qr-code svg {
+    display: block;
+    max-width: 100%;
+    height: auto;
+    margin: 5px 0;
+  }
+"#;
+
+const CACHE_CONTROL: &str = "public, max-age=31536000, immutable";
+
+/// Stylesheet given by `--stylesheet`, read once at startup.
+pub struct Stylesheet {
+    css: String,
+    /// Hash of the content, changing the url whenever the file does, see [`CACHE_CONTROL`].
+    version: String,
+}
+
+impl Stylesheet {
+    pub fn read(path: &Path) -> anyhow::Result<Stylesheet> {
+        let css = std::fs::read_to_string(path)
+            .with_context(|| format!("failed to read stylesheet {}", path.display()))?;
+        Ok(Stylesheet {
+            version: version(&css),
+            css,
+        })
+    }
+}
+
+fn version(content: impl AsRef<[u8]>) -> String {
+    hex(&ring::digest::digest(&ring::digest::SHA256, content.as_ref()).as_ref()[..8])
+}
+
+/// Links to the icon, the built-in styles, then the custom stylesheet so its rules win.
+pub fn stylesheets(state: &AppState) -> Markup {
+    html! {
+        link rel="icon" type=(state.favicon.content_type) href=(state.absolute_link(&format!("favicon.ico?v={}", state.favicon.version)));
+        link rel="stylesheet" href=(state.absolute_link(&format!("static/base.css?v={}", version(BASE_CSS))));
+        @if let Some(stylesheet) = &state.stylesheet {
+            link rel="stylesheet" href=(state.absolute_link(&format!("static/custom.css?v={}", stylesheet.version)));
+        }
+    }
+}
+
+pub async fn base_css() -> impl IntoResponse {
+    (
+        [
+            (header::CONTENT_TYPE, "text/css; charset=utf-8"),
+            (header::CACHE_CONTROL, CACHE_CONTROL),
+        ],
+        BASE_CSS,
+    )
+}
+
+pub async fn custom_css(
+    State(state): State<Arc<AppState>>,
+) -> Result<impl IntoResponse, StatusCode> {
+    let stylesheet = state.stylesheet.as_ref().ok_or(StatusCode::NOT_FOUND)?;
+    Ok((
+        [
+            (header::CONTENT_TYPE, "text/css; charset=utf-8"),
+            (header::CACHE_CONTROL, CACHE_CONTROL),
+        ],
+        stylesheet.css.clone(),
+    ))
+}
+
+/// Built-in icon, overridden by `--favicon`.
+const DEFAULT_FAVICON: &[u8] = include_bytes!("../static/favicon.ico");
+
+/// Icon served at /favicon.ico, the built-in one unless `--favicon` is given.
+pub struct Favicon {
+    bytes: Vec<u8>,
+    content_type: &'static str,
+    /// Hash of the content, changing the url like the stylesheet's.
+    version: String,
+}
+
+impl Default for Favicon {
+    fn default() -> Self {
+        Favicon {
+            bytes: DEFAULT_FAVICON.to_vec(),
+            content_type: "image/x-icon",
+            version: version(DEFAULT_FAVICON),
+        }
+    }
+}
+
+impl Favicon {
+    pub fn read(path: &Path) -> anyhow::Result<Favicon> {
+        let content_type = match path.extension().and_then(|ext| ext.to_str()) {
+            Some("ico") => "image/x-icon",
+            Some("png") => "image/png",
+            Some("svg") => "image/svg+xml",
+            _ => anyhow::bail!(
+                "favicon {} is not an .ico, .png or .svg file",
+                path.display()
+            ),
+        };
+        let bytes = std::fs::read(path)
+            .with_context(|| format!("failed to read favicon {}", path.display()))?;
+        Ok(Favicon {
+            version: version(&bytes),
+            bytes,
+            content_type,
+        })
+    }
+}
+
+pub async fn favicon(State(state): State<Arc<AppState>>) -> impl IntoResponse {
+    (
+        [
+            (header::CONTENT_TYPE, state.favicon.content_type),
+            (header::CACHE_CONTROL, CACHE_CONTROL),
+        ],
+        state.favicon.bytes.clone(),
+    )
+}
+
+/// What /robots.txt allows crawlers to index, see `--robots-policy`.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub enum RobotsPolicy {
+    /// Everything.
+    Allow,
+    /// Nothing but the room list, keeping the forms and confirmation links out of indexes.
+    #[default]
+    Disallow,
+    /// The given robots.txt.
+    File(PathBuf),
+}
+
+impl FromStr for RobotsPolicy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "allow" => Ok(RobotsPolicy::Allow),
+            "disallow" => Ok(RobotsPolicy::Disallow),
+            _ => anyhow::bail!("invalid robots policy {:?}, expected allow or disallow", s),
+        }
+    }
+}
+
+/// The content of /robots.txt, read once at startup.
+pub fn robots_txt(policy: &RobotsPolicy, base_path: &str) -> anyhow::Result<String> {
+    Ok(match policy {
+        RobotsPolicy::Allow => "User-agent: *\nDisallow:\n".to_string(),
+        RobotsPolicy::Disallow => {
+            format!("User-agent: *\nAllow: {0}/$\nDisallow: {0}/\n", base_path)
+        }
+        RobotsPolicy::File(path) => std::fs::read_to_string(path)
+            .with_context(|| format!("failed to read robots file {}", path.display()))?,
+    })
+}
+
+pub async fn robots(State(state): State<Arc<AppState>>) -> impl IntoResponse {
+    (
+        [(header::CONTENT_TYPE, "text/plain; charset=utf-8")],
+        state.robots_txt.clone(),
+    )
+}
@@ -0,0 +1,103 @@
+use maud::{html, Markup};
+use oauth2::CsrfToken;
+use ruma::{
+    api::client::message::send_message_event,
+    events::{
+        relation::Replacement,
+        room::message::{
+            MessageType, Relation, RoomMessageEventContent, RoomMessageEventContentWithoutRelation,
+        },
+    },
+    EventId, OwnedEventId, RoomId, UserId,
+};
+
+use crate::{secret::redact, AppState};
+
+/// Post a notice to the admin room, if one is configured. Failures are only logged.
+pub async fn post(state: &AppState, plain: String, html: Markup) -> Option<OwnedEventId> {
+    send(
+        state,
+        RoomMessageEventContent::notice_html(plain, html.into_string()),
+    )
+    .await
+}
+
+/// Replace the text of an earlier admin room notice.
+pub async fn edit(state: &AppState, event_id: &EventId, plain: String) {
+    let mut content = RoomMessageEventContent::notice_plain(format!("* {}", plain));
+    content.relates_to = Some(Relation::Replacement(Replacement::new(
+        event_id.to_owned(),
+        RoomMessageEventContentWithoutRelation::new(MessageType::notice_plain(plain)),
+    )));
+    send(state, content).await;
+}
+
+async fn send(state: &AppState, content: RoomMessageEventContent) -> Option<OwnedEventId> {
+    let room_id = state.admin_room.as_ref()?;
+    let request = send_message_event::v3::Request::new(
+        room_id.clone(),
+        CsrfToken::new_random().secret().clone().into(),
+        &content,
+    )
+    .map_err(|err| log::error!("failed to build admin room message: {}", redact(&err)))
+    .ok()?;
+    match state.client.send_request(request).await {
+        Ok(response) => Some(response.event_id),
+        Err(err) => {
+            log::error!("failed to post to admin room {}: {}", room_id, redact(&err));
+            None
+        }
+    }
+}
+
+fn pill(user_id: &UserId) -> Markup {
+    html! { a href=(format!("https://matrix.to/#/{}", user_id)) { (user_id) } }
+}
+
+fn github(login: &str) -> Markup {
+    html! { a href=(format!("https://github.com/{}", login)) { (login) } }
+}
+
+pub async fn invited(state: &AppState, user_id: &UserId, room_id: &RoomId, login: &str) {
+    let room = state.room_name(room_id).await;
+    post(
+        state,
+        format!(
+            "{} was invited to {}, vouched for by GitHub user {}",
+            user_id, room, login
+        ),
+        html! {
+            (pill(user_id)) " was invited to " (room) ", vouched for by GitHub user " (github(login))
+        },
+    )
+    .await;
+}
+
+pub async fn dry_run(state: &AppState, user_id: &UserId, room_id: &RoomId, login: &str) {
+    let room = state.room_name(room_id).await;
+    post(
+        state,
+        format!(
+            "Dry run: {} would have been invited to {}, vouched for by GitHub user {}",
+            user_id, room, login
+        ),
+        html! {
+            "Dry run: " (pill(user_id)) " would have been invited to " (room) ", vouched for by GitHub user " (github(login))
+        },
+    )
+    .await;
+}
+
+pub async fn denied(state: &AppState, user_id: &UserId, room: &str, login: &str, reason: &str) {
+    post(
+        state,
+        format!(
+            "{} was refused an invite to {} as GitHub user {}: {}",
+            user_id, room, login, reason
+        ),
+        html! {
+            (pill(user_id)) " was refused an invite to " (room) " as GitHub user " (github(login)) ": " (reason)
+        },
+    )
+    .await;
+}
@@ -0,0 +1,174 @@
+use std::{sync::Arc, time::Duration};
+
+use maud::html;
+use ruma::{
+    api::client::{
+        filter::{FilterDefinition, RoomEventFilter},
+        membership::{join_room_by_id, leave_room},
+        sync::sync_events,
+    },
+    events::AnyStrippedStateEvent,
+    OwnedUserId, RoomId,
+};
+
+use crate::{audit, discovery, room_policy, secret::redact, AppState};
+
+/// How long the sync loop waits for new invites before polling again.
+const SYNC_TIMEOUT: Duration = Duration::from_secs(30);
+/// Delays before retrying a failed sync, doubling from the first up to the second.
+const MIN_BACKOFF: Duration = Duration::from_secs(5);
+const MAX_BACKOFF: Duration = Duration::from_secs(300);
+/// Attempts at joining a room the bot was invited to.
+const JOIN_ATTEMPTS: u32 = 3;
+const JOIN_RETRY_DELAY: Duration = Duration::from_secs(5);
+
+/// Invites only, the timelines of joined rooms are not needed.
+fn filter() -> FilterDefinition {
+    let mut filter = FilterDefinition::ignore_all();
+    filter.room.rooms = None;
+    filter.room.timeline = RoomEventFilter::ignore_all();
+    filter
+}
+
+/// Accept invites of the bot from `--auto-join-invites-from` and serve the rooms.
+pub fn spawn(state: Arc<AppState>) {
+    if state.auto_join_invites_from.is_empty() {
+        return;
+    }
+    tokio::spawn(async move {
+        let mut since = None;
+        let mut backoff = MIN_BACKOFF;
+        loop {
+            let mut request = sync_events::v3::Request::new();
+            request.filter = Some(sync_events::v3::Filter::FilterDefinition(filter()));
+            request.timeout = since.is_some().then_some(SYNC_TIMEOUT);
+            request.since = since.clone();
+            let response = match state.client.send_request(request).await {
+                Ok(response) => response,
+                Err(err) => {
+                    log::error!("failed to sync invites: {}", redact(&err));
+                    tokio::time::sleep(backoff).await;
+                    backoff = (backoff * 2).min(MAX_BACKOFF);
+                    continue;
+                }
+            };
+            backoff = MIN_BACKOFF;
+            for (room_id, room) in &response.rooms.invite {
+                let inviter =
+                    room.invite_state
+                        .events
+                        .iter()
+                        .find_map(|event| match event.deserialize() {
+                            Ok(AnyStrippedStateEvent::RoomMember(event))
+                                if event.state_key == state.user_id =>
+                            {
+                                Some(event.sender)
+                            }
+                            _ => None,
+                        });
+                handle(&state, room_id, inviter).await;
+            }
+            since = Some(response.next_batch);
+        }
+    });
+}
+
+async fn reject(state: &AppState, room_id: &RoomId) {
+    if let Err(err) = state
+        .client
+        .send_request(leave_room::v3::Request::new(room_id.to_owned()))
+        .await
+    {
+        log::error!(
+            "failed to reject invite to room {}: {}",
+            room_id,
+            redact(&err)
+        );
+    }
+}
+
+async fn handle(state: &AppState, room_id: &RoomId, inviter: Option<OwnedUserId>) {
+    let inviter = match inviter {
+        Some(inviter) if state.auto_join_invites_from.contains(&inviter) => inviter,
+        inviter => {
+            log::warn!(
+                "rejecting invite to room {} from untrusted user {}",
+                room_id,
+                inviter.map_or("unknown".to_string(), |inviter| inviter.to_string())
+            );
+            reject(state, room_id).await;
+            return;
+        }
+    };
+
+    for attempt in 1..=JOIN_ATTEMPTS {
+        match state
+            .client
+            .send_request(join_room_by_id::v3::Request::new(room_id.to_owned()))
+            .await
+        {
+            Ok(_) => break,
+            Err(err) if attempt < JOIN_ATTEMPTS => {
+                log::warn!(
+                    "failed to join room {} (attempt {}): {}",
+                    room_id,
+                    attempt,
+                    err
+                );
+                tokio::time::sleep(JOIN_RETRY_DELAY).await;
+            }
+            Err(err) => {
+                log::error!(
+                    "giving up joining room {} invited to by {}: {}",
+                    room_id,
+                    inviter,
+                    redact(&err)
+                );
+                return;
+            }
+        }
+    }
+    log::warn!("joined room {} invited to by {}", room_id, inviter);
+
+    if !state.room_filter.read().await.allows(room_id) {
+        log::warn!(
+            "room {} is excluded by configuration, not serving it",
+            room_id
+        );
+        return;
+    }
+    let mut room = match discovery::inspect_room(&state.client, &state.user_id, room_id).await {
+        Ok(Some(room)) => room,
+        Ok(None) => return,
+        Err(err) => {
+            log::error!("failed to inspect room {}: {:#}", room_id, redact(&err));
+            return;
+        }
+    };
+    room_policy::display(&state.rules.load().room_configs, &mut room);
+    if state.room_filter.read().await.hides(&room) {
+        log::warn!("room {} is public and hidden by configuration", room_id);
+        state
+            .public_rooms
+            .write()
+            .await
+            .insert(room.room_id.clone(), room);
+        return;
+    }
+
+    let name = room.name.clone().unwrap_or_else(|| room.display_id());
+    state.rooms.write().await.insert(room.room_id.clone(), room);
+    log::warn!("now serving room {} invited to by {}", room_id, inviter);
+    audit::post(
+        state,
+        format!(
+            "{} is now offered on the invite page, added by {}",
+            name, inviter
+        ),
+        html! {
+            (name) " is now offered on the invite page, added by "
+            a href=(format!("https://matrix.to/#/{}", inviter)) { (inviter) }
+        },
+    )
+    .await;
+}
@@ -0,0 +1,159 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::Arc,
+};
+
+use axum::{
+    extract::{Path, State},
+    http::{header, StatusCode},
+    response::{IntoResponse, Response},
+};
+use ruma::{
+    api::client::{authenticated_media::get_content_thumbnail, profile::get_profile},
+    media::Method,
+    uint, OwnedMxcUri, OwnedRoomId, OwnedUserId,
+};
+use tokio::sync::Mutex;
+
+use crate::{secret::redact, sessions, AppState};
+
+/// Total bytes of thumbnails kept in memory.
+const CACHE_CAPACITY: usize = 8 * 1024 * 1024;
+
+#[derive(Clone)]
+struct Thumbnail {
+    content_type: String,
+    data: Arc<[u8]>,
+}
+
+/// Size-capped in-memory thumbnail cache, evicting the oldest entries first.
+#[derive(Default)]
+pub struct AvatarCache {
+    inner: Mutex<CacheInner>,
+}
+
+#[derive(Default)]
+struct CacheInner {
+    entries: HashMap<OwnedMxcUri, Thumbnail>,
+    order: VecDeque<OwnedMxcUri>,
+    size: usize,
+}
+
+impl AvatarCache {
+    async fn get(&self, uri: &OwnedMxcUri) -> Option<Thumbnail> {
+        self.inner.lock().await.entries.get(uri).cloned()
+    }
+
+    async fn insert(&self, uri: OwnedMxcUri, thumbnail: Thumbnail) {
+        if thumbnail.data.len() > CACHE_CAPACITY {
+            return;
+        }
+        let mut inner = self.inner.lock().await;
+        while inner.size + thumbnail.data.len() > CACHE_CAPACITY {
+            let Some(oldest) = inner.order.pop_front() else {
+                break;
+            };
+            if let Some(evicted) = inner.entries.remove(&oldest) {
+                inner.size -= evicted.data.len();
+            }
+        }
+        inner.size += thumbnail.data.len();
+        if let Some(replaced) = inner.entries.insert(uri.clone(), thumbnail) {
+            inner.size -= replaced.data.len();
+        } else {
+            inner.order.push_back(uri);
+        }
+    }
+}
+
+/// Thumbnail of an avatar, cached or fetched through the bot's homeserver.
+async fn thumbnail(state: &AppState, uri: OwnedMxcUri) -> Result<Thumbnail, (StatusCode, String)> {
+    if let Some(thumbnail) = state.avatars.get(&uri).await {
+        return Ok(thumbnail);
+    }
+    let mut request = get_content_thumbnail::v1::Request::from_uri(&uri, uint!(64), uint!(64))
+        .map_err(|err| {
+            log::error!("invalid avatar url {}: {}", &uri, redact(&err));
+            (StatusCode::NOT_FOUND, "no avatar".to_string())
+        })?;
+    request.method = Some(Method::Crop);
+    let response = state.client.send_request(request).await.map_err(|err| {
+        log::error!("failed to fetch avatar {}: {}", &uri, redact(&err));
+        (
+            StatusCode::BAD_GATEWAY,
+            "failed to fetch avatar".to_string(),
+        )
+    })?;
+    let thumbnail = Thumbnail {
+        content_type: response
+            .content_type
+            .unwrap_or_else(|| "application/octet-stream".to_string()),
+        data: response.file.into(),
+    };
+    state.avatars.insert(uri, thumbnail.clone()).await;
+    Ok(thumbnail)
+}
+
+fn respond(thumbnail: Thumbnail, cache_control: &str) -> Response {
+    (
+        [
+            (header::CONTENT_TYPE, thumbnail.content_type),
+            (header::CACHE_CONTROL, cache_control.to_string()),
+        ],
+        thumbnail.data.to_vec(),
+    )
+        .into_response()
+}
+
+/// Proxy the avatar thumbnail of a served room through the bot's homeserver.
+pub async fn avatar(
+    State(state): State<Arc<AppState>>,
+    Path(room_id): Path<OwnedRoomId>,
+) -> Result<Response, (StatusCode, String)> {
+    let uri = {
+        let rooms = state.rooms.read().await;
+        let public_rooms = state.public_rooms.read().await;
+        rooms
+            .get(&room_id)
+            .or_else(|| public_rooms.get(&room_id))
+            .and_then(|room| room.avatar_url.clone())
+            .ok_or((StatusCode::NOT_FOUND, "no avatar".to_string()))?
+    };
+    Ok(respond(
+        thumbnail(&state, uri).await?,
+        "public, max-age=86400",
+    ))
+}
+
+/// Proxy the avatar of a user shown on a confirmation page. Only users of an invite awaiting
+/// confirmation are served, so the bot does not proxy arbitrary profiles.
+pub async fn user_avatar(
+    State(state): State<Arc<AppState>>,
+    Path((token, user_id)): Path<(String, OwnedUserId)>,
+) -> Result<Response, (StatusCode, String)> {
+    let confirming = state
+        .sessions
+        .confirmation(&token)
+        .await
+        .map_err(sessions::unavailable)?
+        .is_some_and(|invite| invite.user_ids.contains(&user_id));
+    if !confirming {
+        return Err((StatusCode::NOT_FOUND, "no avatar".to_string()));
+    }
+    let profile = state
+        .client
+        .send_request(get_profile::v3::Request::new(user_id.clone()))
+        .await
+        .map_err(|err| {
+            log::error!(
+                "failed to get user profile for {}: {}",
+                user_id,
+                redact(&err)
+            );
+            (StatusCode::BAD_GATEWAY, "failed to get profile".to_string())
+        })?;
+    let uri = profile
+        .avatar_url
+        .ok_or((StatusCode::NOT_FOUND, "no avatar".to_string()))?;
+    Ok(respond(thumbnail(&state, uri).await?, "private, no-store"))
+}
@@ -0,0 +1,83 @@
+//! Captcha checks of submitted forms, see [`CaptchaVerifier`].
+
+use std::{collections::HashMap, net::IpAddr, time::Duration};
+
+use axum::{async_trait, http::StatusCode};
+
+use crate::{
+    i18n::t,
+    secret::{redact, Secret},
+    send_idempotent, Turnstile, CAPTCHA_FAILED,
+};
+
+/// Where the Turnstile widget loads its script and frames from.
+pub const TURNSTILE_ORIGIN: &str = "https://challenges.cloudflare.com";
+
+/// Checks the captcha response a form was submitted with.
+#[async_trait]
+pub trait CaptchaVerifier: Send + Sync {
+    /// `remote_ip` is the address of the client that solved the captcha.
+    async fn verify(&self, response: &str, remote_ip: IpAddr) -> Result<(), (StatusCode, String)>;
+    /// Script rendering the widget, loaded by every page.
+    fn script_src(&self) -> String;
+    /// Origins of the widget's script and frames, allowed by the Content-Security-Policy.
+    fn origins(&self) -> Vec<String>;
+}
+
+/// Cloudflare Turnstile, checked with the siteverify endpoint.
+pub struct TurnstileVerifier {
+    pub http_client: reqwest::Client,
+    pub http_timeout: Duration,
+    pub siteverify_url: String,
+    pub secret_key: Secret<String>,
+}
+
+#[async_trait]
+impl CaptchaVerifier for TurnstileVerifier {
+    async fn verify(&self, response: &str, remote_ip: IpAddr) -> Result<(), (StatusCode, String)> {
+        let request = self
+            .http_client
+            .post(&self.siteverify_url)
+            .form::<HashMap<String, String>>(
+                &[
+                    ("secret".to_string(), self.secret_key.expose().clone()),
+                    ("response".to_string(), response.to_string()),
+                    ("remoteip".to_string(), remote_ip.to_string()),
+                ]
+                .into(),
+            );
+        // Verifying a response twice is harmless: a repeated check fails, it cannot pass.
+        let response: Turnstile =
+            send_idempotent("Turnstile verification", request, self.http_timeout)
+                .await
+                .map_err(|err| {
+                    log::error!("failed to verify turnstile response: {}", redact(&err));
+                    (
+                        StatusCode::INTERNAL_SERVER_ERROR,
+                        t("failed to verify turnstile response"),
+                    )
+                })?
+                .json()
+                .await
+                .map_err(|err| {
+                    log::error!("failed to decode turnstile verify result: {}", redact(&err));
+                    (
+                        StatusCode::INTERNAL_SERVER_ERROR,
+                        t("failed to decode turnstile verify result"),
+                    )
+                })?;
+
+        if !response.success {
+            return Err((StatusCode::FORBIDDEN, t(CAPTCHA_FAILED)));
+        }
+        Ok(())
+    }
+
+    fn script_src(&self) -> String {
+        format!("{}/turnstile/v0/api.js", TURNSTILE_ORIGIN)
+    }
+
+    fn origins(&self) -> Vec<String> {
+        vec![TURNSTILE_ORIGIN.to_string()]
+    }
+}
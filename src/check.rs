@@ -0,0 +1,107 @@
+use std::{
+    collections::HashMap,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use axum::{
+    extract::{Query, State},
+    http::StatusCode,
+    Json,
+};
+use ruma::{events::room::member::MembershipState, OwnedRoomId, OwnedUserId};
+use tokio::sync::Mutex;
+
+use crate::{client_ip::ClientIp, membership::membership, normalize_user_id, sessions, AppState};
+
+/// How long a looked up membership is reused.
+const CACHE_TTL: Duration = Duration::from_secs(30);
+/// Lookups a single client may make per window.
+const RATE_LIMIT: u32 = 30;
+const RATE_WINDOW: Duration = Duration::from_secs(60);
+
+/// Short-lived membership cache for the /check endpoint.
+#[derive(Default)]
+pub struct MembershipCheck {
+    cache: Mutex<HashMap<(OwnedRoomId, OwnedUserId), (Instant, Membership)>>,
+}
+
+#[derive(Clone, Copy, serde::Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Membership {
+    Join,
+    Invite,
+    Ban,
+    None,
+}
+
+impl From<Option<MembershipState>> for Membership {
+    fn from(membership: Option<MembershipState>) -> Self {
+        match membership {
+            Some(MembershipState::Join) => Membership::Join,
+            Some(MembershipState::Invite) => Membership::Invite,
+            Some(MembershipState::Ban) => Membership::Ban,
+            _ => Membership::None,
+        }
+    }
+}
+
+impl MembershipCheck {
+    async fn get(&self, key: &(OwnedRoomId, OwnedUserId)) -> Option<Membership> {
+        self.cache
+            .lock()
+            .await
+            .get(key)
+            .filter(|(fetched, _)| fetched.elapsed() < CACHE_TTL)
+            .map(|(_, membership)| *membership)
+    }
+
+    async fn insert(&self, key: (OwnedRoomId, OwnedUserId), membership: Membership) {
+        let now = Instant::now();
+        let mut cache = self.cache.lock().await;
+        cache.retain(|_, (fetched, _)| now.duration_since(*fetched) < CACHE_TTL);
+        cache.insert(key, (now, membership));
+    }
+}
+
+#[derive(serde::Deserialize)]
+pub struct CheckQuery {
+    room_id: OwnedRoomId,
+    user_id: String,
+}
+
+#[derive(serde::Serialize)]
+pub struct CheckResponse {
+    membership: Membership,
+}
+
+/// Membership of a user in a served room, for hints on the invite form.
+pub async fn check(
+    State(state): State<Arc<AppState>>,
+    ClientIp(client_ip): ClientIp,
+    Query(query): Query<CheckQuery>,
+) -> Result<Json<CheckResponse>, (StatusCode, String)> {
+    let key = format!("check:{}", client_ip);
+    if !sessions::allow(&*state.sessions, &key, RATE_LIMIT, RATE_WINDOW).await {
+        return Err((
+            StatusCode::TOO_MANY_REQUESTS,
+            "too many membership checks".to_string(),
+        ));
+    }
+    if !state.rooms.read().await.contains_key(&query.room_id) {
+        return Err((StatusCode::NOT_FOUND, "room is not served".to_string()));
+    }
+    let user_id = normalize_user_id(&query.user_id)
+        .ok_or((StatusCode::BAD_REQUEST, "invalid user_id".to_string()))?;
+
+    let key = (query.room_id, user_id);
+    let membership = match state.membership_check.get(&key).await {
+        Some(membership) => membership,
+        None => {
+            let membership = membership(&state.client, &key.0, &key.1).await.into();
+            state.membership_check.insert(key, membership).await;
+            membership
+        }
+    };
+    Ok(Json(CheckResponse { membership }))
+}
@@ -0,0 +1,210 @@
+use std::time::{Duration, Instant};
+
+use ruma::{events::room::member::MembershipState, OwnedRoomAliasId, OwnedRoomId};
+
+use crate::{
+    config::ClientConfig,
+    connect,
+    discovery::{self, RoomFilter, SkipReason},
+    invite::{send_invite, InviteError},
+    membership::membership,
+    normalize_user_id, AppState, RoomInfo,
+};
+
+/// Stands in for the GitHub login in the audit log for invites sent with `bouncer invite`.
+pub const CLI_VOUCHER: &str = "command line";
+/// How long `--wait` watches for the invite to be accepted.
+const WAIT: Duration = Duration::from_secs(60);
+const POLL_INTERVAL: Duration = Duration::from_secs(3);
+
+#[derive(serde::Serialize)]
+struct ListedRoom {
+    room_id: OwnedRoomId,
+    alias: Option<OwnedRoomAliasId>,
+    name: Option<String>,
+    join_rule: Option<String>,
+    /// Why the room is not served, `None` for served rooms.
+    skipped: Option<SkipReason>,
+}
+
+impl ListedRoom {
+    fn new(room_id: OwnedRoomId, room: Option<RoomInfo>, skipped: Option<SkipReason>) -> Self {
+        ListedRoom {
+            room_id,
+            alias: room.as_ref().and_then(|room| room.canonical_alias.clone()),
+            name: room.as_ref().and_then(|room| room.name.clone()),
+            join_rule: room.map(|room| room.join_rule.to_string()),
+            skipped,
+        }
+    }
+}
+
+/// Print the rooms the bot would serve and the ones discovery leaves out, with the reason.
+pub async fn list_rooms(config: ClientConfig, json: bool) -> anyhow::Result<()> {
+    let (client, user_id) = connect(
+        config.homeserver_url,
+        config.credentials,
+        config.proxy.as_ref(),
+        Default::default(),
+    )
+    .await?;
+    let filter = RoomFilter::resolve(&client, &config.rooms).await?;
+    let discovery = discovery::discover(&client, &user_id, &filter).await?;
+    let (rooms, public_rooms) = filter.partition(discovery.rooms);
+
+    let mut listed = vec![];
+    for (room_id, room) in rooms {
+        listed.push(ListedRoom::new(room_id, Some(room), None));
+    }
+    for (room_id, room) in public_rooms {
+        listed.push(ListedRoom::new(
+            room_id,
+            Some(room),
+            Some(SkipReason::Public),
+        ));
+    }
+    for (room_id, reason) in discovery.skipped {
+        // Rooms the bot is not in may not be visible to it.
+        let room = discovery::describe_room(&client, &room_id)
+            .await
+            .map_err(|err| log::debug!("failed to describe room {}: {:#}", room_id, err))
+            .ok();
+        listed.push(ListedRoom::new(room_id, room, Some(reason)));
+    }
+    listed
+        .sort_by(|a, b| (a.skipped.is_some(), &a.room_id).cmp(&(b.skipped.is_some(), &b.room_id)));
+
+    if json {
+        println!("{}", serde_json::to_string_pretty(&listed)?);
+        return Ok(());
+    }
+    let rows = listed
+        .iter()
+        .map(|room| {
+            [
+                room.room_id.to_string(),
+                room.alias
+                    .as_ref()
+                    .map(ToString::to_string)
+                    .unwrap_or_default(),
+                room.name.clone().unwrap_or_default(),
+                room.join_rule.clone().unwrap_or_default(),
+                match room.skipped {
+                    Some(reason) => format!("skipped: {}", reason),
+                    None => "served".to_string(),
+                },
+            ]
+        })
+        .collect::<Vec<_>>();
+    print_table(["ROOM ID", "ALIAS", "NAME", "JOIN RULE", "STATUS"], &rows);
+    Ok(())
+}
+
+fn print_table<const N: usize>(header: [&str; N], rows: &[[String; N]]) {
+    let mut widths = header.map(|title| title.chars().count());
+    for row in rows {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.chars().count());
+        }
+    }
+    let line = |cells: Vec<&str>| {
+        let line = cells
+            .iter()
+            .zip(widths)
+            .map(|(cell, width)| format!("{:width$}", cell, width = width))
+            .collect::<Vec<_>>()
+            .join("  ");
+        println!("{}", line.trim_end());
+    };
+    line(header.to_vec());
+    for row in rows {
+        line(row.iter().map(String::as_str).collect());
+    }
+}
+
+/// Invite one user to one room from the command line, through the same checks and audit
+/// trail as the web form minus the GitHub login.
+pub async fn invite(
+    state: &AppState,
+    user: &str,
+    room: &str,
+    reason: Option<String>,
+    force: bool,
+    wait: bool,
+) -> anyhow::Result<()> {
+    let Some(user_id) = normalize_user_id(user) else {
+        anyhow::bail!(
+            "{:?} is not a valid Matrix ID, expected the form @user:example.com",
+            user
+        );
+    };
+    let room_id = discovery::resolve_room(&state.client, room).await?;
+    if discovery::inspect_room(&state.client, &state.user_id, &room_id)
+        .await?
+        .is_none()
+    {
+        anyhow::bail!("the bot may not invite users to room {}", room_id);
+    }
+    if let Some(list) = state.policy.banned(&user_id).await {
+        if !force {
+            anyhow::bail!(
+                "{} is banned by the policy list {}, use --force to invite anyway",
+                user_id,
+                list
+            );
+        }
+        log::warn!(
+            "inviting {} despite the policy list {} with --force",
+            user_id,
+            list
+        );
+    }
+
+    match send_invite(state, &room_id, &user_id, CLI_VOUCHER, reason).await {
+        Ok(()) if state.dry_run => {
+            println!("dry run, not invited {} to {}", user_id, room_id);
+            return Ok(());
+        }
+        Ok(()) => println!("invited {} to {}", user_id, room_id),
+        Err(InviteError::Failed {
+            kind: Some(kind),
+            message,
+        }) => anyhow::bail!(
+            "failed to invite {} to {}: {}: {}",
+            user_id,
+            room_id,
+            kind,
+            message
+        ),
+        Err(err) => anyhow::bail!("failed to invite {} to {}: {}", user_id, room_id, err),
+    }
+    if !wait {
+        return Ok(());
+    }
+
+    let started = Instant::now();
+    while started.elapsed() < WAIT {
+        tokio::time::sleep(POLL_INTERVAL).await;
+        match membership(&state.client, &room_id, &user_id).await {
+            Some(MembershipState::Join) => {
+                println!("{} accepted the invite", user_id);
+                return Ok(());
+            }
+            Some(MembershipState::Invite) => {}
+            Some(MembershipState::Leave) => {
+                println!("{} rejected the invite", user_id);
+                return Ok(());
+            }
+            membership => {
+                println!("{} has membership {:?} now", user_id, membership);
+                return Ok(());
+            }
+        }
+    }
+    println!(
+        "{} has not accepted the invite after {}s, it stays pending",
+        user_id,
+        WAIT.as_secs()
+    );
+    Ok(())
+}
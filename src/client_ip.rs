@@ -0,0 +1,181 @@
+//! The address of the client behind reverse proxies, see [`ClientIp`].
+
+use std::{
+    fmt,
+    net::{IpAddr, SocketAddr},
+    str::FromStr,
+    sync::Arc,
+};
+
+use anyhow::Context;
+use axum::{
+    async_trait,
+    extract::{ConnectInfo, FromRequestParts},
+    http::{
+        header::{HeaderName, FORWARDED},
+        request::Parts,
+        HeaderMap, StatusCode,
+    },
+};
+
+use crate::AppState;
+
+const X_FORWARDED_FOR: HeaderName = HeaderName::from_static("x-forwarded-for");
+
+/// An address range of `--trusted-proxies`, e.g. 10.0.0.0/8 or a single address.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Cidr {
+    network: IpAddr,
+    prefix: u8,
+}
+
+impl Cidr {
+    pub fn contains(&self, address: IpAddr) -> bool {
+        match (self.network, address.to_canonical()) {
+            (IpAddr::V4(network), IpAddr::V4(address)) => {
+                let mask = u32::MAX.checked_shl(32 - self.prefix as u32).unwrap_or(0);
+                u32::from(network) & mask == u32::from(address) & mask
+            }
+            (IpAddr::V6(network), IpAddr::V6(address)) => {
+                let mask = u128::MAX.checked_shl(128 - self.prefix as u32).unwrap_or(0);
+                u128::from(network) & mask == u128::from(address) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+impl FromStr for Cidr {
+    type Err = anyhow::Error;
+
+    fn from_str(value: &str) -> anyhow::Result<Self> {
+        let (network, prefix) = value.split_once('/').unwrap_or((value, ""));
+        let network = network
+            .parse::<IpAddr>()
+            .with_context(|| format!("invalid address in {}", value))?
+            .to_canonical();
+        let max = if network.is_ipv4() { 32 } else { 128 };
+        let prefix = if prefix.is_empty() {
+            max
+        } else {
+            prefix
+                .parse()
+                .ok()
+                .filter(|prefix| *prefix <= max)
+                .with_context(|| format!("invalid prefix length in {}", value))?
+        };
+        Ok(Cidr { network, prefix })
+    }
+}
+
+impl fmt::Display for Cidr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.network, self.prefix)
+    }
+}
+
+/// An address as given in X-Forwarded-For, possibly with a port or in brackets.
+fn parse_address(value: &str) -> Option<IpAddr> {
+    let value = value.trim().trim_matches('"');
+    value
+        .parse::<IpAddr>()
+        .ok()
+        .or_else(|| value.parse::<SocketAddr>().ok().map(|address| address.ip()))
+        .or_else(|| {
+            value
+                .strip_prefix('[')?
+                .strip_suffix(']')?
+                .parse::<IpAddr>()
+                .ok()
+        })
+}
+
+/// The `for` parameter of every RFC 7239 Forwarded element, `None` for elements without one
+/// or with an obfuscated or unknown node.
+fn forwarded_for(value: &str) -> Vec<Option<IpAddr>> {
+    value
+        .split(',')
+        .map(|element| {
+            element.split(';').find_map(|pair| {
+                let (name, node) = pair.split_once('=')?;
+                if !name.trim().eq_ignore_ascii_case("for") {
+                    return None;
+                }
+                parse_address(node)
+            })
+        })
+        .collect()
+}
+
+/// The client address of a request received from `peer`.
+///
+/// The forwarding headers are only believed as far as they were appended by trusted proxies:
+/// walking from the right, each hop is taken while the address it was received from is
+/// trusted. A malformed or unknown hop ends the walk. Forwarded takes precedence over
+/// X-Forwarded-For when both are given.
+pub fn client_ip(peer: IpAddr, headers: &HeaderMap, trusted_proxies: &[Cidr]) -> IpAddr {
+    let peer = peer.to_canonical();
+    let trusted = |address: IpAddr| trusted_proxies.iter().any(|cidr| cidr.contains(address));
+    if !trusted(peer) {
+        return peer;
+    }
+    let values = |name: HeaderName| {
+        headers
+            .get_all(name)
+            .iter()
+            .map(|value| value.to_str().ok())
+            .collect::<Option<Vec<_>>>()
+            .map(|values| values.join(","))
+    };
+    let hops = match values(FORWARDED).filter(|value| !value.is_empty()) {
+        Some(forwarded) => forwarded_for(&forwarded),
+        None => values(X_FORWARDED_FOR)
+            .unwrap_or_default()
+            .split(',')
+            .filter(|hop| !hop.trim().is_empty())
+            .map(parse_address)
+            .collect(),
+    };
+
+    let mut client = peer;
+    for hop in hops.into_iter().rev() {
+        if !trusted(client) {
+            break;
+        }
+        match hop {
+            Some(hop) => client = hop.to_canonical(),
+            None => break,
+        }
+    }
+    client
+}
+
+/// Extractor yielding the client address, see [`client_ip`].
+pub struct ClientIp(pub IpAddr);
+
+#[async_trait]
+impl FromRequestParts<Arc<AppState>> for ClientIp {
+    type Rejection = (StatusCode, String);
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &Arc<AppState>,
+    ) -> Result<Self, Self::Rejection> {
+        let ConnectInfo(peer) = parts
+            .extensions
+            .get::<ConnectInfo<SocketAddr>>()
+            .copied()
+            .ok_or_else(|| {
+                log::error!("missing the peer address of a request");
+                (
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    "missing the peer address".to_string(),
+                )
+            })?;
+        Ok(ClientIp(client_ip(
+            peer.ip(),
+            &parts.headers,
+            &state.trusted_proxies,
+        )))
+    }
+}
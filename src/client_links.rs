@@ -0,0 +1,85 @@
+//! Links opening a room in a Matrix client, offered once the invite was sent so the invitee
+//! knows where to accept it.
+
+use percent_encoding::{utf8_percent_encode, AsciiSet, NON_ALPHANUMERIC};
+use qrcode::{render::svg, QrCode};
+use ruma::ServerName;
+
+use crate::{i18n::tr, AppState, RoomInfo};
+
+/// Element Web as hosted by Element, linked unless `--element-web-url` replaces it.
+const ELEMENT_WEB_URL: &str = "https://app.element.io";
+
+/// Minimum side of a QR code in pixels, for scanning it off a laptop screen with a phone.
+const QR_CODE_SIZE: u32 = 240;
+
+/// Characters escaped in identifiers and parameters, everything but RFC 3986 unreserved ones.
+const COMPONENT: &AsciiSet = &NON_ALPHANUMERIC
+    .remove(b'-')
+    .remove(b'.')
+    .remove(b'_')
+    .remove(b'~');
+
+/// The canonical alias, or the room ID with a `via` server to join it over federation.
+pub fn room_reference(room: &RoomInfo, via: &ServerName) -> String {
+    match &room.canonical_alias {
+        Some(alias) => utf8_percent_encode(alias.as_str(), COMPONENT).to_string(),
+        None => format!(
+            "{}?via={}",
+            utf8_percent_encode(room.room_id.as_str(), COMPONENT),
+            utf8_percent_encode(via.as_str(), COMPONENT)
+        ),
+    }
+}
+
+pub fn matrix_to(room: &RoomInfo, via: &ServerName) -> String {
+    format!("https://matrix.to/#/{}", room_reference(room, via))
+}
+
+/// A room in Element Web, or any client taking the same `#/room/` fragment, such as the
+/// `element://vector/webapp` scheme of Element Desktop.
+pub fn element(base_url: &str, room: &RoomInfo, via: &ServerName) -> String {
+    format!(
+        "{}/#/room/{}",
+        base_url.trim_end_matches('/'),
+        room_reference(room, via)
+    )
+}
+
+/// Client name and url of every link offered for a room, via the homeserver of the bot.
+pub fn links(state: &AppState, room: &RoomInfo) -> Vec<(String, String)> {
+    let via = state.user_id.server_name();
+    let mut links = vec![
+        ("matrix.to".to_string(), matrix_to(room, via)),
+        ("Element".to_string(), element(ELEMENT_WEB_URL, room, via)),
+    ];
+    if let Some(base_url) = &state.element_web_url {
+        let name = url::Url::parse(base_url)
+            .ok()
+            .and_then(|url| url.host_str().map(str::to_string))
+            .unwrap_or_else(|| base_url.clone());
+        links.push((name, element(base_url, room, via)));
+    }
+    links
+}
+
+/// Accessible name of a link, naming the room as well as the client.
+pub fn label(room: &str, client: &str) -> String {
+    tr(
+        "Open {room} in {client}",
+        &[("room", &room), ("client", &client)],
+    )
+}
+
+/// A QR code of the link as inline SVG, to continue on a phone without an image request.
+pub fn qr_code(url: &str) -> Option<String> {
+    let svg = QrCode::new(url)
+        .map_err(|err| log::error!("failed to encode {} as QR code: {}", url, err))
+        .ok()?
+        .render::<svg::Color>()
+        .min_dimensions(QR_CODE_SIZE, QR_CODE_SIZE)
+        .build();
+    // The XML declaration is not allowed inside HTML.
+    let start = svg.find("<svg")?;
+    Some(svg[start..].to_string())
+}
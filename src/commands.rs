@@ -0,0 +1,188 @@
+use maud::{html, Markup};
+use ruma::{api::client::membership::invite_user, UserId};
+
+use crate::{
+    admin, audit, find_room, membership, normalize_user_id, reload, secret::redact, AppState,
+};
+
+const USAGE: &str = "Usage: !bouncer rooms | !bouncer refresh | !bouncer invite @user:server #room:server | !bouncer pending";
+
+/// Run a `!bouncer` command sent to the admin room and post the reply there.
+pub async fn handle(state: &AppState, sender: &UserId, command: &str) {
+    let Some(admin_room) = &state.admin_room else {
+        return;
+    };
+    match membership::power_level(&state.client, admin_room, sender).await {
+        Ok(level) if level >= state.command_power_level => {}
+        Ok(_) => {
+            log::warn!(
+                "ignoring bouncer command of {} without sufficient power level",
+                sender
+            );
+            return;
+        }
+        Err(err) => {
+            log::error!(
+                "failed to get power level of {}: {:#}",
+                sender,
+                redact(&err)
+            );
+            return;
+        }
+    }
+    log::warn!("{} ran bouncer command {:?}", sender, command);
+
+    let args = command.split_whitespace().collect::<Vec<_>>();
+    let (plain, html) = match args.as_slice() {
+        ["rooms"] => rooms(state).await,
+        ["refresh"] => refresh(state).await,
+        ["invite", user, room] => invite(state, sender, user, room).await,
+        ["pending"] => pending(state).await,
+        _ => (USAGE.to_string(), html! { (USAGE) }),
+    };
+    audit::post(state, plain, html).await;
+}
+
+async fn rooms(state: &AppState) -> (String, Markup) {
+    let rooms = state.rooms.read().await;
+    let mut rooms = rooms.values().collect::<Vec<_>>();
+    state.room_order.sort(&mut rooms);
+    let counts = state.invite_counts.lock().await;
+    let lines = rooms
+        .iter()
+        .map(|room| {
+            (
+                room.name.clone().unwrap_or_default(),
+                room.display_id(),
+                counts.get(&room.room_id).copied().unwrap_or(0),
+            )
+        })
+        .collect::<Vec<_>>();
+    let plain = std::iter::once(format!("{} served rooms:", lines.len()))
+        .chain(
+            lines
+                .iter()
+                .map(|(name, id, count)| format!("{} ({}): {} invites", name, id, count)),
+        )
+        .collect::<Vec<_>>()
+        .join("\n");
+    let html = html! {
+        p { (lines.len()) " served rooms:" }
+        ul {
+            @for (name, id, count) in &lines {
+                li { (name) " (" code { (id) } "): " (count) " invites" }
+            }
+        }
+    };
+    (plain, html)
+}
+
+async fn refresh(state: &AppState) -> (String, Markup) {
+    let plain = match reload::refresh_rooms(state).await {
+        Ok(diff) => format!(
+            "Rooms refreshed: {} served, added {}, removed {}",
+            state.rooms.read().await.len(),
+            diff.added.len(),
+            diff.removed.len()
+        ),
+        Err(err) => format!("Refreshing rooms failed: {}", redact(&err)),
+    };
+    (plain.clone(), html! { (plain) })
+}
+
+async fn invite(state: &AppState, moderator: &UserId, user: &str, room: &str) -> (String, Markup) {
+    let Some(user_id) = normalize_user_id(user) else {
+        let plain = format!("Invalid user id {}", user);
+        return (plain.clone(), html! { (plain) });
+    };
+    let Some(room_id) =
+        find_room(&*state.rooms.read().await, room).map(|room| room.room_id.clone())
+    else {
+        let plain = format!("Room {} is not served", room);
+        return (plain.clone(), html! { (plain) });
+    };
+
+    let mut request = invite_user::v3::Request::new(
+        room_id.clone(),
+        invite_user::v3::InvitationRecipient::UserId {
+            user_id: user_id.clone(),
+        },
+    );
+    request.reason = Some(format!("Invited by {}", moderator));
+    let plain = match state.client.send_request(request).await {
+        Ok(_) => {
+            state.count_invite(&room_id).await;
+            log::warn!(
+                "admin-initiated invite of {} to room {} by {}",
+                &user_id,
+                &room_id,
+                moderator
+            );
+            format!("Invited {} to {}", user_id, room)
+        }
+        Err(err) => {
+            log::error!(
+                "failed to invite user {} to room {}: {}",
+                &user_id,
+                &room_id,
+                redact(&err)
+            );
+            format!("Failed to invite {} to {}: {}", user_id, room, redact(&err))
+        }
+    };
+    (plain.clone(), html! { (plain) })
+}
+
+async fn pending(state: &AppState) -> (String, Markup) {
+    let pending = match state.sessions.pending().await {
+        Ok(pending) => pending,
+        Err(err) => {
+            let plain = format!("Listing pending invites failed: {}", redact(&err));
+            return (plain.clone(), html! { (plain) });
+        }
+    };
+    let mut pending = pending
+        .iter()
+        .map(|(token, invite)| {
+            (
+                token
+                    .chars()
+                    .take(admin::SHOWN_TOKEN_PREFIX)
+                    .collect::<String>(),
+                invite
+                    .user_ids
+                    .iter()
+                    .map(ToString::to_string)
+                    .chain(
+                        (!invite.emails.is_empty())
+                            .then(|| format!("{} email addresses", invite.emails.len())),
+                    )
+                    .collect::<Vec<_>>()
+                    .join(", "),
+                invite
+                    .room_ids
+                    .iter()
+                    .map(ToString::to_string)
+                    .collect::<Vec<_>>()
+                    .join(", "),
+                invite.age_seconds(),
+            )
+        })
+        .collect::<Vec<_>>();
+    pending.sort_by_key(|(_, _, _, age)| *age);
+    let plain = std::iter::once(format!("{} pending GitHub logins:", pending.len()))
+        .chain(pending.iter().map(|(token, users, rooms, age)| {
+            format!("{}: {} to {}, {}s ago", token, users, rooms, age)
+        }))
+        .collect::<Vec<_>>()
+        .join("\n");
+    let html = html! {
+        p { (pending.len()) " pending GitHub logins:" }
+        ul {
+            @for (token, users, rooms, age) in &pending {
+                li { code { (token) } ": " (users) " to " (rooms) ", " (age) "s ago" }
+            }
+        }
+    };
+    (plain, html)
+}
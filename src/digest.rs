@@ -0,0 +1,150 @@
+use std::{collections::HashMap, sync::Arc, time::Duration};
+
+use chrono::{DateTime, Utc};
+use maud::html;
+use ruma::OwnedRoomId;
+
+use crate::{audit, store::Entry, webhook::EventKind, AppState};
+
+/// How often the scheduler checks whether a digest is due.
+const CHECK_INTERVAL: Duration = Duration::from_secs(60);
+/// GitHub users listed in the digest as top vouchers.
+const TOP_VOUCHERS: usize = 5;
+
+/// Post a summary of the audit store to the admin room every `--digest-interval-hours`.
+pub fn spawn(state: Arc<AppState>) {
+    let Some(interval) = state.digest_interval else {
+        return;
+    };
+    tokio::spawn(async move {
+        loop {
+            tick(&state, interval).await;
+            tokio::time::sleep(CHECK_INTERVAL).await;
+        }
+    });
+}
+
+async fn tick(state: &AppState, interval: Duration) {
+    let Some(store) = &state.store else {
+        return;
+    };
+    let now = Utc::now();
+    // The time of the last digest is persisted, so restarts neither repeat nor skip one.
+    let Some(since) = store.read(|data| data.last_digest).await else {
+        store.update(|data| data.last_digest = Some(now)).await;
+        return;
+    };
+    if (now - since).to_std().unwrap_or_default() < interval {
+        return;
+    }
+
+    let entries = store
+        .entries(|entries| {
+            entries
+                .iter()
+                .filter(|entry| entry.timestamp > since && entry.timestamp <= now)
+                .cloned()
+                .collect::<Vec<_>>()
+        })
+        .await;
+    if (!entries.is_empty() || !state.digest_skip_empty) && !post(state, since, &entries).await {
+        return;
+    }
+    store.update(|data| data.last_digest = Some(now)).await;
+}
+
+fn sorted<K: Ord>(counts: HashMap<K, usize>) -> Vec<(K, usize)> {
+    let mut counts = counts.into_iter().collect::<Vec<_>>();
+    counts.sort_by(|(a, a_count), (b, b_count)| b_count.cmp(a_count).then(a.cmp(b)));
+    counts
+}
+
+/// Compose and post the digest, returning whether it reached the admin room.
+async fn post(state: &AppState, since: DateTime<Utc>, entries: &[Entry]) -> bool {
+    let since = since.format("%Y-%m-%d %H:%M UTC").to_string();
+    if entries.is_empty() {
+        let plain = format!("No invite activity since {}.", since);
+        return audit::post(state, plain.clone(), html! { (plain) })
+            .await
+            .is_some();
+    }
+
+    let mut sent = HashMap::<OwnedRoomId, usize>::new();
+    let mut denied = HashMap::<String, usize>::new();
+    let mut vouchers = HashMap::<String, usize>::new();
+    let mut failed = 0;
+    let mut dry_runs = 0;
+    for entry in entries {
+        match entry.event {
+            EventKind::InviteSent => {
+                *sent.entry(entry.room_id.clone()).or_default() += 1;
+                *vouchers.entry(entry.github_login.clone()).or_default() += 1;
+            }
+            EventKind::InviteDenied => {
+                if let Some(denial) = entry.reason_code {
+                    *denied.entry(denial.label().to_string()).or_default() += 1;
+                }
+            }
+            EventKind::InviteFailed => failed += 1,
+            EventKind::InviteDryRun => dry_runs += 1,
+            EventKind::Test => {}
+        }
+    }
+    let mut rooms = vec![];
+    for (room_id, count) in sorted(sent) {
+        rooms.push((state.room_name(&room_id).await, count));
+    }
+    let denied = sorted(denied);
+    let mut vouchers = sorted(vouchers);
+    vouchers.truncate(TOP_VOUCHERS);
+
+    let lines = |title: &str, counts: &[(String, usize)]| {
+        let counts = counts
+            .iter()
+            .map(|(name, count)| format!("{}: {}", name, count))
+            .collect::<Vec<_>>();
+        format!(
+            "{}: {}",
+            title,
+            if counts.is_empty() {
+                "none".to_string()
+            } else {
+                counts.join(", ")
+            }
+        )
+    };
+    let plain = [
+        format!("Invite digest since {}", since),
+        lines("Invites sent", &rooms),
+        lines("Denials", &denied),
+        format!("Failed invites: {}", failed),
+        format!("Dry runs: {}", dry_runs),
+        lines("Top vouchers", &vouchers),
+    ]
+    .join("\n");
+    let list = |counts: &[(String, usize)]| {
+        html! {
+            @if counts.is_empty() {
+                p { "none" }
+            } @else {
+                ul {
+                    @for (name, count) in counts {
+                        li { (name) ": " (count) }
+                    }
+                }
+            }
+        }
+    };
+    let html = html! {
+        h4 { "Invite digest since " (since) }
+        p { "Invites sent:" }
+        (list(&rooms))
+        p { "Denials:" }
+        (list(&denied))
+        p { "Failed invites: " (failed) }
+        p { "Dry runs: " (dry_runs) }
+        p { "Top vouchers:" }
+        (list(&vouchers))
+    };
+    audit::post(state, plain, html).await.is_some()
+}
@@ -0,0 +1,882 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fmt,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+use anyhow::Context;
+use axum::http::StatusCode;
+use ruma::{
+    api::{
+        client::{
+            self,
+            error::ErrorKind,
+            room::get_summary,
+            space::{get_hierarchy, SpaceHierarchyRoomsChunk},
+        },
+        error::FromHttpResponseError,
+        OutgoingRequest,
+    },
+    events::{
+        direct::DirectEventContent,
+        room::{
+            canonical_alias::RoomCanonicalAliasEventContent,
+            create::RoomCreateEventContent,
+            join_rules::{JoinRule, RoomJoinRulesEventContent},
+            name::RoomNameEventContent,
+            power_levels::{RoomPowerLevels, RoomPowerLevelsEventContent},
+            tombstone::RoomTombstoneEventContent,
+            topic::RoomTopicEventContent,
+        },
+        GlobalAccountDataEventType, StateEventType,
+    },
+    room::RoomType,
+    space::SpaceRoomJoinRule,
+    uint, OwnedRoomId, OwnedRoomOrAliasId, RoomId, UInt, UserId,
+};
+use tokio::{sync::Semaphore, task::JoinSet};
+
+use crate::{config::RoomSettings, secret::redact, token, MatrixClient, RoomInfo, SpaceParent};
+
+pub type Rooms = HashMap<OwnedRoomId, RoomInfo>;
+
+/// Operator-configured restrictions on which rooms are served.
+#[derive(Clone, Default)]
+pub struct RoomFilter {
+    pub include: Option<HashSet<OwnedRoomId>>,
+    pub exclude: HashSet<OwnedRoomId>,
+    pub include_dm_rooms: bool,
+    pub hide_public_rooms: bool,
+    pub spaces: Vec<OwnedRoomId>,
+    pub auto_join_children: bool,
+    /// Rooms inspected at once, see [`discover`].
+    pub concurrency: usize,
+}
+
+pub async fn resolve_room(client: &MatrixClient, room: &str) -> anyhow::Result<OwnedRoomId> {
+    let room_or_alias = OwnedRoomOrAliasId::try_from(room)
+        .with_context(|| format!("invalid room id or alias {}", room))?;
+    match OwnedRoomId::try_from(room_or_alias) {
+        Ok(room_id) => Ok(room_id),
+        Err(alias) => Ok(client
+            .send_request(client::alias::get_alias::v3::Request::new(alias))
+            .await
+            .with_context(|| format!("failed to resolve room alias {}", room))?
+            .room_id),
+    }
+}
+
+impl RoomFilter {
+    /// Build a filter from room ids and aliases, resolving the aliases via the homeserver.
+    pub async fn resolve(
+        client: &MatrixClient,
+        settings: &RoomSettings,
+    ) -> anyhow::Result<RoomFilter> {
+        let mut filter = RoomFilter {
+            include_dm_rooms: settings.include_dm_rooms,
+            hide_public_rooms: settings.hide_public_rooms,
+            auto_join_children: settings.auto_join_children,
+            concurrency: settings.discovery_concurrency,
+            ..Default::default()
+        };
+        for space in &settings.space {
+            filter.spaces.push(resolve_room(client, space).await?);
+        }
+        if !settings.room.is_empty() {
+            let mut rooms = HashSet::new();
+            for room in &settings.room {
+                rooms.insert(resolve_room(client, room).await?);
+            }
+            filter.include = Some(rooms);
+        }
+        for room in &settings.exclude_room {
+            filter.exclude.insert(resolve_room(client, room).await?);
+        }
+        Ok(filter)
+    }
+
+    /// Whether a room is hidden from the invite table because anyone can join it.
+    pub fn hides(&self, room: &RoomInfo) -> bool {
+        self.hide_public_rooms && room.join_rule == SpaceRoomJoinRule::Public
+    }
+
+    /// Split discovered rooms into those offered for invites and the hidden public ones.
+    pub fn partition(&self, rooms: Rooms) -> (Rooms, Rooms) {
+        rooms.into_iter().partition(|(_, room)| !self.hides(room))
+    }
+
+    /// Whether a room passes `--room` and `--exclude-room`, space children included.
+    pub fn allows(&self, room_id: &RoomId) -> bool {
+        !self.exclude.contains(room_id)
+            && self
+                .include
+                .as_ref()
+                .map_or(true, |include| include.contains(room_id))
+    }
+}
+
+/// Why discovery left out a room.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, serde::Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SkipReason {
+    /// Configured with `--room` or listed in a space, but the bot is not in it.
+    NotJoined,
+    /// Space child the bot could not join with `--auto-join-children`.
+    CannotJoin,
+    Excluded,
+    DirectMessage,
+    NoInvitePermission,
+    /// Reading its power levels or summary failed, see the log.
+    Failed,
+    /// Anyone can join it and `--hide-public-rooms` is set, see [`RoomFilter::partition`].
+    Public,
+}
+
+impl fmt::Display for SkipReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            SkipReason::NotJoined => "not joined",
+            SkipReason::CannotJoin => "cannot join",
+            SkipReason::Excluded => "excluded",
+            SkipReason::DirectMessage => "direct message",
+            SkipReason::NoInvitePermission => "no invite permission",
+            SkipReason::Failed => "failed to inspect",
+            SkipReason::Public => "public",
+        })
+    }
+}
+
+/// Rooms discovery settled on, and the ones it left out.
+#[derive(Default)]
+pub struct Discovery {
+    pub rooms: Rooms,
+    pub skipped: Vec<(OwnedRoomId, SkipReason)>,
+}
+
+impl Discovery {
+    fn skip(&mut self, room_id: &RoomId, reason: SkipReason) {
+        if !self.skipped.iter().any(|(skipped, _)| skipped == room_id) {
+            self.skipped.push((room_id.to_owned(), reason));
+        }
+    }
+}
+
+/// Collect the joined rooms the bot is allowed to invite users to.
+pub async fn discover_rooms(
+    client: &MatrixClient,
+    user_id: &UserId,
+    filter: &RoomFilter,
+) -> anyhow::Result<Rooms> {
+    Ok(discover(client, user_id, filter).await?.rooms)
+}
+
+/// Collect the rooms the bot is allowed to invite users to, recording why every other room
+/// it knows of is left out. Joined rooms are inspected `filter.concurrency` at a time, and a
+/// room failing to be inspected is skipped rather than failing the whole discovery.
+pub async fn discover(
+    client: &MatrixClient,
+    user_id: &UserId,
+    filter: &RoomFilter,
+) -> anyhow::Result<Discovery> {
+    let started = Instant::now();
+    let joined_rooms = send(client, client::membership::joined_rooms::v3::Request::new())
+        .await
+        .context("failed to list the joined rooms of the bot")?
+        .joined_rooms;
+
+    let mut discovery = Discovery::default();
+    if let Some(include) = &filter.include {
+        for room_id in include
+            .iter()
+            .filter(|room_id| !joined_rooms.contains(room_id))
+        {
+            log::warn!(
+                "Configured room {} is not joined by the bot, ignoring",
+                room_id
+            );
+            discovery.skip(room_id, SkipReason::NotJoined);
+        }
+    }
+
+    let direct_rooms = if filter.include_dm_rooms {
+        HashSet::new()
+    } else {
+        direct_rooms(client, user_id).await?
+    };
+
+    for space_id in &filter.spaces {
+        discover_space(
+            client,
+            user_id,
+            filter,
+            space_id,
+            &joined_rooms,
+            &mut discovery,
+        )
+        .await
+        .with_context(|| format!("failed to walk space {}", space_id))?;
+    }
+    let permits = Arc::new(Semaphore::new(filter.concurrency.max(1)));
+    let mut tasks = JoinSet::new();
+    for room_id in joined_rooms {
+        if discovery.rooms.contains_key(&room_id) {
+            continue;
+        }
+        if !filter.allows(&room_id) {
+            log::debug!("Room {} is excluded by configuration, ignoring", &room_id);
+            discovery.skip(&room_id, SkipReason::Excluded);
+            continue;
+        }
+        if direct_rooms.contains(&room_id) {
+            log::debug!("Room {} is a direct message room, ignoring", &room_id);
+            discovery.skip(&room_id, SkipReason::DirectMessage);
+            continue;
+        }
+        let client = client.clone();
+        let user_id = user_id.to_owned();
+        let permits = permits.clone();
+        tasks.spawn(async move {
+            let _permit = permits.acquire_owned().await;
+            let summary = summary_if_invitable(&client, &user_id, &room_id).await;
+            (room_id, summary)
+        });
+    }
+
+    let mut joined_spaces = vec![];
+    while let Some(result) = tasks.join_next().await {
+        let (room_id, summary) = result.context("room inspection task failed")?;
+        let Summary { room, room_type } = match summary {
+            Ok(Some(summary)) => summary,
+            Ok(None) => {
+                discovery.skip(&room_id, SkipReason::NoInvitePermission);
+                continue;
+            }
+            Err(err) => {
+                log::error!(
+                    "Failed to inspect room {}, ignoring: {:#}",
+                    &room_id,
+                    redact(&err)
+                );
+                discovery.skip(&room_id, SkipReason::Failed);
+                continue;
+            }
+        };
+        if !filter.include_dm_rooms
+            && room.members == Some(2)
+            && room.name.is_none()
+            && room.canonical_alias.is_none()
+        {
+            log::debug!(
+                "Room {} looks like a direct message room, ignoring",
+                &room_id
+            );
+            discovery.skip(&room_id, SkipReason::DirectMessage);
+            continue;
+        }
+        if room_type == Some(RoomType::Space) {
+            joined_spaces.push(room_id.clone());
+        }
+        discovery.rooms.insert(room.room_id.clone(), room);
+    }
+
+    for space_id in joined_spaces {
+        match walk_hierarchy(client, &space_id, Some(uint!(1))).await {
+            Ok(chunks) => {
+                for (room_id, parent) in parents(&chunks) {
+                    if let Some(room) = discovery.rooms.get_mut(&room_id) {
+                        room.parent.get_or_insert(parent);
+                    }
+                }
+            }
+            Err(err) => log::warn!("Failed to walk space {}: {:#}", &space_id, err),
+        }
+    }
+    follow_tombstones(client, user_id, filter, &mut discovery.rooms).await;
+    let failed = discovery
+        .skipped
+        .iter()
+        .filter(|(_, reason)| *reason == SkipReason::Failed)
+        .count();
+    log::warn!(
+        "Discovered {} rooms in {:.1}s, skipped {}, of which {} failed; read room summaries {}",
+        discovery.rooms.len(),
+        started.elapsed().as_secs_f64(),
+        discovery.skipped.len(),
+        failed,
+        if SUMMARY_UNSUPPORTED.load(Ordering::Relaxed) {
+            "from room state"
+        } else {
+            "through MSC3266"
+        }
+    );
+    Ok(discovery)
+}
+
+/// First delay after a rate limited request, doubling on every further attempt.
+const RATE_LIMIT_BACKOFF: Duration = Duration::from_secs(1);
+const RATE_LIMIT_ATTEMPTS: u32 = 5;
+
+type RequestError = ruma::client::Error<token::ClientError, client::Error>;
+
+/// Send a request, backing off and retrying while the homeserver answers 429.
+async fn send<R>(client: &MatrixClient, request: R) -> Result<R::IncomingResponse, RequestError>
+where
+    R: OutgoingRequest<EndpointError = client::Error> + Clone,
+{
+    let mut backoff = RATE_LIMIT_BACKOFF;
+    for _ in 1..RATE_LIMIT_ATTEMPTS {
+        match client.send_request(request.clone()).await {
+            Err(ruma::client::Error::FromHttpResponse(FromHttpResponseError::Server(err)))
+                if err.status_code == StatusCode::TOO_MANY_REQUESTS =>
+            {
+                log::warn!("Rate limited by the homeserver, retrying in {:?}", backoff);
+                tokio::time::sleep(backoff).await;
+                backoff *= 2;
+            }
+            result => return result,
+        }
+    }
+    client.send_request(request).await
+}
+
+/// Replacement named by the m.room.tombstone event of an upgraded room.
+async fn tombstone(client: &MatrixClient, room_id: &RoomId) -> anyhow::Result<Option<OwnedRoomId>> {
+    match client
+        .send_request(client::state::get_state_events_for_key::v3::Request::new(
+            room_id.to_owned(),
+            StateEventType::RoomTombstone,
+            "".to_string(),
+        ))
+        .await
+    {
+        Ok(response) => Ok(Some(
+            response
+                .content
+                .deserialize_as::<RoomTombstoneEventContent>()?
+                .replacement_room,
+        )),
+        Err(ruma::client::Error::FromHttpResponse(FromHttpResponseError::Server(err)))
+            if err.status_code == StatusCode::NOT_FOUND =>
+        {
+            Ok(None)
+        }
+        Err(err) => Err(err.into()),
+    }
+}
+
+/// Join the successor of an upgraded room and inspect it.
+async fn follow(
+    client: &MatrixClient,
+    user_id: &UserId,
+    room_id: &RoomId,
+) -> anyhow::Result<Option<RoomInfo>> {
+    client
+        .send_request(client::membership::join_room_by_id::v3::Request::new(
+            room_id.to_owned(),
+        ))
+        .await?;
+    inspect_room(client, user_id, room_id).await
+}
+
+/// Serve the successors of upgraded rooms in their place, keeping the display name. Rooms whose
+/// successor cannot be served stay listed, marked with the replacement.
+async fn follow_tombstones(
+    client: &MatrixClient,
+    user_id: &UserId,
+    filter: &RoomFilter,
+    rooms: &mut Rooms,
+) {
+    let room_ids = rooms.keys().cloned().collect::<Vec<_>>();
+    for room_id in room_ids {
+        let replacement = match tombstone(client, &room_id).await {
+            Ok(Some(replacement)) => replacement,
+            Ok(None) => continue,
+            Err(err) => {
+                log::warn!(
+                    "Failed to check room {} for a tombstone: {:#}",
+                    &room_id,
+                    err
+                );
+                continue;
+            }
+        };
+        let successor = match rooms.remove(&replacement) {
+            Some(successor) => Ok(Some(successor)),
+            None if filter.exclude.contains(&replacement) => Ok(None),
+            None => follow(client, user_id, &replacement).await,
+        };
+        match successor {
+            Ok(Some(mut successor)) => {
+                let Some(old) = rooms.remove(&room_id) else {
+                    continue;
+                };
+                successor.name = successor.name.or(old.name);
+                successor.parent = successor.parent.or(old.parent);
+                successor.suggested |= old.suggested;
+                successor.predecessor = Some(room_id.clone());
+                log::warn!(
+                    "Room {} was upgraded, serving its successor {} instead",
+                    &room_id,
+                    &replacement
+                );
+                rooms.insert(replacement, successor);
+            }
+            result => {
+                log::error!(
+                    "Room {} was upgraded to {}, but the bot cannot serve the successor{}",
+                    &room_id,
+                    &replacement,
+                    match result {
+                        Err(err) => format!(": {}", redact(&err)),
+                        _ => "".to_string(),
+                    }
+                );
+                if let Some(room) = rooms.get_mut(&room_id) {
+                    room.replacement = Some(replacement);
+                }
+            }
+        }
+    }
+}
+
+async fn walk_hierarchy(
+    client: &MatrixClient,
+    space_id: &RoomId,
+    max_depth: Option<UInt>,
+) -> anyhow::Result<Vec<SpaceHierarchyRoomsChunk>> {
+    let mut chunks = vec![];
+    let mut from = None;
+    loop {
+        let mut request = get_hierarchy::v1::Request::new(space_id.to_owned());
+        request.from = from;
+        request.max_depth = max_depth;
+        let response = client.send_request(request).await?;
+        chunks.extend(response.rooms);
+        match response.next_batch {
+            Some(next_batch) => from = Some(next_batch),
+            None => break,
+        }
+    }
+    Ok(chunks)
+}
+
+/// Map every child room in a hierarchy to the space listing it.
+fn parents(chunks: &[SpaceHierarchyRoomsChunk]) -> HashMap<OwnedRoomId, SpaceParent> {
+    let mut parents = HashMap::new();
+    for chunk in chunks {
+        let parent = SpaceParent {
+            room_id: chunk.room_id.clone(),
+            name: chunk.name.clone(),
+        };
+        for event in &chunk.children_state {
+            if let Ok(event) = event.deserialize() {
+                parents
+                    .entry(event.state_key)
+                    .or_insert_with(|| parent.clone());
+            }
+        }
+    }
+    parents
+}
+
+/// Collect the child rooms of a space the bot is (or can become) able to invite users to.
+async fn discover_space(
+    client: &MatrixClient,
+    user_id: &UserId,
+    filter: &RoomFilter,
+    space_id: &RoomId,
+    joined_rooms: &[OwnedRoomId],
+    discovery: &mut Discovery,
+) -> anyhow::Result<()> {
+    let chunks = walk_hierarchy(client, space_id, None).await?;
+    let parents = parents(&chunks);
+
+    let suggested = chunks
+        .iter()
+        .flat_map(|chunk| &chunk.children_state)
+        .filter_map(|event| event.deserialize().ok())
+        .filter(|event| event.content.suggested)
+        .map(|event| event.state_key)
+        .collect::<HashSet<_>>();
+
+    for chunk in chunks {
+        let room_id = chunk.room_id.clone();
+        if room_id == space_id || chunk.room_type == Some(RoomType::Space) {
+            continue;
+        }
+        if !filter.allows(&room_id) {
+            log::debug!("Room {} is excluded by configuration, ignoring", &room_id);
+            discovery.skip(&room_id, SkipReason::Excluded);
+            continue;
+        }
+        if !joined_rooms.contains(&room_id) {
+            if !filter.auto_join_children {
+                log::warn!(
+                    "Space child {} of {} is not joined by the bot, ignoring",
+                    &room_id,
+                    space_id
+                );
+                discovery.skip(&room_id, SkipReason::NotJoined);
+                continue;
+            }
+            if !matches!(
+                chunk.join_rule,
+                SpaceRoomJoinRule::Public
+                    | SpaceRoomJoinRule::Restricted
+                    | SpaceRoomJoinRule::KnockRestricted
+            ) {
+                log::warn!(
+                    "Space child {} of {} has join rule {}, cannot join it, ignoring",
+                    &room_id,
+                    space_id,
+                    chunk.join_rule
+                );
+                discovery.skip(&room_id, SkipReason::CannotJoin);
+                continue;
+            }
+            if let Err(err) = client
+                .send_request(client::membership::join_room_by_id::v3::Request::new(
+                    room_id.clone(),
+                ))
+                .await
+            {
+                log::warn!(
+                    "Failed to join space child {} of {}: {}, ignoring",
+                    &room_id,
+                    space_id,
+                    err
+                );
+                discovery.skip(&room_id, SkipReason::CannotJoin);
+                continue;
+            }
+            log::warn!("Joined space child {} of {}", &room_id, space_id);
+        }
+        match can_invite(client, user_id, &room_id).await {
+            Ok(true) => {}
+            Ok(false) => {
+                discovery.skip(&room_id, SkipReason::NoInvitePermission);
+                continue;
+            }
+            Err(err) => {
+                log::error!(
+                    "Failed to inspect room {}, ignoring: {:#}",
+                    &room_id,
+                    redact(&err)
+                );
+                discovery.skip(&room_id, SkipReason::Failed);
+                continue;
+            }
+        }
+        discovery.rooms.insert(
+            room_id.clone(),
+            RoomInfo {
+                suggested: suggested.contains(&room_id),
+                parent: parents.get(&room_id).cloned(),
+                members: Some(chunk.num_joined_members.into()),
+                topic: chunk.topic,
+                avatar_url: chunk.avatar_url,
+                room_id,
+                canonical_alias: chunk.canonical_alias,
+                name: chunk.name,
+                join_rule: chunk.join_rule,
+                predecessor: None,
+                replacement: None,
+            },
+        );
+    }
+    Ok(())
+}
+
+/// Rooms listed in the bot's m.direct account data.
+async fn direct_rooms(
+    client: &MatrixClient,
+    user_id: &UserId,
+) -> anyhow::Result<HashSet<OwnedRoomId>> {
+    let response = client
+        .send_request(client::config::get_global_account_data::v3::Request::new(
+            user_id.to_owned(),
+            GlobalAccountDataEventType::Direct,
+        ))
+        .await;
+    let content = match response {
+        Ok(response) => response
+            .account_data
+            .deserialize_as::<DirectEventContent>()
+            .context("invalid m.direct account data")?,
+        Err(ruma::client::Error::FromHttpResponse(FromHttpResponseError::Server(err)))
+            if err.status_code == StatusCode::NOT_FOUND =>
+        {
+            return Ok(HashSet::new())
+        }
+        Err(err) => return Err(err.into()),
+    };
+    Ok(content.values().flatten().cloned().collect())
+}
+
+/// Fetch the summary of a room, or `None` if the bot may not invite users to it.
+pub async fn inspect_room(
+    client: &MatrixClient,
+    user_id: &UserId,
+    room_id: &RoomId,
+) -> anyhow::Result<Option<RoomInfo>> {
+    Ok(summary_if_invitable(client, user_id, room_id)
+        .await?
+        .map(|summary| summary.room))
+}
+
+async fn summary_if_invitable(
+    client: &MatrixClient,
+    user_id: &UserId,
+    room_id: &RoomId,
+) -> anyhow::Result<Option<Summary>> {
+    if !can_invite(client, user_id, room_id).await? {
+        return Ok(None);
+    }
+    Ok(Some(summarize(client, room_id).await?))
+}
+
+/// Summary of a room regardless of the bot's permissions in it.
+pub async fn describe_room(client: &MatrixClient, room_id: &RoomId) -> anyhow::Result<RoomInfo> {
+    Ok(summarize(client, room_id).await?.room)
+}
+
+async fn can_invite(
+    client: &MatrixClient,
+    user_id: &UserId,
+    room_id: &RoomId,
+) -> anyhow::Result<bool> {
+    // Rooms without usable power levels get the defaults of the spec, as homeservers apply them.
+    let content = match send(
+        client,
+        client::state::get_state_events_for_key::v3::Request::new(
+            room_id.to_owned(),
+            StateEventType::RoomPowerLevels,
+            "".to_string(),
+        ),
+    )
+    .await
+    {
+        Ok(response) => response
+            .content
+            .deserialize_as::<RoomPowerLevelsEventContent>()
+            .unwrap_or_else(|err| {
+                log::warn!(
+                    "Invalid power levels in room {} ({}), assuming the defaults",
+                    room_id,
+                    err
+                );
+                RoomPowerLevelsEventContent::default()
+            }),
+        Err(ruma::client::Error::FromHttpResponse(FromHttpResponseError::Server(err)))
+            if err.status_code == StatusCode::NOT_FOUND =>
+        {
+            log::warn!(
+                "Room {} has no power levels, assuming the defaults",
+                room_id
+            );
+            RoomPowerLevelsEventContent::default()
+        }
+        Err(err) => {
+            return Err(err)
+                .with_context(|| format!("failed to read the power levels of room {}", room_id))
+        }
+    };
+    let power_levels: RoomPowerLevels = content.into();
+    if !power_levels.user_can_invite(user_id) {
+        log::warn!(
+            "Do not have invite permission for room {}, ignoring",
+            room_id
+        );
+        return Ok(false);
+    };
+    Ok(true)
+}
+
+/// What discovery reads of a room.
+struct Summary {
+    room: RoomInfo,
+    room_type: Option<RoomType>,
+}
+
+/// Set once the homeserver turned out not to support MSC3266, see [`summarize`].
+static SUMMARY_UNSUPPORTED: AtomicBool = AtomicBool::new(false);
+
+/// Whether the homeserver does not know the endpoint of a request.
+fn unrecognized(err: &RequestError) -> bool {
+    match err {
+        ruma::client::Error::FromHttpResponse(FromHttpResponseError::Server(err)) => {
+            matches!(err.error_kind(), Some(ErrorKind::Unrecognized))
+                || matches!(
+                    err.status_code,
+                    StatusCode::NOT_FOUND | StatusCode::METHOD_NOT_ALLOWED
+                )
+        }
+        _ => false,
+    }
+}
+
+/// Summarize a room through MSC3266, or from its state on homeservers without it, such as
+/// Conduit and older Synapse.
+async fn summarize(client: &MatrixClient, room_id: &RoomId) -> anyhow::Result<Summary> {
+    if !SUMMARY_UNSUPPORTED.load(Ordering::Relaxed) {
+        match send(
+            client,
+            get_summary::msc3266::Request::new(room_id.to_owned().into(), vec![]),
+        )
+        .await
+        {
+            Ok(summary) => {
+                return Ok(Summary {
+                    room_type: summary.room_type.clone(),
+                    room: room_info(summary),
+                })
+            }
+            Err(err) if unrecognized(&err) => {
+                if !SUMMARY_UNSUPPORTED.swap(true, Ordering::Relaxed) {
+                    log::warn!(
+                        "The homeserver does not support MSC3266 room summaries ({}), reading room state instead",
+                        err
+                    );
+                }
+            }
+            Err(err) => {
+                return Err(err)
+                    .with_context(|| format!("failed to fetch the summary of room {}", room_id))
+            }
+        }
+    }
+    summarize_from_state(client, room_id).await
+}
+
+/// Content of a state event with an empty state key, `None` if the room has none.
+async fn state_content<C: serde::de::DeserializeOwned>(
+    client: &MatrixClient,
+    room_id: &RoomId,
+    event_type: StateEventType,
+) -> anyhow::Result<Option<C>> {
+    match send(
+        client,
+        client::state::get_state_events_for_key::v3::Request::new(
+            room_id.to_owned(),
+            event_type.clone(),
+            "".to_string(),
+        ),
+    )
+    .await
+    {
+        Ok(response) => {
+            Ok(Some(response.content.deserialize_as::<C>().with_context(
+                || format!("invalid {} in room {}", event_type, room_id),
+            )?))
+        }
+        Err(ruma::client::Error::FromHttpResponse(FromHttpResponseError::Server(err)))
+            if err.status_code == StatusCode::NOT_FOUND =>
+        {
+            Ok(None)
+        }
+        Err(err) => {
+            Err(err).with_context(|| format!("failed to read {} of room {}", event_type, room_id))
+        }
+    }
+}
+
+/// Summary composed from the state events of a room. Missing events leave their fields empty,
+/// a missing join rule counts as invite only, and the member count is unknown.
+async fn summarize_from_state(client: &MatrixClient, room_id: &RoomId) -> anyhow::Result<Summary> {
+    let name = state_content::<RoomNameEventContent>(client, room_id, StateEventType::RoomName)
+        .await?
+        .map(|content| content.name)
+        .filter(|name| !name.is_empty());
+    let canonical_alias = state_content::<RoomCanonicalAliasEventContent>(
+        client,
+        room_id,
+        StateEventType::RoomCanonicalAlias,
+    )
+    .await?
+    .and_then(|content| content.alias);
+    let join_rule =
+        state_content::<RoomJoinRulesEventContent>(client, room_id, StateEventType::RoomJoinRules)
+            .await?
+            .map_or(SpaceRoomJoinRule::Invite, |content| {
+                space_join_rule(&content.join_rule)
+            });
+    let topic = state_content::<RoomTopicEventContent>(client, room_id, StateEventType::RoomTopic)
+        .await?
+        .map(|content| content.topic)
+        .filter(|topic| !topic.is_empty());
+    let room_type =
+        state_content::<RoomCreateEventContent>(client, room_id, StateEventType::RoomCreate)
+            .await?
+            .and_then(|content| content.room_type);
+    Ok(Summary {
+        room: RoomInfo {
+            room_id: room_id.to_owned(),
+            canonical_alias,
+            name,
+            join_rule,
+            suggested: false,
+            parent: None,
+            members: None,
+            topic,
+            avatar_url: None,
+            predecessor: None,
+            replacement: None,
+        },
+        room_type,
+    })
+}
+
+fn space_join_rule(join_rule: &JoinRule) -> SpaceRoomJoinRule {
+    match join_rule {
+        JoinRule::Public => SpaceRoomJoinRule::Public,
+        JoinRule::Knock => SpaceRoomJoinRule::Knock,
+        JoinRule::Restricted(_) => SpaceRoomJoinRule::Restricted,
+        JoinRule::KnockRestricted(_) => SpaceRoomJoinRule::KnockRestricted,
+        JoinRule::Private => SpaceRoomJoinRule::Private,
+        // Unknown join rules are treated like invite only, the most restrictive.
+        _ => SpaceRoomJoinRule::Invite,
+    }
+}
+
+fn room_info(summary: get_summary::msc3266::Response) -> RoomInfo {
+    RoomInfo {
+        room_id: summary.room_id,
+        canonical_alias: summary.canonical_alias,
+        name: summary.name,
+        join_rule: summary.join_rule,
+        suggested: false,
+        parent: None,
+        members: Some(summary.num_joined_members.into()),
+        topic: summary.topic,
+        avatar_url: summary.avatar_url,
+        predecessor: None,
+        replacement: None,
+    }
+}
+
+#[derive(Clone, serde::Serialize)]
+pub struct RoomsDiff {
+    pub added: Vec<OwnedRoomId>,
+    pub removed: Vec<OwnedRoomId>,
+}
+
+impl RoomsDiff {
+    pub fn between(old: &Rooms, new: &Rooms) -> RoomsDiff {
+        RoomsDiff {
+            added: new
+                .keys()
+                .filter(|room_id| !old.contains_key(*room_id))
+                .cloned()
+                .collect(),
+            removed: old
+                .keys()
+                .filter(|room_id| !new.contains_key(*room_id))
+                .cloned()
+                .collect(),
+        }
+    }
+}
@@ -0,0 +1,143 @@
+use std::{collections::HashSet, sync::Arc, time::Duration};
+
+use axum::http::StatusCode;
+use chrono::Utc;
+use maud::html;
+use ruma::{
+    api::{client::membership::kick_user, error::FromHttpResponseError},
+    events::room::member::MembershipState,
+    OwnedRoomId,
+};
+
+use crate::{audit, membership, secret::redact, webhook::EventKind, AppState};
+
+/// How often stale invites are looked for.
+const RUN_INTERVAL: Duration = Duration::from_secs(24 * 60 * 60);
+const KICK_REASON: &str = "Invite expired without being accepted";
+
+/// Rescind invites older than `--invite-expiry` that were never accepted.
+pub fn spawn(state: Arc<AppState>) {
+    let Some(expiry) = state.invite_expiry else {
+        return;
+    };
+    tokio::spawn(async move {
+        loop {
+            run(&state, expiry).await;
+            tokio::time::sleep(RUN_INTERVAL).await;
+        }
+    });
+}
+
+async fn run(state: &AppState, expiry: Duration) {
+    let Some(store) = &state.store else {
+        return;
+    };
+    let cutoff = Utc::now() - chrono::Duration::from_std(expiry).unwrap_or(chrono::Duration::MAX);
+    let stale = store
+        .entries(|entries| {
+            entries
+                .iter()
+                .filter(|entry| {
+                    entry.event == EventKind::InviteSent
+                        && entry.accepted.is_none()
+                        && entry.expired.is_none()
+                        && entry.timestamp < cutoff
+                })
+                .filter_map(|entry| Some((entry.user_id.clone()?, entry.room_id.clone())))
+                .collect::<HashSet<_>>()
+        })
+        .await;
+
+    let mut forbidden = HashSet::<OwnedRoomId>::new();
+    for (user_id, room_id) in stale {
+        if forbidden.contains(&room_id) {
+            continue;
+        }
+        // The invite may have been accepted since the store was last updated.
+        match membership::membership(&state.client, &room_id, &user_id).await {
+            Some(MembershipState::Invite) => {}
+            Some(MembershipState::Join) => {
+                let now = Utc::now();
+                store
+                    .amend(|entry| {
+                        let matches = entry.user_id.as_ref() == Some(&user_id)
+                            && entry.room_id == room_id
+                            && entry.event == EventKind::InviteSent
+                            && entry.accepted.is_none();
+                        if matches {
+                            entry.accepted = Some(now);
+                        }
+                        matches
+                    })
+                    .await;
+                continue;
+            }
+            _ => continue,
+        }
+
+        if state.invite_expiry_dry_run {
+            log::warn!(
+                "dry run: would rescind the invite of {} to room {}",
+                user_id,
+                room_id
+            );
+            continue;
+        }
+        let mut request = kick_user::v3::Request::new(room_id.clone(), user_id.clone());
+        request.reason = Some(KICK_REASON.to_string());
+        match state.client.send_request(request).await {
+            Ok(_) => {}
+            Err(ruma::client::Error::FromHttpResponse(FromHttpResponseError::Server(err)))
+                if err.status_code == StatusCode::FORBIDDEN =>
+            {
+                log::warn!(
+                    "cannot rescind expired invites in room {}, the bot lacks kick permission",
+                    room_id
+                );
+                forbidden.insert(room_id);
+                continue;
+            }
+            Err(err) => {
+                log::error!(
+                    "failed to rescind the invite of {} to room {}: {}",
+                    user_id,
+                    room_id,
+                    redact(&err)
+                );
+                continue;
+            }
+        }
+
+        let now = Utc::now();
+        store
+            .amend(|entry| {
+                let matches = entry.user_id.as_ref() == Some(&user_id)
+                    && entry.room_id == room_id
+                    && entry.event == EventKind::InviteSent
+                    && entry.accepted.is_none();
+                if matches {
+                    entry.expired = Some(now);
+                }
+                matches
+            })
+            .await;
+        log::warn!(
+            "rescinded the expired invite of {} to room {}",
+            user_id,
+            room_id
+        );
+        let room = state.room_name(&room_id).await;
+        audit::post(
+            state,
+            format!(
+                "{} was invited to {} but never joined, the invite was rescinded",
+                user_id, room
+            ),
+            html! {
+                a href=(format!("https://matrix.to/#/{}", user_id)) { (user_id) }
+                " was invited to " (room) " but never joined, the invite was rescinded"
+            },
+        )
+        .await;
+    }
+}
@@ -0,0 +1,166 @@
+//! The audit store as CSV for spreadsheets, `GET /admin/audit.csv`.
+//!
+//! Rows are written RFC 4180 style with a header row, and streamed in chunks so a large store
+//! is never copied as a whole; entries are only ever appended, so the chunks stay consistent.
+
+use std::{borrow::Cow, sync::Arc};
+
+use axum::{
+    body::Body,
+    extract::{Query, State},
+    http::{header, StatusCode},
+    response::{IntoResponse, Response},
+    Json,
+};
+use chrono::{DateTime, SecondsFormat, Utc};
+use ruma::OwnedRoomId;
+
+use crate::{
+    admin::Admin,
+    store::{Denial, Entry},
+    AppState,
+};
+
+pub const HEADER: &[&str] = &[
+    "timestamp",
+    "event",
+    "user_id",
+    "email_sha256",
+    "room_id",
+    "github_login",
+    "reason",
+    "reason_code",
+    "accepted",
+    "expired",
+    "terms",
+    "initiated_by",
+];
+
+/// Entries looked at per chunk of the response.
+const CHUNK: usize = 500;
+
+/// A field, quoted when it contains a separator, quote or line break.
+pub fn field(value: &str) -> Cow<'_, str> {
+    if value.contains([',', '"', '\r', '\n']) {
+        Cow::Owned(format!("\"{}\"", value.replace('"', "\"\"")))
+    } else {
+        Cow::Borrowed(value)
+    }
+}
+
+/// One line of fields, terminated by CRLF.
+pub fn row<'a>(fields: impl IntoIterator<Item = &'a str>) -> String {
+    let mut row = fields.into_iter().map(field).collect::<Vec<_>>().join(",");
+    row.push_str("\r\n");
+    row
+}
+
+fn timestamp(time: &DateTime<Utc>) -> String {
+    time.to_rfc3339_opts(SecondsFormat::Secs, true)
+}
+
+/// The columns of [`HEADER`] for one entry, empty where unknown.
+pub fn entry_row(entry: &Entry) -> String {
+    let optional = |time: &Option<DateTime<Utc>>| time.as_ref().map(timestamp).unwrap_or_default();
+    let event = serde_json::to_value(entry.event)
+        .ok()
+        .and_then(|event| event.as_str().map(str::to_string))
+        .unwrap_or_default();
+    row([
+        timestamp(&entry.timestamp).as_str(),
+        event.as_str(),
+        entry
+            .user_id
+            .as_ref()
+            .map_or("", |user_id| user_id.as_str()),
+        entry.email_sha256.as_deref().unwrap_or_default(),
+        entry.room_id.as_str(),
+        entry.github_login.as_str(),
+        entry.reason.as_deref().unwrap_or_default(),
+        entry.reason_code.map_or("", Denial::as_str),
+        optional(&entry.accepted).as_str(),
+        optional(&entry.expired).as_str(),
+        entry.terms.as_deref().unwrap_or_default(),
+        entry.initiated_by.as_deref().unwrap_or_default(),
+    ])
+}
+
+#[derive(Default, serde::Deserialize)]
+pub struct ExportQuery {
+    pub since: Option<DateTime<Utc>>,
+    pub until: Option<DateTime<Utc>>,
+    pub room_id: Option<OwnedRoomId>,
+    pub github_login: Option<String>,
+}
+
+impl ExportQuery {
+    pub fn matches(&self, entry: &Entry) -> bool {
+        self.since.map_or(true, |since| entry.timestamp >= since)
+            && self.until.map_or(true, |until| entry.timestamp < until)
+            && self
+                .room_id
+                .as_ref()
+                .map_or(true, |room_id| entry.room_id == *room_id)
+            && self
+                .github_login
+                .as_ref()
+                .map_or(true, |login| entry.github_login.eq_ignore_ascii_case(login))
+    }
+}
+
+/// Stream the matching entries of the audit store, oldest first.
+pub async fn audit_csv(
+    _: Admin,
+    State(state): State<Arc<AppState>>,
+    Query(query): Query<ExportQuery>,
+) -> Response {
+    if state.store.is_none() {
+        return (
+            StatusCode::NOT_FOUND,
+            Json(
+                serde_json::json!({ "error": "the audit store is not enabled, see --audit-store" }),
+            ),
+        )
+            .into_response();
+    }
+    let query = Arc::new(query);
+    let chunks = futures_util::stream::unfold(Some(0), move |next| {
+        let state = state.clone();
+        let query = query.clone();
+        async move {
+            let next = next?;
+            let store = state.store.as_ref()?;
+            let (chunk, more) = store
+                .entries(|entries| {
+                    let entries = entries.get(next..).unwrap_or_default();
+                    let chunk = entries
+                        .iter()
+                        .take(CHUNK)
+                        .filter(|entry| query.matches(entry))
+                        .map(entry_row)
+                        .collect::<String>();
+                    (chunk, entries.len() > CHUNK)
+                })
+                .await;
+            let chunk = match next {
+                0 => row(HEADER.iter().copied()) + &chunk,
+                _ => chunk,
+            };
+            Some((
+                Ok::<_, std::convert::Infallible>(chunk),
+                more.then_some(next + CHUNK),
+            ))
+        }
+    });
+    (
+        [
+            (header::CONTENT_TYPE, "text/csv; charset=utf-8"),
+            (
+                header::CONTENT_DISPOSITION,
+                "attachment; filename=\"audit.csv\"",
+            ),
+        ],
+        Body::from_stream(chunks),
+    )
+        .into_response()
+}
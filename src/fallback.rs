@@ -0,0 +1,79 @@
+//! Answers for unknown paths and wrong methods, styled like the other pages instead of axum's
+//! empty defaults, or as a JSON error object for clients asking for JSON.
+
+use std::sync::Arc;
+
+use axum::{
+    extract::{Request, State},
+    http::{
+        header::{ACCEPT, ALLOW, CONTENT_TYPE},
+        HeaderMap, StatusCode,
+    },
+    middleware::Next,
+    response::{IntoResponse, Response},
+    Json,
+};
+
+use crate::{i18n::t, page, AppState};
+
+pub fn wants_json(headers: &HeaderMap) -> bool {
+    headers
+        .get_all(ACCEPT)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .any(|value| value.contains("application/json"))
+}
+
+pub fn error(
+    state: &AppState,
+    nonce: &str,
+    json: bool,
+    status: StatusCode,
+    message: &str,
+) -> Response {
+    if json {
+        let body = serde_json::json!({ "status": status.as_u16(), "error": message });
+        (status, Json(body)).into_response()
+    } else {
+        page::error_page(state, nonce, status, message).into_response()
+    }
+}
+
+/// Router fallback for paths no route matches.
+pub async fn not_found(State(state): State<Arc<AppState>>, request: Request) -> Response {
+    error(
+        &state,
+        &page::nonce(request.extensions()),
+        wants_json(request.headers()),
+        StatusCode::NOT_FOUND,
+        &t("Page not found."),
+    )
+}
+
+/// Replace the empty 405 axum answers for a known path requested with another method, such as
+/// a GET of /invite after reloading the result of a form submission, keeping its Allow header.
+pub async fn method_not_allowed(
+    State(state): State<Arc<AppState>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let nonce = page::nonce(request.extensions());
+    let json = wants_json(request.headers());
+    let response = next.run(request).await;
+    if response.status() != StatusCode::METHOD_NOT_ALLOWED
+        || response.headers().contains_key(CONTENT_TYPE)
+    {
+        return response;
+    }
+    let mut answer = error(
+        &state,
+        &nonce,
+        json,
+        StatusCode::METHOD_NOT_ALLOWED,
+        &t("This address only accepts submitted forms, please start from the invite form."),
+    );
+    if let Some(allow) = response.headers().get(ALLOW) {
+        answer.headers_mut().insert(ALLOW, allow.clone());
+    }
+    answer
+}
@@ -0,0 +1,116 @@
+//! Country restrictions on invite submissions, see `--geoip-database`.
+//!
+//! Lookups fail open: addresses the database does not know, or errors reading it, let the
+//! submission through. The database is reopened on SIGHUP, as MaxMind updates it weekly.
+
+use std::{
+    collections::HashSet,
+    net::IpAddr,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use anyhow::Context;
+use axum::http::StatusCode;
+use maxminddb::{geoip2, Reader};
+use tokio::sync::RwLock;
+
+use crate::{i18n::t, logging::AUDIT_TARGET, AppState};
+
+/// The country database with the lists of `--blocked-countries` and `--allowed-countries`.
+pub struct GeoIp {
+    path: Option<PathBuf>,
+    reader: RwLock<Option<Arc<Reader<Vec<u8>>>>>,
+    blocked: HashSet<String>,
+    allowed: HashSet<String>,
+}
+
+fn open(path: &Path) -> anyhow::Result<Reader<Vec<u8>>> {
+    Reader::open_readfile(path)
+        .with_context(|| format!("failed to open geoip database {}", path.display()))
+}
+
+impl GeoIp {
+    /// Open the database; without one every submission is allowed.
+    pub fn new(
+        path: Option<PathBuf>,
+        blocked: HashSet<String>,
+        allowed: HashSet<String>,
+    ) -> anyhow::Result<GeoIp> {
+        let reader = path.as_deref().map(open).transpose()?.map(Arc::new);
+        Ok(GeoIp {
+            path,
+            reader: RwLock::new(reader),
+            blocked,
+            allowed,
+        })
+    }
+
+    /// Read the database file again, keeping the previous one if it is unreadable.
+    pub async fn reopen(&self) {
+        let Some(path) = &self.path else {
+            return;
+        };
+        match open(path) {
+            Ok(reader) => {
+                *self.reader.write().await = Some(Arc::new(reader));
+                log::warn!("reopened geoip database {}", path.display());
+            }
+            Err(err) => log::error!("{:#}, keeping the previous one", err),
+        }
+    }
+
+    /// ISO code of the country of an address, if the database knows it.
+    pub async fn country(&self, address: IpAddr) -> Option<String> {
+        let reader = self.reader.read().await.clone()?;
+        match reader.lookup::<geoip2::Country>(address.to_canonical()) {
+            Ok(country) => Some(country.country?.iso_code?.to_ascii_uppercase()),
+            Err(maxminddb::MaxMindDBError::AddressNotFoundError(_)) => None,
+            Err(err) => {
+                log::warn!(
+                    "geoip lookup of {} failed, letting it pass: {}",
+                    address,
+                    err
+                );
+                None
+            }
+        }
+    }
+
+    /// Whether submissions from the country are refused. Unknown countries never are.
+    pub fn blocks(&self, country: Option<&str>) -> bool {
+        let Some(country) = country else {
+            return false;
+        };
+        self.blocked.contains(country)
+            || !self.allowed.is_empty() && !self.allowed.contains(country)
+    }
+
+    fn enabled(&self) -> bool {
+        self.path.is_some()
+    }
+}
+
+/// Refuse submissions from blocked countries, with a page that does not say why. The country
+/// of every submission goes to the audit log.
+pub async fn check(state: &AppState, address: IpAddr) -> Result<(), (StatusCode, String)> {
+    if !state.geoip.enabled() {
+        return Ok(());
+    }
+    let country = state.geoip.country(address).await;
+    let blocked = state.geoip.blocks(country.as_deref());
+    let decision = if blocked { "blocked" } else { "allowed" };
+    log::warn!(
+        target: AUDIT_TARGET,
+        event = "invite_request",
+        decision = decision,
+        client_country = country.as_deref();
+        "invite request from {}: {}",
+        country.as_deref().unwrap_or("an unknown country"),
+        decision,
+    );
+    if blocked {
+        return Err((StatusCode::FORBIDDEN, t("This request cannot be accepted.")));
+    }
+    Ok(())
+}
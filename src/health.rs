@@ -0,0 +1,98 @@
+//! Periodic check of the access token of the bot, see `--token-check-interval`.
+
+use std::sync::Arc;
+
+use axum::{extract::State, http::StatusCode};
+use maud::html;
+use ruma::api::client::account::whoami;
+use tokio::sync::Mutex;
+
+use crate::{audit, secret::redact, startup, AppState};
+
+/// Outcome of the token checks.
+#[derive(Default)]
+pub struct Health {
+    /// Failed checks in a row, zero while the token works.
+    failures: Mutex<u64>,
+}
+
+/// Call whoami every `--token-check-interval`, alerting once when it starts failing and once
+/// when it recovers.
+pub fn spawn(state: Arc<AppState>) {
+    let Some(interval) = state.token_check_interval else {
+        return;
+    };
+    tokio::spawn(async move {
+        loop {
+            tokio::time::sleep(interval).await;
+            check(&state).await;
+        }
+    });
+}
+
+async fn check(state: &AppState) {
+    let result = state.client.send_request(whoami::v3::Request::new()).await;
+    let mut failures = state.health.failures.lock().await;
+    let message = match result {
+        Ok(_) if *failures == 0 => return,
+        Ok(_) => {
+            log::warn!(
+                "The access token check succeeded again after {} failures",
+                *failures
+            );
+            *failures = 0;
+            "The access token of the bot works again, invites can be sent.".to_string()
+        }
+        Err(err) => {
+            *failures += 1;
+            log::error!(
+                "The access token check failed, {} failures in a row: {}",
+                *failures,
+                redact(&err)
+            );
+            if *failures > 1 {
+                return;
+            }
+            format!(
+                "The access token check of the bot failed, invites cannot be sent until it recovers: {}",
+                redact(&err)
+            )
+        }
+    };
+    drop(failures);
+    alert(state, message).await;
+}
+
+/// Post to the admin room and `--token-alert-url`. An invalid token cannot post to the admin
+/// room, which is why the alert url exists.
+async fn alert(state: &AppState, message: String) {
+    audit::post(state, message.clone(), html! { (message) }).await;
+    let Some(url) = &state.token_alert_url else {
+        return;
+    };
+    if let Err(err) = state
+        .http_client
+        .post(url.clone())
+        .json(&serde_json::json!({ "text": message }))
+        .send()
+        .await
+        .and_then(|response| response.error_for_status())
+    {
+        log::error!(
+            "failed to post the token alert to {}: {}",
+            startup::redact_url(url.as_str()),
+            redact(&err)
+        );
+    }
+}
+
+/// Readiness probe, failing while the access token check does.
+pub async fn readyz(State(state): State<Arc<AppState>>) -> (StatusCode, String) {
+    match *state.health.failures.lock().await {
+        0 => (StatusCode::OK, "ready".to_string()),
+        failures => (
+            StatusCode::SERVICE_UNAVAILABLE,
+            format!("access token check failed {} times in a row", failures),
+        ),
+    }
+}
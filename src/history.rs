@@ -0,0 +1,219 @@
+//! Invite history for moderation bots, `GET /admin/audit`: who was invited, and which GitHub
+//! account vouched for it.
+//!
+//! Entries come newest first, a page at a time; the `next` cursor of a page continues below
+//! it, which stays stable as the audit store only ever appends. Reasons are free text, such as
+//! invite reasons and homeserver errors, so only their [`category`] is returned.
+
+use std::{collections::HashMap, sync::Arc};
+
+use axum::{
+    extract::{Query, State},
+    http::StatusCode,
+    Json,
+};
+use chrono::{DateTime, Utc};
+use ruma::{OwnedRoomId, OwnedUserId};
+
+use crate::{
+    admin::Admin,
+    store::{Denial, Entry},
+    webhook::EventKind,
+    AppState,
+};
+
+pub const DEFAULT_LIMIT: usize = 50;
+pub const MAX_LIMIT: usize = 500;
+
+/// Filters of a request, see [`HistoryQuery::parse`].
+#[derive(Debug, Default)]
+pub struct HistoryQuery {
+    pub user_id: Option<OwnedUserId>,
+    pub github_login: Option<String>,
+    pub room_id: Option<OwnedRoomId>,
+    pub decision: Option<EventKind>,
+    pub since: Option<DateTime<Utc>>,
+    pub until: Option<DateTime<Utc>>,
+    pub limit: usize,
+    /// Entries below this index of the store are returned.
+    pub cursor: Option<usize>,
+}
+
+fn decision(value: &str) -> Option<EventKind> {
+    match value {
+        "invite_sent" => Some(EventKind::InviteSent),
+        "invite_denied" => Some(EventKind::InviteDenied),
+        "invite_failed" => Some(EventKind::InviteFailed),
+        "invite_dry_run" => Some(EventKind::InviteDryRun),
+        _ => None,
+    }
+}
+
+fn time(value: &str) -> Result<DateTime<Utc>, String> {
+    DateTime::parse_from_rfc3339(value)
+        .map(|time| time.to_utc())
+        .map_err(|_| "expected an RFC 3339 timestamp, e.g. 2024-05-01T00:00:00Z".to_string())
+}
+
+impl HistoryQuery {
+    /// Parse query parameters, or return a message for every invalid one by name.
+    pub fn parse(params: &HashMap<String, String>) -> Result<Self, HashMap<String, String>> {
+        let mut query = HistoryQuery {
+            limit: DEFAULT_LIMIT,
+            ..Default::default()
+        };
+        let mut errors = HashMap::new();
+        for (name, value) in params {
+            let result = match name.as_str() {
+                "user_id" => OwnedUserId::try_from(value.as_str())
+                    .map(|user_id| query.user_id = Some(user_id))
+                    .map_err(|err| format!("not a Matrix user ID: {}", err)),
+                "github_login" => {
+                    query.github_login = Some(value.clone());
+                    Ok(())
+                }
+                "room_id" => OwnedRoomId::try_from(value.as_str())
+                    .map(|room_id| query.room_id = Some(room_id))
+                    .map_err(|err| format!("not a room ID: {}", err)),
+                "decision" => decision(value)
+                    .map(|decision| query.decision = Some(decision))
+                    .ok_or_else(|| {
+                        "expected invite_sent, invite_denied, invite_failed or invite_dry_run"
+                            .to_string()
+                    }),
+                "since" => time(value).map(|since| query.since = Some(since)),
+                "until" => time(value).map(|until| query.until = Some(until)),
+                "limit" => match value.parse() {
+                    Ok(limit) if (1..=MAX_LIMIT).contains(&limit) => {
+                        query.limit = limit;
+                        Ok(())
+                    }
+                    _ => Err(format!("expected a number from 1 to {}", MAX_LIMIT)),
+                },
+                "cursor" => value
+                    .parse()
+                    .map(|cursor| query.cursor = Some(cursor))
+                    .map_err(|_| "not a cursor returned as next".to_string()),
+                _ => Err("unknown parameter".to_string()),
+            };
+            if let Err(error) = result {
+                errors.insert(name.clone(), error);
+            }
+        }
+        if query
+            .since
+            .zip(query.until)
+            .is_some_and(|(since, until)| since > until)
+        {
+            errors.insert("until".to_string(), "is before since".to_string());
+        }
+        match errors.is_empty() {
+            true => Ok(query),
+            false => Err(errors),
+        }
+    }
+
+    pub fn matches(&self, entry: &Entry) -> bool {
+        entry.event != EventKind::Test
+            && self
+                .user_id
+                .as_ref()
+                .map_or(true, |user_id| entry.user_id.as_ref() == Some(user_id))
+            && self
+                .github_login
+                .as_ref()
+                .map_or(true, |login| entry.github_login.eq_ignore_ascii_case(login))
+            && self
+                .room_id
+                .as_ref()
+                .map_or(true, |room_id| entry.room_id == *room_id)
+            && self
+                .decision
+                .map_or(true, |decision| entry.event == decision)
+            && self.since.map_or(true, |since| entry.timestamp >= since)
+            && self.until.map_or(true, |until| entry.timestamp < until)
+    }
+}
+
+/// Why an invite was denied or failed, without the free text of the reason.
+pub fn category(entry: &Entry) -> Option<&'static str> {
+    match entry.event {
+        EventKind::InviteFailed => Some("homeserver_error"),
+        _ => entry.reason_code.map(Denial::as_str),
+    }
+}
+
+#[derive(Debug, serde::Serialize)]
+pub struct HistoryEntry {
+    pub timestamp: DateTime<Utc>,
+    pub decision: EventKind,
+    pub user_id: Option<OwnedUserId>,
+    pub email_sha256: Option<String>,
+    pub room_id: OwnedRoomId,
+    pub github_login: String,
+    pub reason_category: Option<&'static str>,
+    pub accepted: Option<DateTime<Utc>>,
+    pub expired: Option<DateTime<Utc>>,
+    pub initiated_by: Option<String>,
+}
+
+#[derive(Debug, serde::Serialize)]
+pub struct HistoryPage {
+    pub entries: Vec<HistoryEntry>,
+    /// Cursor of the next page, absent on the last one.
+    pub next: Option<String>,
+}
+
+/// One page of matching entries, newest first.
+pub fn page(entries: &[Entry], query: &HistoryQuery) -> HistoryPage {
+    let end = query.cursor.unwrap_or(usize::MAX).min(entries.len());
+    let mut matched = vec![];
+    let mut next = None;
+    for (index, entry) in entries[..end].iter().enumerate().rev() {
+        if !query.matches(entry) {
+            continue;
+        }
+        if matched.len() == query.limit {
+            next = Some((index + 1).to_string());
+            break;
+        }
+        matched.push(HistoryEntry {
+            timestamp: entry.timestamp,
+            decision: entry.event,
+            user_id: entry.user_id.clone(),
+            email_sha256: entry.email_sha256.clone(),
+            room_id: entry.room_id.clone(),
+            github_login: entry.github_login.clone(),
+            reason_category: category(entry),
+            accepted: entry.accepted,
+            expired: entry.expired,
+            initiated_by: entry.initiated_by.clone(),
+        });
+    }
+    HistoryPage {
+        entries: matched,
+        next,
+    }
+}
+
+pub async fn history(
+    _: Admin,
+    State(state): State<Arc<AppState>>,
+    Query(params): Query<HashMap<String, String>>,
+) -> Result<Json<HistoryPage>, (StatusCode, Json<serde_json::Value>)> {
+    let Some(store) = &state.store else {
+        return Err((
+            StatusCode::NOT_FOUND,
+            Json(
+                serde_json::json!({ "error": "the audit store is not enabled, see --audit-store" }),
+            ),
+        ));
+    };
+    let query = HistoryQuery::parse(&params).map_err(|fields| {
+        (
+            StatusCode::BAD_REQUEST,
+            Json(serde_json::json!({ "error": "invalid query", "fields": fields })),
+        )
+    })?;
+    Ok(Json(store.entries(|entries| page(entries, &query)).await))
+}
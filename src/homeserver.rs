@@ -0,0 +1,184 @@
+//! Liveness check of the invitees' homeservers before the GitHub login, catching typos in the
+//! server name such as `matirx.org` while the requester can still fix them.
+
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant},
+};
+
+use axum::http::StatusCode;
+use ruma::{OwnedServerName, OwnedUserId, ServerName};
+use tokio::sync::Mutex;
+
+use crate::{i18n::tr, secret::redact};
+
+/// Timeout of each request of a check.
+const CHECK_TIMEOUT: Duration = Duration::from_secs(3);
+/// How long a server that answered is not checked again.
+const CACHE_TTL: Duration = Duration::from_secs(6 * 60 * 60);
+/// Servers remembered at most, dropping expired ones first.
+const CACHE_SIZE: usize = 10_000;
+
+/// Servers suggested for names a typo away from them.
+const POPULAR_SERVERS: &[&str] = &[
+    "matrix.org",
+    "gitter.im",
+    "mozilla.org",
+    "kde.org",
+    "fedora.im",
+    "nixos.org",
+    "envs.net",
+    "tchncs.de",
+    "element.io",
+    "beeper.com",
+];
+
+/// Checks enabled unless `--skip-homeserver-check`, with the servers that answered recently.
+pub struct HomeserverCheck {
+    enabled: bool,
+    alive: Mutex<HashMap<OwnedServerName, Instant>>,
+}
+
+impl HomeserverCheck {
+    pub fn new(enabled: bool) -> HomeserverCheck {
+        HomeserverCheck {
+            enabled,
+            alive: Mutex::default(),
+        }
+    }
+
+    /// Refuse invitees whose homeserver does not answer. Servers named by an IP address and
+    /// `own`, the homeserver of the bot, are not checked.
+    pub async fn check(
+        &self,
+        client: &reqwest::Client,
+        own: &ServerName,
+        user_ids: &[OwnedUserId],
+    ) -> Result<(), (StatusCode, String)> {
+        if !self.enabled {
+            return Ok(());
+        }
+        let mut checked: Vec<&ServerName> = vec![];
+        for user_id in user_ids {
+            let server = user_id.server_name();
+            if server == own || server.is_ip_literal() || checked.contains(&server) {
+                continue;
+            }
+            checked.push(server);
+            if self.recently_alive(server).await {
+                continue;
+            }
+            if !answers(client, server).await {
+                log::warn!(
+                    "refused invite of {}, its homeserver did not answer",
+                    user_id
+                );
+                let mut message = tr(
+                    "The homeserver {server} of {user} did not answer. Please double-check the server name.",
+                    &[("server", &server), ("user", user_id)],
+                );
+                if let Some(suggestion) = suggestion(server.as_str()) {
+                    message.push(' ');
+                    message.push_str(&tr(
+                        "Did you mean {suggestion}?",
+                        &[("suggestion", &suggestion)],
+                    ));
+                }
+                return Err((StatusCode::BAD_REQUEST, message));
+            }
+            self.remember(server).await;
+        }
+        Ok(())
+    }
+
+    async fn recently_alive(&self, server: &ServerName) -> bool {
+        self.alive
+            .lock()
+            .await
+            .get(server)
+            .is_some_and(|checked| checked.elapsed() < CACHE_TTL)
+    }
+
+    async fn remember(&self, server: &ServerName) {
+        let mut alive = self.alive.lock().await;
+        if alive.len() >= CACHE_SIZE {
+            alive.retain(|_, checked| checked.elapsed() < CACHE_TTL);
+            if alive.len() >= CACHE_SIZE {
+                alive.clear();
+            }
+        }
+        alive.insert(server.to_owned(), Instant::now());
+    }
+}
+
+/// Whether anything answers for the server, through its well-known delegation or federation
+/// API. Only failures to connect, such as unknown names, count against it; other errors such
+/// as timeouts let it pass, so the check fails open.
+async fn answers(client: &reqwest::Client, server: &ServerName) -> bool {
+    for path in [
+        "/.well-known/matrix/server",
+        "/_matrix/federation/v1/version",
+    ] {
+        match client
+            .get(format!("https://{}{}", server, path))
+            .timeout(CHECK_TIMEOUT)
+            .send()
+            .await
+        {
+            Ok(_) => return true,
+            Err(err) if err.is_connect() => {
+                log::warn!(
+                    "homeserver {} did not answer {}: {}",
+                    server,
+                    path,
+                    redact(&err)
+                )
+            }
+            Err(err) => {
+                log::warn!(
+                    "homeserver check of {} failed, letting it pass: {}",
+                    server,
+                    redact(&err)
+                );
+                return true;
+            }
+        }
+    }
+    false
+}
+
+/// A popular server the given name is likely a typo of.
+pub fn suggestion(server: &str) -> Option<&'static str> {
+    let server = server.to_lowercase();
+    POPULAR_SERVERS
+        .iter()
+        .map(|popular| (distance(&server, popular), *popular))
+        .filter(|(distance, _)| (1..=2).contains(distance))
+        .min()
+        .map(|(_, popular)| popular)
+}
+
+/// Edit distance counting swapped neighbours as one edit, the most common typo.
+fn distance(a: &str, b: &str) -> usize {
+    let a = a.chars().collect::<Vec<_>>();
+    let b = b.chars().collect::<Vec<_>>();
+    let mut rows = vec![vec![0; b.len() + 1]; a.len() + 1];
+    for (i, row) in rows.iter_mut().enumerate() {
+        row[0] = i;
+    }
+    for (j, cell) in rows[0].iter_mut().enumerate() {
+        *cell = j;
+    }
+    for i in 1..=a.len() {
+        for j in 1..=b.len() {
+            let cost = usize::from(a[i - 1] != b[j - 1]);
+            rows[i][j] = (rows[i - 1][j] + 1)
+                .min(rows[i][j - 1] + 1)
+                .min(rows[i - 1][j - 1] + cost);
+            if i > 1 && j > 1 && a[i - 1] == b[j - 2] && a[i - 2] == b[j - 1] {
+                rows[i][j] = rows[i][j].min(rows[i - 2][j - 2] + 1);
+            }
+        }
+    }
+    rows[a.len()][b.len()]
+}
@@ -0,0 +1,73 @@
+use std::time::Duration;
+
+use axum::http::StatusCode;
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use chrono::Utc;
+use maud::{html, Markup};
+use ring::hmac;
+
+use crate::{i18n::t, AppState, CAPTCHA_FAILED};
+
+/// Stamps older than this are refused, so a harvested stamp cannot be replayed forever.
+const MAX_AGE: Duration = Duration::from_secs(24 * 60 * 60);
+
+fn sign(state: &AppState, rendered: i64) -> String {
+    let payload = format!("form:{}", rendered);
+    URL_SAFE_NO_PAD.encode(hmac::sign(&state.form_key, payload.as_bytes()))
+}
+
+/// Signed time the form was rendered at, in milliseconds.
+fn stamp(state: &AppState) -> String {
+    let rendered = Utc::now().timestamp_millis();
+    format!("{}.{}", rendered, sign(state, rendered))
+}
+
+/// Milliseconds since a stamp was rendered, if its signature is valid.
+fn elapsed(state: &AppState, stamp: &str) -> Option<i64> {
+    let (rendered, signature) = stamp.split_once('.')?;
+    let rendered: i64 = rendered.parse().ok()?;
+    hmac::verify(
+        &state.form_key,
+        format!("form:{}", rendered).as_bytes(),
+        &URL_SAFE_NO_PAD.decode(signature).ok()?,
+    )
+    .ok()?;
+    Some(Utc::now().timestamp_millis() - rendered)
+}
+
+/// Field hidden from humans by the stylesheet, and the render stamp of the form.
+pub fn fields(state: &AppState) -> Markup {
+    html! {
+        div class="website" aria-hidden="true" {
+            label for="website" { (t("Leave this field empty")) }
+            input type="text" id="website" name="website" tabindex="-1" autocomplete="off";
+        }
+        input type="hidden" name="rendered" value=(stamp(state));
+    }
+}
+
+/// Refuse submissions filling the hidden field or arriving faster than `--min-submit-time`,
+/// with the same answer as a failed captcha so bots cannot tell which check tripped.
+pub fn check(
+    state: &AppState,
+    website: &str,
+    rendered: Option<&str>,
+) -> Result<(), (StatusCode, String)> {
+    let refuse = |signal: &str| {
+        log::warn!("refused invite form submission: {}", signal);
+        Err((StatusCode::FORBIDDEN, t(CAPTCHA_FAILED)))
+    };
+    if !website.is_empty() {
+        return refuse("honeypot field filled");
+    }
+    let Some(elapsed) = rendered.and_then(|stamp| elapsed(state, stamp)) else {
+        return refuse("missing or forged render stamp");
+    };
+    if elapsed < state.min_submit_time.as_millis() as i64 {
+        return refuse(&format!("submitted {}ms after rendering", elapsed));
+    }
+    if elapsed > MAX_AGE.as_millis() as i64 {
+        return refuse("render stamp too old");
+    }
+    Ok(())
+}
@@ -0,0 +1,124 @@
+use std::{collections::HashMap, fmt, sync::Arc, sync::OnceLock};
+
+use anyhow::Context;
+use axum::{
+    extract::{Request, State},
+    http::{
+        header::{ACCEPT_LANGUAGE, SET_COOKIE},
+        HeaderMap,
+    },
+    middleware::Next,
+    response::Response,
+};
+
+use crate::{login, AppState};
+
+include!(concat!(env!("OUT_DIR"), "/locales.rs"));
+
+/// Language of the source strings, used when no catalog matches.
+pub const FALLBACK: &str = "en";
+
+/// Translations by locale code, each mapping an English source string to its translation.
+static CATALOGS: OnceLock<HashMap<&'static str, HashMap<String, String>>> = OnceLock::new();
+
+tokio::task_local! {
+    static LANGUAGE: &'static str;
+}
+
+/// Parse the embedded catalogs, failing on the first malformed one.
+pub fn load() -> anyhow::Result<()> {
+    let catalogs = LOCALES
+        .iter()
+        .map(|(code, text)| {
+            let catalog = toml::from_str(text)
+                .with_context(|| format!("invalid translation catalog locales/{}.toml", code))?;
+            Ok((*code, catalog))
+        })
+        .collect::<anyhow::Result<_>>()?;
+    let _ = CATALOGS.set(catalogs);
+    Ok(())
+}
+
+/// Language of the current request.
+pub fn current() -> &'static str {
+    LANGUAGE.try_with(|language| *language).unwrap_or(FALLBACK)
+}
+
+/// Translate a source string into the language of the current request.
+pub fn t(text: &str) -> String {
+    CATALOGS
+        .get()
+        .and_then(|catalogs| catalogs.get(current()))
+        .and_then(|catalog| catalog.get(text))
+        .cloned()
+        .unwrap_or_else(|| text.to_string())
+}
+
+/// Translate a source string and fill in its `{name}` placeholders.
+pub fn tr(text: &str, args: &[(&str, &dyn fmt::Display)]) -> String {
+    fill(t(text), args)
+}
+
+/// Fill in the `{name}` placeholders of a source string without translating it, for logs and
+/// the audit store.
+pub fn fill(text: impl Into<String>, args: &[(&str, &dyn fmt::Display)]) -> String {
+    args.iter().fold(text.into(), |text, (name, value)| {
+        text.replace(&format!("{{{}}}", name), &value.to_string())
+    })
+}
+
+/// Codes of every available language.
+pub fn languages() -> impl Iterator<Item = &'static str> {
+    std::iter::once(FALLBACK).chain(LOCALES.iter().map(|(code, _)| *code))
+}
+
+/// Available language matching a tag like `de-AT`, by its primary subtag if need be.
+fn supported(tag: &str) -> Option<&'static str> {
+    let tag = tag.trim().to_lowercase();
+    let primary = tag.split('-').next().unwrap_or_default();
+    languages()
+        .find(|code| code.to_lowercase() == tag)
+        .or_else(|| languages().find(|code| code.to_lowercase() == primary))
+}
+
+/// Best supported language of an Accept-Language header, honouring the quality values.
+fn accepted(headers: &HeaderMap) -> Option<&'static str> {
+    let mut tags = headers
+        .get_all(ACCEPT_LANGUAGE)
+        .iter()
+        .filter_map(|header| header.to_str().ok())
+        .flat_map(|header| header.split(','))
+        .filter_map(|tag| {
+            let mut parts = tag.split(';');
+            let tag = parts.next()?.trim();
+            let quality = parts
+                .find_map(|param| param.trim().strip_prefix("q="))
+                .map_or(Some(1.0), |q| q.trim().parse::<f32>().ok())?;
+            (quality > 0.0).then_some((tag, quality))
+        })
+        .collect::<Vec<_>>();
+    tags.sort_by(|(_, a), (_, b)| b.total_cmp(a));
+    tags.into_iter().find_map(|(tag, _)| supported(tag))
+}
+
+/// Pick the language of a request: `?lang=`, remembered in a cookie, then Accept-Language.
+pub async fn negotiate(
+    State(state): State<Arc<AppState>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let requested = request.uri().query().and_then(|query| {
+        url::form_urlencoded::parse(query.as_bytes())
+            .find(|(key, _)| key == "lang")
+            .and_then(|(_, lang)| supported(&lang))
+    });
+    let language = requested
+        .or_else(|| login::language(request.headers()).and_then(supported))
+        .or_else(|| accepted(request.headers()))
+        .unwrap_or(FALLBACK);
+    let mut response = LANGUAGE.scope(language, next.run(request)).await;
+    if let Some(cookie) = requested.and_then(|language| login::language_cookie(&state, language)) {
+        response.headers_mut().append(SET_COOKIE, cookie);
+    }
+    response
+}
@@ -0,0 +1,220 @@
+//! Identities vouching for invites, see [`IdentityProvider`].
+
+use std::time::Duration;
+
+use axum::{async_trait, http::StatusCode};
+use oauth2::{
+    basic::BasicClient, reqwest::async_http_client, AuthorizationCode, PkceCodeVerifier,
+    TokenResponse,
+};
+
+use crate::{
+    i18n::t,
+    secret::{redact, Secret},
+    send_idempotent, GitHubUser,
+};
+
+/// Turns the authorization code of a completed OAuth login into the account that logged in,
+/// along with its memberships of `teams`, given as `org/team-slug`.
+#[async_trait]
+pub trait IdentityProvider: Send + Sync {
+    async fn identify(
+        &self,
+        code: String,
+        pkce_verifier: Option<String>,
+        teams: &[String],
+    ) -> Result<GitHubUser, (StatusCode, String)>;
+}
+
+/// GitHub, looking up the user through its REST API.
+pub struct GitHub {
+    pub oauth2_client: BasicClient,
+    pub http_client: reqwest::Client,
+    pub http_timeout: Duration,
+    /// See `--github-api-url`.
+    pub api_url: String,
+    /// See `--github-token`.
+    pub token: Option<Secret<String>>,
+}
+
+#[derive(serde::Deserialize)]
+struct Organization {
+    login: String,
+}
+
+#[derive(serde::Deserialize)]
+struct OrgMembership {
+    organization: Organization,
+}
+
+#[derive(serde::Deserialize)]
+struct TeamMembership {
+    state: String,
+}
+
+impl GitHub {
+    /// A list from the GitHub API, only its first page of 100 entries.
+    async fn list<T: serde::de::DeserializeOwned>(
+        &self,
+        what: &str,
+        path: &str,
+        token: &str,
+    ) -> anyhow::Result<Vec<T>> {
+        let request = self
+            .http_client
+            .get(format!("{}{}", self.api_url, path))
+            .query(&[("per_page", "100")])
+            .bearer_auth(token);
+        Ok(send_idempotent(what, request, self.http_timeout)
+            .await?
+            .json()
+            .await?)
+    }
+
+    /// Fill in the organizations of the user. Failures are logged and leave them empty, which
+    /// only refuses rooms requiring a membership.
+    async fn organizations(&self, user: &mut GitHubUser, token: &str) {
+        match self
+            .list::<OrgMembership>(
+                "GitHub organization lookup",
+                "/user/memberships/orgs?state=active",
+                token,
+            )
+            .await
+        {
+            Ok(memberships) => {
+                user.orgs = memberships
+                    .into_iter()
+                    .map(|membership| membership.organization.login)
+                    .collect()
+            }
+            Err(err) => log::error!(
+                "failed to get organizations of GitHub user {}: {}",
+                user.login,
+                redact(&err)
+            ),
+        }
+    }
+
+    /// Fill in the teams the user is an active member of, and those whose invitation is still
+    /// pending. Like for organizations, failures leave them out.
+    async fn teams(&self, user: &mut GitHubUser, teams: &[String], token: &str) {
+        for team in teams {
+            let Some((org, slug)) = team.split_once('/') else {
+                continue;
+            };
+            let request = self
+                .http_client
+                .get(format!(
+                    "{}/orgs/{}/teams/{}/memberships/{}",
+                    self.api_url, org, slug, user.login
+                ))
+                .bearer_auth(token);
+            let membership =
+                match send_idempotent("GitHub team membership lookup", request, self.http_timeout)
+                    .await
+                {
+                    Ok(response) => response.json::<TeamMembership>().await,
+                    Err(err) if err.status() == Some(reqwest::StatusCode::NOT_FOUND) => {
+                        log::warn!(
+                            "GitHub user {} is not a member of team {}",
+                            user.login,
+                            team
+                        );
+                        continue;
+                    }
+                    Err(err) => Err(err),
+                };
+            match membership {
+                Ok(membership) if membership.state == "active" => user.teams.push(team.clone()),
+                Ok(membership) => {
+                    log::warn!(
+                        "GitHub user {} has a {} membership of team {}, not an active one",
+                        user.login,
+                        membership.state,
+                        team
+                    );
+                    user.pending_teams.push(team.clone());
+                }
+                Err(err) => log::error!(
+                    "failed to get membership of GitHub user {} in team {}: {}",
+                    user.login,
+                    team,
+                    redact(&err)
+                ),
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl IdentityProvider for GitHub {
+    async fn identify(
+        &self,
+        code: String,
+        pkce_verifier: Option<String>,
+        teams: &[String],
+    ) -> Result<GitHubUser, (StatusCode, String)> {
+        let mut exchange = self
+            .oauth2_client
+            .exchange_code(AuthorizationCode::new(code));
+        if let Some(verifier) = pkce_verifier {
+            exchange = exchange.set_pkce_verifier(PkceCodeVerifier::new(verifier));
+        }
+        let token = exchange
+            .request_async(async_http_client)
+            .await
+            .map_err(|err| {
+                log::error!("failed to exchange for token: {}", redact(&err));
+                (StatusCode::BAD_REQUEST, t("failed to exchange for token"))
+            })?;
+
+        let request = self
+            .http_client
+            .get(format!("{}/user", self.api_url))
+            .bearer_auth(token.access_token().secret());
+        let mut user: GitHubUser =
+            send_idempotent("GitHub user lookup", request, self.http_timeout)
+                .await
+                .map_err(|err| {
+                    log::error!("failed to get user info: {}", redact(&err));
+                    (
+                        StatusCode::INTERNAL_SERVER_ERROR,
+                        t("failed to get user info"),
+                    )
+                })?
+                .json()
+                .await
+                .map_err(|err| {
+                    log::error!("failed to decode user info: {}", redact(&err));
+                    (
+                        StatusCode::INTERNAL_SERVER_ERROR,
+                        t("failed to decode user info"),
+                    )
+                })?;
+        // Granted when the invite is to a room requiring a membership, see `authorize`.
+        let read_org = token
+            .scopes()
+            .is_some_and(|scopes| scopes.iter().any(|scope| scope.as_str() == "read:org"));
+        if read_org {
+            self.organizations(&mut user, token.access_token().secret())
+                .await;
+        }
+        if !teams.is_empty() {
+            match (&self.token, read_org) {
+                (Some(server_token), _) => {
+                    self.teams(&mut user, teams, server_token.expose()).await
+                }
+                (None, true) => {
+                    self.teams(&mut user, teams, token.access_token().secret())
+                        .await
+                }
+                (None, false) => log::warn!(
+                    "cannot look up the teams of GitHub user {} without the read:org scope",
+                    user.login
+                ),
+            }
+        }
+        Ok(user)
+    }
+}
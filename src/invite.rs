@@ -0,0 +1,421 @@
+use std::fmt;
+
+use axum::async_trait;
+use ruma::{
+    api::{
+        client::membership::{invite_user, Invite3pidInit},
+        error::FromHttpResponseError,
+    },
+    events::room::member::MembershipState,
+    thirdparty::Medium,
+    OwnedRoomId, OwnedUserId, RoomId, UserId,
+};
+
+use crate::{
+    audit,
+    i18n::t,
+    membership,
+    secret::redact,
+    store::{self, Denial},
+    webhook::EventKind,
+    AppState, MatrixClient,
+};
+
+pub const BANNED: &str = "You cannot be invited to this room, please contact its moderators.";
+
+/// Why an invite was not sent.
+#[derive(Debug)]
+pub enum InviteError {
+    Banned,
+    Failed {
+        /// Matrix error code such as `M_FORBIDDEN`, if the homeserver gave one.
+        kind: Option<String>,
+        message: String,
+    },
+}
+
+impl fmt::Display for InviteError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            InviteError::Banned => f.write_str("banned from the room"),
+            InviteError::Failed { message, .. } => f.write_str(message),
+        }
+    }
+}
+
+fn error_kind<E>(err: &ruma::client::Error<E, ruma::api::client::Error>) -> Option<String> {
+    match err {
+        ruma::client::Error::FromHttpResponse(FromHttpResponseError::Server(err)) => {
+            err.error_kind().map(ToString::to_string)
+        }
+        _ => None,
+    }
+}
+
+/// Invite a user to one room, refusing users banned from it.
+pub async fn invite_user(
+    state: &AppState,
+    room_id: &OwnedRoomId,
+    user_id: &OwnedUserId,
+    login: &str,
+    reason: Option<String>,
+) -> Result<(), String> {
+    send_invite(state, room_id, user_id, login, reason)
+        .await
+        .map_err(|err| match err {
+            InviteError::Banned => t(BANNED),
+            InviteError::Failed { .. } => t("failed to invite user"),
+        })
+}
+
+/// Homeserver calls behind [`attempt`], made by the Matrix client.
+#[async_trait]
+pub trait Inviter: Send + Sync {
+    /// Membership of a user in a room, if it has one and the homeserver tells.
+    async fn membership(&self, room_id: &RoomId, user_id: &UserId) -> Option<MembershipState>;
+    async fn invite(
+        &self,
+        room_id: &RoomId,
+        user_id: &UserId,
+        reason: Option<String>,
+    ) -> Result<(), InviteError>;
+}
+
+#[async_trait]
+impl Inviter for MatrixClient {
+    async fn membership(&self, room_id: &RoomId, user_id: &UserId) -> Option<MembershipState> {
+        membership::membership(self, room_id, user_id).await
+    }
+
+    async fn invite(
+        &self,
+        room_id: &RoomId,
+        user_id: &UserId,
+        reason: Option<String>,
+    ) -> Result<(), InviteError> {
+        let mut request = invite_user::v3::Request::new(
+            room_id.to_owned(),
+            invite_user::v3::InvitationRecipient::UserId {
+                user_id: user_id.to_owned(),
+            },
+        );
+        request.reason = reason;
+        self.send_request(request)
+            .await
+            .map(|_| ())
+            .map_err(|err| InviteError::Failed {
+                kind: error_kind(&err),
+                message: redact(&err),
+            })
+    }
+}
+
+/// What [`attempt`] did.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Attempt {
+    Sent,
+    /// Nothing was sent, see `--dry-run`.
+    DryRun,
+}
+
+/// Invite a user to one room, unless it is banned from it or this is a dry run. Unlike
+/// [`send_invite`] nothing is logged or recorded.
+pub async fn attempt(
+    inviter: &dyn Inviter,
+    room_id: &RoomId,
+    user_id: &UserId,
+    reason: Option<String>,
+    check_ban: bool,
+    dry_run: bool,
+) -> Result<Attempt, InviteError> {
+    if check_ban && inviter.membership(room_id, user_id).await == Some(MembershipState::Ban) {
+        return Err(InviteError::Banned);
+    }
+    if dry_run {
+        return Ok(Attempt::DryRun);
+    }
+    inviter.invite(room_id, user_id, reason).await?;
+    Ok(Attempt::Sent)
+}
+
+/// [`invite_user`], telling apart why the invite was not sent.
+pub async fn send_invite(
+    state: &AppState,
+    room_id: &OwnedRoomId,
+    user_id: &OwnedUserId,
+    login: &str,
+    reason: Option<String>,
+) -> Result<(), InviteError> {
+    let outcome = attempt(
+        &*state.inviter,
+        room_id,
+        user_id,
+        reason.clone(),
+        !state.skip_ban_check,
+        state.dry_run,
+    )
+    .await;
+    match &outcome {
+        Err(InviteError::Banned) => {
+            log::error!(
+                "banned matrix user {} tried to get invited to room {} as GitHub user {}",
+                user_id,
+                room_id,
+                login,
+            );
+            let denial = "banned from the room";
+            audit::denied(
+                state,
+                user_id,
+                &state.room_name(room_id).await,
+                login,
+                denial,
+            )
+            .await;
+            store::deny(
+                state,
+                user_id,
+                room_id,
+                login,
+                Denial::RoomBan,
+                denial.to_string(),
+            )
+            .await;
+        }
+        Err(InviteError::Failed { message, .. }) => {
+            log::error!(
+                "failed to invite user {} to room {}: {}",
+                user_id,
+                room_id,
+                message
+            );
+            store::record(
+                state,
+                EventKind::InviteFailed,
+                user_id,
+                room_id,
+                login,
+                Some(message.clone()),
+            )
+            .await;
+        }
+        Ok(Attempt::DryRun) => {
+            log::warn!(
+                "dry run, not inviting {} to room {} for GitHub user {}",
+                user_id,
+                room_id,
+                login
+            );
+            audit::dry_run(state, user_id, room_id, login).await;
+            store::record(
+                state,
+                EventKind::InviteDryRun,
+                user_id,
+                room_id,
+                login,
+                reason,
+            )
+            .await;
+        }
+        Ok(Attempt::Sent) => {
+            state.count_invite(room_id).await;
+            audit::invited(state, user_id, room_id, login).await;
+            store::record(
+                state,
+                EventKind::InviteSent,
+                user_id,
+                room_id,
+                login,
+                reason,
+            )
+            .await;
+        }
+    }
+    outcome.map(|_| ())
+}
+
+/// Invite an email address to one room through the identity server, which sends the invite
+/// by mail and binds it to whichever account later claims the address.
+pub async fn invite_email(
+    state: &AppState,
+    room_id: &OwnedRoomId,
+    email: &str,
+    login: &str,
+) -> Result<(), String> {
+    let (Some(id_server), Some(id_access_token)) = (&state.id_server, &state.id_access_token)
+    else {
+        return Err(t("email invites are not enabled"));
+    };
+    if state.dry_run {
+        log::warn!(
+            "dry run, not inviting an email address to room {} for GitHub user {}",
+            room_id,
+            login
+        );
+        store::record_email(state, EventKind::InviteDryRun, email, room_id, login, None).await;
+        return Ok(());
+    }
+    let request = invite_user::v3::Request::new(
+        room_id.clone(),
+        invite_user::v3::InvitationRecipient::ThirdPartyId(
+            Invite3pidInit {
+                id_server: id_server.clone(),
+                id_access_token: id_access_token.expose().clone(),
+                medium: Medium::Email,
+                address: email.to_string(),
+            }
+            .into(),
+        ),
+    );
+    if let Err(err) = state.client.send_request(request).await {
+        log::error!(
+            "failed to invite an email address to room {} for GitHub user {}: {}",
+            room_id,
+            login,
+            redact(&err)
+        );
+        store::record_email(
+            state,
+            EventKind::InviteFailed,
+            email,
+            room_id,
+            login,
+            Some(redact(&err)),
+        )
+        .await;
+        return Err(t("failed to invite email address"));
+    }
+    state.count_invite(room_id).await;
+    log::warn!(
+        "invited an email address to room {} for GitHub user {}",
+        room_id,
+        login
+    );
+    store::record_email(state, EventKind::InviteSent, email, room_id, login, None).await;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use axum::http::StatusCode;
+    use chrono::Utc;
+    use ruma::{events::room::member::MembershipState, room_id, user_id};
+
+    use super::{attempt, Attempt, InviteError};
+    use crate::{
+        captcha::CaptchaVerifier,
+        config::RoomConfig,
+        login,
+        mock::{invite, teams, user, MockCaptcha, MockIdentity, MockInviter, CLIENT, LIMITS},
+        room_policy::check_github_config,
+        sessions::{stash, MemoryStore},
+        CAPTCHA_FAILED,
+    };
+
+    #[tokio::test]
+    async fn invites_unless_banned_or_dry_run() {
+        let room_id = room_id!("!room:localhost");
+        let banned = user_id!("@banned:localhost");
+        let alice = user_id!("@alice:localhost");
+        let inviter = MockInviter {
+            memberships: HashMap::from([(
+                (room_id.to_owned(), banned.to_owned()),
+                MembershipState::Ban,
+            )]),
+            ..Default::default()
+        };
+
+        let outcome = attempt(&inviter, room_id, banned, None, true, false).await;
+        assert!(matches!(outcome, Err(InviteError::Banned)), "{:?}", outcome);
+        let outcome = attempt(&inviter, room_id, alice, None, true, true).await;
+        assert_eq!(outcome.unwrap(), Attempt::DryRun);
+        assert!(inviter.invites().is_empty());
+
+        let reason = Some("vouched for by octocat".to_string());
+        let outcome = attempt(&inviter, room_id, alice, reason.clone(), true, false).await;
+        assert_eq!(outcome.unwrap(), Attempt::Sent);
+        // Without the ban check the homeserver decides.
+        let outcome = attempt(&inviter, room_id, banned, None, false, false).await;
+        assert_eq!(outcome.unwrap(), Attempt::Sent);
+        assert_eq!(
+            inviter.invites(),
+            [
+                (room_id.to_owned(), alice.to_owned(), reason),
+                (room_id.to_owned(), banned.to_owned(), None),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn reports_the_kind_of_failed_invites() {
+        let inviter = MockInviter {
+            error: Some("M_FORBIDDEN"),
+            ..Default::default()
+        };
+        let outcome = attempt(
+            &inviter,
+            room_id!("!room:localhost"),
+            user_id!("@alice:localhost"),
+            None,
+            true,
+            false,
+        )
+        .await;
+        match outcome {
+            Err(InviteError::Failed { kind, message }) => {
+                assert_eq!(kind.as_deref(), Some("M_FORBIDDEN"));
+                assert!(message.contains("not allowed"), "{}", message);
+            }
+            outcome => panic!("unexpected outcome {:?}", outcome),
+        }
+    }
+
+    #[tokio::test]
+    async fn walks_an_invite_from_the_captcha_to_the_homeserver() {
+        let captcha = MockCaptcha("solved");
+        let store = MemoryStore::default();
+        let identity = MockIdentity::new(user(100));
+        let inviter = MockInviter::default();
+        let config = RoomConfig {
+            github_org: Some("rust-lang".to_string()),
+            github_team: Some("infra".to_string()),
+            ..Default::default()
+        };
+
+        let (status, message) = captcha.verify("forged", CLIENT).await.unwrap_err();
+        assert_eq!(status, StatusCode::FORBIDDEN);
+        assert_eq!(message, CAPTCHA_FAILED);
+
+        captcha.verify("solved", CLIENT).await.unwrap();
+        stash(&store, "csrf", &invite(Some("nonce")), LIMITS)
+            .await
+            .unwrap();
+        let (invite, user) = login::complete(
+            &store,
+            &identity,
+            "csrf",
+            "code".into(),
+            Some("nonce"),
+            teams,
+        )
+        .await
+        .unwrap();
+        check_github_config(&config, &user, Utc::now() - user.created_at).unwrap();
+        for room_id in &invite.room_ids {
+            for user_id in &invite.user_ids {
+                let outcome = attempt(&inviter, room_id, user_id, None, true, false).await;
+                assert_eq!(outcome.unwrap(), Attempt::Sent);
+            }
+        }
+        assert_eq!(
+            inviter.invites(),
+            [(
+                room_id!("!room:localhost").to_owned(),
+                user_id!("@alice:localhost").to_owned(),
+                None
+            )]
+        );
+    }
+}
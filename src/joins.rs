@@ -0,0 +1,119 @@
+use std::{sync::Arc, time::Duration};
+
+use chrono::{DateTime, Utc};
+use ruma::{
+    api::client::{
+        filter::{FilterDefinition, RoomEventFilter},
+        sync::sync_events,
+    },
+    events::{
+        room::member::MembershipState, AnySyncStateEvent, AnySyncTimelineEvent, SyncStateEvent,
+        TimelineEventType,
+    },
+    RoomId, UserId,
+};
+
+use crate::{reload, secret::redact, webhook::EventKind, AppState};
+
+/// How long the sync loop waits for new events before polling again.
+const SYNC_TIMEOUT: Duration = Duration::from_secs(30);
+/// Delays before retrying a failed sync, doubling from the first up to the second.
+const MIN_BACKOFF: Duration = Duration::from_secs(5);
+const MAX_BACKOFF: Duration = Duration::from_secs(300);
+
+/// Only membership and tombstone events of the served rooms.
+async fn filter(state: &AppState) -> FilterDefinition {
+    let mut timeline = RoomEventFilter::default();
+    timeline.types = Some(vec![
+        TimelineEventType::RoomMember.to_string(),
+        TimelineEventType::RoomTombstone.to_string(),
+    ]);
+    let mut filter = FilterDefinition::ignore_all();
+    filter.room.rooms = Some(state.rooms.read().await.keys().cloned().collect());
+    filter.room.timeline = timeline;
+    filter
+}
+
+/// Watch the served rooms for invited users joining and for upgrades, if `--track-joins` is set.
+pub fn spawn(state: Arc<AppState>) {
+    if !state.track_joins {
+        return;
+    }
+    tokio::spawn(async move {
+        let Some(store) = &state.store else {
+            return;
+        };
+        let mut since = store.read(|data| data.join_sync_token.clone()).await;
+        let mut backoff = MIN_BACKOFF;
+        loop {
+            let mut request = sync_events::v3::Request::new();
+            request.filter = Some(sync_events::v3::Filter::FilterDefinition(
+                filter(&state).await,
+            ));
+            request.timeout = since.is_some().then_some(SYNC_TIMEOUT);
+            request.since = since.clone();
+            let response = match state.client.send_request(request).await {
+                Ok(response) => response,
+                Err(err) => {
+                    log::error!("failed to sync room memberships: {}", redact(&err));
+                    tokio::time::sleep(backoff).await;
+                    backoff = (backoff * 2).min(MAX_BACKOFF);
+                    continue;
+                }
+            };
+            backoff = MIN_BACKOFF;
+            let mut upgraded = false;
+            for (room_id, room) in &response.rooms.join {
+                for event in &room.timeline.events {
+                    match event.deserialize() {
+                        Ok(AnySyncTimelineEvent::State(AnySyncStateEvent::RoomMember(
+                            SyncStateEvent::Original(event),
+                        ))) if event.content.membership == MembershipState::Join => {
+                            let joined =
+                                DateTime::from_timestamp_millis(event.origin_server_ts.0.into())
+                                    .unwrap_or_else(Utc::now);
+                            accept(&state, room_id, &event.state_key, joined).await;
+                        }
+                        Ok(AnySyncTimelineEvent::State(AnySyncStateEvent::RoomTombstone(_))) => {
+                            log::warn!("room {} was upgraded, refreshing rooms", room_id);
+                            upgraded = true;
+                        }
+                        _ => {}
+                    }
+                }
+            }
+            // Discovery follows the tombstone to the successor room.
+            if upgraded {
+                let _ = reload::refresh_rooms(&state).await;
+            }
+            let next_batch = response.next_batch;
+            store
+                .update(|data| data.join_sync_token = Some(next_batch.clone()))
+                .await;
+            since = Some(next_batch);
+        }
+    });
+}
+
+/// Mark the invites of a user who joined a room as accepted.
+async fn accept(state: &AppState, room_id: &RoomId, user_id: &UserId, joined: DateTime<Utc>) {
+    let Some(store) = &state.store else {
+        return;
+    };
+    let accepted = store
+        .amend(|entry| {
+            let matches = entry.event == EventKind::InviteSent
+                && entry.accepted.is_none()
+                && *entry.room_id == *room_id
+                && entry.user_id.as_deref() == Some(user_id)
+                && entry.timestamp <= joined;
+            if matches {
+                entry.accepted = Some(joined);
+            }
+            matches
+        })
+        .await;
+    if accepted > 0 {
+        log::warn!("invited user {} joined room {}", user_id, room_id);
+    }
+}
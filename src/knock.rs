@@ -0,0 +1,244 @@
+use std::{collections::HashMap, sync::Arc, time::Duration};
+
+use axum::http::StatusCode;
+use chrono::{DateTime, Utc};
+use ruma::{
+    api::{
+        client::{
+            filter::{FilterDefinition, RoomEventFilter},
+            membership::{get_member_events, kick_user},
+            sync::sync_events,
+        },
+        error::FromHttpResponseError,
+    },
+    events::{
+        room::member::{MembershipState, RoomMemberEvent},
+        AnySyncStateEvent, AnySyncTimelineEvent, SyncStateEvent, TimelineEventType,
+    },
+    space::SpaceRoomJoinRule,
+    OwnedRoomId, OwnedUserId, RoomId, UserId,
+};
+use tokio::sync::Mutex;
+
+use crate::{i18n::t, invite::invite_user, membership, secret::redact, AppState};
+
+/// How long the sync loop waits for new events before polling again.
+const SYNC_TIMEOUT: Duration = Duration::from_secs(30);
+/// Delays before retrying a failed sync, doubling from the first up to the second.
+const MIN_BACKOFF: Duration = Duration::from_secs(5);
+const MAX_BACKOFF: Duration = Duration::from_secs(300);
+const DECLINE_REASON: &str = "Knock was not verified in time";
+
+/// Knocks on the served rooms not accepted or withdrawn yet, with the time of the knock.
+#[derive(Default)]
+pub struct Knocks {
+    pending: Mutex<HashMap<OwnedRoomId, HashMap<OwnedUserId, DateTime<Utc>>>>,
+}
+
+impl Knocks {
+    /// Pending knocks per room.
+    pub async fn counts(&self) -> HashMap<OwnedRoomId, usize> {
+        self.pending
+            .lock()
+            .await
+            .iter()
+            .map(|(room_id, knocks)| (room_id.clone(), knocks.len()))
+            .collect()
+    }
+
+    async fn insert(&self, room_id: &RoomId, user_id: OwnedUserId, knocked: DateTime<Utc>) {
+        self.pending
+            .lock()
+            .await
+            .entry(room_id.to_owned())
+            .or_default()
+            .insert(user_id, knocked);
+    }
+
+    async fn remove(&self, room_id: &RoomId, user_id: &UserId) -> bool {
+        self.pending
+            .lock()
+            .await
+            .get_mut(room_id)
+            .is_some_and(|knocks| knocks.remove(user_id).is_some())
+    }
+}
+
+/// Whether invites to the room go through knocks, with `--knock-mode` and a knock join rule.
+pub async fn required(state: &AppState, room_id: &RoomId) -> bool {
+    state.knock_mode
+        && state.rooms.read().await.get(room_id).is_some_and(|room| {
+            matches!(
+                room.join_rule,
+                SpaceRoomJoinRule::Knock | SpaceRoomJoinRule::KnockRestricted
+            )
+        })
+}
+
+/// Accept the knock of a verified user by inviting them. The membership is looked up as well,
+/// in case the sync loop has not seen the knock yet.
+pub async fn accept(
+    state: &AppState,
+    room_id: &OwnedRoomId,
+    user_id: &OwnedUserId,
+    login: &str,
+    reason: Option<String>,
+) -> Result<(), String> {
+    let knocked = state.knocks.remove(room_id, user_id).await
+        || membership::membership(&state.client, room_id, user_id).await
+            == Some(MembershipState::Knock);
+    if !knocked {
+        return Err(t("knock on the room from your Matrix client first"));
+    }
+    invite_user(state, room_id, user_id, login, reason).await?;
+    log::warn!("accepted the knock of {} on room {}", user_id, room_id);
+    Ok(())
+}
+
+/// Only membership events of the served rooms.
+async fn filter(state: &AppState) -> FilterDefinition {
+    let mut timeline = RoomEventFilter::default();
+    timeline.types = Some(vec![TimelineEventType::RoomMember.to_string()]);
+    let mut filter = FilterDefinition::ignore_all();
+    filter.room.rooms = Some(state.rooms.read().await.keys().cloned().collect());
+    filter.room.timeline = timeline;
+    filter
+}
+
+/// Knocks already pending when the bouncer starts.
+async fn load(state: &AppState) {
+    let room_ids = state.rooms.read().await.keys().cloned().collect::<Vec<_>>();
+    for room_id in room_ids {
+        if !required(state, &room_id).await {
+            continue;
+        }
+        let members = match state
+            .client
+            .send_request(get_member_events::v3::Request::new(room_id.clone()))
+            .await
+        {
+            Ok(response) => response.chunk,
+            Err(err) => {
+                log::error!(
+                    "failed to get members of room {}: {}",
+                    room_id,
+                    redact(&err)
+                );
+                continue;
+            }
+        };
+        for member in members {
+            if let Ok(RoomMemberEvent::Original(event)) = member.deserialize() {
+                if event.content.membership == MembershipState::Knock {
+                    let knocked = DateTime::from_timestamp_millis(event.origin_server_ts.0.into())
+                        .unwrap_or_else(Utc::now);
+                    state
+                        .knocks
+                        .insert(&room_id, event.state_key, knocked)
+                        .await;
+                }
+            }
+        }
+    }
+}
+
+/// Collect knocks on the served rooms, if `--knock-mode` is set.
+pub fn spawn(state: Arc<AppState>) {
+    if !state.knock_mode {
+        return;
+    }
+    tokio::spawn(async move {
+        load(&state).await;
+        let mut since = None;
+        let mut backoff = MIN_BACKOFF;
+        loop {
+            let mut request = sync_events::v3::Request::new();
+            request.filter = Some(sync_events::v3::Filter::FilterDefinition(
+                filter(&state).await,
+            ));
+            request.timeout = since.is_some().then_some(SYNC_TIMEOUT);
+            request.since = since.clone();
+            let response = match state.client.send_request(request).await {
+                Ok(response) => response,
+                Err(err) => {
+                    log::error!("failed to sync knocks: {}", redact(&err));
+                    tokio::time::sleep(backoff).await;
+                    backoff = (backoff * 2).min(MAX_BACKOFF);
+                    continue;
+                }
+            };
+            backoff = MIN_BACKOFF;
+            // The initial sync only repeats what was loaded above.
+            if since.is_some() {
+                for (room_id, room) in &response.rooms.join {
+                    for event in &room.timeline.events {
+                        if let Ok(AnySyncTimelineEvent::State(AnySyncStateEvent::RoomMember(
+                            SyncStateEvent::Original(event),
+                        ))) = event.deserialize()
+                        {
+                            if event.content.membership == MembershipState::Knock {
+                                let knocked = DateTime::from_timestamp_millis(
+                                    event.origin_server_ts.0.into(),
+                                )
+                                .unwrap_or_else(Utc::now);
+                                log::warn!("{} knocked on room {}", event.state_key, room_id);
+                                state.knocks.insert(room_id, event.state_key, knocked).await;
+                            } else {
+                                state.knocks.remove(room_id, &event.state_key).await;
+                            }
+                        }
+                    }
+                }
+            }
+            since = Some(response.next_batch);
+            if let Some(decline_after) = state.knock_decline_after {
+                decline_stale(&state, decline_after).await;
+            }
+        }
+    });
+}
+
+/// Decline knocks older than `--knock-decline-after` by kicking the knocking user.
+async fn decline_stale(state: &AppState, decline_after: Duration) {
+    let cutoff =
+        Utc::now() - chrono::Duration::from_std(decline_after).unwrap_or(chrono::Duration::MAX);
+    let stale = state
+        .knocks
+        .pending
+        .lock()
+        .await
+        .iter()
+        .flat_map(|(room_id, knocks)| {
+            knocks
+                .iter()
+                .filter(|(_, knocked)| **knocked < cutoff)
+                .map(|(user_id, _)| (room_id.clone(), user_id.clone()))
+        })
+        .collect::<Vec<_>>();
+    for (room_id, user_id) in stale {
+        state.knocks.remove(&room_id, &user_id).await;
+        let mut request = kick_user::v3::Request::new(room_id.clone(), user_id.clone());
+        request.reason = Some(DECLINE_REASON.to_string());
+        match state.client.send_request(request).await {
+            Ok(_) => log::warn!(
+                "declined the stale knock of {} on room {}",
+                user_id,
+                room_id
+            ),
+            Err(ruma::client::Error::FromHttpResponse(FromHttpResponseError::Server(err)))
+                if err.status_code == StatusCode::FORBIDDEN =>
+            {
+                log::warn!(
+                    "cannot decline knocks on room {}, the bot lacks kick permission",
+                    room_id
+                );
+            }
+            Err(err) => log::error!(
+                "failed to decline the knock of {} on room {}: {}",
+                user_id,
+                room_id,
+                redact(&err)
+            ),
+        }
+    }
+}
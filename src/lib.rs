@@ -1,110 +1,1124 @@
-use std::{collections::HashMap, sync::Arc};
+use std::{
+    collections::{HashMap, HashSet},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
 
-use axum::extract::State;
-use maud::{html, Markup, DOCTYPE};
+use anyhow::Context;
+use arc_swap::ArcSwap;
+use axum::{
+    extract::{Extension, Query, RawQuery, State},
+    http::{
+        header::{CACHE_CONTROL, ETAG, IF_NONE_MATCH, VARY},
+        HeaderMap, StatusCode,
+    },
+    response::{IntoResponse, Response},
+};
+use maud::{html, Markup};
 use oauth2::basic::BasicClient;
-use ruma::{space::SpaceRoomJoinRule, Client, OwnedRoomAliasId, OwnedRoomId, OwnedUserId};
-use tokio::sync::Mutex;
+use ruma::{
+    events::room::member::MembershipState, space::SpaceRoomJoinRule, Client, OwnedMxcUri,
+    OwnedRoomAliasId, OwnedRoomId, OwnedUserId, RoomId, UserId,
+};
+use tokio::sync::{Mutex, RwLock};
+
+pub mod admin;
+pub mod approval;
+pub mod assets;
+pub mod audit;
+pub mod autojoin;
+pub mod avatar;
+pub mod captcha;
+pub mod check;
+pub mod cli;
+pub mod client_ip;
+pub mod client_links;
+pub mod commands;
+pub mod config;
+pub mod digest;
+pub mod discovery;
+pub mod expiry;
+pub mod export;
+pub mod fallback;
+pub mod geoip;
+pub mod health;
+pub mod history;
+pub mod homeserver;
+pub mod honeypot;
+pub mod i18n;
+pub mod identity;
+pub mod invite;
+pub mod joins;
+pub mod knock;
+pub mod limits;
+pub mod links;
+pub mod localpart;
+pub mod logging;
+pub mod login;
+pub mod mail;
+pub mod membership;
+#[cfg(test)]
+mod mock;
+pub mod order;
+pub mod page;
+pub mod pages;
+pub mod policy;
+pub mod queue;
+pub mod quota;
+pub mod reload;
+pub mod room_policy;
+pub mod secret;
+pub mod security;
+pub mod serve;
+pub mod sessions;
+pub mod startup;
+pub mod stats;
+pub mod store;
+pub mod throttle;
+pub mod tls;
+pub mod token;
+pub mod webhook;
+
+use i18n::t;
+use security::CspNonce;
+
+/// Answer for a failed captcha, also given by the other bot checks, see [`honeypot`].
+pub const CAPTCHA_FAILED: &str = "The captcha could not be verified, please try again.";
+
+#[derive(serde::Deserialize)]
+pub(crate) struct Turnstile {
+    pub(crate) success: bool,
+    #[serde(rename = "error-codes", default)]
+    pub(crate) error_codes: Vec<String>,
+}
+
+pub type MatrixClient = Client<token::RefreshingClient>;
+
+/// Client for the Turnstile and GitHub APIs, shared by all handlers to reuse connections. Every
+/// attempt of a request is bounded by `timeout`.
+pub fn http_client(
+    timeout: Duration,
+    proxy: Option<&config::OutboundProxy>,
+) -> reqwest::Result<reqwest::Client> {
+    let mut builder = reqwest::Client::builder()
+        .user_agent("Matrix Bouncer")
+        .connect_timeout(timeout)
+        .timeout(timeout);
+    if let Some(proxy) = proxy {
+        builder = builder.proxy(proxy.proxy.clone());
+    }
+    builder.build()
+}
+
+/// Attempts of an idempotent upstream request, see [`send_idempotent`].
+const UPSTREAM_ATTEMPTS: u32 = 3;
+/// Retries of upstream requests since startup, logged with every retry.
+static UPSTREAM_RETRIES: AtomicU64 = AtomicU64::new(0);
+
+/// Whether a failed upstream request may succeed when sent again.
+fn retryable(err: &reqwest::Error) -> bool {
+    err.is_timeout()
+        || err.is_connect()
+        || err.status().is_some_and(|status| {
+            status.is_server_error() || status == reqwest::StatusCode::TOO_MANY_REQUESTS
+        })
+}
+
+/// Between half and all of `backoff`, so that clients failing together do not retry together.
+fn jitter(backoff: Duration) -> Duration {
+    use ring::rand::SecureRandom;
+    let mut byte = [0u8];
+    let _ = ring::rand::SystemRandom::new().fill(&mut byte);
+    backoff / 2 + backoff / 2 * u32::from(byte[0]) / 255
+}
+
+/// Send an idempotent request to an upstream API, failing on error statuses. Timeouts,
+/// connection failures, 429 and server errors are retried with jittered exponential backoff,
+/// up to [`UPSTREAM_ATTEMPTS`] times and only while within twice `http_timeout`.
+pub async fn send_idempotent(
+    what: &str,
+    request: reqwest::RequestBuilder,
+    http_timeout: Duration,
+) -> reqwest::Result<reqwest::Response> {
+    let started = Instant::now();
+    let mut backoff = Duration::from_millis(500);
+    let mut attempt = 1;
+    loop {
+        let result = request
+            .try_clone()
+            .expect("upstream requests have no streaming body")
+            .send()
+            .await
+            .and_then(|response| response.error_for_status());
+        match result {
+            Err(err)
+                if attempt < UPSTREAM_ATTEMPTS
+                    && retryable(&err)
+                    && started.elapsed() + backoff < http_timeout * 2 =>
+            {
+                let retries = UPSTREAM_RETRIES.fetch_add(1, Ordering::Relaxed) + 1;
+                log::warn!(
+                    "{} failed on attempt {}, retrying ({} upstream retries since startup): {}",
+                    what,
+                    attempt,
+                    retries,
+                    err
+                );
+                tokio::time::sleep(jitter(backoff)).await;
+                backoff *= 2;
+                attempt += 1;
+            }
+            result => return result,
+        }
+    }
+}
+
+/// Connect to the homeserver and look up the account of the bot, failing on a rejected token.
+pub async fn connect(
+    homeserver_url: String,
+    mut credentials: token::Credentials,
+    proxy: Option<&config::OutboundProxy>,
+    throttle: Arc<throttle::Throttle>,
+) -> anyhow::Result<(MatrixClient, OwnedUserId)> {
+    credentials.tokens = token::load(credentials.tokens, credentials.state_file.as_deref())
+        .context(startup::Failure::Config)?;
+    let sender = credentials.appservice_sender.clone();
+    let mut http_client = reqwest::Client::builder();
+    if let Some(proxy) = proxy {
+        log::warn!(
+            "Sending outbound HTTPS requests through the proxy {}",
+            proxy.redacted_url
+        );
+        http_client = http_client.proxy(proxy.proxy.clone());
+    }
+    let http_client = http_client
+        .build()
+        .context("failed to build the Matrix client")
+        .context(startup::Failure::Config)?;
+    let client = Client::builder()
+        .homeserver_url(homeserver_url.clone())
+        .access_token(Some(credentials.tokens.access_token.expose().clone()))
+        .http_client(token::RefreshingClient::new(
+            http_client,
+            homeserver_url.clone(),
+            credentials,
+            throttle,
+        ))
+        .await
+        .context("failed to build the Matrix client")
+        .context(startup::Failure::Config)?;
+    match client
+        .send_request(ruma::api::client::account::whoami::v3::Request::new())
+        .await
+    {
+        Ok(response) => {
+            if let Some(sender) = sender.filter(|sender| *sender != response.user_id) {
+                return Err(anyhow::anyhow!(
+                    "the homeserver answered as {} instead of appservice_sender {}",
+                    response.user_id,
+                    sender
+                )
+                .context(startup::Failure::Auth));
+            }
+            Ok((client, response.user_id))
+        }
+        Err(ruma::client::Error::FromHttpResponse(
+            ruma::api::error::FromHttpResponseError::Server(err),
+        )) if matches!(
+            err.status_code,
+            StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN
+        ) =>
+        {
+            let rejected = match &sender {
+                Some(sender) => format!(
+                    "appservice_token [redacted] for appservice_sender {}, which must be within the user namespaces of the appservice registration",
+                    sender
+                ),
+                None => "access_token [redacted]".to_string(),
+            };
+            Err(anyhow::Error::new(err)
+                .context(format!(
+                    "the homeserver at homeserver_url {} rejected {}",
+                    startup::redact_url(&homeserver_url),
+                    rejected
+                ))
+                .context(startup::Failure::Auth))
+        }
+        Err(err) => Err(anyhow::Error::new(err)
+            .context(format!(
+                "failed to reach the homeserver at homeserver_url {}",
+                startup::redact_url(&homeserver_url)
+            ))
+            .context(startup::Failure::Network)),
+    }
+}
 
 pub struct AppState {
-    pub client: Client<ruma::client::http_client::Reqwest>,
+    pub client: MatrixClient,
     pub oauth2_client: BasicClient,
-    pub rooms: HashMap<OwnedRoomId, RoomInfo>,
+    /// See [`http_client`].
+    pub http_client: reqwest::Client,
+    pub captcha: Box<dyn captcha::CaptchaVerifier>,
+    pub identity: Box<dyn identity::IdentityProvider>,
+    /// Sends the invites of [`invite::send_invite`], through `client`.
+    pub inviter: Box<dyn invite::Inviter>,
+    pub http_timeout: Duration,
+    pub request_timeout: Duration,
+    /// Shared by every request of `client`, see [`throttle`].
+    pub throttle: Arc<throttle::Throttle>,
+    pub user_id: OwnedUserId,
+    pub rooms: RwLock<discovery::Rooms>,
+    /// Public rooms hidden from the invite table by `--hide-public-rooms`.
+    pub public_rooms: RwLock<discovery::Rooms>,
+    pub list_public_rooms: bool,
+    /// Age after which pages refresh the rooms in the background, see [`reload::revalidate`].
+    pub room_cache_ttl: Option<std::time::Duration>,
+    pub hide_topics: bool,
+    pub topic_length: usize,
+    pub avatars: avatar::AvatarCache,
+    pub membership_check: check::MembershipCheck,
+    pub room_order: order::RoomOrder,
+    pub skip_ban_check: bool,
+    pub max_rooms_per_invite: usize,
+    pub max_batch_size: usize,
+    pub quota: quota::Quota,
+    pub homeserver_check: homeserver::HomeserverCheck,
+    /// Swapped as a whole on reload, see [`room_policy::Rules`].
+    pub rules: ArcSwap<room_policy::Rules>,
+    /// Template for the reason attached to invites, `None` to send none.
+    pub invite_reason: Option<String>,
+    /// Room receiving a notice for every invite and denial.
+    pub admin_room: Option<OwnedRoomId>,
+    pub approval_power_level: i64,
+    pub approval_expiry: std::time::Duration,
+    pub approvals: approval::Approvals,
+    pub webhook: Option<Arc<webhook::Webhook>>,
+    pub mailer: Option<Arc<mail::Mailer>>,
+    pub token_check_interval: Option<std::time::Duration>,
+    /// Endpoint receiving `{"text": ...}` when the token check fails or recovers.
+    pub token_alert_url: Option<url::Url>,
+    pub health: health::Health,
+    /// Audit log of invite attempts, when `--audit-store` is set.
+    pub store: Option<store::Store>,
+    pub digest_interval: Option<std::time::Duration>,
+    pub digest_skip_empty: bool,
+    pub disable_stats: bool,
+    pub stats: stats::StatsCache,
+    pub track_joins: bool,
+    /// Age after which invites that were never accepted are rescinded.
+    pub invite_expiry: Option<std::time::Duration>,
+    pub invite_expiry_dry_run: bool,
+    /// How long invites queued by [`queue`] are retried, `None` to send them right away.
+    pub queue_invites: Option<std::time::Duration>,
+    /// Users whose invites of the bot are accepted, adding the room to the served list.
+    pub auto_join_invites_from: HashSet<OwnedUserId>,
+    pub command_power_level: i64,
+    pub policy: policy::PolicyLists,
+    /// Identity server and its access token for email invites, which are offered only when set.
+    pub id_server: Option<String>,
+    pub id_access_token: Option<secret::Secret<String>>,
+    pub knock_mode: bool,
+    pub knock_decline_after: Option<std::time::Duration>,
+    pub knocks: knock::Knocks,
+    /// Rooms removed through the admin API, kept out of every refresh until re-added.
+    pub hidden_rooms: RwLock<HashSet<OwnedRoomId>>,
+    pub room_filter: RwLock<discovery::RoomFilter>,
+    /// See `--turnstile-url`.
+    pub turnstile_url: String,
     pub turnstile_site_key: String,
-    pub turnstile_secret_key: String,
-    pub csrf: Mutex<HashMap<String, Invite>>,
+    pub turnstile_secret_key: secret::Secret<String>,
+    pub base_path: String,
+    /// Origin of `--github-url`, which the invite form posts and redirects to for the login.
+    pub github_origin: String,
+    pub csp_directives: Vec<String>,
+    pub trusted_proxies: Vec<client_ip::Cidr>,
+    pub geoip: geoip::GeoIp,
+    pub admin_token: Option<secret::Secret<String>>,
+    pub refresh: reload::Refresh,
+    /// Invites waiting for the GitHub login by csrf token, and rate limit counters.
+    pub sessions: Box<dyn sessions::SessionStore>,
+    pub pending_limits: sessions::PendingLimits,
+    /// Successful invites per room, since startup or across restarts with an audit store.
+    pub invite_counts: Mutex<HashMap<OwnedRoomId, u64>>,
+    /// Outstanding invite links by token, see [`links`].
+    pub links: Mutex<HashMap<String, links::Link>>,
+    /// Signs the cookie remembering GitHub logins, which is only set with a key.
+    pub session_key: Option<ring::hmac::Key>,
+    pub session_ttl: std::time::Duration,
+    pub skip_confirmation: bool,
+    /// Signs the render time of the invite form, see [`honeypot`].
+    pub form_key: ring::hmac::Key,
+    pub min_submit_time: std::time::Duration,
+    /// Terms invitees agree to on the invite form, see [`page::invite_controls`].
+    pub tos_url: Option<String>,
+    pub tos_text: Option<String>,
+    /// Pages given by `--page`, linked in the footer.
+    pub pages: Vec<pages::StaticPage>,
+    /// `--site-title`, see [`AppState::site_title`].
+    pub site_title: Option<String>,
+    /// Rendered `--site-intro`, shown above the room list.
+    pub site_intro: Option<Markup>,
+    /// Footer links by label, replacing the source code link when given.
+    pub footer_links: Vec<(String, String)>,
+    pub stylesheet: Option<assets::Stylesheet>,
+    /// See [`client_links::links`].
+    pub element_web_url: Option<String>,
+    /// Leave out the QR code of [`client_links::qr_code`] on the success page.
+    pub hide_qr_code: bool,
+    pub favicon: assets::Favicon,
+    /// Content of /robots.txt, see [`assets::robots_txt`].
+    pub robots_txt: String,
+    /// Skip sending invites after every check passed, see [`invite::send_invite`].
+    pub dry_run: bool,
 }
 
-#[derive(serde::Serialize)]
+#[derive(Clone, serde::Serialize)]
 pub struct RoomInfo {
     pub room_id: OwnedRoomId,
     pub canonical_alias: Option<OwnedRoomAliasId>,
     pub name: Option<String>,
     pub join_rule: SpaceRoomJoinRule,
+    /// Marked as suggested by the space it was discovered through.
+    pub suggested: bool,
+    pub parent: Option<SpaceParent>,
+    pub members: Option<u64>,
+    pub topic: Option<String>,
+    pub avatar_url: Option<OwnedMxcUri>,
+    /// Upgraded room this one replaced.
+    pub predecessor: Option<OwnedRoomId>,
+    /// Successor of an upgraded room the bot could not serve yet; invites are refused.
+    pub replacement: Option<OwnedRoomId>,
 }
 
-#[derive(serde::Deserialize)]
-pub struct Invite {
+#[derive(Clone, PartialEq, Eq, PartialOrd, Ord, serde::Serialize)]
+pub struct SpaceParent {
     pub room_id: OwnedRoomId,
-    pub user_id: OwnedUserId,
+    pub name: Option<String>,
+}
+
+impl RoomInfo {
+    /// Canonical alias if the room has one, the room id otherwise.
+    pub fn display_id(&self) -> String {
+        self.canonical_alias
+            .as_ref()
+            .map(OwnedRoomAliasId::to_string)
+            .unwrap_or_else(|| self.room_id.to_string())
+    }
+
+    /// Case-insensitive search over name, alias and optionally topic; `needle` must be lowercase.
+    pub fn matches(&self, needle: &str, topic: bool) -> bool {
+        [
+            self.name.as_deref(),
+            self.canonical_alias.as_ref().map(|alias| alias.as_str()),
+            self.topic.as_deref().filter(|_| topic),
+        ]
+        .into_iter()
+        .flatten()
+        .any(|field| field.to_lowercase().contains(needle))
+    }
+
+    pub fn matrix_to(&self) -> String {
+        format!("https://matrix.to/#/{}", self.display_id())
+    }
+}
+
+/// Invite form submission, from the HTML form or the JSON API.
+#[derive(serde::Deserialize)]
+pub struct InviteRequest {
+    /// Selected rooms; a single `room_id` is accepted as well.
+    #[serde(default, alias = "room_id")]
+    pub room_ids: Vec<String>,
+    /// Room alias typed into the form, invited to in addition to the selected rooms.
+    pub room: Option<String>,
+    /// Matrix ID as typed, see [`normalize_user_id`].
+    #[serde(default)]
+    pub user_id: String,
+    /// Several Matrix IDs, one per line, used instead of `user_id` when given.
+    pub user_ids: Option<String>,
+    /// Email address invited through the identity server instead of a Matrix ID.
+    pub email: Option<String>,
     #[serde(alias = "cf-turnstile-response")]
     pub cf_turnstile_response: String,
+    /// Hidden field only bots fill in, see [`honeypot`].
+    #[serde(default)]
+    pub website: String,
+    /// Signed time the HTML form was rendered at.
+    pub rendered: Option<String>,
+    /// Agreement to the terms of `--tos-url`.
+    #[serde(default)]
+    pub tos: bool,
 }
 
-pub async fn index(State(state): State<Arc<AppState>>) -> Markup {
-    let rooms = state.rooms.values().collect::<Vec<_>>();
-    html! {
-        (DOCTYPE)
-        html lang="en" {
-            head {
-                meta charset="utf-8";
-                meta name="viewport" content="width=device-width, initial-scale=1";
-                title { "Matrix Bouncer" }
-                script src="https://challenges.cloudflare.com/turnstile/v0/api.js" async defer {}
-                style {
-                    r#"
-                      table, th, td {
-                        border: 1px solid;
-                      }
-                      th, td {
-                        padding: 5px;
-                      }
-                      table {
-                        border-collapse: collapse;
-                      }
-                    "#
+/// GitHub account that completed the login, as returned by the GitHub API.
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+pub struct GitHubUser {
+    pub login: String,
+    pub created_at: chrono::DateTime<chrono::Utc>,
+    /// Organizations the user is an active member of, looked up when a room requires one.
+    #[serde(default)]
+    pub orgs: Vec<String>,
+    /// Teams required by a room the user is an active member of, as `org/team-slug`.
+    #[serde(default)]
+    pub teams: Vec<String>,
+    /// Required teams whose membership the user has not accepted yet.
+    #[serde(default)]
+    pub pending_teams: Vec<String>,
+}
+
+/// Invite waiting for the GitHub login to complete, then for the user to confirm it.
+#[derive(Clone, serde::Serialize, serde::Deserialize)]
+pub struct Invite {
+    pub created: chrono::DateTime<chrono::Utc>,
+    pub room_ids: Vec<OwnedRoomId>,
+    pub user_ids: Vec<OwnedUserId>,
+    /// Email addresses receiving a third-party invite, see [`normalize_email`].
+    pub emails: Vec<String>,
+    /// Batch lines that did not parse, reported on the outcome page.
+    pub malformed: Vec<String>,
+    /// Batch users already joined or invited to a requested room, skipped after the login.
+    #[serde(default)]
+    pub existing: Vec<(OwnedUserId, OwnedRoomId, MembershipState)>,
+    /// PKCE verifier of the GitHub login, set when it starts.
+    #[serde(default)]
+    pub pkce_verifier: Option<String>,
+    /// Nonce of the cookie set on the browser that submitted the form, checked by the callback.
+    #[serde(default)]
+    pub browser_nonce: Option<String>,
+    /// GitHub user who logged in, set once the invite waits for confirmation.
+    #[serde(default)]
+    pub github_user: Option<GitHubUser>,
+    /// Terms of service URL the requester agreed to.
+    #[serde(default)]
+    pub terms: Option<String>,
+    /// Address of the client that submitted the invite, see [`sessions::PendingLimits`].
+    #[serde(default)]
+    pub client_ip: Option<String>,
+}
+
+impl Invite {
+    pub fn age_seconds(&self) -> u64 {
+        (chrono::Utc::now() - self.created)
+            .num_seconds()
+            .try_into()
+            .unwrap_or(0)
+    }
+}
+
+/// Parse a Matrix ID the way users tend to paste it: with surrounding whitespace, as a
+/// matrix.to link, or without the leading @. The server name is lowercased.
+pub fn normalize_user_id(input: &str) -> Option<OwnedUserId> {
+    let input = input.trim();
+    let input = match ["https://matrix.to/#/", "http://matrix.to/#/"]
+        .iter()
+        .find_map(|prefix| input.strip_prefix(prefix))
+    {
+        Some(link) => {
+            let link = link.split('?').next().unwrap_or_default();
+            percent_encoding::percent_decode_str(link)
+                .decode_utf8()
+                .ok()?
+                .into_owned()
+        }
+        None => input.to_string(),
+    };
+    let input = input.strip_prefix('@').unwrap_or(&input);
+    let (localpart, server_name) = input.split_once(':')?;
+    OwnedUserId::try_from(format!("@{}:{}", localpart, server_name.to_lowercase())).ok()
+}
+
+/// Check an email address syntactically: one `@`, no whitespace and a dotted domain, which is
+/// lowercased.
+pub fn normalize_email(input: &str) -> Option<String> {
+    let input = input.trim();
+    let (local, domain) = input.split_once('@')?;
+    let valid = !local.is_empty()
+        && input.len() <= 254
+        && !domain.contains('@')
+        && !input.contains(char::is_whitespace)
+        && domain.contains('.')
+        && domain
+            .split('.')
+            .all(|label| !label.is_empty() && !label.starts_with('-') && !label.ends_with('-'));
+    valid.then(|| format!("{}@{}", local, domain.to_lowercase()))
+}
+
+/// Lowercase hex encoding, for digests and signatures.
+pub fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+/// SHA-256 of an email address, recorded in the audit store instead of the address itself.
+pub fn email_hash(email: &str) -> String {
+    hex(ring::digest::digest(&ring::digest::SHA256, email.as_bytes()).as_ref())
+}
+
+/// Longest invite reason sent to the homeserver, in characters.
+const INVITE_REASON_LENGTH: usize = 300;
+
+/// Fill in the `{github_login}`, `{github_age}` and `{matrix_user}` placeholders of an invite
+/// reason template, flattening whitespace and control characters and capping the length.
+pub fn invite_reason(
+    template: &str,
+    github_login: &str,
+    github_age: &str,
+    user: &UserId,
+) -> String {
+    let reason = template
+        .replace("{github_login}", github_login)
+        .replace("{github_age}", github_age)
+        .replace("{matrix_user}", user.as_str())
+        .replace(char::is_control, " ");
+    truncate(&normalize_whitespace(&reason), INVITE_REASON_LENGTH)
+}
+
+/// Normalize a base path to either "" or "/prefix" without a trailing slash.
+pub fn normalize_base_path(path: &str) -> String {
+    let path = path.trim_matches('/');
+    if path.is_empty() {
+        String::new()
+    } else {
+        format!("/{}", path)
+    }
+}
+
+impl AppState {
+    /// Path of a route relative to the page it is linked from.
+    pub fn link(&self, route: &str) -> String {
+        if self.base_path.is_empty() {
+            route.to_string()
+        } else {
+            format!("{}/{}", self.base_path, route)
+        }
+    }
+
+    /// Path of a route from the site root, for pages not served at the index.
+    pub fn absolute_link(&self, route: &str) -> String {
+        format!("{}/{}", self.base_path, route)
+    }
+
+    pub fn site_title(&self) -> &str {
+        self.site_title.as_deref().unwrap_or("Matrix Bouncer")
+    }
+
+    /// Title of a page, followed by the site title.
+    pub fn page_title(&self, page: &str) -> String {
+        format!("{} - {}", page, self.site_title())
+    }
+
+    /// Name of a served room, falling back to its alias or id.
+    pub async fn room_name(&self, room_id: &RoomId) -> String {
+        self.rooms.read().await.get(room_id).map_or_else(
+            || room_id.to_string(),
+            |room| room.name.clone().unwrap_or_else(|| room.display_id()),
+        )
+    }
+
+    /// Count an invite the homeserver accepted, persisting the counters when an audit store is
+    /// configured.
+    pub async fn count_invite(&self, room_id: &RoomId) {
+        let mut counts = self.invite_counts.lock().await;
+        *counts.entry(room_id.to_owned()).or_default() += 1;
+        if let Some(store) = &self.store {
+            store
+                .update(|data| data.invite_counts = counts.clone())
+                .await;
+        }
+    }
+
+    /// Check the captcha secret with Cloudflare using a dummy response, which a valid secret
+    /// answers with `invalid-input-response` only.
+    pub async fn validate_turnstile_secret(&self) -> anyhow::Result<()> {
+        let request = self
+            .http_client
+            .post(format!("{}/turnstile/v0/siteverify", self.turnstile_url))
+            .form(&[
+                ("secret", self.turnstile_secret_key.expose().as_str()),
+                ("response", "bouncer-startup-check"),
+            ]);
+        let response: Turnstile =
+            send_idempotent("Turnstile secret check", request, self.http_timeout)
+                .await
+                .context("failed to reach the turnstile siteverify endpoint")
+                .context(startup::Failure::Network)?
+                .json()
+                .await
+                .context("failed to decode the turnstile siteverify result")
+                .context(startup::Failure::Network)?;
+        if response
+            .error_codes
+            .iter()
+            .any(|code| code == "invalid-input-secret" || code == "missing-input-secret")
+        {
+            return Err(anyhow::anyhow!(
+                "Cloudflare rejected turnstile_secret_key [redacted] for turnstile_site_key {}: {}",
+                self.turnstile_site_key,
+                response.error_codes.join(", ")
+            )
+            .context(startup::Failure::Auth));
+        }
+        Ok(())
+    }
+
+    /// Origins the captcha widget loads its script and frames from.
+    pub fn captcha_origins(&self) -> Vec<String> {
+        self.captcha.origins()
+    }
+}
+
+fn normalize_whitespace(text: &str) -> String {
+    text.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+/// Cut text to at most `length` characters, marking the cut with an ellipsis.
+fn truncate(text: &str, length: usize) -> String {
+    if text.chars().count() <= length {
+        text.to_string()
+    } else {
+        let mut truncated = text
+            .chars()
+            .take(length.saturating_sub(1))
+            .collect::<String>();
+        truncated.push('…');
+        truncated
+    }
+}
+
+/// Look up a served room by room id or canonical alias.
+pub fn find_room<'a>(rooms: &'a discovery::Rooms, room: &str) -> Option<&'a RoomInfo> {
+    match OwnedRoomId::try_from(room) {
+        Ok(room_id) => rooms.get(&room_id),
+        Err(_) => rooms.values().find(|info| {
+            info.canonical_alias
+                .as_ref()
+                .is_some_and(|alias| alias.as_str() == room)
+        }),
+    }
+}
+
+/// Format a number with comma thousands separators.
+fn thousands(n: u64) -> String {
+    let digits = n.to_string();
+    let mut out = String::new();
+    for (i, digit) in digits.chars().enumerate() {
+        if i > 0 && (digits.len() - i) % 3 == 0 {
+            out.push(',');
+        }
+        out.push(digit);
+    }
+    out
+}
+
+/// Group rooms by parent space, spaces ordered by name with spaceless rooms last, and rooms
+/// within a group in the configured order.
+fn group_by_parent<'a>(
+    rooms: impl Iterator<Item = &'a RoomInfo>,
+    order: &order::RoomOrder,
+) -> Vec<(Option<&'a SpaceParent>, Vec<&'a RoomInfo>)> {
+    let mut groups: Vec<(Option<&SpaceParent>, Vec<&RoomInfo>)> = vec![];
+    for room in rooms {
+        match groups
+            .iter_mut()
+            .find(|(parent, _)| *parent == room.parent.as_ref())
+        {
+            Some((_, rooms)) => rooms.push(room),
+            None => groups.push((room.parent.as_ref(), vec![room])),
+        }
+    }
+    groups.sort_by(|(a, _), (b, _)| match (a, b) {
+        (Some(a), Some(b)) => (&a.name, &a.room_id).cmp(&(&b.name, &b.room_id)),
+        (Some(_), None) => std::cmp::Ordering::Less,
+        (None, Some(_)) => std::cmp::Ordering::Greater,
+        (None, None) => std::cmp::Ordering::Equal,
+    });
+    for (_, rooms) in &mut groups {
+        order.sort(rooms);
+    }
+    groups
+}
+
+#[derive(serde::Deserialize)]
+pub struct IndexQuery {
+    pub q: Option<String>,
+    pub page: Option<usize>,
+    pub per_page: Option<usize>,
+    /// Room id or alias to preselect.
+    pub room: Option<String>,
+    /// Show the batch form taking several Matrix IDs.
+    pub batch: Option<bool>,
+}
+
+pub const DEFAULT_PER_PAGE: usize = 25;
+pub const MAX_PER_PAGE: usize = 100;
+
+/// Keep `limit` rooms starting at `offset`, counted across groups in display order.
+fn paginate<'a>(
+    groups: Vec<(Option<&'a SpaceParent>, Vec<&'a RoomInfo>)>,
+    mut offset: usize,
+    mut limit: usize,
+) -> Vec<(Option<&'a SpaceParent>, Vec<&'a RoomInfo>)> {
+    let mut page = vec![];
+    for (parent, rooms) in groups {
+        if limit == 0 {
+            break;
+        }
+        if offset >= rooms.len() {
+            offset -= rooms.len();
+            continue;
+        }
+        let rooms = rooms
+            .into_iter()
+            .skip(offset)
+            .take(limit)
+            .collect::<Vec<_>>();
+        offset = 0;
+        limit -= rooms.len();
+        page.push((parent, rooms));
+    }
+    page
+}
+
+/// Query of another page of the index, keeping the search and the preselected room.
+pub fn page_link(
+    search: &str,
+    room: Option<&str>,
+    page: usize,
+    per_page: usize,
+    batch: bool,
+) -> String {
+    let mut query = url::form_urlencoded::Serializer::new(String::from("?"));
+    if !search.is_empty() {
+        query.append_pair("q", search);
+    }
+    if let Some(room) = room {
+        query.append_pair("room", room);
+    }
+    query.append_pair("page", &page.to_string());
+    if per_page != DEFAULT_PER_PAGE {
+        query.append_pair("per_page", &per_page.to_string());
+    }
+    if batch {
+        query.append_pair("batch", "true");
+    }
+    query.finish()
+}
+
+/// How long browsers may reuse the index page before revalidating it.
+const INDEX_MAX_AGE: Duration = Duration::from_secs(60);
+
+/// ETag of the index page without query parameters, derived from everything it shows: the
+/// rooms and their invite counts, the reloadable room rules, the site branding, the language
+/// and GitHub login of the visitor, and the hour, which keeps the render stamp of a reused form
+/// fresh. It is keyed with the form key, so it
+/// changes with the process unless `--session-key` is shared.
+async fn index_etag(state: &AppState, login: Option<&str>) -> String {
+    let serialize = |rooms: &discovery::Rooms| {
+        let mut rooms = rooms
+            .values()
+            .map(|room| serde_json::to_string(room).unwrap_or_default())
+            .collect::<Vec<_>>();
+        rooms.sort();
+        rooms
+    };
+    let rooms = serialize(&*state.rooms.read().await);
+    let public_rooms = if state.list_public_rooms {
+        serialize(&*state.public_rooms.read().await)
+    } else {
+        vec![]
+    };
+    let mut counts = state
+        .invite_counts
+        .lock()
+        .await
+        .iter()
+        .map(|(room_id, count)| format!("{} {}", room_id, count))
+        .collect::<Vec<_>>();
+    counts.sort();
+    let branding = [
+        state.site_title().to_string(),
+        state
+            .site_intro
+            .as_ref()
+            .map(|intro| intro.clone().into_string())
+            .unwrap_or_default(),
+        format!("{:?}", state.footer_links),
+        state
+            .pages
+            .iter()
+            .map(|page| format!("{} {}", page.path, page.title))
+            .collect::<Vec<_>>()
+            .join(" "),
+        assets::stylesheets(state).into_string(),
+        format!("{:?} {:?}", state.tos_url, state.tos_text),
+    ];
+    let fingerprint = [
+        env!("CARGO_PKG_VERSION").to_string(),
+        (chrono::Utc::now().timestamp() / 3600).to_string(),
+        i18n::current().to_string(),
+        login.unwrap_or_default().to_string(),
+        rooms.join("\n"),
+        public_rooms.join("\n"),
+        counts.join("\n"),
+        state.rules.load().fingerprint(),
+        branding.join("\n"),
+    ]
+    .join("\n");
+    let tag = ring::hmac::sign(&state.form_key, fingerprint.as_bytes());
+    format!("\"{}\"", hex(&tag.as_ref()[..16]))
+}
+
+/// Whether an If-None-Match header lists `etag`, compared weakly.
+fn not_modified(headers: &HeaderMap, etag: &str) -> bool {
+    headers
+        .get_all(IF_NONE_MATCH)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(|tag| tag.trim().trim_start_matches("W/"))
+        .any(|tag| tag == etag || tag == "*")
+}
+
+pub async fn index(
+    State(state): State<Arc<AppState>>,
+    Extension(CspNonce(nonce)): Extension<CspNonce>,
+    headers: HeaderMap,
+    RawQuery(raw_query): RawQuery,
+    Query(query): Query<IndexQuery>,
+) -> Response {
+    reload::revalidate(&state).await;
+    let login = login::from_headers(&state, &headers);
+    // Searches and preselected rooms are not worth caching.
+    let etag = match raw_query {
+        None => Some(index_etag(&state, login.as_ref().map(|login| login.login.as_str())).await),
+        Some(_) => None,
+    };
+    let cache_headers = etag.as_ref().map(|etag| {
+        [
+            (ETAG, etag.clone()),
+            (
+                CACHE_CONTROL,
+                format!("private, max-age={}", INDEX_MAX_AGE.as_secs()),
+            ),
+            (VARY, "Cookie, Accept-Language".to_string()),
+        ]
+    });
+    if let (Some(etag), Some(cache_headers)) = (&etag, &cache_headers) {
+        if not_modified(&headers, etag) {
+            return (StatusCode::NOT_MODIFIED, cache_headers.clone()).into_response();
+        }
+    }
+    let search = query.q.as_deref().map(str::trim).unwrap_or_default();
+    let needle = search.to_lowercase();
+    let rooms = state.rooms.read().await;
+    let matching = rooms
+        .values()
+        .filter(|room| !room_policy::hidden(&state, &room.room_id))
+        .filter(|room| needle.is_empty() || room.matches(&needle, !state.hide_topics))
+        .collect::<Vec<_>>();
+    let grouped = matching.iter().any(|room| room.parent.is_some());
+    let total = matching.len();
+    let groups = group_by_parent(matching.into_iter(), &state.room_order);
+    let per_page = query
+        .per_page
+        .unwrap_or(DEFAULT_PER_PAGE)
+        .clamp(1, MAX_PER_PAGE);
+    let pages = total.div_ceil(per_page).max(1);
+    let requested = query
+        .room
+        .as_deref()
+        .map(str::trim)
+        .filter(|room| !room.is_empty());
+    let selected =
+        requested.map(|requested| find_room(&rooms, requested).map(|room| &room.room_id));
+    let selected_position = selected.flatten().and_then(|room_id| {
+        groups
+            .iter()
+            .flat_map(|(_, rooms)| rooms)
+            .position(|room| room.room_id == *room_id)
+    });
+    let page = query
+        .page
+        .or(selected_position.map(|position| position / per_page + 1))
+        .unwrap_or(1)
+        .clamp(1, pages);
+    let groups = paginate(groups, (page - 1) * per_page, per_page);
+    let batch = query.batch.unwrap_or(false);
+    let columns = if state.hide_topics { 8 } else { 9 };
+    let counts = state.invite_counts.lock().await.clone();
+    let public_rooms = state.public_rooms.read().await;
+    let mut public_rooms = if state.list_public_rooms {
+        public_rooms.values().collect::<Vec<_>>()
+    } else {
+        vec![]
+    };
+    state.room_order.sort(&mut public_rooms);
+    let page = page::layout(
+        &state,
+        &nonce,
+        state.site_title(),
+        html! {
+            @if let Some(title) = &state.site_title {
+                h1 { (title) }
+            }
+            @if let Some(intro) = &state.site_intro {
+                (intro)
+            }
+            @if let Some(login) = &login {
+                p {
+                    (i18n::tr("Verified as {login}.", &[("login", &login.login)])) " "
+                    a href=(state.absolute_link("logout")) { (t("Log out")) }
                 }
             }
-            body {
-                div {
-                    form action="invite" method="post" {
-                        table {
-                            thead {
-                                tr {
-                                    th { "Select" }
-                                    th { "Name" }
-                                    th { "Alias" }
-                                    th { "Join Rule" }
-                                    th { "ID" }
+            @if selected == Some(None) {
+                p { (t("The requested room is not available.")) }
+            }
+            @if state.knock_mode {
+                p { (t("For rooms with the knock join rule, knock from your Matrix client first, then verify here to have the knock accepted.")) }
+            }
+            div {
+                form method="get" class="controls" {
+                    input type="search" name="q" value=(search) placeholder=(t("Search rooms")) aria-label=(t("Search rooms"));
+                    @if let Some(room) = requested {
+                        input type="hidden" name="room" value=(room);
+                    }
+                    @if batch {
+                        input type="hidden" name="batch" value="true";
+                    }
+                    button type="submit" { (t("Search")) }
+                }
+            }
+            div {
+                form action=(state.link("invite")) method="post" {
+                    table {
+                        thead {
+                            tr {
+                                th { (t("Select")) }
+                                th {}
+                                th { (t("Name")) }
+                                th { (t("Alias")) }
+                                th { (t("Join Rule")) }
+                                th { (t("Members")) }
+                                th { (t("Invites sent")) }
+                                @if !state.hide_topics {
+                                    th { (t("Topic")) }
                                 }
+                                th { (t("ID")) }
                             }
+                        }
+                        @if groups.is_empty() {
                             tbody {
-                                @for room in &rooms {
-                                    tr {
-                                        td {
-                                            input type="radio" name="room_id" value=(room.room_id);
+                                tr {
+                                    td colspan=(columns) { (t("No rooms match")) }
+                                }
+                            }
+                        }
+                        @for (parent, rooms) in &groups {
+                          tbody {
+                            @if grouped {
+                                tr {
+                                    th colspan=(columns) {
+                                        @match parent {
+                                            Some(parent) => {
+                                                (parent.name.clone().unwrap_or_else(|| parent.room_id.to_string()))
+                                            }
+                                            None => { (t("Other")) }
                                         }
-                                        td { (room.name.clone().unwrap_or_default()) }
-                                        td {
-                                          (room.canonical_alias
-                                            .as_ref()
-                                            .map(OwnedRoomAliasId::to_string)
-                                            .unwrap_or_default())
+                                    }
+                                }
+                            }
+                            @for room in rooms {
+                                @let checked = selected == Some(Some(&room.room_id));
+                                tr id=[checked.then_some("selected-room")] {
+                                    td {
+                                        input type="checkbox" name="room_ids" value=(room.room_id) checked[checked];
+                                    }
+                                    td {
+                                        @if room.avatar_url.is_some() {
+                                            img class="avatar" src=(state.link(&format!("avatar/{}", room.room_id))) alt="";
+                                        } @else {
+                                            div class="avatar" {}
                                         }
-                                        td { (room.join_rule) }
-                                        td { (room.room_id) }
                                     }
+                                    td {
+                                        a href=(state.absolute_link(&format!("invite/{}", page::room_segment(&room.display_id())))) {
+                                            (room.name.clone().unwrap_or_default())
+                                        }
+                                        @if room.replacement.is_some() {
+                                            " " (t("(upgraded, invites paused)"))
+                                        }
+                                        (page::requirements(&state, &room.room_id))
+                                    }
+                                    td {
+                                      (room.canonical_alias
+                                        .as_ref()
+                                        .map(OwnedRoomAliasId::to_string)
+                                        .unwrap_or_default())
+                                    }
+                                    td { (room.join_rule) }
+                                    td class="number" {
+                                        @match room.members {
+                                            Some(members) => { (thousands(members)) }
+                                            None => { "—" }
+                                        }
+                                    }
+                                    td class="number" { (thousands(counts.get(&room.room_id).copied().unwrap_or(0))) }
+                                    @if !state.hide_topics {
+                                        @let topic = room.topic.as_deref().map(normalize_whitespace).unwrap_or_default();
+                                        td title=(topic) { (truncate(&topic, state.topic_length)) }
+                                    }
+                                    td { (room.room_id) }
                                 }
                             }
+                          }
                         }
-                        div style="display: flex; padding: 5px;" {
-                          div style="display: flex; flex-direction: column;" {
-                            div style="padding: 5px;" {
-                                label for="user" style="padding-right: 5px;" { "User ID" }
-                                input type="text" id="user" name="user_id" placeholder="@user:example.com" required;
+                    }
+                    @if pages > 1 {
+                        div class="controls" {
+                            @if page > 1 {
+                                a href=(page_link(search, requested, page - 1, per_page, batch)) { (t("Previous")) }
                             }
-                            div style="padding: 5px;" {
-                              button type="submit" style="width: 100%;" { "Login with GitHub to Invite" }
+                            span class="field" { (i18n::tr("Page {page} of {pages}", &[("page", &page), ("pages", &pages)])) }
+                            @if page < pages {
+                                a href=(page_link(search, requested, page + 1, per_page, batch)) { (t("Next")) }
                             }
-                          }
-                          div class="cf-turnstile" data-sitekey=(&state.turnstile_site_key) style="padding: 5px;" {}
+                        }
+                    }
+                    div class="controls" {
+                        div class="field" {
+                            label for="room" { (t("Or room alias")) }
+                            input type="text" id="room" name="room" placeholder="#room:example.com";
+                        }
+                    }
+                    (page::invite_controls(&state, &nonce, batch))
+                    div class="controls" {
+                        @if batch {
+                            a href=(page_link(search, requested, page, per_page, false)) { (t("Invite a single person")) }
+                        } @else {
+                            a href=(page_link(search, requested, page, per_page, true)) { (t("Invite several people at once")) }
                         }
                     }
                 }
-                footer {
-                  "Source Code:" a href="https://github.com/NickCao/bouncer" { "https://github.com/NickCao/bouncer" }
+            }
+            @if !public_rooms.is_empty() {
+                div {
+                    p { (t("These rooms are public, you can just join them:")) }
+                    ul {
+                        @for room in &public_rooms {
+                            li {
+                                a href=(room.matrix_to()) {
+                                    (room.name.clone().unwrap_or_else(|| room.display_id()))
+                                }
+                            }
+                        }
+                    }
                 }
             }
-        }
+        },
+    );
+    match cache_headers {
+        Some(cache_headers) => (cache_headers, page).into_response(),
+        None => page.into_response(),
     }
 }
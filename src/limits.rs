@@ -0,0 +1,40 @@
+use std::sync::Arc;
+
+use axum::{
+    extract::{Request, State},
+    http::StatusCode,
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+
+use crate::{i18n::t, page, AppState};
+
+/// Answer requests still running after `--request-timeout` with the HTML error page.
+///
+/// Upstream requests made so far are not rolled back; invites sent before the timeout are
+/// recorded as usual.
+pub async fn request_timeout(
+    State(state): State<Arc<AppState>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let nonce = page::nonce(request.extensions());
+    let path = request.uri().path().to_string();
+    match tokio::time::timeout(state.request_timeout, next.run(request)).await {
+        Ok(response) => response,
+        Err(_) => {
+            log::warn!(
+                "request to {} timed out after {:?}",
+                path,
+                state.request_timeout
+            );
+            page::error_page(
+                &state,
+                &nonce,
+                StatusCode::GATEWAY_TIMEOUT,
+                &t("The request took too long, please try again."),
+            )
+            .into_response()
+        }
+    }
+}
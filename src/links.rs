@@ -0,0 +1,328 @@
+use std::{collections::HashMap, sync::Arc, time::Duration};
+
+use axum::{
+    extract::{Extension, Path, State},
+    http::StatusCode,
+};
+use chrono::{DateTime, Utc};
+use maud::{html, Markup};
+use oauth2::CsrfToken;
+use ruma::OwnedRoomId;
+
+use crate::{
+    admin::SHOWN_TOKEN_PREFIX,
+    audit,
+    client_ip::ClientIp,
+    i18n::{self, t, tr},
+    invite::invite_user,
+    normalize_user_id,
+    page::{self, HtmlForm},
+    security::CspNonce,
+    sessions, AppState,
+};
+
+const UNKNOWN_LINK: &str = "This invite link is unknown or was revoked.";
+/// How long expired or used up links are kept, to explain why they stopped working.
+const LINGER: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+const SWEEP_INTERVAL: Duration = Duration::from_secs(60 * 60);
+/// Claims a single client may attempt per window.
+const RATE_LIMIT: u32 = 5;
+const RATE_WINDOW: Duration = Duration::from_secs(10 * 60);
+
+/// Invite link handed out by an admin, inviting whoever claims it without a GitHub login.
+#[derive(Clone, serde::Serialize, serde::Deserialize)]
+pub struct Link {
+    pub room_id: OwnedRoomId,
+    pub note: String,
+    /// Admin who created the link, see [`crate::admin::Admin`].
+    pub created_by: String,
+    pub created: DateTime<Utc>,
+    #[serde(default)]
+    pub expires_at: Option<DateTime<Utc>>,
+    #[serde(default = "single_use")]
+    pub max_uses: u32,
+    #[serde(default)]
+    pub uses: u32,
+    #[serde(default)]
+    pub last_claimed: Option<DateTime<Utc>>,
+}
+
+fn single_use() -> u32 {
+    1
+}
+
+impl Link {
+    /// Why the link cannot be claimed anymore, if it cannot.
+    fn unusable(&self) -> Option<String> {
+        match self.expires_at {
+            Some(expires_at) if expires_at <= Utc::now() => Some(tr(
+                "This invite link expired on {date}.",
+                &[("date", &expires_at.format("%Y-%m-%d %H:%M UTC"))],
+            )),
+            _ if self.uses >= self.max_uses => Some(tr(
+                "This invite link was used {uses} times, as often as it allows.",
+                &[("uses", &self.uses)],
+            )),
+            _ => None,
+        }
+    }
+
+    /// Whether an unusable link has been kept around long enough.
+    fn stale(&self, now: DateTime<Utc>) -> bool {
+        let linger = chrono::Duration::from_std(LINGER).unwrap_or(chrono::Duration::MAX);
+        let expired = self
+            .expires_at
+            .is_some_and(|expires_at| expires_at + linger < now);
+        let used_up = self.uses >= self.max_uses
+            && self
+                .last_claimed
+                .is_some_and(|claimed| claimed + linger < now);
+        expired || used_up
+    }
+}
+
+async fn persist(state: &AppState, links: &HashMap<String, Link>) {
+    if let Some(store) = &state.store {
+        store.update(|data| data.links = links.clone()).await;
+    }
+}
+
+/// Create an invite link to a room, claimed up to `max_uses` times until `expires_at`, and
+/// return its token.
+pub async fn create(
+    state: &AppState,
+    room_id: OwnedRoomId,
+    note: String,
+    admin: &str,
+    expires_at: Option<DateTime<Utc>>,
+    max_uses: u32,
+) -> String {
+    let token = CsrfToken::new_random().secret().to_string();
+    let link = Link {
+        room_id,
+        note,
+        created_by: admin.to_string(),
+        created: Utc::now(),
+        expires_at,
+        max_uses,
+        uses: 0,
+        last_claimed: None,
+    };
+    let room = state.room_name(&link.room_id).await;
+    log::warn!("{} created an invite link to room {}", admin, link.room_id);
+    audit::post(
+        state,
+        format!(
+            "{} created an invite link to {}: {}",
+            admin, room, link.note
+        ),
+        html! { (admin) " created an invite link to " (room) ": " (link.note) },
+    )
+    .await;
+    let mut links = state.links.lock().await;
+    links.insert(token.clone(), link);
+    persist(state, &links).await;
+    token
+}
+
+/// Drop outstanding links whose token starts with the prefix.
+pub async fn revoke(state: &AppState, prefix: &str) -> usize {
+    let mut links = state.links.lock().await;
+    let before = links.len();
+    links.retain(|token, _| !token.starts_with(prefix));
+    persist(state, &links).await;
+    before - links.len()
+}
+
+/// Count a use of the link before the invite is sent, so concurrent claims cannot exceed
+/// its uses; the error explains why it cannot be claimed.
+async fn reserve(state: &AppState, token: &str) -> Result<Link, (StatusCode, String)> {
+    let mut links = state.links.lock().await;
+    let link = links
+        .get_mut(token)
+        .ok_or_else(|| (StatusCode::NOT_FOUND, t(UNKNOWN_LINK)))?;
+    if let Some(reason) = link.unusable() {
+        return Err((StatusCode::GONE, reason));
+    }
+    link.uses += 1;
+    link.last_claimed = Some(Utc::now());
+    let link = link.clone();
+    persist(state, &links).await;
+    Ok(link)
+}
+
+/// Give back the use of a claim whose invite failed.
+async fn release(state: &AppState, token: &str) {
+    let mut links = state.links.lock().await;
+    if let Some(link) = links.get_mut(token) {
+        link.uses = link.uses.saturating_sub(1);
+        persist(state, &links).await;
+    }
+}
+
+/// Delete links that expired or were used up a while ago.
+pub fn spawn(state: Arc<AppState>) {
+    tokio::spawn(async move {
+        loop {
+            tokio::time::sleep(SWEEP_INTERVAL).await;
+            let now = Utc::now();
+            let mut links = state.links.lock().await;
+            let before = links.len();
+            links.retain(|_, link| !link.stale(now));
+            if links.len() < before {
+                log::warn!("deleted {} stale invite links", before - links.len());
+                persist(&state, &links).await;
+            }
+        }
+    });
+}
+
+#[derive(serde::Deserialize)]
+pub struct ClaimRequest {
+    #[serde(default)]
+    user_id: String,
+    #[serde(alias = "cf-turnstile-response")]
+    cf_turnstile_response: String,
+}
+
+/// Form of an invite link, asking only for the Matrix ID and the captcha.
+pub async fn claim_form(
+    State(state): State<Arc<AppState>>,
+    Extension(CspNonce(nonce)): Extension<CspNonce>,
+    Path(token): Path<String>,
+) -> Result<Markup, (StatusCode, Markup)> {
+    let link = state.links.lock().await.get(&token).cloned();
+    let room_id = match link {
+        None => {
+            return Err(page::error_page(
+                &state,
+                &nonce,
+                StatusCode::NOT_FOUND,
+                &t(UNKNOWN_LINK),
+            ))
+        }
+        Some(link) => match link.unusable() {
+            Some(reason) => {
+                return Err(page::error_page(&state, &nonce, StatusCode::GONE, &reason))
+            }
+            None => link.room_id,
+        },
+    };
+    let name = state.room_name(&room_id).await;
+    Ok(page::layout(
+        &state,
+        &nonce,
+        &state.page_title(&tr("Join {room}", &[("room", &name)])),
+        html! {
+            h1 { (name) }
+            p { (t("Enter the Matrix account to invite to the room.")) }
+            form action=(state.absolute_link(&format!("claim/{}", token))) method="post" {
+                div class="controls" {
+                    div class="fields" {
+                        div class="field" {
+                            label for="user" { (t("User ID")) }
+                            input type="text" id="user" name="user_id" placeholder="@user:example.com" required;
+                        }
+                        div class="field" {
+                            button type="submit" { (t("Invite")) }
+                        }
+                    }
+                    div class="cf-turnstile" data-sitekey=(&state.turnstile_site_key) data-language=(i18n::current()) {}
+                }
+            }
+        },
+    ))
+}
+
+/// Invite the given user with an invite link, using it up once.
+pub async fn claim(
+    State(state): State<Arc<AppState>>,
+    Extension(CspNonce(nonce)): Extension<CspNonce>,
+    ClientIp(client_ip): ClientIp,
+    Path(token): Path<String>,
+    HtmlForm(request): HtmlForm<ClaimRequest>,
+) -> Result<Markup, (StatusCode, Markup)> {
+    let error = |(status, message): (StatusCode, String)| {
+        page::error_page(&state, &nonce, status, &message)
+    };
+    let key = format!("claim:{}", client_ip);
+    if !sessions::allow(&*state.sessions, &key, RATE_LIMIT, RATE_WINDOW).await {
+        return Err(error((
+            StatusCode::TOO_MANY_REQUESTS,
+            t("Too many invite link claims, please try again later."),
+        )));
+    }
+    match state.links.lock().await.get(&token) {
+        None => return Err(error((StatusCode::NOT_FOUND, t(UNKNOWN_LINK)))),
+        Some(link) => {
+            if let Some(reason) = link.unusable() {
+                return Err(error((StatusCode::GONE, reason)));
+            }
+        }
+    }
+    state
+        .captcha
+        .verify(&request.cf_turnstile_response, client_ip)
+        .await
+        .map_err(error)?;
+    let user_id = normalize_user_id(&request.user_id).ok_or_else(|| {
+        error((
+            StatusCode::BAD_REQUEST,
+            tr(
+                "{user} is not a valid Matrix ID, expected the form @user:example.com",
+                &[("user", &format!("{:?}", request.user_id))],
+            ),
+        ))
+    })?;
+    let link = reserve(&state, &token).await.map_err(error)?;
+
+    let prefix = token.chars().take(SHOWN_TOKEN_PREFIX).collect::<String>();
+    let outcome = invite_user(
+        &state,
+        &link.room_id,
+        &user_id,
+        &format!("invite link {}", prefix),
+        None,
+    )
+    .await;
+    let room = state.room_name(&link.room_id).await;
+    let open = match &outcome {
+        Ok(()) => state.rooms.read().await.get(&link.room_id).cloned(),
+        Err(_) => None,
+    };
+    match &outcome {
+        Ok(()) => {
+            log::warn!(
+                "{} claimed invite link {} to room {} created by {}",
+                user_id,
+                prefix,
+                link.room_id,
+                link.created_by
+            );
+            audit::post(
+                &state,
+                format!(
+                    "{} claimed an invite link to {} created by {}: {}",
+                    user_id, room, link.created_by, link.note
+                ),
+                html! {
+                    a href=(format!("https://matrix.to/#/{}", user_id)) { (user_id) }
+                    " claimed an invite link to " (room) " created by " (link.created_by) ": " (link.note)
+                },
+            )
+            .await;
+        }
+        Err(_) => release(&state, &token).await,
+    }
+    Ok(page::invite_outcome(
+        &state,
+        &nonce,
+        &[page::UserOutcome {
+            user: user_id.to_string(),
+            rooms: Ok(vec![(room, outcome.map(|()| t("invited")))]),
+        }],
+        &[],
+        open.as_slice(),
+        &[],
+    ))
+}
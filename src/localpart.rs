@@ -0,0 +1,118 @@
+//! Refusing invitees by the localpart of their Matrix ID, such as throwaway accounts named
+//! like spam, before an invite is wasted on them.
+
+use anyhow::Context;
+use regex::{Regex, RegexBuilder};
+
+/// Rules given by `--blocked-localpart-regex`, `--min-localpart-length` and
+/// `--max-localpart-length`.
+#[derive(Clone, Debug, Default)]
+pub struct LocalpartRules {
+    blocked: Vec<Regex>,
+    min_length: Option<usize>,
+    max_length: Option<usize>,
+}
+
+impl LocalpartRules {
+    /// Compile the patterns, which match anywhere in the localpart and ignore case unless they
+    /// turn it back on with `(?-i)`.
+    pub fn new(
+        blocked: &[String],
+        min_length: Option<usize>,
+        max_length: Option<usize>,
+    ) -> anyhow::Result<LocalpartRules> {
+        if let (Some(min), Some(max)) = (min_length, max_length) {
+            if min > max {
+                anyhow::bail!(
+                    "min_localpart_length {} exceeds max_localpart_length {}",
+                    min,
+                    max
+                );
+            }
+        }
+        let blocked = blocked
+            .iter()
+            .map(|pattern| {
+                RegexBuilder::new(pattern)
+                    .case_insensitive(true)
+                    .build()
+                    .with_context(|| format!("invalid blocked_localpart_regex {:?}", pattern))
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        Ok(LocalpartRules {
+            blocked,
+            min_length,
+            max_length,
+        })
+    }
+
+    /// The rule a localpart breaks, for the log; never shown to the requester. Lengths count
+    /// characters, not bytes.
+    pub fn violation(&self, localpart: &str) -> Option<String> {
+        let length = localpart.chars().count();
+        if let Some(min) = self.min_length.filter(|min| length < *min) {
+            return Some(format!("shorter than {} characters", min));
+        }
+        if let Some(max) = self.max_length.filter(|max| length > *max) {
+            return Some(format!("longer than {} characters", max));
+        }
+        self.blocked
+            .iter()
+            .find(|pattern| pattern.is_match(localpart))
+            .map(|pattern| format!("matches blocked pattern {:?}", pattern.as_str()))
+    }
+}
+
+/// How `--require-matching-localpart` compares a GitHub login with the localpart of the
+/// invitee: ignoring case, with `--localpart-mapping` characters taken as equal, and skipped
+/// for `--matching-localpart-exception` logins and Matrix IDs.
+#[derive(Clone, Debug, Default)]
+pub struct LoginMatch {
+    mapping: Vec<(char, char)>,
+    exceptions: Vec<String>,
+}
+
+impl LoginMatch {
+    /// `mapping` entries are `from=to`, e.g. `-=_` for a GitHub login `jane-doe` to match the
+    /// localpart `jane_doe` as well as `jane-doe`.
+    pub fn new(mapping: &[String], exceptions: &[String]) -> anyhow::Result<LoginMatch> {
+        let mapping = mapping
+            .iter()
+            .map(|entry| {
+                let mut chars = entry.chars();
+                match (chars.next(), chars.next(), chars.next(), chars.next()) {
+                    (Some(from), Some('='), Some(to), None) => Ok((from, to)),
+                    _ => anyhow::bail!(
+                        "invalid localpart_mapping {:?}, expected a character pair like -=_",
+                        entry
+                    ),
+                }
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        Ok(LoginMatch {
+            mapping,
+            exceptions: exceptions
+                .iter()
+                .map(|exception| exception.trim().to_lowercase())
+                .collect(),
+        })
+    }
+
+    fn normalize(&self, name: &str) -> String {
+        name.to_lowercase()
+            .chars()
+            .map(|c| {
+                self.mapping
+                    .iter()
+                    .find(|(from, _)| *from == c)
+                    .map_or(c, |(_, to)| *to)
+            })
+            .collect()
+    }
+
+    pub fn matches(&self, login: &str, user_id: &ruma::UserId) -> bool {
+        self.exceptions.iter().any(|exception| {
+            *exception == login.to_lowercase() || *exception == user_id.as_str().to_lowercase()
+        }) || self.normalize(login) == self.normalize(user_id.localpart())
+    }
+}
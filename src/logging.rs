@@ -0,0 +1,104 @@
+//! Log output, as env_logger's text or as JSON lines with `--log-format json`.
+//!
+//! Audit lines are logged with the [`AUDIT_TARGET`] target and carry key-values, which JSON
+//! lines put next to `timestamp`, `level`, `target` and `message`. Log pipelines parse them,
+//! so the keys are an interface and must stay stable:
+//!
+//! - `event`: `github_login` once a Matrix user is vouched for by a GitHub login, `invite`
+//!   for the outcome of an invite to one room, `invite_request` for the origin of a
+//!   submission with `--geoip-database`
+//! - `decision`: for `invite`, one of `invited`, `denied`, `failed` or `dry_run`; for
+//!   `invite_request`, `allowed` or `blocked`
+//! - `client_country`: for `invite_request`, the ISO code of the client's country, null if
+//!   unknown
+//! - `matrix_user`: the Matrix ID, null for email invites
+//! - `github_login`: the GitHub login vouching for the invite
+//! - `github_age_seconds`: for `github_login`, the age of the GitHub account
+//! - `room_id`: for `invite`, the room
+//! - `reason`: for `invite`, why it was denied or failed, if known
+
+use std::{io::Write, str::FromStr};
+
+use log::kv::{Key, Value, VisitSource};
+
+use crate::webhook::{self, EventKind};
+
+pub const AUDIT_TARGET: &str = "bouncer::audit";
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum LogFormat {
+    /// env_logger's human-readable lines.
+    #[default]
+    Text,
+    /// One JSON object per line.
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(LogFormat::Text),
+            "json" => Ok(LogFormat::Json),
+            _ => anyhow::bail!("invalid log format {:?}, expected text or json", s),
+        }
+    }
+}
+
+/// Install the logger, configured by `RUST_LOG` as usual.
+pub fn init(format: LogFormat) {
+    let mut builder = env_logger::Builder::from_default_env();
+    if format == LogFormat::Json {
+        builder.format(|buf, record| writeln!(buf, "{}", json_line(record)));
+    }
+    builder.init();
+}
+
+struct Fields<'a>(&'a mut serde_json::Map<String, serde_json::Value>);
+
+impl<'kvs> VisitSource<'kvs> for Fields<'_> {
+    fn visit_pair(&mut self, key: Key<'kvs>, value: Value<'kvs>) -> Result<(), log::kv::Error> {
+        let value = serde_json::to_value(&value).unwrap_or(serde_json::Value::Null);
+        self.0.insert(key.as_str().to_string(), value);
+        Ok(())
+    }
+}
+
+/// A log record as a JSON object, with its key-values as top-level fields.
+pub fn json_line(record: &log::Record) -> serde_json::Value {
+    let mut line = serde_json::Map::new();
+    line.insert("timestamp".into(), chrono::Utc::now().to_rfc3339().into());
+    line.insert("level".into(), record.level().as_str().into());
+    line.insert("target".into(), record.target().into());
+    line.insert("message".into(), record.args().to_string().into());
+    // Collecting into a map cannot fail.
+    let _ = record.key_values().visit(&mut Fields(&mut line));
+    serde_json::Value::Object(line)
+}
+
+/// Log the outcome of an invite to one room, see the module documentation for its fields.
+pub fn invite(event: &webhook::Event) {
+    let decision = match event.event {
+        EventKind::InviteSent => "invited",
+        EventKind::InviteDenied => "denied",
+        EventKind::InviteFailed => "failed",
+        EventKind::InviteDryRun => "dry_run",
+        EventKind::Test => return,
+    };
+    log::warn!(
+        target: AUDIT_TARGET,
+        event = "invite",
+        decision = decision,
+        matrix_user = event.user_id.as_deref(),
+        github_login = event.github_login.as_str(),
+        room_id = event.room_id.as_str(),
+        reason = event.reason.as_deref();
+        "invite of {} to room {} for GitHub user {}: {}{}",
+        event.user_id.as_deref().unwrap_or("an email address"),
+        event.room_id,
+        event.github_login,
+        event.event.label(),
+        event.reason.as_deref().map(|reason| format!(", {}", reason)).unwrap_or_default(),
+    );
+}
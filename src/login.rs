@@ -0,0 +1,254 @@
+use std::sync::Arc;
+
+use axum::{
+    extract::State,
+    http::{
+        header::{COOKIE, SET_COOKIE},
+        HeaderMap, HeaderValue, StatusCode,
+    },
+    response::{IntoResponse, Redirect},
+};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use chrono::{DateTime, Utc};
+use ring::hmac;
+
+use crate::{
+    i18n::t,
+    identity::IdentityProvider,
+    sessions::{self, SessionStore, PENDING_TTL},
+    AppState, GitHubUser, Invite,
+};
+
+const COOKIE_NAME: &str = "bouncer_login";
+/// Nonce binding a started GitHub login to the browser, see [`state_cookie`].
+const STATE_COOKIE: &str = "bouncer_state";
+/// Language picked with `?lang=`, see [`crate::i18n`].
+const LANGUAGE_COOKIE: &str = "bouncer_lang";
+/// How long a picked language is remembered.
+const LANGUAGE_TTL: u64 = 365 * 24 * 60 * 60;
+/// Answer for a GitHub callback from another browser than the one that submitted the form.
+pub const BROWSER_MISMATCH: &str = "This GitHub login was not started from this browser, or the browser blocked the cookie set by the invite form. Please start again from the invite form, with cookies allowed for this site.";
+
+/// GitHub identity verified by a completed login, remembered in a signed cookie for
+/// `--session-ttl`. Never holds the GitHub access token.
+#[derive(Clone, serde::Serialize, serde::Deserialize)]
+pub struct Login {
+    pub login: String,
+    /// When the GitHub account was created, for the account age checks.
+    pub created_at: DateTime<Utc>,
+    pub expires: DateTime<Utc>,
+}
+
+fn attributes(state: &AppState, max_age: u64) -> String {
+    let path = if state.base_path.is_empty() {
+        "/"
+    } else {
+        &state.base_path
+    };
+    format!(
+        "Path={}; Max-Age={}; HttpOnly; Secure; SameSite=Lax",
+        path, max_age
+    )
+}
+
+fn read_cookie<'a>(headers: &'a HeaderMap, name: &str) -> Option<&'a str> {
+    headers
+        .get_all(COOKIE)
+        .iter()
+        .filter_map(|header| header.to_str().ok())
+        .flat_map(|header| header.split(';'))
+        .find_map(|cookie| cookie.trim().strip_prefix(name)?.strip_prefix('='))
+}
+
+fn clear_cookie(state: &AppState, name: &str) -> Option<HeaderValue> {
+    HeaderValue::from_str(&format!("{}=; {}", name, attributes(state, 0))).ok()
+}
+
+/// Set-Cookie value binding the OAuth state to the browser starting the GitHub login.
+pub fn state_cookie(state: &AppState, nonce: &str) -> Option<HeaderValue> {
+    HeaderValue::from_str(&format!(
+        "{}={}; {}",
+        STATE_COOKIE,
+        nonce,
+        attributes(state, PENDING_TTL.as_secs())
+    ))
+    .ok()
+}
+
+/// Browser nonce sent back to the GitHub callback.
+pub fn state_nonce(headers: &HeaderMap) -> Option<&str> {
+    read_cookie(headers, STATE_COOKIE)
+}
+
+/// Set-Cookie value removing the browser nonce once the login completed.
+pub fn clear_state_cookie(state: &AppState) -> Option<HeaderValue> {
+    clear_cookie(state, STATE_COOKIE)
+}
+
+/// Set-Cookie value remembering the language picked with `?lang=`.
+pub fn language_cookie(state: &AppState, language: &str) -> Option<HeaderValue> {
+    HeaderValue::from_str(&format!(
+        "{}={}; {}",
+        LANGUAGE_COOKIE,
+        language,
+        attributes(state, LANGUAGE_TTL)
+    ))
+    .ok()
+}
+
+/// Language remembered by [`language_cookie`].
+pub fn language(headers: &HeaderMap) -> Option<&str> {
+    read_cookie(headers, LANGUAGE_COOKIE)
+}
+
+/// Set-Cookie value remembering a login, if `--session-key` is set.
+pub fn cookie(state: &AppState, login: &str, created_at: DateTime<Utc>) -> Option<HeaderValue> {
+    let key = state.session_key.as_ref()?;
+    let ttl = chrono::Duration::from_std(state.session_ttl).ok()?;
+    let payload = serde_json::to_vec(&Login {
+        login: login.to_string(),
+        created_at,
+        expires: Utc::now() + ttl,
+    })
+    .ok()?;
+    let payload = URL_SAFE_NO_PAD.encode(payload);
+    let signature = URL_SAFE_NO_PAD.encode(hmac::sign(key, payload.as_bytes()));
+    HeaderValue::from_str(&format!(
+        "{}={}.{}; {}",
+        COOKIE_NAME,
+        payload,
+        signature,
+        attributes(state, state.session_ttl.as_secs())
+    ))
+    .ok()
+}
+
+/// Login of the request's cookie, if it is signed with the current key and not expired.
+pub fn from_headers(state: &AppState, headers: &HeaderMap) -> Option<Login> {
+    let key = state.session_key.as_ref()?;
+    let value = read_cookie(headers, COOKIE_NAME)?;
+    let (payload, signature) = value.split_once('.')?;
+    hmac::verify(
+        key,
+        payload.as_bytes(),
+        &URL_SAFE_NO_PAD.decode(signature).ok()?,
+    )
+    .ok()?;
+    let login: Login = serde_json::from_slice(&URL_SAFE_NO_PAD.decode(payload).ok()?).ok()?;
+    (login.expires > Utc::now()).then_some(login)
+}
+
+/// Forget the remembered login and go back to the invite form.
+pub async fn logout(State(state): State<Arc<AppState>>) -> impl IntoResponse {
+    let mut headers = HeaderMap::new();
+    if let Ok(clear) =
+        HeaderValue::from_str(&format!("{}=; {}", COOKIE_NAME, attributes(&state, 0)))
+    {
+        headers.insert(SET_COOKIE, clear);
+    }
+    (headers, Redirect::to(&state.absolute_link("")))
+}
+
+/// Complete the GitHub login of the invite stashed under the csrf token `csrf`, taking it so
+/// each login completes at most once. Invites started from the form only complete in the
+/// browser holding their nonce; `teams` are the memberships to look up for the invite.
+pub async fn complete(
+    sessions: &dyn SessionStore,
+    identity: &dyn IdentityProvider,
+    csrf: &str,
+    code: String,
+    browser_nonce: Option<&str>,
+    teams: impl FnOnce(&Invite) -> Vec<String>,
+) -> Result<(Invite, GitHubUser), (StatusCode, String)> {
+    let invite = sessions
+        .take_pending(csrf)
+        .await
+        .map_err(sessions::unavailable)?
+        .ok_or_else(|| (StatusCode::BAD_REQUEST, t("invalid csrf token")))?;
+    // Invites started through the API are not bound to a browser.
+    if let Some(expected) = &invite.browser_nonce {
+        if browser_nonce != Some(expected.as_str()) {
+            log::warn!("GitHub callback without the browser nonce of its invite");
+            return Err((StatusCode::FORBIDDEN, t(BROWSER_MISMATCH)));
+        }
+    }
+    let user = identity
+        .identify(code, invite.pkce_verifier.clone(), &teams(&invite))
+        .await?;
+    Ok((invite, user))
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::StatusCode;
+    use ruma::user_id;
+
+    use super::{complete, BROWSER_MISMATCH};
+    use crate::{
+        mock::{invite, teams, user, MockIdentity, LIMITS},
+        sessions::{stash, MemoryStore},
+    };
+
+    #[tokio::test]
+    async fn completes_each_login_once() {
+        let store = MemoryStore::default();
+        let identity = MockIdentity::new(user(100));
+        stash(&store, "csrf", &invite(None), LIMITS).await.unwrap();
+
+        let (invite, user) = complete(&store, &identity, "csrf", "code".into(), None, teams)
+            .await
+            .unwrap();
+        assert_eq!(invite.user_ids, [user_id!("@alice:localhost").to_owned()]);
+        assert_eq!(user.login, "octocat");
+        assert_eq!(
+            identity.calls(),
+            [(
+                "code".to_string(),
+                Some("verifier".to_string()),
+                teams(&invite)
+            )]
+        );
+
+        // A replayed or unknown csrf token never reaches GitHub.
+        for csrf in ["csrf", "unknown"] {
+            let (status, _) = complete(&store, &identity, csrf, "code".into(), None, teams)
+                .await
+                .unwrap_err();
+            assert_eq!(status, StatusCode::BAD_REQUEST);
+        }
+        assert_eq!(identity.calls().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn completes_logins_only_in_the_submitting_browser() {
+        let store = MemoryStore::default();
+        let identity = MockIdentity::new(user(100));
+        stash(&store, "stolen", &invite(Some("nonce")), LIMITS)
+            .await
+            .unwrap();
+        stash(&store, "own", &invite(Some("nonce")), LIMITS)
+            .await
+            .unwrap();
+
+        for (csrf, nonce) in [("stolen", None), ("own", Some("other"))] {
+            let (status, message) = complete(&store, &identity, csrf, "code".into(), nonce, teams)
+                .await
+                .unwrap_err();
+            assert_eq!(status, StatusCode::FORBIDDEN);
+            assert_eq!(message, BROWSER_MISMATCH);
+        }
+        assert!(identity.calls().is_empty());
+        // The refused login used up its invite.
+        let (status, _) = complete(
+            &store,
+            &identity,
+            "own",
+            "code".into(),
+            Some("nonce"),
+            teams,
+        )
+        .await
+        .unwrap_err();
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+    }
+}
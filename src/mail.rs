@@ -0,0 +1,190 @@
+//! Plain-text emails to administrators about invite events, see `--smtp-url`.
+//!
+//! Sent invites are mailed one by one, denials and failures are collected and mailed at most
+//! once per `--notify-email-interval`, so an attack does not flood the inboxes.
+
+use std::{sync::Arc, time::Duration};
+
+use anyhow::Context;
+use lettre::{
+    message::{header::ContentType, Mailbox},
+    transport::smtp::authentication::Credentials,
+    AsyncSmtpTransport, AsyncTransport, Message, Tokio1Executor,
+};
+use tokio::sync::Mutex;
+
+use crate::{
+    secret::{redact, Secret},
+    webhook::{Event, EventKind},
+    AppState,
+};
+
+pub const DEFAULT_INTERVAL: Duration = Duration::from_secs(10 * 60);
+/// Events mailed without `--notify-email-events`.
+pub const DEFAULT_EVENTS: &[EventKind] = &[EventKind::InviteSent, EventKind::InviteDenied];
+/// Lines of a batch at most, the rest is only counted.
+const BATCH_LINES: usize = 100;
+
+/// SMTP settings from the command line, checked by [`crate::config::Config::from_args`].
+#[derive(Debug)]
+pub struct SmtpSettings {
+    /// `smtp://` or `smtps://` url of the server, see [`AsyncSmtpTransport::from_url`].
+    pub url: Secret<String>,
+    pub username: Option<String>,
+    pub password: Option<Secret<String>>,
+    pub from: Mailbox,
+    pub to: Vec<Mailbox>,
+    pub events: Vec<EventKind>,
+    pub interval: Duration,
+}
+
+pub struct Mailer {
+    transport: AsyncSmtpTransport<Tokio1Executor>,
+    from: Mailbox,
+    to: Vec<Mailbox>,
+    events: Vec<EventKind>,
+    interval: Duration,
+    /// Denials and failures waiting for the next batch, and how many did not fit.
+    batch: Mutex<(Vec<String>, usize)>,
+}
+
+impl Mailer {
+    pub fn new(settings: SmtpSettings) -> anyhow::Result<Mailer> {
+        let mut transport = AsyncSmtpTransport::<Tokio1Executor>::from_url(settings.url.expose())
+            .context("invalid smtp_url")?;
+        if let Some(username) = settings.username {
+            let password = settings
+                .password
+                .map(|password| password.expose().clone())
+                .unwrap_or_default();
+            transport = transport.credentials(Credentials::new(username, password));
+        }
+        Ok(Mailer {
+            transport: transport.build(),
+            from: settings.from,
+            to: settings.to,
+            events: settings.events,
+            interval: settings.interval,
+            batch: Mutex::default(),
+        })
+    }
+
+    /// Send one email to every `--notify-email` address.
+    pub async fn send(&self, subject: &str, body: String) -> anyhow::Result<()> {
+        let mut message = Message::builder()
+            .from(self.from.clone())
+            .subject(subject)
+            .header(ContentType::TEXT_PLAIN);
+        for to in &self.to {
+            message = message.to(to.clone());
+        }
+        self.transport
+            .send(message.body(body).context("failed to build the email")?)
+            .await
+            .context("the SMTP server refused the email")?;
+        Ok(())
+    }
+
+    async fn collect(&self, line: String) {
+        let mut batch = self.batch.lock().await;
+        if batch.0.len() < BATCH_LINES {
+            batch.0.push(line);
+        } else {
+            batch.1 += 1;
+        }
+    }
+
+    /// Mail the collected denials and failures, if any.
+    async fn flush(&self) {
+        let (lines, dropped) = std::mem::take(&mut *self.batch.lock().await);
+        if lines.is_empty() {
+            return;
+        }
+        let count = lines.len() + dropped;
+        let mut body = lines.join("\n");
+        if dropped > 0 {
+            body.push_str(&format!("\n… and {} more", dropped));
+        }
+        let subject = format!("{} invites denied or failed", count);
+        if let Err(err) = self.send(&subject, body).await {
+            log::error!("failed to send notification email: {}", redact(&err));
+        }
+    }
+}
+
+/// One line describing an event, in emails.
+fn describe(event: &Event) -> String {
+    let user = event.user_id.as_deref().unwrap_or("an email address");
+    let mut line = format!(
+        "{} {}: {} to {} for GitHub user {}",
+        event.timestamp.format("%Y-%m-%d %H:%M:%S UTC"),
+        event.event.label(),
+        user,
+        event.room_id,
+        event.github_login
+    );
+    if let Some(reason) = &event.reason {
+        line.push_str(&format!(" ({})", reason));
+    }
+    line
+}
+
+/// Mail an invite event in the background, if it is one of `--notify-email-events`.
+pub fn notify(state: &AppState, event: &Event) {
+    let Some(mailer) = state.mailer.clone() else {
+        return;
+    };
+    if !mailer.events.contains(&event.event) {
+        return;
+    }
+    let line = describe(event);
+    match event.event {
+        EventKind::InviteDenied | EventKind::InviteFailed => {
+            tokio::spawn(async move { mailer.collect(line).await });
+        }
+        _ => {
+            let subject = format!(
+                "{} {} to {}",
+                event.user_id.as_deref().unwrap_or("an email address"),
+                event.event.label(),
+                event.room_id
+            );
+            tokio::spawn(async move {
+                if let Err(err) = mailer.send(&subject, line).await {
+                    log::error!("failed to send notification email: {}", redact(&err));
+                }
+            });
+        }
+    }
+}
+
+/// Mail the collected denials and failures every `--notify-email-interval`.
+pub fn spawn(state: Arc<AppState>) {
+    let Some(mailer) = state.mailer.clone() else {
+        return;
+    };
+    tokio::spawn(async move {
+        loop {
+            tokio::time::sleep(mailer.interval).await;
+            mailer.flush().await;
+        }
+    });
+}
+
+/// Check the SMTP settings by mailing every `--notify-email` address, for `test-email`.
+pub async fn test(settings: SmtpSettings) -> anyhow::Result<()> {
+    let to = settings
+        .to
+        .iter()
+        .map(ToString::to_string)
+        .collect::<Vec<_>>()
+        .join(", ");
+    Mailer::new(settings)?
+        .send(
+            "Test email of the Matrix Bouncer",
+            "The SMTP settings of the Matrix Bouncer work.".to_string(),
+        )
+        .await?;
+    println!("sent a test email to {}", to);
+    Ok(())
+}
@@ -1,33 +1,48 @@
+use anyhow::Context;
+use arc_swap::ArcSwap;
 use axum::{
-    extract::{Query, State},
-    http::StatusCode,
-    response::Redirect,
-    routing::{get, post},
-    Form, Router,
+    extract::{DefaultBodyLimit, Extension, Path, Query, State},
+    http::{header, HeaderMap, HeaderValue, Method, StatusCode},
+    middleware,
+    response::{IntoResponse, Redirect, Response},
+    routing::{delete, get, post, put},
+    Json, Router,
+};
+use bouncer::{
+    approval::{self, Approval},
+    audit,
+    client_ip::ClientIp,
+    config::{Args, ClientConfig, Command, Config},
+    discovery::{discover_rooms, resolve_room, RoomFilter},
+    honeypot,
+    i18n::{t, tr},
+    invite::{invite_email, invite_user, BANNED},
+    invite_reason, knock, logging, login, membership, normalize_email, normalize_user_id,
+    page::{self, HtmlForm, HtmlQuery},
+    queue, room_policy,
+    secret::redact,
+    security::CspNonce,
+    sessions::{self, MemoryStore, RedisStore, SessionStore},
+    startup::{self, Failure},
+    store::{self, Denial, Store},
+    webhook::Webhook,
+    AppState, GitHubUser, Invite, InviteRequest, RoomInfo,
 };
-use bouncer::{AppState, Invite, RoomInfo};
 use chrono::{Duration, Local};
 use chrono_humanize::{Accuracy, HumanTime, Tense};
-use clap::Parser;
+use maud::{html, Markup};
 use oauth2::{
-    basic::BasicClient, reqwest::async_http_client, AuthUrl, AuthorizationCode, ClientId,
-    ClientSecret, CsrfToken, RedirectUrl, TokenResponse, TokenUrl,
+    basic::BasicClient, AuthUrl, ClientId, ClientSecret, CsrfToken, PkceCodeChallenge, RedirectUrl,
+    Scope, TokenUrl,
 };
 use ruma::{
-    api::client,
-    events::{
-        room::power_levels::{RoomPowerLevels, RoomPowerLevelsEventContent},
-        StateEventType,
-    },
-    Client,
+    api::{client, error::FromHttpResponseError},
+    events::room::member::MembershipState,
+    OwnedRoomAliasId, OwnedRoomId, OwnedUserId,
 };
-use std::{collections::HashMap, sync::Arc};
-use tokio::sync::Mutex;
-
-#[derive(serde::Deserialize)]
-struct Turnstile {
-    success: bool,
-}
+use std::{collections::HashMap, net::IpAddr, sync::Arc};
+use tokio::sync::{Mutex, RwLock};
+use tower_http::cors::{AllowOrigin, CorsLayer};
 
 #[derive(Debug, serde::Deserialize)]
 struct Callback {
@@ -35,310 +50,1592 @@ struct Callback {
     state: String,
 }
 
-#[derive(Debug, serde::Deserialize)]
-struct GitHubUser {
-    login: String,
-    created_at: chrono::DateTime<chrono::Utc>,
+async fn callback(
+    State(state): State<Arc<AppState>>,
+    Extension(CspNonce(nonce)): Extension<CspNonce>,
+    headers: HeaderMap,
+    HtmlQuery(query): HtmlQuery<Callback>,
+) -> Result<Response, (StatusCode, Markup)> {
+    let error = |(status, message): (StatusCode, String)| {
+        page::error_page(&state, &nonce, status, &message)
+    };
+    let (invite, user) = login::complete(
+        &*state.sessions,
+        &*state.identity,
+        &query.state,
+        query.code,
+        login::state_nonce(&headers),
+        |invite| room_policy::teams(&state, &invite.room_ids),
+    )
+    .await
+    .map_err(error)?;
+    let mut headers = HeaderMap::new();
+    if let Some(clear) = login::clear_state_cookie(&state) {
+        headers.append(header::SET_COOKIE, clear);
+    }
+    if let Some(cookie) = login::cookie(&state, &user.login, user.created_at) {
+        headers.append(header::SET_COOKIE, cookie);
+    }
+    let outcome = confirm_or_invite(&state, &nonce, invite, user)
+        .await
+        .map_err(error)?;
+    Ok((headers, outcome).into_response())
 }
 
-async fn callback(
+/// Send the invites right away with `--skip-confirmation`, otherwise stash them and redirect
+/// to the confirmation page, which reloads without sending anything.
+async fn confirm_or_invite(
+    state: &AppState,
+    nonce: &str,
+    mut invite: Invite,
+    user: GitHubUser,
+) -> Result<Response, (StatusCode, String)> {
+    if state.skip_confirmation {
+        return Ok(invite_all(state, nonce, &invite, &user)
+            .await
+            .into_response());
+    }
+    let token = CsrfToken::new_random().secret().to_string();
+    invite.github_user = Some(user);
+    state
+        .sessions
+        .insert_confirmation(&token, &invite)
+        .await
+        .map_err(sessions::unavailable)?;
+    Ok(Redirect::to(&state.absolute_link(&format!("confirm/{}", token))).into_response())
+}
+
+const CONFIRMATION_GONE: &str = "This invite was already sent or waited too long for confirmation. Please start again from the invite form.";
+
+/// Show who is about to be invited where, with the button sending the invites.
+async fn confirmation(
     State(state): State<Arc<AppState>>,
-    Query(query): Query<Callback>,
-) -> Result<String, (StatusCode, String)> {
+    Extension(CspNonce(nonce)): Extension<CspNonce>,
+    Path(token): Path<String>,
+) -> Result<Markup, (StatusCode, Markup)> {
+    let error = |(status, message): (StatusCode, String)| {
+        page::error_page(&state, &nonce, status, &message)
+    };
     let invite = state
-        .csrf
-        .lock()
+        .sessions
+        .confirmation(&token)
         .await
-        .remove(&query.state)
-        .ok_or((StatusCode::BAD_REQUEST, "invalid csrf token".to_string()))?;
+        .map_err(sessions::unavailable)
+        .map_err(error)?
+        .ok_or_else(|| error((StatusCode::GONE, t(CONFIRMATION_GONE))))?;
+    let login = invite
+        .github_user
+        .as_ref()
+        .map(|user| user.login.clone())
+        .unwrap_or_default();
 
-    let token = state
-        .oauth2_client
-        .exchange_code(AuthorizationCode::new(query.code))
-        .request_async(async_http_client)
-        .await
-        .map_err(|err| {
-            log::error!("failed to exchange for token: {}", err);
-            (
-                StatusCode::BAD_REQUEST,
-                "failed to exchange for token".to_string(),
-            )
-        })?;
+    let mut users = vec![];
+    for user_id in &invite.user_ids {
+        let profile = state
+            .client
+            .send_request(client::profile::get_profile::v3::Request::new(
+                user_id.clone(),
+            ))
+            .await
+            .map_err(|err| {
+                log::error!(
+                    "failed to get user profile for {}: {}",
+                    user_id,
+                    redact(&err)
+                )
+            })
+            .ok();
+        users.push(page::ConfirmedUser {
+            user_id: user_id.clone(),
+            displayname: profile
+                .as_ref()
+                .and_then(|profile| profile.displayname.clone()),
+            has_avatar: profile.is_some_and(|profile| profile.avatar_url.is_some()),
+        });
+    }
+    let mut rooms = vec![];
+    for room_id in &invite.room_ids {
+        rooms.push(state.room_name(room_id).await);
+    }
+    Ok(page::confirmation(
+        &state,
+        &nonce,
+        &token,
+        &login,
+        &users,
+        &invite.emails,
+        &rooms,
+    ))
+}
 
-    let user: GitHubUser = reqwest::Client::builder()
-        .user_agent("Matrix Bouncer")
-        .build()
-        .map_err(|err| {
-            log::error!("failed to build client: {}", err);
-            (
-                StatusCode::INTERNAL_SERVER_ERROR,
-                "failed to build client".to_string(),
-            )
-        })?
-        .get("https://api.github.com/user")
-        .bearer_auth(token.access_token().secret())
-        .send()
+#[derive(serde::Deserialize)]
+struct Confirm {
+    token: String,
+}
+
+/// Send the confirmed invites; each confirmation is used at most once.
+async fn confirm(
+    State(state): State<Arc<AppState>>,
+    Extension(CspNonce(nonce)): Extension<CspNonce>,
+    HtmlForm(request): HtmlForm<Confirm>,
+) -> Result<Markup, (StatusCode, Markup)> {
+    let error = |(status, message): (StatusCode, String)| {
+        page::error_page(&state, &nonce, status, &message)
+    };
+    let invite = state
+        .sessions
+        .take_confirmation(&request.token)
         .await
-        .map_err(|err| {
-            log::error!("failed to get user info: {}", err);
-            (
-                StatusCode::INTERNAL_SERVER_ERROR,
-                "failed to get user info".to_string(),
+        .map_err(sessions::unavailable)
+        .map_err(error)?
+        .ok_or_else(|| error((StatusCode::GONE, t(CONFIRMATION_GONE))))?;
+    let user = invite
+        .github_user
+        .clone()
+        .ok_or_else(|| error((StatusCode::GONE, t(CONFIRMATION_GONE))))?;
+    Ok(invite_all(&state, &nonce, &invite, &user).await)
+}
+
+/// Send every invite of a request on behalf of a verified GitHub user.
+async fn invite_all(state: &AppState, nonce: &str, invite: &Invite, user: &GitHubUser) -> Markup {
+    let age = Local::now().to_utc().signed_duration_since(user.created_at);
+
+    let granted = state
+        .quota
+        .take(&user.login, invite.user_ids.len() + invite.emails.len())
+        .await;
+    let mut outcomes = vec![];
+    let mut jobs = vec![];
+    for (index, user_id) in invite.user_ids.iter().enumerate() {
+        outcomes.push(if index < granted {
+            let existing = invite
+                .existing
+                .iter()
+                .filter(|(member, _, _)| member == user_id)
+                .map(|(_, room_id, membership)| (room_id.clone(), membership.clone()))
+                .collect::<Vec<_>>();
+            invite_member(
+                state,
+                &invite.room_ids,
+                &existing,
+                user_id,
+                user,
+                age,
+                &mut jobs,
             )
-        })?
-        .json()
-        .await
-        .map_err(|err| {
-            log::error!("failed to decode user info: {}", err);
-            (
-                StatusCode::INTERNAL_SERVER_ERROR,
-                "failed to decode user info".to_string(),
+            .await
+        } else {
+            over_quota(
+                state,
+                &invite.room_ids,
+                Some(user_id),
+                user_id.as_str(),
+                user,
             )
-        })?;
+            .await
+        });
+    }
+    for (index, email) in invite.emails.iter().enumerate() {
+        outcomes.push(if invite.user_ids.len() + index < granted {
+            invite_address(state, &invite.room_ids, email, user).await
+        } else {
+            over_quota(state, &invite.room_ids, None, email, user).await
+        });
+    }
+    if let Some(terms) = &invite.terms {
+        agreed(state, invite, user, terms).await;
+    }
 
-    let age = Local::now().to_utc().signed_duration_since(user.created_at);
+    // Outcomes list the rooms in the order of the request.
+    let rooms = state.rooms.read().await;
+    let open = invite
+        .room_ids
+        .iter()
+        .enumerate()
+        .filter(|(index, _)| {
+            outcomes.iter().any(|outcome| {
+                outcome
+                    .rooms
+                    .as_ref()
+                    .is_ok_and(|rooms| rooms.get(*index).is_some_and(|(_, result)| result.is_ok()))
+            })
+        })
+        .filter_map(|(_, room_id)| rooms.get(room_id).cloned())
+        .collect::<Vec<_>>();
+    page::invite_outcome(state, nonce, &outcomes, &invite.malformed, &open, &jobs)
+}
+
+/// Record that the requester agreed to the terms, in the admin room and on the invites sent.
+async fn agreed(state: &AppState, invite: &Invite, user: &GitHubUser, terms: &str) {
+    let recipients = invite
+        .user_ids
+        .iter()
+        .map(|user_id| user_id.to_string())
+        .chain(invite.emails.iter().map(|_| "an email address".to_string()))
+        .collect::<Vec<_>>()
+        .join(", ");
+    log::warn!(
+        "GitHub user {} agreed to the terms at {} for {}",
+        &user.login,
+        terms,
+        recipients
+    );
+    audit::post(
+        state,
+        format!(
+            "GitHub user {} agreed to the terms at {} for {}",
+            &user.login, terms, recipients
+        ),
+        html! {
+            "GitHub user " (user.login) " agreed to the terms at " a href=(terms) { (terms) } " for " (recipients)
+        },
+    )
+    .await;
+    for room_id in &invite.room_ids {
+        for user_id in &invite.user_ids {
+            store::agreed(state, Some(user_id), None, room_id, terms).await;
+        }
+        for email in &invite.emails {
+            store::agreed(state, None, Some(email), room_id, terms).await;
+        }
+    }
+}
+
+const OVER_QUOTA: &str = "over the daily invite limit of the GitHub account";
+
+/// Refuse an invitee beyond `--max-invitees-per-account`; `user_id` is `None` for an email
+/// address.
+async fn over_quota(
+    state: &AppState,
+    room_ids: &[OwnedRoomId],
+    user_id: Option<&OwnedUserId>,
+    invitee: &str,
+    user: &GitHubUser,
+) -> page::UserOutcome {
+    log::warn!(
+        "refused invite of {} for GitHub user {}, who reached --max-invitees-per-account",
+        invitee,
+        &user.login
+    );
+    for room_id in room_ids {
+        let reason = OVER_QUOTA.to_string();
+        match user_id {
+            Some(user_id) => {
+                store::deny(
+                    state,
+                    user_id,
+                    room_id,
+                    &user.login,
+                    Denial::InviteQuota,
+                    reason,
+                )
+                .await
+            }
+            None => {
+                store::deny_email(
+                    state,
+                    invitee,
+                    room_id,
+                    &user.login,
+                    Denial::InviteQuota,
+                    reason,
+                )
+                .await
+            }
+        }
+    }
+    page::UserOutcome {
+        user: invitee.to_string(),
+        rooms: Err(t(OVER_QUOTA)),
+    }
+}
 
+/// Apply the per-user checks and invite one user to every requested room, except those in
+/// `existing` it already joined or is invited to. Invites left to the queue are added to
+/// `jobs` by label and job id.
+async fn invite_member(
+    state: &AppState,
+    room_ids: &[OwnedRoomId],
+    existing: &[(OwnedRoomId, MembershipState)],
+    user_id: &OwnedUserId,
+    user: &GitHubUser,
+    age: Duration,
+    jobs: &mut Vec<(String, String)>,
+) -> page::UserOutcome {
     log::warn!(
+        target: logging::AUDIT_TARGET,
+        event = "github_login",
+        matrix_user = user_id.as_str(),
+        github_login = user.login.as_str(),
+        github_age_seconds = age.num_seconds();
         "matrix user {} is GitHub user {}, age {:?}",
-        &invite.user_id,
+        user_id,
         &user.login,
         HumanTime::from(age).to_text_en(Accuracy::Rough, Tense::Present),
     );
 
-    if invite.user_id.server_name() == "matrix.org" && age.le(&Duration::days(1)) {
+    if user_id.server_name() == "matrix.org" && age.le(&Duration::days(1)) {
         log::error!(
             "matrix user {} is from matrix.org and GitHub user {} age {:?} less than 1 day",
-            &invite.user_id,
+            user_id,
             &user.login,
             HumanTime::from(age).to_text_en(Accuracy::Rough, Tense::Present),
         );
-        return Err((StatusCode::FORBIDDEN, "".to_string()));
+        let mut rooms = vec![];
+        for room_id in room_ids {
+            rooms.push(state.room_name(room_id).await);
+        }
+        let reason = "matrix.org account with a GitHub account younger than a day";
+        audit::denied(state, user_id, &rooms.join(", "), &user.login, reason).await;
+        for room_id in room_ids {
+            store::deny(
+                state,
+                user_id,
+                room_id,
+                &user.login,
+                Denial::GithubAccountAge,
+                reason.to_string(),
+            )
+            .await;
+        }
+        return page::UserOutcome {
+            user: user_id.to_string(),
+            rooms: Err(t("refused")),
+        };
     }
 
-    let profile = state
+    // The policy lists may have changed since the invite was submitted.
+    if let Some(list) = state.policy.banned(user_id).await {
+        log::warn!(
+            "refused invite of {} banned by policy list {}",
+            user_id,
+            list
+        );
+        let reason = "banned by a policy list";
+        let mut rooms = vec![];
+        for room_id in room_ids {
+            rooms.push(state.room_name(room_id).await);
+            store::deny(
+                state,
+                user_id,
+                room_id,
+                &user.login,
+                Denial::PolicyList,
+                reason.to_string(),
+            )
+            .await;
+        }
+        audit::denied(state, user_id, &rooms.join(", "), &user.login, reason).await;
+        return page::UserOutcome {
+            user: user_id.to_string(),
+            rooms: Err(tr("banned by the policy list {list}", &[("list", &list)])),
+        };
+    }
+
+    let profile = match state
         .client
         .send_request(client::profile::get_profile::v3::Request::new(
-            invite.user_id.clone(),
+            user_id.clone(),
         ))
         .await
-        .map_err(|err| {
+    {
+        Ok(profile) => profile,
+        Err(err) => {
             log::error!(
                 "failed to get user profile for {}: {}",
-                &invite.user_id,
-                err
+                user_id,
+                redact(&err)
             );
-            (
-                StatusCode::INTERNAL_SERVER_ERROR,
-                "failed to get user profile".to_string(),
-            )
-        })?;
+            return page::UserOutcome {
+                user: user_id.to_string(),
+                rooms: Err(t("failed to get user profile")),
+            };
+        }
+    };
 
-    state
-        .client
-        .send_request(client::membership::invite_user::v3::Request::new(
-            invite.room_id.clone(),
-            client::membership::invite_user::v3::InvitationRecipient::UserId {
-                user_id: invite.user_id.clone(),
-            },
-        ))
-        .await
-        .map_err(|err| {
-            log::error!(
-                "failed to invite user {} to room {}: {}",
-                &invite.user_id,
-                &invite.room_id,
-                err
+    let account_age = age;
+    let age = HumanTime::from(age).to_text_en(Accuracy::Rough, Tense::Present);
+    let reason = state
+        .invite_reason
+        .as_deref()
+        .map(|template| invite_reason(template, &user.login, &age, user_id));
+    let mut rooms = vec![];
+    for room_id in room_ids {
+        let membership = existing
+            .iter()
+            .find(|(member_of, _)| member_of == room_id)
+            .map(|(_, membership)| membership);
+        let outcome = if let Some(membership) = membership {
+            Ok(match membership {
+                MembershipState::Join => t("already a member"),
+                _ => t("invite already pending"),
+            })
+        } else if let Err(refusal) = room_policy::check_github(state, room_id, user, account_age)
+            .and_then(|()| room_policy::check_localpart(state, room_id, user_id, &user.login))
+        {
+            log::warn!(
+                "refused invite of {} to {} for GitHub user {}: {}",
+                user_id,
+                room_id,
+                &user.login,
+                refusal.reason
             );
-            (
-                StatusCode::INTERNAL_SERVER_ERROR,
-                "failed to invite user".to_string(),
+            let room = state.room_name(room_id).await;
+            audit::denied(state, user_id, &room, &user.login, &refusal.reason).await;
+            store::deny(
+                state,
+                user_id,
+                room_id,
+                &user.login,
+                refusal.denial,
+                refusal.reason,
             )
-        })?;
+            .await;
+            Err(refusal.message)
+        } else if room_policy::needs_approval(state, room_id) {
+            let approval = Approval {
+                user_id: user_id.clone(),
+                room_id: room_id.clone(),
+                login: user.login.clone(),
+                reason: reason.clone(),
+                requested: chrono::Utc::now(),
+            };
+            approval::request(state, approval, &age)
+                .await
+                .map(|()| t("awaiting moderator approval"))
+        } else if knock::required(state, room_id).await {
+            knock::accept(state, room_id, user_id, &user.login, reason.clone())
+                .await
+                .map(|()| t("knock accepted"))
+        } else if state.queue_invites.is_some() && !state.dry_run {
+            queue::enqueue(state, room_id, user_id, &user.login, reason.clone())
+                .await
+                .map(|id| {
+                    jobs.push((format!("{} – {}", user_id, room_id), id));
+                    t("being processed")
+                })
+        } else {
+            invite_user(state, room_id, user_id, &user.login, reason.clone())
+                .await
+                .map(|()| t("invited"))
+        };
+        rooms.push((state.room_name(room_id).await, outcome));
+    }
 
-    Ok(format!(
-        "successfully invited user {} ({}) to room {}",
-        profile.displayname.unwrap_or_default(),
-        invite.user_id,
-        invite.room_id,
-    ))
+    page::UserOutcome {
+        user: format!("{} ({})", profile.displayname.unwrap_or_default(), user_id),
+        rooms: Ok(rooms),
+    }
+}
+
+/// Invite one email address to every requested room. Rooms needing approval are refused, as
+/// moderators are shown the Matrix ID to approve.
+async fn invite_address(
+    state: &AppState,
+    room_ids: &[OwnedRoomId],
+    email: &str,
+    user: &GitHubUser,
+) -> page::UserOutcome {
+    log::warn!("GitHub user {} requested an email invite", &user.login);
+    let age = Local::now().to_utc().signed_duration_since(user.created_at);
+    let mut rooms = vec![];
+    for room_id in room_ids {
+        let outcome = if let Err(refusal) = room_policy::check_github(state, room_id, user, age) {
+            log::warn!(
+                "refused email invite to {} for GitHub user {}: {}",
+                room_id,
+                &user.login,
+                refusal.reason
+            );
+            store::deny_email(
+                state,
+                email,
+                room_id,
+                &user.login,
+                refusal.denial,
+                refusal.reason,
+            )
+            .await;
+            Err(refusal.message)
+        } else if room_policy::needs_approval(state, room_id) {
+            Err(t(
+                "needs moderator approval, which email invites cannot get",
+            ))
+        } else if room_policy::needs_matching_localpart(state, room_id) {
+            Err(t(
+                "needs a Matrix ID matching the GitHub login, which email invites do not have",
+            ))
+        } else {
+            invite_email(state, room_id, email, &user.login)
+                .await
+                .map(|()| t("invite sent by email"))
+        };
+        rooms.push((state.room_name(room_id).await, outcome));
+    }
+    page::UserOutcome {
+        user: email.to_string(),
+        rooms: Ok(rooms),
+    }
 }
 
 async fn invite(
     State(state): State<Arc<AppState>>,
-    Form(invite): Form<Invite>,
-) -> Result<Redirect, (StatusCode, String)> {
-    let response: Turnstile = reqwest::Client::new()
-        .post("https://challenges.cloudflare.com/turnstile/v0/siteverify")
-        .form::<HashMap<String, String>>(
-            &[
-                ("secret".to_string(), state.turnstile_secret_key.clone()),
-                ("response".to_string(), invite.cf_turnstile_response.clone()),
-            ]
-            .into(),
-        )
-        .send()
+    ClientIp(client_ip): ClientIp,
+    Extension(CspNonce(nonce)): Extension<CspNonce>,
+    headers: HeaderMap,
+    HtmlForm(invite): HtmlForm<InviteRequest>,
+) -> Result<Response, (StatusCode, Markup)> {
+    let error = |(status, message): (StatusCode, String)| {
+        page::error_page(&state, &nonce, status, &message)
+    };
+    honeypot::check(&state, &invite.website, invite.rendered.as_deref()).map_err(error)?;
+    bouncer::reload::revalidate(&state).await;
+    let mut invite = check_invite(&state, invite, client_ip)
         .await
-        .map_err(|err| {
-            log::error!("failed to verify turnstile response: {}", err);
-            (
-                StatusCode::INTERNAL_SERVER_ERROR,
-                "failed to verify turnstile response".to_string(),
-            )
-        })?
-        .json()
+        .map_err(error)?;
+    invite.client_ip = Some(client_ip.to_string());
+    let existing = existing_membership(&state, &mut invite)
         .await
-        .map_err(|err| {
-            log::error!("failed to decode turnstile verify result: {}", err);
+        .map_err(error)?;
+    if !existing.is_empty() && (invite.room_ids.is_empty() || invite.user_ids.is_empty()) {
+        let rooms = state.rooms.read().await;
+        let existing = existing
+            .iter()
+            .filter_map(|(user_id, room_id, membership)| {
+                Some((&**user_id, rooms.get(room_id)?, membership.clone()))
+            })
+            .collect::<Vec<_>>();
+        return Ok(page::existing_membership(&state, &nonce, &existing).into_response());
+    }
+    // A remembered GitHub login skips the OAuth round trip, unless memberships are needed.
+    if let Some(login) = login::from_headers(&state, &headers)
+        .filter(|_| !room_policy::needs_memberships(&state, &invite.room_ids))
+    {
+        let user = GitHubUser {
+            login: login.login,
+            created_at: login.created_at,
+            orgs: vec![],
+            teams: vec![],
+            pending_teams: vec![],
+        };
+        return confirm_or_invite(&state, &nonce, invite, user)
+            .await
+            .map_err(error);
+    }
+    let browser_nonce = CsrfToken::new_random().secret().to_string();
+    invite.browser_nonce = Some(browser_nonce.clone());
+    let authorize_url = authorize(&state, invite).await.map_err(error)?;
+    let mut response = Redirect::to(&authorize_url).into_response();
+    if let Some(cookie) = login::state_cookie(&state, &browser_nonce) {
+        response.headers_mut().insert(header::SET_COOKIE, cookie);
+    }
+    Ok(response)
+}
+
+/// Requested rooms each user already joined or has a pending invite to; banned users are
+/// refused outright. Such rooms are dropped from the invite of a single user. A batch keeps
+/// them in [`Invite::existing`] to be skipped after the login, and drops users with nothing
+/// left to invite to.
+async fn existing_membership(
+    state: &AppState,
+    invite: &mut Invite,
+) -> Result<Vec<(OwnedUserId, OwnedRoomId, MembershipState)>, (StatusCode, String)> {
+    let mut existing = vec![];
+    for user_id in &invite.user_ids {
+        for room_id in &invite.room_ids {
+            match membership::membership(&state.client, room_id, user_id).await {
+                Some(MembershipState::Ban) if !state.skip_ban_check => {
+                    log::error!(
+                        "banned matrix user {} requested an invite to room {}",
+                        user_id,
+                        room_id
+                    );
+                    return Err(match invite.user_ids.len() {
+                        1 => (StatusCode::FORBIDDEN, t(BANNED)),
+                        _ => (
+                            StatusCode::FORBIDDEN,
+                            tr(
+                                "{user} cannot be invited to a requested room, please contact its moderators.",
+                                &[("user", user_id)],
+                            ),
+                        ),
+                    });
+                }
+                Some(membership @ (MembershipState::Join | MembershipState::Invite)) => {
+                    existing.push((user_id.clone(), room_id.clone(), membership));
+                }
+                _ => {}
+            }
+        }
+    }
+    let count = |user_id: &OwnedUserId| {
+        existing
+            .iter()
+            .filter(|(member, _, _)| member == user_id)
+            .count()
+    };
+    if invite.user_ids.len() == 1 {
+        invite.room_ids.retain(|room_id| {
+            !existing
+                .iter()
+                .any(|(_, member_of, _)| member_of == room_id)
+        });
+    } else {
+        let rooms = invite.room_ids.len();
+        invite.user_ids.retain(|user_id| count(user_id) < rooms);
+        invite.existing = existing
+            .iter()
+            .filter(|(user_id, _, _)| invite.user_ids.contains(user_id))
+            .cloned()
+            .collect();
+    }
+    Ok(existing)
+}
+
+#[derive(serde::Serialize)]
+struct ApiInviteResponse {
+    authorize_url: String,
+    /// Set with `--dry-run`, when the invite will not be sent after the login.
+    dry_run: bool,
+}
+
+#[derive(serde::Deserialize)]
+struct ApiRoomsQuery {
+    offset: Option<usize>,
+    limit: Option<usize>,
+}
+
+#[derive(serde::Serialize)]
+struct ApiRoom {
+    #[serde(flatten)]
+    room: RoomInfo,
+    invites_sent: u64,
+}
+
+async fn api_rooms(
+    State(state): State<Arc<AppState>>,
+    Query(query): Query<ApiRoomsQuery>,
+) -> Json<Vec<ApiRoom>> {
+    let rooms = state.rooms.read().await;
+    let mut rooms = rooms
+        .values()
+        .filter(|room| !room_policy::hidden(&state, &room.room_id))
+        .collect::<Vec<_>>();
+    state.room_order.sort(&mut rooms);
+    let counts = state.invite_counts.lock().await;
+    Json(
+        rooms
+            .into_iter()
+            .skip(query.offset.unwrap_or(0))
+            .take(query.limit.unwrap_or(usize::MAX))
+            .cloned()
+            .map(|room| ApiRoom {
+                invites_sent: counts.get(&room.room_id).copied().unwrap_or(0),
+                room: RoomInfo {
+                    topic: room.topic.filter(|_| !state.hide_topics),
+                    ..room
+                },
+            })
+            .collect(),
+    )
+}
+
+async fn api_invite(
+    State(state): State<Arc<AppState>>,
+    ClientIp(client_ip): ClientIp,
+    Json(invite): Json<InviteRequest>,
+) -> Result<Json<ApiInviteResponse>, (StatusCode, String)> {
+    bouncer::reload::revalidate(&state).await;
+    let mut invite = check_invite(&state, invite, client_ip).await?;
+    invite.client_ip = Some(client_ip.to_string());
+    let existing = existing_membership(&state, &mut invite).await?;
+    if !existing.is_empty() && (invite.room_ids.is_empty() || invite.user_ids.is_empty()) {
+        return Err((
+            StatusCode::CONFLICT,
+            t("user is already a member of or invited to every requested room"),
+        ));
+    }
+    Ok(Json(ApiInviteResponse {
+        authorize_url: authorize(&state, invite).await?,
+        dry_run: state.dry_run,
+    }))
+}
+
+/// Email address the invite is for, accepted only with an identity server configured and
+/// instead of any Matrix ID.
+fn requested_emails(
+    state: &AppState,
+    invite: &InviteRequest,
+) -> Result<Vec<String>, (StatusCode, String)> {
+    let Some(email) = invite
+        .email
+        .as_deref()
+        .filter(|email| !email.trim().is_empty())
+    else {
+        return Ok(vec![]);
+    };
+    if state.id_server.is_none() {
+        return Err((StatusCode::BAD_REQUEST, t("email invites are not enabled")));
+    }
+    let batch = invite.user_ids.as_deref().unwrap_or_default();
+    if !invite.user_id.trim().is_empty() || !batch.trim().is_empty() {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            t("give either a Matrix ID or an email address, not both"),
+        ));
+    }
+    let email = normalize_email(email).ok_or_else(|| {
+        (
+            StatusCode::BAD_REQUEST,
+            tr(
+                "{email} is not a valid email address",
+                &[("email", &format!("{:?}", email.trim()))],
+            ),
+        )
+    })?;
+    Ok(vec![email])
+}
+
+/// Users the invite is for: the single user id, or every line of the batch field. Malformed
+/// batch lines are returned separately so the valid ones can still be invited.
+fn requested_users(
+    state: &AppState,
+    invite: &InviteRequest,
+) -> Result<(Vec<OwnedUserId>, Vec<String>), (StatusCode, String)> {
+    let Some(batch) = invite
+        .user_ids
+        .as_deref()
+        .filter(|batch| !batch.trim().is_empty())
+    else {
+        let user_id = normalize_user_id(&invite.user_id).ok_or_else(|| {
             (
-                StatusCode::INTERNAL_SERVER_ERROR,
-                "failed to decode turnstile verify result".to_string(),
+                StatusCode::BAD_REQUEST,
+                tr(
+                    "{user} is not a valid Matrix ID, expected the form @user:example.com",
+                    &[("user", &format!("{:?}", invite.user_id))],
+                ),
             )
         })?;
+        return Ok((vec![user_id], vec![]));
+    };
 
-    if !response.success {
+    let mut user_ids = vec![];
+    let mut malformed = vec![];
+    for (number, line) in batch.lines().enumerate() {
+        if line.trim().is_empty() {
+            continue;
+        }
+        match normalize_user_id(line) {
+            Some(user_id) if !user_ids.contains(&user_id) => user_ids.push(user_id),
+            Some(_) => {}
+            None => malformed.push(tr(
+                "line {number}: {line} is not a valid Matrix ID",
+                &[
+                    ("number", &(number + 1)),
+                    ("line", &format!("{:?}", line.trim())),
+                ],
+            )),
+        }
+    }
+    if user_ids.is_empty() {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            tr(
+                "no valid Matrix ID given, expected the form @user:example.com. {lines}",
+                &[("lines", &malformed.join(", "))],
+            ),
+        ));
+    }
+    if user_ids.len() > state.max_batch_size {
         return Err((
-            StatusCode::FORBIDDEN,
-            "turnstile verification failed".to_string(),
+            StatusCode::BAD_REQUEST,
+            tr(
+                "at most {count} people can be invited at once",
+                &[("count", &state.max_batch_size)],
+            ),
         ));
     }
+    Ok((user_ids, malformed))
+}
 
-    if !state.rooms.contains_key(&invite.room_id) {
-        return Err((StatusCode::BAD_REQUEST, "invalid room_id".to_string()));
+/// Rooms the invite is for: the selected rooms plus the typed alias, if any.
+async fn requested_rooms(
+    state: &AppState,
+    invite: &InviteRequest,
+) -> Result<Vec<OwnedRoomId>, (StatusCode, String)> {
+    let mut room_ids = invite
+        .room_ids
+        .iter()
+        .map(|room_id| {
+            OwnedRoomId::try_from(room_id.as_str()).map_err(|_| {
+                (
+                    StatusCode::BAD_REQUEST,
+                    tr(
+                        "room_id {room} is not a room ID like !abc:example.com",
+                        &[("room", &format!("{:?}", room_id))],
+                    ),
+                )
+            })
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    if let Some(alias) = invite
+        .room
+        .as_deref()
+        .map(str::trim)
+        .filter(|room| !room.is_empty())
+    {
+        room_ids.push(resolve_alias(state, alias).await?);
     }
 
-    let (auth_url, csrf_token) = state
-        .oauth2_client
-        .authorize_url(CsrfToken::new_random)
-        .url();
+    let mut unique = vec![];
+    for room_id in room_ids {
+        if !unique.contains(&room_id) {
+            unique.push(room_id);
+        }
+    }
+    if unique.is_empty() {
+        return Err((StatusCode::BAD_REQUEST, t("no room selected")));
+    }
+    if unique.len() > state.max_rooms_per_invite {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            tr(
+                "at most {count} rooms can be requested at once",
+                &[("count", &state.max_rooms_per_invite)],
+            ),
+        ));
+    }
+    Ok(unique)
+}
 
-    state
-        .csrf
-        .lock()
+async fn resolve_alias(state: &AppState, alias: &str) -> Result<OwnedRoomId, (StatusCode, String)> {
+    let alias = OwnedRoomAliasId::try_from(alias).map_err(|_| {
+        (
+            StatusCode::BAD_REQUEST,
+            tr(
+                "{alias} is not a room alias like #room:example.com",
+                &[("alias", &alias)],
+            ),
+        )
+    })?;
+    match state
+        .client
+        .send_request(client::alias::get_alias::v3::Request::new(alias.clone()))
         .await
-        .insert(csrf_token.secret().to_string(), invite);
+    {
+        Ok(response) => Ok(response.room_id),
+        Err(ruma::client::Error::FromHttpResponse(FromHttpResponseError::Server(err)))
+            if err.status_code == StatusCode::NOT_FOUND =>
+        {
+            Err((
+                StatusCode::BAD_REQUEST,
+                tr("room alias {alias} does not exist", &[("alias", &alias)]),
+            ))
+        }
+        Err(err) => {
+            log::error!("failed to resolve room alias {}: {}", &alias, redact(&err));
+            Err((
+                StatusCode::BAD_GATEWAY,
+                tr("failed to resolve room alias {alias}", &[("alias", &alias)]),
+            ))
+        }
+    }
+}
+
+/// Verify the origin, captcha, user id and rooms of an invite request.
+async fn check_invite(
+    state: &AppState,
+    invite: InviteRequest,
+    client_ip: IpAddr,
+) -> Result<Invite, (StatusCode, String)> {
+    bouncer::geoip::check(state, client_ip).await?;
+    if state.tos_url.is_some() && !invite.tos {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            tr(
+                "Please tick \"{terms}\" to request an invite.",
+                &[(
+                    "terms",
+                    &state.tos_text.clone().unwrap_or_else(|| t(page::TOS_TEXT)),
+                )],
+            ),
+        ));
+    }
+    let emails = requested_emails(state, &invite)?;
+    let (user_ids, malformed) = if emails.is_empty() {
+        requested_users(state, &invite)?
+    } else {
+        (vec![], vec![])
+    };
+    for user_id in &user_ids {
+        if let Some(rule) = state
+            .rules
+            .load()
+            .localpart_rules
+            .violation(user_id.localpart())
+        {
+            log::warn!("refused invite of {}, its localpart {}", user_id, rule);
+            return Err((
+                StatusCode::FORBIDDEN,
+                tr(
+                    "{user} cannot be invited through this bouncer",
+                    &[("user", user_id)],
+                ),
+            ));
+        }
+        if let Some(list) = state.policy.banned(user_id).await {
+            log::warn!(
+                "refused invite of {} banned by policy list {}",
+                user_id,
+                list
+            );
+            return Err((
+                StatusCode::FORBIDDEN,
+                tr(
+                    "{user} is banned by the policy list {list}",
+                    &[("user", user_id), ("list", &list)],
+                ),
+            ));
+        }
+    }
 
-    Ok(Redirect::to(auth_url.as_str()))
+    state
+        .captcha
+        .verify(&invite.cf_turnstile_response, client_ip)
+        .await?;
+    // After the captcha, so the outbound requests cannot be triggered in bulk.
+    state
+        .homeserver_check
+        .check(&state.http_client, state.user_id.server_name(), &user_ids)
+        .await?;
+
+    let room_ids = requested_rooms(state, &invite).await?;
+    let rooms = state.rooms.read().await;
+    for room_id in &room_ids {
+        match rooms.get(room_id) {
+            Some(RoomInfo {
+                replacement: Some(replacement),
+                ..
+            }) => {
+                return Err((
+                    StatusCode::BAD_REQUEST,
+                    tr(
+                        "room {room} was upgraded to {successor}, which this bouncer cannot invite to yet",
+                        &[("room", room_id), ("successor", replacement)],
+                    ),
+                ))
+            }
+            Some(_) => {}
+            None => {
+                let successor = rooms
+                    .values()
+                    .find(|room| room.predecessor.as_ref() == Some(room_id));
+                return Err((
+                    StatusCode::BAD_REQUEST,
+                    match successor {
+                        Some(successor) => tr(
+                            "room {room} was upgraded, request an invite to {successor} instead",
+                            &[("room", room_id), ("successor", &successor.display_id())],
+                        ),
+                        None => tr(
+                            "room {room} is not served by this bouncer",
+                            &[("room", room_id)],
+                        ),
+                    },
+                ));
+            }
+        }
+        for user_id in &user_ids {
+            room_policy::check_user(state, room_id, user_id)
+                .map_err(|message| (StatusCode::FORBIDDEN, message))?;
+        }
+        if !emails.is_empty() && !room_policy::accepts_email(state, room_id) {
+            return Err((
+                StatusCode::BAD_REQUEST,
+                tr(
+                    "room {room} only accepts Matrix IDs on certain homeservers, not email addresses",
+                    &[("room", room_id)],
+                ),
+            ));
+        }
+    }
+
+    Ok(Invite {
+        created: chrono::Utc::now(),
+        room_ids,
+        user_ids,
+        emails,
+        malformed,
+        existing: vec![],
+        pkce_verifier: None,
+        browser_nonce: None,
+        github_user: None,
+        terms: state.tos_url.clone(),
+        client_ip: None,
+    })
 }
 
-#[derive(clap::Parser)]
-#[command(version, about, long_about = None)]
-struct Args {
-    #[arg(long, env = "MATRIX_ACCESS_TOKEN")]
-    access_token: String,
-    #[arg(long, env = "MATRIX_HOMESERVER_URL")]
-    homeserver_url: String,
-    #[arg(long, env = "GITHUB_CLIENT_ID")]
-    github_client_id: String,
-    #[arg(long, env = "GITHUB_CLIENT_SECRET")]
-    github_client_secret: String,
-    #[arg(long, env = "GITHUB_REDIRECT_URL")]
-    github_redirect_url: String,
-    #[arg(long, env, default_value = "1x00000000000000000000AA")]
-    turnstile_site_key: String,
-    #[arg(long, env, default_value = "1x0000000000000000000000000000000AA")]
-    turnstile_secret_key: String,
-    #[arg(long)]
-    listen_address: String,
+/// Stash the invite until the GitHub login completes and return the authorize url.
+async fn authorize(state: &AppState, mut invite: Invite) -> Result<String, (StatusCode, String)> {
+    let (pkce_challenge, pkce_verifier) = PkceCodeChallenge::new_random_sha256();
+    let mut request = state
+        .oauth2_client
+        .authorize_url(CsrfToken::new_random)
+        .set_pkce_challenge(pkce_challenge);
+    if room_policy::needs_memberships(state, &invite.room_ids) {
+        request = request.add_scope(Scope::new("read:org".to_string()));
+    }
+    let (auth_url, csrf_token) = request.url();
+    invite.pkce_verifier = Some(pkce_verifier.secret().to_string());
+
+    sessions::stash(
+        &*state.sessions,
+        csrf_token.secret(),
+        &invite,
+        state.pending_limits,
+    )
+    .await?;
+
+    Ok(auth_url.to_string())
 }
 
 #[tokio::main]
-async fn main() -> anyhow::Result<()> {
-    env_logger::init();
+async fn main() {
+    if let Err(err) = run().await {
+        eprintln!("Error: {:?}", err);
+        std::process::exit(startup::exit_code(&err));
+    }
+}
 
-    let args = Args::parse();
+async fn run() -> anyhow::Result<()> {
+    let args = Args::load().context(Failure::Config)?;
+    let log_format = args
+        .log_format
+        .as_deref()
+        .map(str::parse)
+        .transpose()
+        .context("invalid log_format")
+        .context(Failure::Config)?
+        .unwrap_or_default();
+    bouncer::logging::init(log_format);
+    match args.command.clone() {
+        None | Some(Command::Serve) => {
+            serve(Config::from_args(args).context(Failure::Config)?).await
+        }
+        Some(Command::ListRooms { json }) => {
+            let config = ClientConfig::from_args(args).context(Failure::Config)?;
+            bouncer::cli::list_rooms(config, json).await
+        }
+        Some(Command::Invite {
+            user_id,
+            room,
+            reason,
+            force,
+            wait,
+        }) => {
+            let state = app_state(Config::from_args(args).context(Failure::Config)?).await?;
+            bouncer::cli::invite(&state, &user_id, &room, reason, force, wait).await
+        }
+        Some(Command::TestEmail) => {
+            let config = Config::from_args(args).context(Failure::Config)?;
+            let settings = config
+                .smtp
+                .context("test-email requires smtp_url")
+                .context(Failure::Config)?;
+            bouncer::mail::test(settings).await
+        }
+    }
+}
+
+async fn serve(mut config: Config) -> anyhow::Result<()> {
+    bouncer::i18n::load().context(Failure::Config)?;
+    log::debug!("effective configuration: {:?}", config);
+
+    let listen_address = std::mem::take(&mut config.listen_address);
+    let cors_allowed_origins = std::mem::take(&mut config.cors_allowed_origins);
+    let validate_captcha = config.validate_captcha;
+    let max_body_size = config.max_body_size;
+    let tls = match (config.tls_cert.take(), config.tls_key.take()) {
+        (Some(cert), Some(key)) => {
+            let config = bouncer::tls::load(&cert, &key).context(Failure::Config)?;
+            bouncer::tls::watch(config.clone(), cert, key, bouncer::tls::POLL_INTERVAL)?;
+            Some(config)
+        }
+        _ => None,
+    };
+    let state = app_state(config).await?;
+    let base_path = state.base_path.clone();
+    if validate_captcha {
+        state.validate_turnstile_secret().await?;
+        log::warn!("Cloudflare accepted the turnstile secret key");
+    }
+
+    bouncer::reload::reload_on_sighup(state.clone())?;
+    bouncer::approval::spawn(state.clone());
+    bouncer::digest::spawn(state.clone());
+    bouncer::joins::spawn(state.clone());
+    bouncer::expiry::spawn(state.clone());
+    bouncer::store::spawn(state.clone());
+    bouncer::autojoin::spawn(state.clone());
+    bouncer::policy::spawn(state.clone());
+    bouncer::knock::spawn(state.clone());
+    bouncer::links::spawn(state.clone());
+    bouncer::queue::spawn(state.clone());
+    bouncer::mail::spawn(state.clone());
+    bouncer::health::spawn(state.clone());
+
+    let api = Router::new()
+        .route("/rooms", get(api_rooms))
+        .route("/invite", post(api_invite));
+    let api = if cors_allowed_origins.is_empty() {
+        api
+    } else {
+        let origins = cors_allowed_origins
+            .iter()
+            .map(|origin| {
+                if origin == "*" {
+                    anyhow::bail!("wildcard cors origin is not supported, list exact origins");
+                }
+                HeaderValue::from_str(origin.trim_end_matches('/'))
+                    .with_context(|| format!("invalid cors origin {}", origin))
+            })
+            .collect::<anyhow::Result<Vec<_>>>()
+            .context(Failure::Config)?;
+        api.layer(
+            CorsLayer::new()
+                .allow_origin(AllowOrigin::list(origins))
+                .allow_methods([Method::GET, Method::POST])
+                .allow_headers([header::CONTENT_TYPE]),
+        )
+    };
+
+    let app = Router::new()
+        .route("/", get(bouncer::index))
+        .route("/invite", post(invite))
+        .route("/invite/:room", get(bouncer::page::room))
+        .route("/callback", get(callback))
+        .route("/confirm", post(confirm))
+        .route("/confirm/:token", get(confirmation))
+        .route(
+            "/confirm/:token/avatar/:user_id",
+            get(bouncer::avatar::user_avatar),
+        )
+        .route("/logout", get(bouncer::login::logout))
+        .route("/avatar/:room_id", get(bouncer::avatar::avatar))
+        .route("/check", get(bouncer::check::check))
+        .route("/status/:job_id", get(bouncer::queue::status))
+        .route("/stats", get(bouncer::stats::stats))
+        .route("/readyz", get(bouncer::health::readyz))
+        .route("/static/base.css", get(bouncer::assets::base_css))
+        .route("/static/custom.css", get(bouncer::assets::custom_css))
+        .route(
+            "/claim/:token",
+            get(bouncer::links::claim_form).post(bouncer::links::claim),
+        )
+        .merge(bouncer::pages::routes(&state))
+        .nest("/api", api)
+        // Admin requests, such as bulk invites, may legitimately run long.
+        .route_layer(middleware::from_fn_with_state(
+            state.clone(),
+            bouncer::limits::request_timeout,
+        ))
+        .route_layer(middleware::from_fn_with_state(
+            state.clone(),
+            bouncer::throttle::busy_page,
+        ))
+        // Cheap and fetched by every browser and crawler, exempt like the admin requests.
+        .route("/favicon.ico", get(bouncer::assets::favicon))
+        .route("/robots.txt", get(bouncer::assets::robots))
+        .route("/admin", get(bouncer::admin::dashboard))
+        .route("/admin/refresh-rooms", post(bouncer::admin::refresh_rooms))
+        .route(
+            "/admin/bulk-invite",
+            post(bouncer::admin::bulk_invite)
+                .layer(DefaultBodyLimit::max(bouncer::admin::BULK_BODY_LIMIT)),
+        )
+        .route(
+            "/admin/pending",
+            get(bouncer::admin::pending).delete(bouncer::admin::revoke_pending_user),
+        )
+        .route(
+            "/admin/pending/:prefix",
+            delete(bouncer::admin::revoke_pending),
+        )
+        .route("/admin/webhook/test", post(bouncer::admin::test_webhook))
+        .route("/admin/audit", get(bouncer::history::history))
+        .route("/admin/audit.csv", get(bouncer::export::audit_csv))
+        .route(
+            "/admin/invite-counts",
+            delete(bouncer::admin::reset_invite_counts),
+        )
+        .route(
+            "/admin/links",
+            get(bouncer::admin::links).post(bouncer::admin::create_link),
+        )
+        .route("/admin/links/:prefix", delete(bouncer::admin::revoke_link))
+        .route(
+            "/admin/rooms/:room_id",
+            put(bouncer::admin::add_room).delete(bouncer::admin::remove_room),
+        )
+        .fallback(bouncer::fallback::not_found)
+        .layer(DefaultBodyLimit::max(max_body_size))
+        .layer(middleware::from_fn_with_state(
+            state.clone(),
+            bouncer::fallback::method_not_allowed,
+        ))
+        .layer(middleware::from_fn_with_state(
+            state.clone(),
+            bouncer::i18n::negotiate,
+        ))
+        .layer(middleware::from_fn_with_state(
+            state.clone(),
+            bouncer::security::security_headers,
+        ))
+        .with_state(state);
+    let app = if base_path.is_empty() {
+        app
+    } else {
+        Router::new().nest(&base_path, app)
+    };
+
+    let mut listeners = vec![];
+    for address in listen_address {
+        let listener = tokio::net::TcpListener::bind(&address)
+            .await
+            .with_context(|| format!("failed to bind listen_address {}", address))
+            .context(Failure::Config)?;
+        log::warn!("Listening on {}", listener.local_addr()?);
+        listeners.push((listener, app.clone()));
+    }
 
-    let Args {
-        access_token,
+    bouncer::serve::serve(listeners, tls).await?;
+
+    Ok(())
+}
+
+/// Connect to the homeserver, discover the rooms and load the policy lists, as shared by
+/// `serve` and the `invite` subcommand.
+async fn app_state(config: Config) -> anyhow::Result<Arc<AppState>> {
+    let Config {
+        credentials,
         homeserver_url,
+        proxy,
         github_client_id,
         github_client_secret,
         github_redirect_url,
+        github_url,
+        github_api_url,
+        github_token,
+        turnstile_url,
         turnstile_site_key,
         turnstile_secret_key,
-        listen_address,
-    } = args;
-
-    let client = Client::builder()
-        .homeserver_url(homeserver_url)
-        .access_token(Some(access_token))
-        .build::<ruma::client::http_client::Reqwest>()
-        .await
-        .unwrap();
+        listen_address: _,
+        base_path,
+        csp_directive,
+        trusted_proxies,
+        geoip_database,
+        blocked_countries,
+        allowed_countries,
+        cors_allowed_origins: _,
+        tls_cert: _,
+        tls_key: _,
+        admin_token,
+        rooms,
+        rules,
+        list_public_rooms,
+        room_cache_ttl,
+        hide_topics,
+        topic_length,
+        room_order,
+        skip_ban_check,
+        max_rooms_per_invite,
+        max_batch_size,
+        max_invitees_per_account,
+        skip_homeserver_check,
+        invite_reason,
+        admin_room,
+        approval_power_level,
+        approval_expiry,
+        webhook_url,
+        webhook_secret,
+        smtp,
+        token_check_interval,
+        token_alert_url,
+        audit_store,
+        audit_retention,
+        digest_interval,
+        digest_skip_empty,
+        disable_stats,
+        track_joins,
+        invite_expiry,
+        invite_expiry_dry_run,
+        queue_invites,
+        auto_join_invites_from,
+        command_power_level,
+        id_server,
+        id_access_token,
+        knock_mode,
+        knock_decline_after,
+        redis_url,
+        pending_limits,
+        session_key,
+        session_ttl,
+        skip_confirmation,
+        min_submit_time,
+        http_timeout,
+        request_timeout,
+        homeserver_concurrency,
+        homeserver_wait_timeout,
+        tos_url,
+        tos_text,
+        pages,
+        site_title,
+        site_intro,
+        footer_links,
+        stylesheet,
+        element_web_url,
+        hide_qr_code,
+        favicon,
+        robots_policy,
+        dry_run,
+        validate_captcha: _,
+        max_body_size: _,
+    } = config;
 
-    let user_id = client
-        .send_request(client::account::whoami::v3::Request::new())
-        .await?
-        .user_id;
+    let throttle = Arc::new(bouncer::throttle::Throttle::new(
+        homeserver_concurrency,
+        homeserver_wait_timeout,
+    ));
+    let (client, user_id) = bouncer::connect(
+        homeserver_url,
+        credentials,
+        proxy.as_ref(),
+        throttle.clone(),
+    )
+    .await?;
     log::warn!("Running under user {}", &user_id);
 
-    let joined_rooms = client
-        .send_request(client::membership::joined_rooms::v3::Request::new())
-        .await?
-        .joined_rooms;
-
-    let mut rooms = HashMap::default();
-    for room_id in joined_rooms {
-        let power_levels: RoomPowerLevels = client
-            .send_request(client::state::get_state_events_for_key::v3::Request::new(
-                room_id.clone(),
-                StateEventType::RoomPowerLevels,
-                "".to_string(),
-            ))
-            .await?
-            .content
-            .deserialize_as::<RoomPowerLevelsEventContent>()?
-            .into();
-        if !power_levels.user_can_invite(&user_id) {
+    let room_filter = RoomFilter::resolve(&client, &rooms)
+        .await
+        .context("failed to resolve room, exclude_room or space")
+        .context(Failure::Config)?;
+    let admin_room = match admin_room {
+        Some(room) => Some(
+            resolve_room(&client, &room)
+                .await
+                .context("failed to resolve admin_room")
+                .context(Failure::Config)?,
+        ),
+        None => None,
+    };
+    let rules = room_policy::Rules::resolve(&client, rules)
+        .await
+        .context(Failure::Config)?;
+    let webhook = match webhook_url {
+        Some(url) => Some(Arc::new(
+            Webhook::new(url, webhook_secret).context(Failure::Config)?,
+        )),
+        None => None,
+    };
+    let geoip = bouncer::geoip::GeoIp::new(geoip_database, blocked_countries, allowed_countries)
+        .context(Failure::Config)?;
+    let mailer = match smtp {
+        Some(settings) => Some(Arc::new(
+            bouncer::mail::Mailer::new(settings).context(Failure::Config)?,
+        )),
+        None => None,
+    };
+    let store = audit_store
+        .as_deref()
+        .map(|path| Store::open(path, audit_retention))
+        .transpose()
+        .context(Failure::Config)?;
+    let sessions: Box<dyn SessionStore> = match &redis_url {
+        Some(url) => Box::new(
+            RedisStore::connect(url)
+                .await
+                .with_context(|| {
+                    format!(
+                        "failed to connect to redis_url {}",
+                        startup::redact_url(url)
+                    )
+                })
+                .context(Failure::Network)?,
+        ),
+        None => Box::new(MemoryStore::default()),
+    };
+    let (invite_counts, links) = match &store {
+        Some(store) => {
+            store
+                .read(|data| (data.invite_counts.clone(), data.links.clone()))
+                .await
+        }
+        None => (HashMap::new(), HashMap::new()),
+    };
+    let mut rooms = discover_rooms(&client, &user_id, &room_filter)
+        .await
+        .context("room discovery failed")
+        .context(Failure::Network)?;
+    for room in rooms.values_mut() {
+        room_policy::display(&rules.room_configs, room);
+    }
+    let (rooms, public_rooms) = room_filter.partition(rooms);
+    for room_id in rules.room_configs.keys() {
+        if !rooms.contains_key(room_id) {
             log::warn!(
-                "Do not have invite permission for room {}, ignoring",
-                &room_id
+                "room_config of {} names a room that is not served, its settings have no effect",
+                room_id
             );
-            continue;
-        };
-        let preview = client
-            .send_request(client::room::get_summary::msc3266::Request::new(
-                room_id.clone().into(),
-                vec![],
-            ))
-            .await?;
-        rooms.insert(
-            preview.room_id.clone(),
-            RoomInfo {
-                room_id: preview.room_id,
-                canonical_alias: preview.canonical_alias,
-                name: preview.name,
-                join_rule: preview.join_rule,
-            },
-        );
+        }
     }
 
+    if dry_run {
+        log::warn!("Dry run, no invite will be sent");
+    }
+    let base_path = bouncer::normalize_base_path(&base_path);
+    let redirect_url = RedirectUrl::new(github_redirect_url.clone())
+        .with_context(|| format!("invalid github_redirect_url {}", github_redirect_url))
+        .context(Failure::Config)?;
+
+    let github_origin = url::Url::parse(&github_url)
+        .with_context(|| format!("invalid github_url {}", github_url))
+        .context(Failure::Config)?
+        .origin()
+        .ascii_serialization();
     let oauth2_client = BasicClient::new(
         ClientId::new(github_client_id),
-        Some(ClientSecret::new(github_client_secret)),
-        AuthUrl::new("https://github.com/login/oauth/authorize".to_string())?,
-        Some(TokenUrl::new(
-            "https://github.com/login/oauth/access_token".to_string(),
-        )?),
+        Some(ClientSecret::new(github_client_secret.expose().clone())),
+        AuthUrl::new(format!("{}/login/oauth/authorize", github_url))?,
+        Some(TokenUrl::new(format!(
+            "{}/login/oauth/access_token",
+            github_url
+        ))?),
     )
-    .set_redirect_uri(RedirectUrl::new(github_redirect_url)?);
+    .set_redirect_uri(redirect_url);
+    let http_client = bouncer::http_client(http_timeout, proxy.as_ref())
+        .context("failed to build the http client")?;
 
     let state = Arc::new(AppState {
+        inviter: Box::new(client.clone()),
         client,
+        captcha: Box::new(bouncer::captcha::TurnstileVerifier {
+            http_client: http_client.clone(),
+            http_timeout,
+            siteverify_url: format!("{}/turnstile/v0/siteverify", turnstile_url),
+            secret_key: turnstile_secret_key.clone(),
+        }),
+        identity: Box::new(bouncer::identity::GitHub {
+            oauth2_client: oauth2_client.clone(),
+            http_client: http_client.clone(),
+            http_timeout,
+            api_url: github_api_url,
+            token: github_token,
+        }),
         oauth2_client,
-        rooms,
+        http_client,
+        http_timeout,
+        request_timeout,
+        throttle,
+        user_id,
+        rooms: RwLock::new(rooms),
+        public_rooms: RwLock::new(public_rooms),
+        list_public_rooms,
+        room_cache_ttl,
+        hide_topics,
+        topic_length,
+        avatars: Default::default(),
+        membership_check: Default::default(),
+        room_order,
+        skip_ban_check,
+        max_rooms_per_invite,
+        max_batch_size,
+        quota: bouncer::quota::Quota::new(max_invitees_per_account),
+        homeserver_check: bouncer::homeserver::HomeserverCheck::new(!skip_homeserver_check),
+        rules: ArcSwap::from_pointee(rules),
+        invite_reason,
+        admin_room,
+        approval_power_level,
+        approval_expiry,
+        approvals: Default::default(),
+        webhook,
+        mailer,
+        token_check_interval,
+        token_alert_url,
+        health: Default::default(),
+        store,
+        digest_interval,
+        digest_skip_empty,
+        disable_stats,
+        stats: Default::default(),
+        track_joins,
+        invite_expiry,
+        invite_expiry_dry_run,
+        queue_invites,
+        auto_join_invites_from,
+        command_power_level,
+        policy: Default::default(),
+        id_server,
+        id_access_token,
+        knock_mode,
+        knock_decline_after,
+        knocks: Default::default(),
+        hidden_rooms: Default::default(),
+        room_filter: RwLock::new(room_filter),
+        turnstile_url,
         turnstile_site_key,
         turnstile_secret_key,
-        csrf: Mutex::new(HashMap::new()),
+        base_path: base_path.clone(),
+        github_origin,
+        csp_directives: csp_directive,
+        trusted_proxies,
+        geoip,
+        admin_token,
+        refresh: Default::default(),
+        sessions,
+        pending_limits,
+        invite_counts: Mutex::new(invite_counts),
+        links: Mutex::new(links),
+        session_key: session_key
+            .as_ref()
+            .map(|key| ring::hmac::Key::new(ring::hmac::HMAC_SHA256, key.expose().as_bytes())),
+        session_ttl,
+        skip_confirmation,
+        // Replicas need the shared session key to accept each other's forms.
+        form_key: match &session_key {
+            Some(key) => ring::hmac::Key::new(
+                ring::hmac::HMAC_SHA256,
+                format!("form:{}", key.expose()).as_bytes(),
+            ),
+            None => {
+                ring::hmac::Key::generate(ring::hmac::HMAC_SHA256, &ring::rand::SystemRandom::new())
+                    .map_err(|_| anyhow::anyhow!("failed to generate the form key"))?
+            }
+        },
+        min_submit_time,
+        tos_url,
+        tos_text,
+        pages: bouncer::pages::read(pages).context(Failure::Config)?,
+        site_title,
+        site_intro: site_intro.as_deref().map(page::markdown),
+        footer_links,
+        stylesheet: stylesheet
+            .as_deref()
+            .map(bouncer::assets::Stylesheet::read)
+            .transpose()
+            .context(Failure::Config)?,
+        element_web_url,
+        hide_qr_code,
+        favicon: favicon
+            .as_deref()
+            .map(bouncer::assets::Favicon::read)
+            .transpose()
+            .context(Failure::Config)?
+            .unwrap_or_default(),
+        robots_txt: bouncer::assets::robots_txt(&robots_policy, &base_path)
+            .context(Failure::Config)?,
+        dry_run,
     });
 
-    let app = Router::new()
-        .route("/", get(bouncer::index))
-        .route("/invite", post(invite))
-        .route("/callback", get(callback))
-        .with_state(state);
-
-    let listener = tokio::net::TcpListener::bind(listen_address).await?;
-    axum::serve(listener, app).await?;
-
-    Ok(())
+    bouncer::policy::load(&state).await;
+    Ok(state)
 }
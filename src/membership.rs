@@ -0,0 +1,69 @@
+use ruma::{
+    api::client,
+    events::{
+        room::{
+            member::{MembershipState, RoomMemberEventContent},
+            power_levels::{RoomPowerLevels, RoomPowerLevelsEventContent},
+        },
+        StateEventType,
+    },
+    RoomId, UserId,
+};
+
+use crate::MatrixClient;
+
+/// Current membership of a user in a room, `None` if they never had one or the lookup failed.
+pub async fn membership(
+    client: &MatrixClient,
+    room_id: &RoomId,
+    user_id: &UserId,
+) -> Option<MembershipState> {
+    let response = client
+        .send_request(client::state::get_state_events_for_key::v3::Request::new(
+            room_id.to_owned(),
+            StateEventType::RoomMember,
+            user_id.to_string(),
+        ))
+        .await
+        .map_err(|err| {
+            log::debug!(
+                "failed to get membership of {} in room {}: {}",
+                user_id,
+                room_id,
+                err
+            );
+        })
+        .ok()?;
+    response
+        .content
+        .deserialize_as::<RoomMemberEventContent>()
+        .map_err(|err| {
+            log::debug!(
+                "invalid membership of {} in room {}: {}",
+                user_id,
+                room_id,
+                err
+            );
+        })
+        .ok()
+        .map(|content| content.membership)
+}
+
+/// Power level of a user in a room.
+pub async fn power_level(
+    client: &MatrixClient,
+    room_id: &RoomId,
+    user_id: &UserId,
+) -> anyhow::Result<i64> {
+    let power_levels: RoomPowerLevels = client
+        .send_request(client::state::get_state_events_for_key::v3::Request::new(
+            room_id.to_owned(),
+            StateEventType::RoomPowerLevels,
+            "".to_string(),
+        ))
+        .await?
+        .content
+        .deserialize_as::<RoomPowerLevelsEventContent>()?
+        .into();
+    Ok(power_levels.for_user(user_id).into())
+}
@@ -0,0 +1,162 @@
+//! Mock captcha, identity and homeserver implementations for testing the invite decisions
+//! without the network.
+
+use std::{
+    collections::HashMap,
+    net::{IpAddr, Ipv4Addr},
+    sync::Mutex,
+};
+
+use axum::{async_trait, http::StatusCode};
+use chrono::{Duration, Utc};
+use ruma::{
+    events::room::member::MembershipState, room_id, user_id, OwnedRoomId, OwnedUserId, RoomId,
+    UserId,
+};
+
+use crate::{
+    captcha::CaptchaVerifier,
+    identity::IdentityProvider,
+    invite::{InviteError, Inviter},
+    sessions::PendingLimits,
+    GitHubUser, Invite, CAPTCHA_FAILED,
+};
+
+pub const CLIENT: IpAddr = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1));
+pub const LIMITS: PendingLimits = PendingLimits {
+    total: 10,
+    per_user: 2,
+    per_client: 3,
+};
+
+/// Accepts a single captcha response.
+pub struct MockCaptcha(pub &'static str);
+
+#[async_trait]
+impl CaptchaVerifier for MockCaptcha {
+    async fn verify(&self, response: &str, _: IpAddr) -> Result<(), (StatusCode, String)> {
+        if response != self.0 {
+            return Err((StatusCode::FORBIDDEN, CAPTCHA_FAILED.to_string()));
+        }
+        Ok(())
+    }
+
+    fn script_src(&self) -> String {
+        "https://captcha.example.com/api.js".to_string()
+    }
+
+    fn origins(&self) -> Vec<String> {
+        vec!["https://captcha.example.com".to_string()]
+    }
+}
+
+/// Identifies every login as `user`, recording the code, PKCE verifier and teams asked for.
+pub struct MockIdentity {
+    user: GitHubUser,
+    calls: Mutex<Vec<(String, Option<String>, Vec<String>)>>,
+}
+
+impl MockIdentity {
+    pub fn new(user: GitHubUser) -> MockIdentity {
+        MockIdentity {
+            user,
+            calls: Default::default(),
+        }
+    }
+
+    pub fn calls(&self) -> Vec<(String, Option<String>, Vec<String>)> {
+        self.calls.lock().unwrap().clone()
+    }
+}
+
+#[async_trait]
+impl IdentityProvider for MockIdentity {
+    async fn identify(
+        &self,
+        code: String,
+        pkce_verifier: Option<String>,
+        teams: &[String],
+    ) -> Result<GitHubUser, (StatusCode, String)> {
+        self.calls
+            .lock()
+            .unwrap()
+            .push((code, pkce_verifier, teams.to_vec()));
+        Ok(self.user.clone())
+    }
+}
+
+/// A homeserver knowing the given memberships, recording invites and failing them with
+/// `error` if set.
+#[derive(Default)]
+pub struct MockInviter {
+    pub memberships: HashMap<(OwnedRoomId, OwnedUserId), MembershipState>,
+    pub error: Option<&'static str>,
+    invites: Mutex<Vec<(OwnedRoomId, OwnedUserId, Option<String>)>>,
+}
+
+impl MockInviter {
+    pub fn invites(&self) -> Vec<(OwnedRoomId, OwnedUserId, Option<String>)> {
+        self.invites.lock().unwrap().clone()
+    }
+}
+
+#[async_trait]
+impl Inviter for MockInviter {
+    async fn membership(&self, room_id: &RoomId, user_id: &UserId) -> Option<MembershipState> {
+        self.memberships
+            .get(&(room_id.to_owned(), user_id.to_owned()))
+            .cloned()
+    }
+
+    async fn invite(
+        &self,
+        room_id: &RoomId,
+        user_id: &UserId,
+        reason: Option<String>,
+    ) -> Result<(), InviteError> {
+        self.invites
+            .lock()
+            .unwrap()
+            .push((room_id.to_owned(), user_id.to_owned(), reason));
+        match self.error {
+            Some(kind) => Err(InviteError::Failed {
+                kind: Some(kind.to_string()),
+                message: format!("[403 / {}] not allowed", kind),
+            }),
+            None => Ok(()),
+        }
+    }
+}
+
+/// A GitHub user created `age_days` ago, a member of rust-lang/infra and invited to
+/// rust-lang/compiler.
+pub fn user(age_days: i64) -> GitHubUser {
+    GitHubUser {
+        login: "octocat".to_string(),
+        created_at: Utc::now() - Duration::days(age_days),
+        orgs: vec!["Rust-Lang".to_string()],
+        teams: vec!["rust-lang/Infra".to_string()],
+        pending_teams: vec!["rust-lang/compiler".to_string()],
+    }
+}
+
+/// An invite of @alice:localhost to !room:localhost, submitted from [`CLIENT`].
+pub fn invite(browser_nonce: Option<&str>) -> Invite {
+    Invite {
+        created: Utc::now(),
+        room_ids: vec![room_id!("!room:localhost").to_owned()],
+        user_ids: vec![user_id!("@alice:localhost").to_owned()],
+        emails: vec![],
+        malformed: vec![],
+        existing: vec![],
+        pkce_verifier: Some("verifier".to_string()),
+        browser_nonce: browser_nonce.map(str::to_string),
+        github_user: None,
+        terms: None,
+        client_ip: Some(CLIENT.to_string()),
+    }
+}
+
+pub fn teams(_: &Invite) -> Vec<String> {
+    vec!["rust-lang/infra".to_string()]
+}
@@ -0,0 +1,86 @@
+use std::{cmp::Ordering, str::FromStr};
+
+use ruma::OwnedRoomId;
+
+use crate::RoomInfo;
+
+/// How rooms are ordered in the invite table and the JSON API.
+#[derive(Clone, Debug, Default)]
+pub enum RoomOrder {
+    /// Alphabetical by name, falling back to alias then room id.
+    #[default]
+    Name,
+    /// Alphabetical by alias, falling back to room id.
+    Alias,
+    /// Most joined members first.
+    Members,
+    /// The listed rooms first in the given order, then everything else by name.
+    Pinned(Vec<OwnedRoomId>),
+}
+
+impl FromStr for RoomOrder {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s {
+            "name" => RoomOrder::Name,
+            "alias" => RoomOrder::Alias,
+            "members" => RoomOrder::Members,
+            pinned => RoomOrder::Pinned(
+                pinned
+                    .split(',')
+                    .map(|room_id| {
+                        OwnedRoomId::try_from(room_id.trim()).map_err(|err| {
+                            anyhow::anyhow!(
+                                "invalid room order {:?}, expected name, alias, members or a \
+                                 comma separated list of room ids: {}",
+                                s,
+                                err
+                            )
+                        })
+                    })
+                    .collect::<anyhow::Result<_>>()?,
+            ),
+        })
+    }
+}
+
+fn name_key(room: &RoomInfo) -> String {
+    room.name
+        .clone()
+        .unwrap_or_else(|| room.display_id())
+        .to_lowercase()
+}
+
+fn alias_key(room: &RoomInfo) -> String {
+    room.display_id().to_lowercase()
+}
+
+impl RoomOrder {
+    pub fn compare(&self, a: &RoomInfo, b: &RoomInfo) -> Ordering {
+        let ordering = match self {
+            RoomOrder::Name => name_key(a).cmp(&name_key(b)),
+            RoomOrder::Alias => alias_key(a).cmp(&alias_key(b)),
+            RoomOrder::Members => b
+                .members
+                .cmp(&a.members)
+                .then_with(|| name_key(a).cmp(&name_key(b))),
+            RoomOrder::Pinned(pinned) => {
+                let position = |room: &RoomInfo| {
+                    pinned
+                        .iter()
+                        .position(|room_id| *room_id == room.room_id)
+                        .unwrap_or(pinned.len())
+                };
+                position(a)
+                    .cmp(&position(b))
+                    .then_with(|| name_key(a).cmp(&name_key(b)))
+            }
+        };
+        ordering.then_with(|| a.room_id.cmp(&b.room_id))
+    }
+
+    pub fn sort(&self, rooms: &mut [&RoomInfo]) {
+        rooms.sort_by(|a, b| self.compare(a, b));
+    }
+}
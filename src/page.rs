@@ -0,0 +1,520 @@
+use std::sync::Arc;
+
+use axum::{
+    async_trait,
+    extract::{Extension, FromRequest, FromRequestParts, Path, Query, RawForm, Request, State},
+    http::{request::Parts, Extensions, StatusCode},
+};
+use maud::{html, Markup, PreEscaped, DOCTYPE};
+use percent_encoding::{utf8_percent_encode, NON_ALPHANUMERIC};
+use serde::de::DeserializeOwned;
+
+use ruma::{events::room::member::MembershipState, OwnedUserId, RoomId, UserId};
+
+use crate::{
+    assets, client_links, find_room, honeypot,
+    i18n::{self, t},
+    normalize_whitespace, room_policy,
+    security::CspNonce,
+    AppState, RoomInfo,
+};
+
+/// Render markdown written by the operator, escaping any raw HTML in it.
+pub fn markdown(text: &str) -> Markup {
+    let parser = pulldown_cmark::Parser::new(text).map(|event| match event {
+        pulldown_cmark::Event::Html(html) | pulldown_cmark::Event::InlineHtml(html) => {
+            pulldown_cmark::Event::Text(html)
+        }
+        event => event,
+    });
+    let mut html = String::new();
+    pulldown_cmark::html::push_html(&mut html, parser);
+    PreEscaped(html)
+}
+
+/// Wrap page content in the shared document head and footer.
+pub fn layout(state: &AppState, nonce: &str, title: &str, body: Markup) -> Markup {
+    html! {
+        (DOCTYPE)
+        html lang=(i18n::current()) {
+            head {
+                meta charset="utf-8";
+                meta name="viewport" content="width=device-width, initial-scale=1";
+                title { (title) }
+                script src=(state.captcha.script_src()) nonce=(nonce) async defer {}
+                (assets::stylesheets(state))
+            }
+            body {
+                (body)
+                footer {
+                  @if !state.pages.is_empty() {
+                      nav {
+                          @for page in &state.pages {
+                              a href=(state.absolute_link(&page.path[1..])) { (page.title) } " "
+                          }
+                      }
+                  }
+                  @if state.footer_links.is_empty() {
+                      (t("Source Code:")) a href="https://github.com/NickCao/bouncer" { "https://github.com/NickCao/bouncer" }
+                  }
+                  @for (label, url) in &state.footer_links {
+                      a href=(url) { (label) } " "
+                  }
+                  nav {
+                      @for language in i18n::languages() {
+                          a href=(format!("?lang={}", language)) lang=(language) { (language) } " "
+                      }
+                  }
+                }
+            }
+        }
+    }
+}
+
+pub fn error_page(
+    state: &AppState,
+    nonce: &str,
+    status: StatusCode,
+    message: &str,
+) -> (StatusCode, Markup) {
+    (
+        status,
+        layout(
+            state,
+            nonce,
+            state.site_title(),
+            html! {
+                h1 { (status) }
+                p { (message) }
+                p {
+                    a href=(state.absolute_link("")) { (t("Back to the invite form")) }
+                }
+            },
+        ),
+    )
+}
+
+/// Explain a rejected form or query string, naming the offending field.
+fn rejection_message(detail: &str) -> String {
+    let hint = if detail.contains("`user_id`") {
+        t("Enter your Matrix ID, e.g. @user:example.com.")
+    } else if detail.contains("`cf_turnstile_response`") {
+        t("Complete the captcha before submitting.")
+    } else if detail.contains("`code`") || detail.contains("`state`") {
+        t("The GitHub login did not complete, please start the invite again.")
+    } else {
+        String::new()
+    };
+    let detail = detail.split_once(": ").map_or(detail, |(_, detail)| detail);
+    let message = i18n::tr(
+        "The submitted request is invalid: {detail}.",
+        &[("detail", &detail)],
+    );
+    if hint.is_empty() {
+        message
+    } else {
+        format!("{} {}", message, hint)
+    }
+}
+
+pub(crate) fn nonce(extensions: &Extensions) -> String {
+    extensions
+        .get::<CspNonce>()
+        .map(|CspNonce(nonce)| nonce.clone())
+        .unwrap_or_default()
+}
+
+/// Form extractor answering malformed submissions with the HTML error page.
+///
+/// Repeated keys deserialize into a `Vec`, as sent by a group of checkboxes.
+pub struct HtmlForm<T>(pub T);
+
+#[async_trait]
+impl<T: DeserializeOwned> FromRequest<Arc<AppState>> for HtmlForm<T> {
+    type Rejection = (StatusCode, Markup);
+
+    async fn from_request(req: Request, state: &Arc<AppState>) -> Result<Self, Self::Rejection> {
+        let nonce = nonce(req.extensions());
+        let reject = |detail: String| {
+            error_page(
+                state,
+                &nonce,
+                StatusCode::BAD_REQUEST,
+                &rejection_message(&detail),
+            )
+        };
+        let RawForm(body) = RawForm::from_request(req, state).await.map_err(|err| {
+            if err.status() == StatusCode::PAYLOAD_TOO_LARGE {
+                error_page(
+                    state,
+                    &nonce,
+                    err.status(),
+                    &t("The submitted request is too large."),
+                )
+            } else {
+                reject(err.body_text())
+            }
+        })?;
+        serde_html_form::from_bytes(&body)
+            .map(HtmlForm)
+            .map_err(|err| reject(err.to_string()))
+    }
+}
+
+/// Query string extractor answering malformed requests with the HTML error page.
+pub struct HtmlQuery<T>(pub T);
+
+#[async_trait]
+impl<T: DeserializeOwned> FromRequestParts<Arc<AppState>> for HtmlQuery<T> {
+    type Rejection = (StatusCode, Markup);
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &Arc<AppState>,
+    ) -> Result<Self, Self::Rejection> {
+        match Query::<T>::from_request_parts(parts, state).await {
+            Ok(Query(value)) => Ok(HtmlQuery(value)),
+            Err(err) => Err(error_page(
+                state,
+                &nonce(&parts.extensions),
+                StatusCode::BAD_REQUEST,
+                &rejection_message(&err.body_text()),
+            )),
+        }
+    }
+}
+
+/// Shown instead of the GitHub login when the user needs no invite.
+pub fn existing_membership(
+    state: &AppState,
+    nonce: &str,
+    rooms: &[(&UserId, &RoomInfo, MembershipState)],
+) -> Markup {
+    layout(
+        state,
+        nonce,
+        state.site_title(),
+        html! {
+            ul {
+                @for (user_id, room, membership) in rooms {
+                    @let name = room.name.clone().unwrap_or_else(|| room.display_id());
+                    li {
+                        @if *membership == MembershipState::Join {
+                            (i18n::tr("{user} is already a member of", &[("user", &user_id)])) " "
+                        } @else {
+                            (i18n::tr("An invite is already pending for {user} to", &[("user", &user_id)])) " "
+                        }
+                        a href=(room.matrix_to()) { (name) }
+                    }
+                }
+            }
+        },
+    )
+}
+
+/// Invites sent for one user after the GitHub login, or why none were sent.
+pub struct UserOutcome {
+    pub user: String,
+    pub rooms: Result<Vec<(String, Result<String, String>)>, String>,
+}
+
+/// Result of the invites sent after the GitHub login, one line per user and room. `jobs` are
+/// the invites left to [`crate::queue`] by label and job id, whose progress the page polls.
+pub fn invite_outcome(
+    state: &AppState,
+    nonce: &str,
+    users: &[UserOutcome],
+    malformed: &[String],
+    open: &[RoomInfo],
+    jobs: &[(String, String)],
+) -> Markup {
+    layout(
+        state,
+        nonce,
+        state.site_title(),
+        html! {
+            @if state.dry_run {
+                p class="dry-run" { strong { (t("Dry run – no invite sent.")) } }
+            }
+            @for outcome in users {
+                p { (i18n::tr("Invites for {user}:", &[("user", &outcome.user)])) }
+                @match &outcome.rooms {
+                    Ok(rooms) => {
+                        ul {
+                            @for (room, result) in rooms {
+                                li {
+                                    (room) ": "
+                                    @match result {
+                                        Ok(outcome) => { (outcome) }
+                                        Err(err) => { (i18n::tr("failed, {error}", &[("error", err)])) }
+                                    }
+                                }
+                            }
+                        }
+                    }
+                    Err(err) => { p { (i18n::tr("Not invited: {error}", &[("error", err)])) } }
+                }
+            }
+            @if !jobs.is_empty() {
+                h2 { (t("Invite progress")) }
+                ul id="job-progress" data-invited=(t("invited")) data-failed=(t("failed")) aria-live="polite" {
+                    @for (label, id) in jobs {
+                        li {
+                            (label) ": "
+                            span class="job-status" data-status=(state.absolute_link(&format!("status/{}", id))) { (t("being processed")) }
+                        }
+                    }
+                }
+                script nonce=(nonce) { (PreEscaped(JOB_PROGRESS)) }
+            }
+            @if !malformed.is_empty() {
+                p { (t("These lines were skipped:")) }
+                ul {
+                    @for line in malformed {
+                        li { (line) }
+                    }
+                }
+            }
+            @if !open.is_empty() {
+                h2 { (t("Open your Matrix client")) }
+                p { (t("The invite waits in your Matrix client, these links open the room there.")) }
+                ul class="client-links" {
+                    @for room in open {
+                        @let name = room.name.clone().unwrap_or_else(|| room.display_id());
+                        li {
+                            (name) ": "
+                            @for (client, url) in client_links::links(state, room) {
+                                a class="button" href=(url) aria-label=(client_links::label(&name, &client)) { (client) } " "
+                            }
+                            @if !state.hide_qr_code {
+                                @if let Some(svg) = client_links::qr_code(&client_links::matrix_to(room, state.user_id.server_name())) {
+                                    div class="qr-code" role="img" aria-label=(i18n::tr("QR code of the link to {room}", &[("room", &name)])) {
+                                        (PreEscaped(svg))
+                                    }
+                                }
+                            }
+                        }
+                    }
+                }
+            }
+        },
+    )
+}
+
+/// Matrix account an invite is about to be sent to, as shown on the confirmation page.
+pub struct ConfirmedUser {
+    pub user_id: OwnedUserId,
+    pub displayname: Option<String>,
+    pub has_avatar: bool,
+}
+
+/// Ask the logged in user to check the invite before it is sent.
+pub fn confirmation(
+    state: &AppState,
+    nonce: &str,
+    token: &str,
+    login: &str,
+    users: &[ConfirmedUser],
+    emails: &[String],
+    rooms: &[String],
+) -> Markup {
+    layout(
+        state,
+        nonce,
+        &state.page_title(&t("Confirm the invite")),
+        html! {
+            h1 { (t("Confirm the invite")) }
+            p { (i18n::tr("Verified as GitHub user {login}.", &[("login", &login)])) }
+            p { (t("Invite:")) }
+            ul {
+                @for user in users {
+                    li {
+                        @if user.has_avatar {
+                            img class="avatar" alt="" src=(state.absolute_link(&format!("confirm/{}/avatar/{}", token, room_segment(user.user_id.as_str()))));
+                        }
+                        @if let Some(displayname) = &user.displayname {
+                            (displayname) " "
+                        }
+                        "(" (user.user_id) ")"
+                    }
+                }
+                @for email in emails {
+                    li { (email) }
+                }
+            }
+            p { (t("To:")) }
+            ul {
+                @for room in rooms {
+                    li { (room) }
+                }
+            }
+            form action=(state.absolute_link("confirm")) method="post" {
+                input type="hidden" name="token" value=(token);
+                button type="submit" { (t("Send invite")) }
+            }
+            p {
+                (t("Not right?")) " "
+                a href=(state.absolute_link("")) { (t("Start over")) }
+            }
+        },
+    )
+}
+
+/// Looks up the membership of the entered user in the selected room when the user id field
+/// loses focus, so existing members are told before solving the captcha.
+const MEMBERSHIP_HINT: &str = r#"
+  const user = document.getElementById("user");
+  const hint = document.getElementById("membership-hint");
+  user.addEventListener("blur", async () => {
+    hint.textContent = "";
+    const room = document.querySelector('input[name="room_ids"]:checked, input[name="room_ids"][type="hidden"]');
+    if (!room || !user.value.trim()) {
+      return;
+    }
+    const params = new URLSearchParams({ room_id: room.value, user_id: user.value });
+    const response = await fetch(hint.dataset.check + "?" + params);
+    if (!response.ok) {
+      return;
+    }
+    const { membership } = await response.json();
+    hint.textContent = {
+      join: hint.dataset.join,
+      invite: hint.dataset.invite,
+      ban: hint.dataset.ban,
+    }[membership] ?? "";
+  });
+"#;
+
+/// Polls the status of queued invites until each one was sent or given up on.
+const JOB_PROGRESS: &str = r#"
+  const progress = document.getElementById("job-progress");
+  const poll = async () => {
+    let pending = false;
+    for (const job of progress.querySelectorAll("[data-status]")) {
+      const response = await fetch(job.dataset.status).catch(() => null);
+      if (!response || !response.ok) {
+        pending = true;
+        continue;
+      }
+      const { status, error } = await response.json();
+      if (status === "invited") {
+        job.textContent = progress.dataset.invited;
+        delete job.dataset.status;
+      } else if (status === "failed") {
+        job.textContent = progress.dataset.failed + (error ? ", " + error : "");
+        delete job.dataset.status;
+      } else {
+        pending = true;
+      }
+    }
+    if (pending) {
+      setTimeout(poll, 3000);
+    }
+  };
+  setTimeout(poll, 1000);
+"#;
+
+/// Label of the terms checkbox without `--tos-text`.
+pub const TOS_TEXT: &str = "I agree to the terms of service";
+
+/// User id field, submit button and captcha shared by the invite forms. In batch mode the
+/// user id field is a textarea taking one Matrix ID per line; otherwise an email field is
+/// offered next to it when an identity server is configured.
+pub fn invite_controls(state: &AppState, nonce: &str, batch: bool) -> Markup {
+    html! {
+        div class="controls" {
+          div class="fields" {
+            @if batch {
+                div class="field" {
+                    label for="users" { (t("User IDs, one per line")) }
+                    textarea id="users" name="user_ids" rows="6" placeholder="@user:example.com" required {}
+                }
+            } @else {
+                div class="field" {
+                    label for="user" { (t("User ID")) }
+                    input type="text" id="user" name="user_id" placeholder="@user:example.com" required[state.id_server.is_none()];
+                }
+                @if state.id_server.is_some() {
+                    div class="field" {
+                        label for="email" { (t("Or email address")) }
+                        input type="email" id="email" name="email" placeholder="user@example.com";
+                    }
+                }
+                div class="field" id="membership-hint" data-check=(state.absolute_link("check")) data-join=(t("You are already a member of this room.")) data-invite=(t("You already have a pending invite to this room.")) data-ban=(t("You cannot be invited to this room.")) aria-live="polite" {}
+            }
+            @if let Some(tos_url) = &state.tos_url {
+                div class="field" {
+                    input type="checkbox" id="tos" name="tos" value="true" required;
+                    label for="tos" { (state.tos_text.clone().unwrap_or_else(|| t(TOS_TEXT))) }
+                    " (" a href=(tos_url) target="_blank" rel="noopener" { (t("read")) } ")"
+                }
+            }
+            div class="field" {
+              button type="submit" { (t("Login with GitHub to Invite")) }
+            }
+          }
+          div class="cf-turnstile" data-sitekey=(&state.turnstile_site_key) data-language=(i18n::current()) {}
+        }
+        (honeypot::fields(state))
+        @if !batch {
+            script nonce=(nonce) { (PreEscaped(MEMBERSHIP_HINT)) }
+        }
+    }
+}
+
+/// Note on what a room requires beyond the global checks, see [`room_policy::requirements`].
+pub fn requirements(state: &AppState, room_id: &RoomId) -> Markup {
+    let requirements = room_policy::requirements(state, room_id);
+    html! {
+        @if !requirements.is_empty() {
+            div class="requirements" {
+                small { (i18n::tr("Requires {requirements}.", &[("requirements", &requirements.join(", "))])) }
+            }
+        }
+    }
+}
+
+/// Path segment for a room id or alias, escaping the `#`, `!` and `:` sigils.
+pub fn room_segment(room: &str) -> String {
+    utf8_percent_encode(room, NON_ALPHANUMERIC).to_string()
+}
+
+/// Single-room invite form for per-room links, e.g. /invite/%23room%3Aexample.com.
+pub async fn room(
+    State(state): State<Arc<AppState>>,
+    Extension(CspNonce(nonce)): Extension<CspNonce>,
+    Path(room): Path<String>,
+) -> Result<Markup, (StatusCode, Markup)> {
+    let rooms = state.rooms.read().await;
+    let room = find_room(&rooms, room.trim()).ok_or_else(|| {
+        error_page(
+            &state,
+            &nonce,
+            StatusCode::NOT_FOUND,
+            &t("The requested room is not available."),
+        )
+    })?;
+    let name = room.name.clone().unwrap_or_else(|| room.display_id());
+    Ok(layout(
+        &state,
+        &nonce,
+        &state.page_title(&i18n::tr("Join {room}", &[("room", &name)])),
+        html! {
+            h1 { (name) }
+            p { (room.display_id()) }
+            (requirements(&state, &room.room_id))
+            @if !state.hide_topics {
+                @if let Some(topic) = &room.topic {
+                    p { (normalize_whitespace(topic)) }
+                }
+            }
+            form action=(state.absolute_link("invite")) method="post" {
+                input type="hidden" name="room_ids" value=(room.room_id);
+                (invite_controls(&state, &nonce, false))
+            }
+            p {
+                a href=(state.absolute_link("")) { (t("All rooms")) }
+            }
+        },
+    ))
+}
@@ -0,0 +1,112 @@
+use std::{path::PathBuf, sync::Arc};
+
+use anyhow::Context;
+use axum::{
+    extract::{Extension, State},
+    routing::get,
+    Router,
+};
+use maud::{html, Markup, PreEscaped};
+use tokio::sync::RwLock;
+
+use crate::{
+    config::PageSpec,
+    page::{self, markdown},
+    security::CspNonce,
+    AppState,
+};
+
+/// Page given by `--page`, rendered from its markdown file.
+pub struct StaticPage {
+    pub path: String,
+    pub title: String,
+    file: PathBuf,
+    html: RwLock<String>,
+}
+
+fn render(file: &PathBuf) -> anyhow::Result<String> {
+    let text = std::fs::read_to_string(file)
+        .with_context(|| format!("failed to read page {}", file.display()))?;
+    Ok(markdown(&text).into_string())
+}
+
+/// Read every page, failing on the first unreadable file.
+pub fn read(specs: Vec<PageSpec>) -> anyhow::Result<Vec<StaticPage>> {
+    specs
+        .into_iter()
+        .map(|spec| {
+            Ok(StaticPage {
+                html: RwLock::new(render(&spec.file)?),
+                path: spec.path,
+                title: spec.title,
+                file: spec.file,
+            })
+        })
+        .collect()
+}
+
+/// Read the page files again for [`swap`], with `None` for files that fail to read so their
+/// page keeps its previous content. Pages are routed at startup, so added or removed pages need
+/// a restart.
+pub fn reread(state: &AppState, specs: &[PageSpec]) -> Vec<Option<String>> {
+    let unchanged = specs.len() == state.pages.len()
+        && specs
+            .iter()
+            .all(|spec| state.pages.iter().any(|page| page.path == spec.path));
+    if !unchanged {
+        log::warn!("the set of pages changed, restart the bouncer to route them");
+    }
+    state
+        .pages
+        .iter()
+        .map(|page| {
+            render(&page.file)
+                .map_err(|err| log::error!("keeping the previous page {}: {:#}", page.path, err))
+                .ok()
+        })
+        .collect()
+}
+
+/// Swap in the pages read by [`reread`], returning how many were swapped.
+pub async fn swap(state: &AppState, pages: Vec<Option<String>>) -> usize {
+    let mut swapped = 0;
+    for (page, html) in state.pages.iter().zip(pages) {
+        if let Some(html) = html {
+            *page.html.write().await = html;
+            swapped += 1;
+        }
+    }
+    swapped
+}
+
+async fn serve(state: &AppState, nonce: &str, static_page: &StaticPage) -> Markup {
+    let body = static_page.html.read().await.clone();
+    page::layout(
+        state,
+        nonce,
+        &state.page_title(&static_page.title),
+        html! {
+            h1 { (static_page.title) }
+            (PreEscaped(body))
+        },
+    )
+}
+
+/// One GET route per page.
+pub fn routes(state: &AppState) -> Router<Arc<AppState>> {
+    state
+        .pages
+        .iter()
+        .enumerate()
+        .fold(Router::new(), |router, (index, static_page)| {
+            router.route(
+                &static_page.path,
+                get(
+                    move |State(state): State<Arc<AppState>>,
+                          Extension(CspNonce(nonce)): Extension<CspNonce>| async move {
+                        serve(&state, &nonce, &state.pages[index]).await
+                    },
+                ),
+            )
+        })
+}
@@ -0,0 +1,183 @@
+use std::{sync::Arc, time::Duration};
+
+use ruma::{api::client::state::get_state_events, OwnedRoomId, UserId};
+use tokio::sync::RwLock;
+
+use crate::{secret::redact, AppState};
+
+/// How often the policy rooms are read again.
+const RELOAD_INTERVAL: Duration = Duration::from_secs(10 * 60);
+
+const USER_RULES: &[&str] = &[
+    "m.policy.rule.user",
+    "m.room.rule.user",
+    "org.matrix.mjolnir.rule.user",
+];
+const SERVER_RULES: &[&str] = &[
+    "m.policy.rule.server",
+    "m.room.rule.server",
+    "org.matrix.mjolnir.rule.server",
+];
+
+#[derive(Clone, PartialEq)]
+enum Entity {
+    User,
+    Server,
+}
+
+/// A ban recommendation from a policy room.
+#[derive(Clone)]
+struct Rule {
+    room_id: OwnedRoomId,
+    list: String,
+    entity: Entity,
+    glob: String,
+}
+
+/// Ban rules of the MSC2313 policy rooms given by `--policy-room`.
+#[derive(Default)]
+pub struct PolicyLists {
+    rules: RwLock<Vec<Rule>>,
+}
+
+#[derive(serde::Deserialize)]
+struct StateEvent {
+    #[serde(rename = "type")]
+    kind: String,
+    #[serde(default)]
+    content: Content,
+}
+
+#[derive(Default, serde::Deserialize)]
+#[serde(default)]
+struct Content {
+    entity: Option<String>,
+    recommendation: Option<String>,
+    name: Option<String>,
+}
+
+/// Whether `text` matches a glob where `*` stands for any run of characters and `?` for exactly
+/// one; every other character, dots included, is literal.
+pub fn glob_matches(glob: &str, text: &str) -> bool {
+    let glob = glob.chars().collect::<Vec<_>>();
+    let text = text.chars().collect::<Vec<_>>();
+    let (mut g, mut t) = (0, 0);
+    let mut backtrack = None;
+    while t < text.len() {
+        match glob.get(g) {
+            Some('*') => {
+                backtrack = Some((g, t));
+                g += 1;
+            }
+            Some(&c) if c == '?' || c == text[t] => {
+                g += 1;
+                t += 1;
+            }
+            _ => match backtrack {
+                Some((star, matched)) => {
+                    g = star + 1;
+                    t = matched + 1;
+                    backtrack = Some((star, matched + 1));
+                }
+                None => return false,
+            },
+        }
+    }
+    glob[g..].iter().all(|&c| c == '*')
+}
+
+impl PolicyLists {
+    /// Name of the first policy list banning the user or their homeserver.
+    pub async fn banned(&self, user_id: &UserId) -> Option<String> {
+        let server = user_id.server_name().as_str().to_lowercase();
+        self.rules
+            .read()
+            .await
+            .iter()
+            .find(|rule| match rule.entity {
+                Entity::User => glob_matches(&rule.glob, user_id.as_str()),
+                Entity::Server => glob_matches(&rule.glob.to_lowercase(), &server),
+            })
+            .map(|rule| rule.list.clone())
+    }
+}
+
+async fn read_rules(state: &AppState, room_id: &OwnedRoomId) -> anyhow::Result<Vec<Rule>> {
+    let events = state
+        .client
+        .send_request(get_state_events::v3::Request::new(room_id.clone()))
+        .await?
+        .room_state
+        .into_iter()
+        .filter_map(|event| event.deserialize_as::<StateEvent>().ok())
+        .collect::<Vec<_>>();
+    let list = events
+        .iter()
+        .find(|event| event.kind == "m.room.name")
+        .and_then(|event| event.content.name.clone())
+        .unwrap_or_else(|| room_id.to_string());
+    Ok(events
+        .into_iter()
+        .filter(|event| event.content.recommendation.as_deref() == Some("m.ban"))
+        .filter_map(|event| {
+            let entity = if USER_RULES.contains(&event.kind.as_str()) {
+                Entity::User
+            } else if SERVER_RULES.contains(&event.kind.as_str()) {
+                Entity::Server
+            } else {
+                return None;
+            };
+            Some(Rule {
+                room_id: room_id.clone(),
+                list: list.clone(),
+                entity,
+                glob: event.content.entity?,
+            })
+        })
+        .collect())
+}
+
+/// Read the ban rules of every policy room, keeping the old rules of rooms that fail to load.
+pub async fn load(state: &AppState) {
+    let policy_rooms = state.rules.load().policy_rooms.clone();
+    if policy_rooms.is_empty() {
+        // Rules of policy rooms dropped by a reload no longer apply.
+        state.policy.rules.write().await.clear();
+        return;
+    }
+    let mut rules = vec![];
+    for room_id in &policy_rooms {
+        match read_rules(state, room_id).await {
+            Ok(room_rules) => rules.extend(room_rules),
+            Err(err) => {
+                log::error!("failed to read policy room {}: {:#}", room_id, redact(&err));
+                rules.extend(
+                    state
+                        .policy
+                        .rules
+                        .read()
+                        .await
+                        .iter()
+                        .filter(|rule| rule.room_id == *room_id)
+                        .cloned(),
+                );
+            }
+        }
+    }
+    log::warn!(
+        "loaded {} ban rules from {} policy rooms",
+        rules.len(),
+        policy_rooms.len()
+    );
+    *state.policy.rules.write().await = rules;
+}
+
+/// Reload the policy rooms periodically, also those added by a reload of the configuration.
+pub fn spawn(state: Arc<AppState>) {
+    tokio::spawn(async move {
+        loop {
+            tokio::time::sleep(RELOAD_INTERVAL).await;
+            load(&state).await;
+        }
+    });
+}
@@ -0,0 +1,257 @@
+//! Invites sent in the background with `--queue-invites`, so that a homeserver outage after
+//! the GitHub login only delays them. Jobs are kept in the audit store and survive restarts.
+
+use std::{sync::Arc, time::Duration};
+
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+    Json,
+};
+use chrono::{DateTime, Utc};
+use maud::html;
+use oauth2::CsrfToken;
+use ruma::{OwnedRoomId, OwnedUserId};
+
+use crate::{
+    audit,
+    i18n::t,
+    invite::{self, InviteError},
+    AppState,
+};
+
+/// How often due jobs are looked for.
+const TICK: Duration = Duration::from_secs(1);
+/// Delay after the first failed attempt, doubling on every further one.
+const FIRST_RETRY: Duration = Duration::from_secs(5);
+const MAX_RETRY: Duration = Duration::from_secs(5 * 60);
+/// How long finished jobs can still be looked up on the status endpoint.
+const FINISHED_TTL: Duration = Duration::from_secs(24 * 60 * 60);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum JobStatus {
+    Queued,
+    Invited,
+    Failed,
+}
+
+/// An invite of one user to one room, vouched for by a GitHub login.
+#[derive(Clone, serde::Serialize, serde::Deserialize)]
+pub struct Job {
+    pub user_id: OwnedUserId,
+    pub room_id: OwnedRoomId,
+    pub github_login: String,
+    pub reason: Option<String>,
+    pub created: DateTime<Utc>,
+    pub status: JobStatus,
+    pub attempts: u32,
+    pub next_attempt: DateTime<Utc>,
+    /// Shown to the requester once the job failed.
+    pub error: Option<String>,
+    pub finished: Option<DateTime<Utc>>,
+}
+
+/// Store an invite for the worker and return the id of its job.
+pub async fn enqueue(
+    state: &AppState,
+    room_id: &OwnedRoomId,
+    user_id: &OwnedUserId,
+    github_login: &str,
+    reason: Option<String>,
+) -> Result<String, String> {
+    let Some(store) = &state.store else {
+        return Err(t("failed to invite user"));
+    };
+    let id = CsrfToken::new_random().secret().to_string();
+    let now = Utc::now();
+    let job = Job {
+        user_id: user_id.clone(),
+        room_id: room_id.clone(),
+        github_login: github_login.to_string(),
+        reason,
+        created: now,
+        status: JobStatus::Queued,
+        attempts: 0,
+        next_attempt: now,
+        error: None,
+        finished: None,
+    };
+    store.update(|data| data.jobs.insert(id.clone(), job)).await;
+    log::warn!(
+        "queued invite of {} to room {} for GitHub user {}",
+        user_id,
+        room_id,
+        github_login
+    );
+    Ok(id)
+}
+
+/// Jobs waiting to be sent, for the admin dashboard.
+pub async fn depth(state: &AppState) -> Option<usize> {
+    let store = state
+        .store
+        .as_ref()
+        .filter(|_| state.queue_invites.is_some())?;
+    Some(
+        store
+            .read(|data| {
+                data.jobs
+                    .values()
+                    .filter(|job| job.status == JobStatus::Queued)
+                    .count()
+            })
+            .await,
+    )
+}
+
+/// Work through due jobs, also those left queued by the previous run.
+pub fn spawn(state: Arc<AppState>) {
+    let Some(max_age) = state.queue_invites else {
+        return;
+    };
+    tokio::spawn(async move {
+        loop {
+            run(&state, max_age).await;
+            tokio::time::sleep(TICK).await;
+        }
+    });
+}
+
+async fn run(state: &AppState, max_age: Duration) {
+    let Some(store) = &state.store else {
+        return;
+    };
+    let now = Utc::now();
+    let due = store
+        .update(|data| {
+            data.jobs.retain(|_, job| {
+                job.finished
+                    .map_or(true, |finished| !older_than(finished, now, FINISHED_TTL))
+            });
+            data.jobs
+                .iter()
+                .filter(|(_, job)| job.status == JobStatus::Queued && job.next_attempt <= now)
+                .map(|(id, job)| (id.clone(), job.clone()))
+                .collect::<Vec<_>>()
+        })
+        .await;
+    for (id, mut job) in due {
+        attempt(state, &mut job, max_age).await;
+        let failed = job.status == JobStatus::Failed;
+        store
+            .update(|data| {
+                // The job may have been pruned meanwhile, which is fine.
+                if let Some(stored) = data.jobs.get_mut(&id) {
+                    *stored = job.clone();
+                }
+            })
+            .await;
+        if failed {
+            notify_failure(state, &job).await;
+        }
+    }
+}
+
+/// Try to send the invite of one job, scheduling a retry when the homeserver may recover.
+async fn attempt(state: &AppState, job: &mut Job, max_age: Duration) {
+    job.attempts += 1;
+    let result = invite::send_invite(
+        state,
+        &job.room_id,
+        &job.user_id,
+        &job.github_login,
+        job.reason.clone(),
+    )
+    .await;
+    let now = Utc::now();
+    let error = match result {
+        Ok(()) => {
+            job.status = JobStatus::Invited;
+            job.finished = Some(now);
+            return;
+        }
+        Err(InviteError::Banned) => t(invite::BANNED),
+        // An answer of the homeserver other than rate limiting will not change on a retry.
+        Err(InviteError::Failed {
+            kind: Some(kind), ..
+        }) if kind != "M_LIMIT_EXCEEDED" => t("failed to invite user"),
+        Err(InviteError::Failed { .. }) => {
+            let retry = FIRST_RETRY
+                .saturating_mul(2u32.saturating_pow(job.attempts - 1))
+                .min(MAX_RETRY);
+            let next_attempt = now + chrono::Duration::from_std(retry).unwrap_or_default();
+            if !older_than(job.created, next_attempt, max_age) {
+                log::warn!(
+                    "queued invite of {} to room {} failed on attempt {}, retrying in {:?}",
+                    job.user_id,
+                    job.room_id,
+                    job.attempts,
+                    retry
+                );
+                job.next_attempt = next_attempt;
+                return;
+            }
+            t("the homeserver stayed unavailable, please try again later")
+        }
+    };
+    log::error!(
+        "gave up on the queued invite of {} to room {} after {} attempts: {}",
+        job.user_id,
+        job.room_id,
+        job.attempts,
+        error
+    );
+    job.status = JobStatus::Failed;
+    job.error = Some(error);
+    job.finished = Some(now);
+}
+
+fn older_than(since: DateTime<Utc>, now: DateTime<Utc>, age: Duration) -> bool {
+    (now - since).to_std().is_ok_and(|elapsed| elapsed >= age)
+}
+
+async fn notify_failure(state: &AppState, job: &Job) {
+    let room = state.room_name(&job.room_id).await;
+    let plain = format!(
+        "Gave up inviting {} to {} for GitHub user {} after {} attempts",
+        job.user_id, room, job.github_login, job.attempts
+    );
+    audit::post(
+        state,
+        plain,
+        html! {
+            "Gave up inviting " (job.user_id) " to " (room) " for GitHub user "
+            (job.github_login) " after " (job.attempts) " attempts"
+        },
+    )
+    .await;
+}
+
+#[derive(serde::Serialize)]
+pub struct JobProgress {
+    pub status: JobStatus,
+    pub attempts: u32,
+    pub error: Option<String>,
+}
+
+/// Progress of a queued invite, polled by the outcome page. The id is as hard to guess as an
+/// OAuth state, and the answer names neither the user nor the room.
+pub async fn status(
+    State(state): State<Arc<AppState>>,
+    Path(id): Path<String>,
+) -> Result<Json<JobProgress>, (StatusCode, String)> {
+    let not_found = || (StatusCode::NOT_FOUND, t("unknown invite job"));
+    let store = state.store.as_ref().ok_or_else(not_found)?;
+    store
+        .read(|data| {
+            data.jobs.get(&id).map(|job| JobProgress {
+                status: job.status,
+                attempts: job.attempts,
+                error: job.error.clone(),
+            })
+        })
+        .await
+        .map(Json)
+        .ok_or_else(not_found)
+}
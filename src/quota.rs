@@ -0,0 +1,46 @@
+//! Cap on the Matrix IDs one GitHub account vouches for, see `--max-invitees-per-account`.
+//!
+//! Every invitee of a batch counts on its own. Counts are kept in memory and reset on restart.
+
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant},
+};
+
+use tokio::sync::Mutex;
+
+/// Period the quota of an account applies to.
+pub const WINDOW: Duration = Duration::from_secs(24 * 60 * 60);
+
+pub struct Quota {
+    limit: Option<usize>,
+    /// When each invitee of an account was vouched for, by lowercase login.
+    vouched: Mutex<HashMap<String, Vec<Instant>>>,
+}
+
+impl Quota {
+    /// Without a limit every invitee is granted.
+    pub fn new(limit: Option<usize>) -> Quota {
+        Quota {
+            limit,
+            vouched: Default::default(),
+        }
+    }
+
+    /// Count up to `wanted` invitees against the quota of an account and return how many fit.
+    pub async fn take(&self, login: &str, wanted: usize) -> usize {
+        let Some(limit) = self.limit else {
+            return wanted;
+        };
+        let now = Instant::now();
+        let mut vouched = self.vouched.lock().await;
+        vouched.retain(|_, times| {
+            times.retain(|time| now.duration_since(*time) < WINDOW);
+            !times.is_empty()
+        });
+        let times = vouched.entry(login.to_lowercase()).or_default();
+        let granted = wanted.min(limit.saturating_sub(times.len()));
+        times.extend(std::iter::repeat(now).take(granted));
+        granted
+    }
+}
@@ -0,0 +1,214 @@
+use std::{
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+use anyhow::Context;
+use tokio::{
+    signal::unix::{signal, SignalKind},
+    sync::Mutex,
+};
+
+use crate::{
+    config::Config,
+    discovery::{self, RoomFilter},
+    pages, policy, room_policy,
+    secret::redact,
+    AppState,
+};
+
+/// Wait after a failed background refresh before a stale page tries again, at most the TTL.
+const RETRY: Duration = Duration::from_secs(60);
+
+/// Coalesces concurrent room refreshes into a single discovery run.
+pub struct Refresh {
+    generation: AtomicU64,
+    last: Mutex<Option<Result<Arc<discovery::RoomsDiff>, String>>>,
+    /// When the rooms were last discovered, and when a refresh last failed since.
+    freshness: Mutex<(Instant, Option<Instant>)>,
+    /// Set while a refresh of [`revalidate`] runs.
+    revalidating: AtomicBool,
+    failures: AtomicU64,
+}
+
+impl Default for Refresh {
+    fn default() -> Self {
+        Refresh {
+            generation: AtomicU64::new(0),
+            last: Mutex::default(),
+            // The rooms are discovered at startup, right before.
+            freshness: Mutex::new((Instant::now(), None)),
+            revalidating: AtomicBool::new(false),
+            failures: AtomicU64::new(0),
+        }
+    }
+}
+
+impl Refresh {
+    /// Time since the rooms were last discovered.
+    pub async fn age(&self) -> Duration {
+        self.freshness.lock().await.0.elapsed()
+    }
+
+    /// Refreshes that failed since startup, keeping the previous rooms.
+    pub fn failures(&self) -> u64 {
+        self.failures.load(Ordering::Relaxed)
+    }
+}
+
+/// Refresh the rooms in the background once they are older than `--room-cache-ttl`, while the
+/// stale list keeps being served. Concurrent pages start at most one refresh.
+pub async fn revalidate(state: &Arc<AppState>) {
+    let Some(ttl) = state.room_cache_ttl else {
+        return;
+    };
+    {
+        let (refreshed, failed) = *state.refresh.freshness.lock().await;
+        if refreshed.elapsed() < ttl
+            || failed.is_some_and(|failed| failed.elapsed() < RETRY.min(ttl))
+        {
+            return;
+        }
+    }
+    if state.refresh.revalidating.swap(true, Ordering::SeqCst) {
+        return;
+    }
+    let state = state.clone();
+    tokio::spawn(async move {
+        // Failures are logged by refresh_rooms and counted by swap_rooms.
+        let _ = refresh_rooms(&state).await;
+        state.refresh.revalidating.store(false, Ordering::SeqCst);
+    });
+}
+
+/// Rediscover rooms and swap them in, leaving the previous list intact on failure.
+///
+/// Callers arriving while a refresh is running wait for it and share its result.
+pub async fn refresh_rooms(state: &AppState) -> Result<Arc<discovery::RoomsDiff>, String> {
+    let seen = state.refresh.generation.load(Ordering::SeqCst);
+    let mut last = state.refresh.last.lock().await;
+    if state.refresh.generation.load(Ordering::SeqCst) != seen {
+        if let Some(result) = last.as_ref() {
+            return result.clone();
+        }
+    }
+
+    let filter = state.room_filter.read().await.clone();
+    let result = swap_rooms(state, &filter)
+        .await
+        .map(Arc::new)
+        .map_err(|err| {
+            log::error!("room discovery failed: {:#}", redact(&err));
+            format!("room discovery failed: {}", redact(&err))
+        });
+    *last = Some(result.clone());
+    state.refresh.generation.fetch_add(1, Ordering::SeqCst);
+    result
+}
+
+async fn swap_rooms(state: &AppState, filter: &RoomFilter) -> anyhow::Result<discovery::RoomsDiff> {
+    let (rooms, public_rooms) = discover(state, filter, &state.rules.load_full()).await?;
+    let mut current = state.rooms.write().await;
+    let diff = discovery::RoomsDiff::between(&current, &rooms);
+    *current = rooms;
+    *state.public_rooms.write().await = public_rooms;
+    log::warn!(
+        "refreshed rooms: {} served, added {:?}, removed {:?}",
+        current.len(),
+        diff.added,
+        diff.removed
+    );
+    Ok(diff)
+}
+
+/// Discover the rooms passing `filter`, as served and public rooms, without swapping them in.
+async fn discover(
+    state: &AppState,
+    filter: &RoomFilter,
+    rules: &room_policy::Rules,
+) -> anyhow::Result<(discovery::Rooms, discovery::Rooms)> {
+    let rooms = discovery::discover_rooms(&state.client, &state.user_id, filter).await;
+    let mut rooms = {
+        let mut freshness = state.refresh.freshness.lock().await;
+        match rooms {
+            Ok(rooms) => {
+                *freshness = (Instant::now(), None);
+                rooms
+            }
+            Err(err) => {
+                freshness.1 = Some(Instant::now());
+                state.refresh.failures.fetch_add(1, Ordering::Relaxed);
+                return Err(err);
+            }
+        }
+    };
+    let hidden = state.hidden_rooms.read().await;
+    rooms.retain(|room_id, _| !hidden.contains(room_id));
+    for room in rooms.values_mut() {
+        room_policy::display(&rules.room_configs, room);
+    }
+    Ok(filter.partition(rooms))
+}
+
+/// Re-read the configuration and pages and rediscover rooms. Everything that can fail runs
+/// first; only then are the room filter, rules, rooms and pages swapped in together, so a
+/// failed reload keeps the previous state as a whole.
+pub async fn reload(state: &AppState) -> anyhow::Result<()> {
+    let config = Config::load().context("invalid configuration")?;
+    let filter = RoomFilter::resolve(&state.client, &config.rooms)
+        .await
+        .context("invalid room filter")?;
+    let rules = room_policy::Rules::resolve(&state.client, config.rules)
+        .await
+        .context("invalid room rules")?;
+    let pages = pages::reread(state, &config.pages);
+
+    let _refresh = state.refresh.last.lock().await;
+    let (rooms, public_rooms) = discover(state, &filter, &rules).await?;
+
+    let mut current_rooms = state.rooms.write().await;
+    let mut current_public_rooms = state.public_rooms.write().await;
+    let mut current_filter = state.room_filter.write().await;
+    let diff = discovery::RoomsDiff::between(&current_rooms, &rooms);
+    let served = rooms.len();
+    let previous = state.rules.swap(Arc::new(rules));
+    *current_rooms = rooms;
+    *current_public_rooms = public_rooms;
+    *current_filter = filter;
+    let pages = pages::swap(state, pages).await;
+    drop((current_rooms, current_public_rooms, current_filter));
+
+    let rules = state.rules.load();
+    log::warn!(
+        "reloaded configuration: {} rooms served, added {:?}, removed {:?}; {} room configs (was {}), {} approval rooms (was {}), {} policy rooms (was {}), {} pages re-read",
+        served,
+        diff.added,
+        diff.removed,
+        rules.room_configs.len(),
+        previous.room_configs.len(),
+        rules.approval_rooms.len(),
+        previous.approval_rooms.len(),
+        rules.policy_rooms.len(),
+        previous.policy_rooms.len(),
+        pages,
+    );
+    if rules.policy_rooms != previous.policy_rooms {
+        policy::load(state).await;
+    }
+    Ok(())
+}
+pub fn reload_on_sighup(state: Arc<AppState>) -> anyhow::Result<()> {
+    let mut hangup = signal(SignalKind::hangup())?;
+    tokio::spawn(async move {
+        while hangup.recv().await.is_some() {
+            state.geoip.reopen().await;
+            if let Err(err) = reload(&state).await {
+                log::error!("reload failed, keeping previous state: {:#}", redact(&err));
+            }
+        }
+    });
+    Ok(())
+}
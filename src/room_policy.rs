@@ -0,0 +1,378 @@
+//! Requirements of single rooms from the `[room_config]` tables of the config file, applied on
+//! top of the global checks, see [`config::RoomConfig`].
+
+use std::collections::{HashMap, HashSet};
+
+use anyhow::Context;
+use ruma::{OwnedRoomId, RoomId, UserId};
+
+use crate::{
+    config,
+    discovery::resolve_room,
+    i18n::{fill, t, tr},
+    localpart::{LocalpartRules, LoginMatch},
+    store::Denial,
+    AppState, GitHubUser, MatrixClient, RoomInfo,
+};
+
+/// Rules on rooms and invitees that SIGHUP reloads, see [`crate::reload::reload`].
+pub struct Rules {
+    /// Rooms whose invites wait for a moderator decision in the admin room.
+    pub approval_rooms: HashSet<OwnedRoomId>,
+    /// Settings of single rooms by room id.
+    pub room_configs: HashMap<OwnedRoomId, config::RoomConfig>,
+    pub policy_rooms: Vec<OwnedRoomId>,
+    pub localpart_rules: LocalpartRules,
+    /// See [`check_localpart`].
+    pub require_matching_localpart: bool,
+    pub login_match: LoginMatch,
+}
+
+impl Rules {
+    /// Everything the rules decide about how rooms are listed, stable across processes so
+    /// the index ETag changes exactly when a reload changes them.
+    pub fn fingerprint(&self) -> String {
+        let mut room_configs = self
+            .room_configs
+            .iter()
+            .map(|(room_id, config)| format!("{} {:?}", room_id, config))
+            .collect::<Vec<_>>();
+        room_configs.sort();
+        let mut approval_rooms = self
+            .approval_rooms
+            .iter()
+            .map(ToString::to_string)
+            .collect::<Vec<_>>();
+        approval_rooms.sort();
+        format!(
+            "{}\n{}\n{:?}\n{:?}\n{}\n{:?}",
+            room_configs.join("\n"),
+            approval_rooms.join(" "),
+            self.policy_rooms,
+            self.localpart_rules,
+            self.require_matching_localpart,
+            self.login_match,
+        )
+    }
+
+    /// Resolve the rooms named in the settings via the homeserver.
+    pub async fn resolve(
+        client: &MatrixClient,
+        settings: config::RuleSettings,
+    ) -> anyhow::Result<Rules> {
+        let mut approval_rooms = HashSet::new();
+        for room in &settings.approval_room {
+            approval_rooms.insert(
+                resolve_room(client, room)
+                    .await
+                    .context("failed to resolve approval_room")?,
+            );
+        }
+        let mut room_configs = HashMap::new();
+        for (room, config) in settings.room_config {
+            room_configs.insert(
+                resolve_room(client, &room)
+                    .await
+                    .with_context(|| format!("failed to resolve room_config {}", room))?,
+                config,
+            );
+        }
+        let mut policy_rooms = vec![];
+        for room in &settings.policy_room {
+            policy_rooms.push(
+                resolve_room(client, room)
+                    .await
+                    .context("failed to resolve policy_room")?,
+            );
+        }
+        Ok(Rules {
+            approval_rooms,
+            room_configs,
+            policy_rooms,
+            localpart_rules: settings.localpart_rules,
+            require_matching_localpart: settings.require_matching_localpart,
+            login_match: settings.login_match,
+        })
+    }
+}
+
+/// Why a room refused a GitHub account or invitee after the login.
+#[derive(Debug)]
+pub struct Refusal {
+    pub denial: Denial,
+    /// In English, for the audit store and the admin room.
+    pub reason: String,
+    /// In the language of the request, for the requester.
+    pub message: String,
+}
+
+fn refusal(denial: Denial, text: &str, args: &[(&str, &dyn std::fmt::Display)]) -> Refusal {
+    Refusal {
+        denial,
+        reason: fill(text, args),
+        message: tr(text, args),
+    }
+}
+
+/// Settings of a room, if the config file has any.
+pub fn get(state: &AppState, room_id: &RoomId) -> Option<config::RoomConfig> {
+    state.rules.load().room_configs.get(room_id).cloned()
+}
+
+/// Show `display_name` and `description` instead of the room's name and topic, whenever a room
+/// is discovered or added.
+pub fn display(configs: &HashMap<OwnedRoomId, config::RoomConfig>, room: &mut RoomInfo) {
+    let Some(config) = configs.get(&room.room_id) else {
+        return;
+    };
+    if let Some(name) = &config.display_name {
+        room.name = Some(name.clone());
+    }
+    if let Some(description) = &config.description {
+        room.topic = Some(description.clone());
+    }
+}
+
+/// Whether invites to the room wait for moderator approval, by default with `--approval-room`.
+pub fn needs_approval(state: &AppState, room_id: &RoomId) -> bool {
+    get(state, room_id)
+        .and_then(|config| config.require_approval)
+        .unwrap_or_else(|| state.rules.load().approval_rooms.contains(room_id))
+}
+
+/// Whether the room is left out of the index and the rooms API; its invite link still works.
+pub fn hidden(state: &AppState, room_id: &RoomId) -> bool {
+    get(state, room_id).is_some_and(|config| config.hidden)
+}
+
+/// Whether a room requires an organization or team, which the GitHub login has to grant the
+/// `read:org` scope for; a login remembered by the cookie does not know the memberships.
+pub fn needs_memberships(state: &AppState, room_ids: &[impl AsRef<RoomId>]) -> bool {
+    room_ids.iter().any(|room_id| {
+        get(state, room_id.as_ref()).is_some_and(|config| config.github_org.is_some())
+    })
+}
+
+/// Teams required by the rooms as `org/team-slug`, whose memberships are looked up at login.
+pub fn teams(state: &AppState, room_ids: &[impl AsRef<RoomId>]) -> Vec<String> {
+    let mut teams = vec![];
+    for room_id in room_ids {
+        if let Some(config) = get(state, room_id.as_ref()) {
+            if let (Some(org), Some(team)) = (config.github_org, config.github_team) {
+                let team = format!("{}/{}", org, team);
+                if !teams.contains(&team) {
+                    teams.push(team);
+                }
+            }
+        }
+    }
+    teams
+}
+
+/// Check the homeserver of an invitee, before the GitHub login.
+pub fn check_user(state: &AppState, room_id: &RoomId, user_id: &UserId) -> Result<(), String> {
+    let Some(config) = get(state, room_id) else {
+        return Ok(());
+    };
+    if config.homeservers.is_empty()
+        || config
+            .homeservers
+            .iter()
+            .any(|server| server.eq_ignore_ascii_case(user_id.server_name().as_str()))
+    {
+        return Ok(());
+    }
+    Err(tr(
+        "{room} only accepts Matrix accounts on {servers}",
+        &[
+            ("room", &room_id),
+            ("servers", &config.homeservers.join(", ")),
+        ],
+    ))
+}
+
+/// Email invitees have no homeserver yet, so rooms restricting it refuse them.
+pub fn accepts_email(state: &AppState, room_id: &RoomId) -> bool {
+    get(state, room_id).map_or(true, |config| config.homeservers.is_empty())
+}
+
+/// Whether the room only invites Matrix IDs whose localpart matches the GitHub login.
+pub fn needs_matching_localpart(state: &AppState, room_id: &RoomId) -> bool {
+    get(state, room_id)
+        .and_then(|config| config.require_matching_localpart)
+        .unwrap_or_else(|| state.rules.load().require_matching_localpart)
+}
+
+/// Check the localpart of an invitee against the GitHub login vouching for it, after the
+/// login, see [`crate::localpart::LoginMatch`].
+pub fn check_localpart(
+    state: &AppState,
+    room_id: &RoomId,
+    user_id: &UserId,
+    login: &str,
+) -> Result<(), Refusal> {
+    if !needs_matching_localpart(state, room_id)
+        || state.rules.load().login_match.matches(login, user_id)
+    {
+        return Ok(());
+    }
+    Err(refusal(
+        Denial::Localpart,
+        "needs a Matrix ID matching the GitHub login, but {localpart} does not match {login}",
+        &[("localpart", &user_id.localpart()), ("login", &login)],
+    ))
+}
+
+/// Check the GitHub account vouching for an invite, after the login.
+pub fn check_github(
+    state: &AppState,
+    room_id: &RoomId,
+    user: &GitHubUser,
+    age: chrono::Duration,
+) -> Result<(), Refusal> {
+    match get(state, room_id) {
+        Some(config) => check_github_config(&config, user, age),
+        None => Ok(()),
+    }
+}
+
+/// [`check_github`] against the settings of a room.
+pub fn check_github_config(
+    config: &config::RoomConfig,
+    user: &GitHubUser,
+    age: chrono::Duration,
+) -> Result<(), Refusal> {
+    if let Some(days) = config.min_github_age_days {
+        if age < chrono::Duration::days(days as i64) {
+            return Err(refusal(
+                Denial::GithubAccountAge,
+                "needs a GitHub account older than {days} days",
+                &[("days", &days.to_string())],
+            ));
+        }
+    }
+    if let Some(org) = &config.github_org {
+        let member = match &config.github_team {
+            Some(team) => {
+                let team = format!("{}/{}", org, team);
+                if user
+                    .pending_teams
+                    .iter()
+                    .any(|pending| pending.eq_ignore_ascii_case(&team))
+                {
+                    return Err(refusal(
+                        Denial::GithubMembership,
+                        "needs membership in the GitHub team {team}, whose invitation is still pending",
+                        &[("team", &team)],
+                    ));
+                }
+                user.teams
+                    .iter()
+                    .any(|member_of| member_of.eq_ignore_ascii_case(&team))
+            }
+            None => user
+                .orgs
+                .iter()
+                .any(|member_of| member_of.eq_ignore_ascii_case(org)),
+        };
+        if !member {
+            return Err(match &config.github_team {
+                Some(team) => refusal(
+                    Denial::GithubMembership,
+                    "needs membership in the GitHub team {org}/{team}",
+                    &[("org", org), ("team", team)],
+                ),
+                None => refusal(
+                    Denial::GithubMembership,
+                    "needs membership in the GitHub organization {org}",
+                    &[("org", org)],
+                ),
+            });
+        }
+    }
+    Ok(())
+}
+
+/// What the room requires beyond the global checks, for the note on the index.
+pub fn requirements(state: &AppState, room_id: &RoomId) -> Vec<String> {
+    let mut requirements = vec![];
+    if let Some(config) = get(state, room_id) {
+        if let Some(days) = config.min_github_age_days {
+            requirements.push(tr(
+                "a GitHub account older than {days} days",
+                &[("days", &days.to_string())],
+            ));
+        }
+        match (&config.github_org, &config.github_team) {
+            (Some(org), Some(team)) => requirements.push(tr(
+                "membership in the GitHub team {org}/{team}",
+                &[("org", org), ("team", team)],
+            )),
+            (Some(org), None) => requirements.push(tr(
+                "membership in the GitHub organization {org}",
+                &[("org", org)],
+            )),
+            _ => {}
+        }
+        if !config.homeservers.is_empty() {
+            requirements.push(tr(
+                "a Matrix account on {servers}",
+                &[("servers", &config.homeservers.join(", "))],
+            ));
+        }
+    }
+    if needs_matching_localpart(state, room_id) {
+        requirements.push(t("a Matrix ID matching your GitHub login"));
+    }
+    if needs_approval(state, room_id) {
+        requirements.push(t("approval by a moderator"));
+    }
+    requirements
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::Utc;
+
+    use super::check_github_config;
+    use crate::{config::RoomConfig, mock::user, store::Denial, GitHubUser};
+
+    #[test]
+    fn checks_the_github_account_against_the_room() {
+        let age = |user: &GitHubUser| Utc::now() - user.created_at;
+        let young = user(10);
+        let old = user(100);
+        assert!(check_github_config(&RoomConfig::default(), &young, age(&young)).is_ok());
+
+        let config = RoomConfig {
+            min_github_age_days: Some(30),
+            ..Default::default()
+        };
+        let refusal = check_github_config(&config, &young, age(&young)).unwrap_err();
+        assert_eq!(refusal.denial, Denial::GithubAccountAge);
+        assert!(
+            refusal.reason.contains("older than 30 days"),
+            "{}",
+            refusal.reason
+        );
+        assert!(check_github_config(&config, &old, age(&old)).is_ok());
+
+        // Organizations and teams compare ignoring case.
+        let org = |org: &str, team: Option<&str>| RoomConfig {
+            github_org: Some(org.to_string()),
+            github_team: team.map(str::to_string),
+            ..Default::default()
+        };
+        assert!(check_github_config(&org("rust-lang", None), &old, age(&old)).is_ok());
+        assert!(check_github_config(&org("rust-lang", Some("infra")), &old, age(&old)).is_ok());
+        for (config, expected) in [
+            (org("tokio-rs", None), "organization tokio-rs"),
+            (org("rust-lang", Some("libs")), "team rust-lang/libs"),
+            (org("rust-lang", Some("compiler")), "still pending"),
+        ] {
+            let refusal = check_github_config(&config, &old, age(&old)).unwrap_err();
+            assert_eq!(refusal.denial, Denial::GithubMembership);
+            assert!(refusal.reason.contains(expected), "{}", refusal.reason);
+        }
+    }
+}
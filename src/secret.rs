@@ -0,0 +1,110 @@
+//! Keeping secrets out of logs and error messages: [`Secret`] values print redacted, and
+//! [`redact`] masks anything token-like in upstream error messages.
+
+use std::{convert::Infallible, fmt, str::FromStr};
+
+/// A secret such as an access token, printed as `[redacted]`; [`Secret::expose`] gives the
+/// value where it is actually needed.
+#[derive(Clone, Default, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(transparent)]
+pub struct Secret<T>(T);
+
+impl<T> Secret<T> {
+    pub fn new(value: T) -> Self {
+        Secret(value)
+    }
+
+    pub fn expose(&self) -> &T {
+        &self.0
+    }
+}
+
+impl<T> fmt::Debug for Secret<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("[redacted]")
+    }
+}
+
+impl<T> fmt::Display for Secret<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("[redacted]")
+    }
+}
+
+impl FromStr for Secret<String> {
+    type Err = Infallible;
+
+    fn from_str(value: &str) -> Result<Self, Infallible> {
+        Ok(Secret(value.to_string()))
+    }
+}
+
+/// Prefixes of Matrix and GitHub tokens.
+const TOKEN_PREFIXES: &[&str] = &[
+    "syt_",
+    "syr_",
+    "gho_",
+    "ghp_",
+    "ghu_",
+    "ghs_",
+    "ghr_",
+    "github_pat_",
+];
+
+/// Shortest word taken for a random token when it mixes letters and digits.
+const TOKEN_LENGTH: usize = 32;
+
+/// Whether the value following `name=` or `"name":` is a secret.
+fn secret_name(name: &str) -> bool {
+    let name = name.to_ascii_lowercase();
+    name == "code"
+        || name == "password"
+        || name == "key"
+        || name.ends_with("token")
+        || name.ends_with("secret")
+        || name.ends_with("_key")
+}
+
+fn token_like(word: &str) -> bool {
+    TOKEN_PREFIXES.iter().any(|prefix| word.starts_with(prefix))
+        || (word.len() >= TOKEN_LENGTH
+            && word.chars().any(|c| c.is_ascii_alphabetic())
+            && word.chars().any(|c| c.is_ascii_digit()))
+}
+
+/// An error message with token-like words masked, for logging upstream errors that may echo
+/// requests: known token prefixes, values of secret-sounding parameters, bearer credentials
+/// and long random-looking words.
+pub fn redact(message: impl fmt::Display) -> String {
+    let message = format!("{:#}", message);
+    let mut redacted = String::with_capacity(message.len());
+    let mut previous: Option<&str> = None;
+    let mut separator = String::new();
+    let mut rest = message.as_str();
+    while !rest.is_empty() {
+        let split = rest
+            .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_' || c == '-'))
+            .unwrap_or(rest.len());
+        if split == 0 {
+            let c = rest.chars().next().unwrap();
+            separator.push(c);
+            redacted.push(c);
+            rest = &rest[c.len_utf8()..];
+            continue;
+        }
+        let (word, tail) = rest.split_at(split);
+        let after_name = previous.is_some_and(secret_name)
+            && matches!(separator.trim_matches(['"', ' ']), "=" | ":");
+        let after_bearer = previous.is_some_and(|previous| previous.eq_ignore_ascii_case("bearer"))
+            && separator == " ";
+        if after_name || after_bearer || token_like(word) {
+            redacted.push_str("[redacted]");
+        } else {
+            redacted.push_str(word);
+        }
+        previous = Some(word);
+        separator.clear();
+        rest = tail;
+    }
+    redacted
+}
@@ -0,0 +1,113 @@
+use std::sync::Arc;
+
+use axum::{
+    extract::{Request, State},
+    http::{
+        header::{
+            CONTENT_SECURITY_POLICY, REFERRER_POLICY, X_CONTENT_TYPE_OPTIONS, X_FRAME_OPTIONS,
+        },
+        HeaderValue, StatusCode,
+    },
+    middleware::Next,
+    response::Response,
+};
+use oauth2::CsrfToken;
+
+use crate::AppState;
+
+/// Per-response nonce allowing the inline script blocks emitted by the templates.
+#[derive(Clone)]
+pub struct CspNonce(pub String);
+
+/// Build the Content-Security-Policy for one response. Forms may post to this site and to
+/// `github_origin`, where the login starts.
+///
+/// Extra directives are merged into existing ones by name, so `img-src https://cdn.example.com`
+/// extends rather than shadows the default `img-src`.
+pub fn content_security_policy(
+    captcha_origins: &[String],
+    github_origin: &str,
+    nonce: &str,
+    extra: &[String],
+) -> String {
+    let captcha = captcha_origins.join(" ");
+    let mut directives: Vec<(String, Vec<String>)> = [
+        ("default-src", "'none'".to_string()),
+        ("script-src", format!("{} 'nonce-{}'", captcha, nonce)),
+        ("frame-src", captcha),
+        ("style-src", "'self'".to_string()),
+        ("img-src", "'self'".to_string()),
+        ("connect-src", "'self'".to_string()),
+        ("form-action", format!("'self' {}", github_origin)),
+        ("frame-ancestors", "'none'".to_string()),
+        ("base-uri", "'none'".to_string()),
+    ]
+    .into_iter()
+    .map(|(name, sources)| {
+        (
+            name.to_string(),
+            sources.split_whitespace().map(str::to_string).collect(),
+        )
+    })
+    .collect();
+
+    for directive in extra {
+        let mut parts = directive.split_whitespace();
+        let Some(name) = parts.next() else {
+            continue;
+        };
+        let sources = parts.map(str::to_string);
+        match directives.iter_mut().find(|(existing, _)| existing == name) {
+            Some((_, existing)) => {
+                existing.retain(|source| source != "'none'");
+                existing.extend(sources);
+            }
+            None => directives.push((name.to_string(), sources.collect())),
+        }
+    }
+
+    directives
+        .into_iter()
+        .map(|(name, sources)| {
+            if sources.is_empty() {
+                name
+            } else {
+                format!("{} {}", name, sources.join(" "))
+            }
+        })
+        .collect::<Vec<_>>()
+        .join("; ")
+}
+
+pub async fn security_headers(
+    State(state): State<Arc<AppState>>,
+    mut request: Request,
+    next: Next,
+) -> Response {
+    let nonce = CsrfToken::new_random().secret().clone();
+    request.extensions_mut().insert(CspNonce(nonce.clone()));
+
+    let mut response = next.run(request).await;
+    // A 304 keeps the cached page and with it the policy allowing the nonce of its scripts.
+    if response.status() != StatusCode::NOT_MODIFIED {
+        let csp = content_security_policy(
+            &state.captcha_origins(),
+            &state.github_origin,
+            &nonce,
+            &state.csp_directives,
+        );
+        match HeaderValue::from_str(&csp) {
+            Ok(value) => {
+                response
+                    .headers_mut()
+                    .insert(CONTENT_SECURITY_POLICY, value);
+            }
+            Err(err) => log::error!("invalid content security policy {:?}: {}", csp, err),
+        }
+    }
+    let headers = response.headers_mut();
+    headers.insert(X_FRAME_OPTIONS, HeaderValue::from_static("DENY"));
+    headers.insert(REFERRER_POLICY, HeaderValue::from_static("strict-origin"));
+    headers.insert(X_CONTENT_TYPE_OPTIONS, HeaderValue::from_static("nosniff"));
+    response
+}
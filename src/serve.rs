@@ -0,0 +1,36 @@
+use std::{io, net::SocketAddr};
+
+use axum::Router;
+use axum_server::tls_rustls::RustlsConfig;
+use tokio::{net::TcpListener, task::JoinSet};
+
+/// Serve each listener with its own router, returning as soon as any of them fails.
+pub async fn serve(
+    listeners: Vec<(TcpListener, Router)>,
+    tls: Option<RustlsConfig>,
+) -> anyhow::Result<()> {
+    let mut tasks = JoinSet::new();
+    for (listener, router) in listeners {
+        let tls = tls.clone();
+        tasks.spawn(async move {
+            match tls {
+                Some(config) => {
+                    axum_server::from_tcp_rustls(listener.into_std()?, config)
+                        .serve(router.into_make_service_with_connect_info::<SocketAddr>())
+                        .await
+                }
+                None => {
+                    axum::serve(
+                        listener,
+                        router.into_make_service_with_connect_info::<SocketAddr>(),
+                    )
+                    .await
+                }
+            }
+        });
+    }
+    while let Some(result) = tasks.join_next().await {
+        result.map_err(io::Error::other)??;
+    }
+    Ok(())
+}
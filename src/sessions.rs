@@ -0,0 +1,540 @@
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant},
+};
+
+use axum::{async_trait, http::StatusCode};
+use chrono::Utc;
+use redis::aio::ConnectionManager;
+use tokio::sync::Mutex;
+
+use crate::{
+    i18n::{t, tr},
+    secret::redact,
+    Invite,
+};
+
+/// How long an invite waits for the GitHub login, as long as the OAuth state stays usable.
+pub const PENDING_TTL: Duration = Duration::from_secs(30 * 60);
+const PENDING_PREFIX: &str = "bouncer:pending:";
+/// Sorted sets of pending tokens by expiry, one across all invites and one per limit key.
+const PENDING_INDEX_PREFIX: &str = "bouncer:pending-index:";
+const CONFIRMATION_PREFIX: &str = "bouncer:confirmation:";
+const COUNTER_PREFIX: &str = "bouncer:counter:";
+
+/// Short-lived state shared by every replica: invites waiting for the GitHub login or for
+/// confirmation, and rate limit counters. Kept in memory by default, or in Redis with `--redis-url`.
+#[async_trait]
+pub trait SessionStore: Send + Sync {
+    /// Stash an invite until the GitHub login completes, unless the store is full or the
+    /// client address already has `limits.per_client` invites waiting. Older invites sharing a
+    /// Matrix ID or email address beyond `limits.per_user` are evicted.
+    async fn insert_pending(
+        &self,
+        token: &str,
+        invite: &Invite,
+        limits: PendingLimits,
+    ) -> anyhow::Result<Stashed>;
+    /// Remove and return a pending invite, so each is used at most once.
+    async fn take_pending(&self, token: &str) -> anyhow::Result<Option<Invite>>;
+    async fn pending(&self) -> anyhow::Result<Vec<(String, Invite)>>;
+    /// Keep a logged in invite for [`PENDING_TTL`] until the user confirms it.
+    async fn insert_confirmation(&self, token: &str, invite: &Invite) -> anyhow::Result<()>;
+    async fn confirmation(&self, token: &str) -> anyhow::Result<Option<Invite>>;
+    /// Remove and return an invite awaiting confirmation, so it is sent at most once.
+    async fn take_confirmation(&self, token: &str) -> anyhow::Result<Option<Invite>>;
+    /// Count a hit on `key`, returning the hits within the window started by the first one.
+    async fn hit(&self, key: &str, window: Duration) -> anyhow::Result<u32>;
+}
+
+/// Answer for requests that cannot proceed while the session store is down.
+pub fn unavailable(err: anyhow::Error) -> (StatusCode, String) {
+    log::error!("session store unavailable: {:#}", redact(&err));
+    (
+        StatusCode::SERVICE_UNAVAILABLE,
+        t("the bouncer is temporarily unavailable, please retry in a moment"),
+    )
+}
+
+/// Stash an invite under the csrf token of its GitHub login, refusing it when the store is full
+/// or its client already has `limits.per_client` invites waiting.
+pub async fn stash(
+    store: &dyn SessionStore,
+    token: &str,
+    invite: &Invite,
+    limits: PendingLimits,
+) -> Result<(), (StatusCode, String)> {
+    match store
+        .insert_pending(token, invite, limits)
+        .await
+        .map_err(unavailable)?
+    {
+        Stashed::Stored => Ok(()),
+        Stashed::Full => {
+            log::warn!(
+                "refused an invite, {} invites are already waiting for a GitHub login",
+                limits.total
+            );
+            Err((
+                StatusCode::SERVICE_UNAVAILABLE,
+                t("too many invites are waiting for a GitHub login, please try again shortly"),
+            ))
+        }
+        Stashed::ClientBusy => {
+            log::warn!(
+                "refused an invite from {}, {} of its invites are already waiting for a GitHub login",
+                invite.client_ip.as_deref().unwrap_or("unknown client"),
+                limits.per_client
+            );
+            Err((
+                StatusCode::TOO_MANY_REQUESTS,
+                tr(
+                    "{count} of your invites are already waiting for a GitHub login. Please complete them, or wait for them to expire after {minutes} minutes, before starting another one.",
+                    &[
+                        ("count", &limits.per_client.to_string()),
+                        ("minutes", &(PENDING_TTL.as_secs() / 60).to_string()),
+                    ],
+                ),
+            ))
+        }
+    }
+}
+
+/// Whether a client is within `limit` hits per window; allowed while the store is down.
+pub async fn allow(store: &dyn SessionStore, key: &str, limit: u32, window: Duration) -> bool {
+    match store.hit(key, window).await {
+        Ok(hits) => hits <= limit,
+        Err(err) => {
+            log::error!("failed to count rate limit hit: {:#}", redact(&err));
+            true
+        }
+    }
+}
+
+/// Caps on invites waiting for the GitHub login, see `--max-pending-invites`.
+#[derive(Clone, Copy, Debug)]
+pub struct PendingLimits {
+    pub total: usize,
+    /// Per Matrix ID and email address, evicting the oldest.
+    pub per_user: usize,
+    /// Per client address, refusing new ones.
+    pub per_client: usize,
+}
+
+/// Whether [`SessionStore::insert_pending`] stashed the invite.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Stashed {
+    Stored,
+    /// `limits.total` invites are waiting.
+    Full,
+    /// The client address has `limits.per_client` invites waiting.
+    ClientBusy,
+}
+
+/// What the per-user cap of [`PendingLimits`] counts pending invites by.
+fn user_keys(invite: &Invite) -> Vec<String> {
+    invite
+        .user_ids
+        .iter()
+        .map(|user_id| format!("user:{}", user_id))
+        .chain(invite.emails.iter().map(|email| format!("email:{}", email)))
+        .collect()
+}
+
+/// Every index a pending invite is counted in: per user and per client address.
+fn limit_keys(invite: &Invite) -> Vec<String> {
+    let mut keys = user_keys(invite);
+    keys.extend(client_key(invite));
+    keys
+}
+
+fn client_key(invite: &Invite) -> Option<String> {
+    invite.client_ip.as_ref().map(|ip| format!("ip:{}", ip))
+}
+
+fn expired(invite: &Invite) -> bool {
+    (Utc::now() - invite.created)
+        .to_std()
+        .is_ok_and(|age| age >= PENDING_TTL)
+}
+
+#[derive(Default)]
+pub struct MemoryStore {
+    pending: Mutex<HashMap<String, Invite>>,
+    confirmations: Mutex<HashMap<String, (Instant, Invite)>>,
+    counters: Mutex<HashMap<String, (Instant, Duration, u32)>>,
+}
+
+#[async_trait]
+impl SessionStore for MemoryStore {
+    async fn insert_pending(
+        &self,
+        token: &str,
+        invite: &Invite,
+        limits: PendingLimits,
+    ) -> anyhow::Result<Stashed> {
+        let mut pending = self.pending.lock().await;
+        pending.retain(|_, invite| !expired(invite));
+        if let Some(key) = client_key(invite) {
+            let waiting = pending
+                .values()
+                .filter(|other| client_key(other).as_ref() == Some(&key))
+                .count();
+            if waiting >= limits.per_client {
+                return Ok(Stashed::ClientBusy);
+            }
+        }
+        for key in user_keys(invite) {
+            let mut sharing = pending
+                .iter()
+                .filter(|(_, other)| user_keys(other).contains(&key))
+                .map(|(token, other)| (other.created, token.clone()))
+                .collect::<Vec<_>>();
+            sharing.sort();
+            let excess = (sharing.len() + 1).saturating_sub(limits.per_user);
+            for (_, token) in sharing.into_iter().take(excess) {
+                pending.remove(&token);
+            }
+        }
+        if pending.len() >= limits.total {
+            return Ok(Stashed::Full);
+        }
+        pending.insert(token.to_string(), invite.clone());
+        Ok(Stashed::Stored)
+    }
+
+    async fn take_pending(&self, token: &str) -> anyhow::Result<Option<Invite>> {
+        Ok(self
+            .pending
+            .lock()
+            .await
+            .remove(token)
+            .filter(|invite| !expired(invite)))
+    }
+
+    async fn pending(&self) -> anyhow::Result<Vec<(String, Invite)>> {
+        Ok(self
+            .pending
+            .lock()
+            .await
+            .iter()
+            .filter(|(_, invite)| !expired(invite))
+            .map(|(token, invite)| (token.clone(), invite.clone()))
+            .collect())
+    }
+
+    async fn insert_confirmation(&self, token: &str, invite: &Invite) -> anyhow::Result<()> {
+        let now = Instant::now();
+        let mut confirmations = self.confirmations.lock().await;
+        confirmations.retain(|_, (stored, _)| now.duration_since(*stored) < PENDING_TTL);
+        confirmations.insert(token.to_string(), (now, invite.clone()));
+        Ok(())
+    }
+
+    async fn confirmation(&self, token: &str) -> anyhow::Result<Option<Invite>> {
+        Ok(self
+            .confirmations
+            .lock()
+            .await
+            .get(token)
+            .filter(|(stored, _)| stored.elapsed() < PENDING_TTL)
+            .map(|(_, invite)| invite.clone()))
+    }
+
+    async fn take_confirmation(&self, token: &str) -> anyhow::Result<Option<Invite>> {
+        Ok(self
+            .confirmations
+            .lock()
+            .await
+            .remove(token)
+            .filter(|(stored, _)| stored.elapsed() < PENDING_TTL)
+            .map(|(_, invite)| invite))
+    }
+
+    async fn hit(&self, key: &str, window: Duration) -> anyhow::Result<u32> {
+        let now = Instant::now();
+        let mut counters = self.counters.lock().await;
+        counters.retain(|_, (start, length, _)| now.duration_since(*start) < *length);
+        let (_, _, hits) = counters.entry(key.to_string()).or_insert((now, window, 0));
+        *hits += 1;
+        Ok(*hits)
+    }
+}
+
+/// Redis backend; the connection manager reconnects on its own after an outage.
+pub struct RedisStore {
+    connection: ConnectionManager,
+}
+
+impl RedisStore {
+    pub async fn connect(url: &str) -> anyhow::Result<Self> {
+        let client = redis::Client::open(url)?;
+        Ok(RedisStore {
+            connection: ConnectionManager::new(client).await?,
+        })
+    }
+}
+
+#[async_trait]
+impl SessionStore for RedisStore {
+    async fn insert_pending(
+        &self,
+        token: &str,
+        invite: &Invite,
+        limits: PendingLimits,
+    ) -> anyhow::Result<Stashed> {
+        let mut connection = self.connection.clone();
+        let now = Utc::now().timestamp_millis();
+        let expires = now + PENDING_TTL.as_millis() as i64;
+        let all = PENDING_INDEX_PREFIX.to_string();
+        redis::cmd("ZREMRANGEBYSCORE")
+            .arg(&all)
+            .arg("-inf")
+            .arg(now)
+            .query_async::<()>(&mut connection)
+            .await?;
+        let keys = limit_keys(invite)
+            .into_iter()
+            .map(|key| format!("{}{}", PENDING_INDEX_PREFIX, key))
+            .collect::<Vec<_>>();
+        for index in &keys {
+            redis::cmd("ZREMRANGEBYSCORE")
+                .arg(index)
+                .arg("-inf")
+                .arg(now)
+                .query_async::<()>(&mut connection)
+                .await?;
+        }
+        // Take the slot before counting, so concurrent submissions from one address on any
+        // replica cannot all pass; the slot is given back if the invite is not stored.
+        let client = client_key(invite).map(|key| format!("{}{}", PENDING_INDEX_PREFIX, key));
+        if let Some(client) = &client {
+            redis::cmd("ZADD")
+                .arg(client)
+                .arg(expires)
+                .arg(token)
+                .query_async::<()>(&mut connection)
+                .await?;
+            let waiting: usize = redis::cmd("ZCARD")
+                .arg(client)
+                .query_async(&mut connection)
+                .await?;
+            if waiting > limits.per_client {
+                redis::cmd("ZREM")
+                    .arg(client)
+                    .arg(token)
+                    .query_async::<()>(&mut connection)
+                    .await?;
+                return Ok(Stashed::ClientBusy);
+            }
+        }
+        for key in user_keys(invite) {
+            let index = &format!("{}{}", PENDING_INDEX_PREFIX, key);
+            // Oldest first, as tokens are scored by their expiry.
+            let sharing: Vec<String> = redis::cmd("ZRANGE")
+                .arg(index)
+                .arg(0)
+                .arg(-1)
+                .query_async(&mut connection)
+                .await?;
+            let excess = (sharing.len() + 1).saturating_sub(limits.per_user);
+            for evicted in sharing.iter().take(excess) {
+                let invite: Option<String> = redis::cmd("GETDEL")
+                    .arg(format!("{}{}", PENDING_PREFIX, evicted))
+                    .query_async(&mut connection)
+                    .await?;
+                // The evicted invite frees its slot of its client address as well.
+                let evicted_client = invite
+                    .and_then(|invite| serde_json::from_str::<Invite>(&invite).ok())
+                    .and_then(|invite| client_key(&invite))
+                    .map(|key| format!("{}{}", PENDING_INDEX_PREFIX, key));
+                for index in [Some(&all), Some(index), evicted_client.as_ref()]
+                    .into_iter()
+                    .flatten()
+                {
+                    redis::cmd("ZREM")
+                        .arg(index)
+                        .arg(evicted)
+                        .query_async::<()>(&mut connection)
+                        .await?;
+                }
+            }
+        }
+        let count: usize = redis::cmd("ZCARD")
+            .arg(&all)
+            .query_async(&mut connection)
+            .await?;
+        if count >= limits.total {
+            if let Some(client) = &client {
+                redis::cmd("ZREM")
+                    .arg(client)
+                    .arg(token)
+                    .query_async::<()>(&mut connection)
+                    .await?;
+            }
+            return Ok(Stashed::Full);
+        }
+
+        redis::cmd("SET")
+            .arg(format!("{}{}", PENDING_PREFIX, token))
+            .arg(serde_json::to_string(invite)?)
+            .arg("PX")
+            .arg(PENDING_TTL.as_millis() as u64)
+            .query_async::<()>(&mut connection)
+            .await?;
+        for index in std::iter::once(&all).chain(&keys) {
+            redis::cmd("ZADD")
+                .arg(index)
+                .arg(expires)
+                .arg(token)
+                .query_async::<()>(&mut connection)
+                .await?;
+            redis::cmd("PEXPIRE")
+                .arg(index)
+                .arg(PENDING_TTL.as_millis() as u64)
+                .query_async::<()>(&mut connection)
+                .await?;
+        }
+        Ok(Stashed::Stored)
+    }
+
+    async fn take_pending(&self, token: &str) -> anyhow::Result<Option<Invite>> {
+        let mut connection = self.connection.clone();
+        let invite: Option<String> = redis::cmd("GETDEL")
+            .arg(format!("{}{}", PENDING_PREFIX, token))
+            .query_async(&mut connection)
+            .await?;
+        let invite: Option<Invite> = invite
+            .map(|invite| serde_json::from_str(&invite))
+            .transpose()?;
+        if let Some(invite) = &invite {
+            let indexes = std::iter::once(PENDING_INDEX_PREFIX.to_string()).chain(
+                limit_keys(invite)
+                    .into_iter()
+                    .map(|key| format!("{}{}", PENDING_INDEX_PREFIX, key)),
+            );
+            for index in indexes {
+                redis::cmd("ZREM")
+                    .arg(index)
+                    .arg(token)
+                    .query_async::<()>(&mut connection)
+                    .await?;
+            }
+        }
+        Ok(invite)
+    }
+
+    async fn pending(&self) -> anyhow::Result<Vec<(String, Invite)>> {
+        let mut connection = self.connection.clone();
+        let mut keys = vec![];
+        let mut cursor = 0u64;
+        loop {
+            let (next, batch): (u64, Vec<String>) = redis::cmd("SCAN")
+                .arg(cursor)
+                .arg("MATCH")
+                .arg(format!("{}*", PENDING_PREFIX))
+                .query_async(&mut connection)
+                .await?;
+            keys.extend(batch);
+            if next == 0 {
+                break;
+            }
+            cursor = next;
+        }
+        if keys.is_empty() {
+            return Ok(vec![]);
+        }
+        let invites: Vec<Option<String>> = redis::cmd("MGET")
+            .arg(&keys)
+            .query_async(&mut connection)
+            .await?;
+        Ok(keys
+            .iter()
+            .zip(invites)
+            .filter_map(|(key, invite)| {
+                let token = key.strip_prefix(PENDING_PREFIX)?.to_string();
+                Some((token, serde_json::from_str(&invite?).ok()?))
+            })
+            .collect())
+    }
+
+    async fn insert_confirmation(&self, token: &str, invite: &Invite) -> anyhow::Result<()> {
+        redis::cmd("SET")
+            .arg(format!("{}{}", CONFIRMATION_PREFIX, token))
+            .arg(serde_json::to_string(invite)?)
+            .arg("PX")
+            .arg(PENDING_TTL.as_millis() as u64)
+            .query_async::<()>(&mut self.connection.clone())
+            .await?;
+        Ok(())
+    }
+
+    async fn confirmation(&self, token: &str) -> anyhow::Result<Option<Invite>> {
+        let invite: Option<String> = redis::cmd("GET")
+            .arg(format!("{}{}", CONFIRMATION_PREFIX, token))
+            .query_async(&mut self.connection.clone())
+            .await?;
+        Ok(invite
+            .map(|invite| serde_json::from_str(&invite))
+            .transpose()?)
+    }
+
+    async fn take_confirmation(&self, token: &str) -> anyhow::Result<Option<Invite>> {
+        let invite: Option<String> = redis::cmd("GETDEL")
+            .arg(format!("{}{}", CONFIRMATION_PREFIX, token))
+            .query_async(&mut self.connection.clone())
+            .await?;
+        Ok(invite
+            .map(|invite| serde_json::from_str(&invite))
+            .transpose()?)
+    }
+
+    async fn hit(&self, key: &str, window: Duration) -> anyhow::Result<u32> {
+        let key = format!("{}{}", COUNTER_PREFIX, key);
+        let mut connection = self.connection.clone();
+        let hits: u32 = redis::cmd("INCR")
+            .arg(&key)
+            .query_async(&mut connection)
+            .await?;
+        if hits == 1 {
+            redis::cmd("PEXPIRE")
+                .arg(&key)
+                .arg(window.as_millis() as u64)
+                .query_async::<()>(&mut connection)
+                .await?;
+        }
+        Ok(hits)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::StatusCode;
+    use ruma::user_id;
+
+    use super::{stash, MemoryStore, PendingLimits};
+    use crate::mock::invite;
+
+    #[tokio::test]
+    async fn refuses_logins_beyond_the_pending_limits() {
+        let store = MemoryStore::default();
+        let limits = PendingLimits {
+            total: 2,
+            per_user: 2,
+            per_client: 1,
+        };
+        stash(&store, "a", &invite(None), limits).await.unwrap();
+        let (status, _) = stash(&store, "b", &invite(None), limits).await.unwrap_err();
+        assert_eq!(status, StatusCode::TOO_MANY_REQUESTS);
+
+        // Other users from other clients, so nothing is evicted.
+        let mut other = invite(None);
+        other.user_ids = vec![user_id!("@bob:localhost").to_owned()];
+        other.client_ip = Some("192.0.2.2".to_string());
+        stash(&store, "c", &other, limits).await.unwrap();
+        other.user_ids = vec![user_id!("@carol:localhost").to_owned()];
+        other.client_ip = Some("192.0.2.3".to_string());
+        let (status, _) = stash(&store, "d", &other, limits).await.unwrap_err();
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+    }
+}
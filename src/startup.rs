@@ -0,0 +1,56 @@
+use std::fmt;
+
+/// Kind of startup failure, deciding the exit code.
+///
+/// | Code | Meaning |
+/// |------|---------|
+/// | 1 | any other failure |
+/// | 2 | invalid configuration, also used by clap for bad flags |
+/// | 3 | the homeserver or another service could not be reached |
+/// | 4 | the access token or another credential was rejected |
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Failure {
+    Config = 2,
+    Network = 3,
+    Auth = 4,
+}
+
+impl fmt::Display for Failure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Failure::Config => "invalid configuration",
+            Failure::Network => "network failure",
+            Failure::Auth => "credentials rejected",
+        })
+    }
+}
+
+impl std::error::Error for Failure {}
+
+/// Exit code for an error, from the [`Failure`] attached as context anywhere in its chain.
+pub fn exit_code(err: &anyhow::Error) -> i32 {
+    err.downcast_ref::<Failure>()
+        .map_or(1, |failure| *failure as i32)
+}
+
+/// Text for the end of `--help`.
+pub const EXIT_CODES: &str = "Exit codes:
+  1  any other failure
+  2  invalid configuration
+  3  the homeserver or another service could not be reached
+  4  the access token or another credential was rejected";
+
+/// Url reduced to its scheme, host and port, to name it in errors without leaking credentials.
+pub fn redact_url(url: &str) -> String {
+    match url::Url::parse(url) {
+        Ok(url) => format!(
+            "{}://{}{}",
+            url.scheme(),
+            url.host_str().unwrap_or_default(),
+            url.port()
+                .map(|port| format!(":{}", port))
+                .unwrap_or_default()
+        ),
+        Err(_) => "[unparsable url]".to_string(),
+    }
+}
@@ -0,0 +1,187 @@
+use std::{
+    collections::HashMap,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use axum::{
+    extract::{Extension, State},
+    http::StatusCode,
+};
+use chrono::Utc;
+use maud::{html, Markup};
+use ruma::OwnedRoomId;
+use tokio::sync::Mutex;
+
+use crate::{page, room_policy, security::CspNonce, store::Denial, webhook::EventKind, AppState};
+
+/// How long computed statistics are reused.
+const CACHE_TTL: Duration = Duration::from_secs(60);
+
+/// Aggregate invite numbers, never naming users.
+#[derive(Clone, Default)]
+pub struct Stats {
+    total: usize,
+    last_week: usize,
+    last_month: usize,
+    accepted: usize,
+    rooms: Vec<(String, usize)>,
+    denials: Vec<(String, usize)>,
+    failures: usize,
+    dry_runs: usize,
+}
+
+#[derive(Default)]
+pub struct StatsCache {
+    cached: Mutex<Option<(Instant, Stats)>>,
+}
+
+async fn compute(state: &AppState) -> Option<Stats> {
+    let store = state.store.as_ref()?;
+    let now = Utc::now();
+    let (mut stats, rooms, denials) = store
+        .entries(|entries| {
+            let mut stats = Stats::default();
+            let mut rooms = HashMap::<OwnedRoomId, usize>::new();
+            let mut denials = HashMap::<Denial, usize>::new();
+            for entry in entries {
+                match entry.event {
+                    EventKind::InviteSent => {
+                        stats.total += 1;
+                        let age = now - entry.timestamp;
+                        if age <= chrono::Duration::days(7) {
+                            stats.last_week += 1;
+                        }
+                        if age <= chrono::Duration::days(30) {
+                            stats.last_month += 1;
+                        }
+                        if entry.accepted.is_some() {
+                            stats.accepted += 1;
+                        }
+                        *rooms.entry(entry.room_id.clone()).or_default() += 1;
+                    }
+                    EventKind::InviteDenied => {
+                        if let Some(denial) = entry.reason_code {
+                            *denials.entry(denial).or_default() += 1;
+                        }
+                    }
+                    EventKind::InviteFailed => stats.failures += 1,
+                    EventKind::InviteDryRun => stats.dry_runs += 1,
+                    EventKind::Test => {}
+                }
+            }
+            (stats, rooms, denials)
+        })
+        .await;
+    for (room_id, count) in rooms {
+        if room_policy::hidden(state, &room_id) {
+            continue;
+        }
+        stats.rooms.push((state.room_name(&room_id).await, count));
+    }
+    stats
+        .rooms
+        .sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+    stats.denials = denials
+        .into_iter()
+        .map(|(denial, count)| (denial.label().to_string(), count))
+        .collect();
+    stats
+        .denials
+        .sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+    Some(stats)
+}
+
+impl StatsCache {
+    async fn get(&self, state: &AppState) -> Option<Stats> {
+        let mut cached = self.cached.lock().await;
+        if let Some((at, stats)) = cached.as_ref() {
+            if at.elapsed() < CACHE_TTL {
+                return Some(stats.clone());
+            }
+        }
+        let stats = compute(state).await?;
+        *cached = Some((Instant::now(), stats.clone()));
+        Some(stats)
+    }
+}
+
+pub fn percent(part: usize, total: usize) -> String {
+    if total == 0 {
+        return "—".to_string();
+    }
+    format!("{:.0}%", part as f64 * 100.0 / total as f64)
+}
+
+fn counts(title: &str, counts: &[(String, usize)]) -> Markup {
+    html! {
+        h2 { (title) }
+        @if counts.is_empty() {
+            p { "None yet." }
+        } @else {
+            table {
+                tbody {
+                    @for (name, count) in counts {
+                        tr {
+                            td { (name) }
+                            td class="number" { (count) }
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Public page with aggregate invite numbers from the audit store.
+pub async fn stats(
+    State(state): State<Arc<AppState>>,
+    Extension(CspNonce(nonce)): Extension<CspNonce>,
+) -> Result<Markup, (StatusCode, Markup)> {
+    if state.disable_stats {
+        return Err(page::error_page(
+            &state,
+            &nonce,
+            StatusCode::NOT_FOUND,
+            "Statistics are disabled.",
+        ));
+    }
+    let Some(stats) = state.stats.get(&state).await else {
+        return Ok(page::layout(
+            &state,
+            &nonce,
+            &state.page_title("Statistics"),
+            html! {
+                h1 { "Statistics" }
+                p { "Statistics are not enabled on this bouncer." }
+            },
+        ));
+    };
+    Ok(page::layout(
+        &state,
+        &nonce,
+        &state.page_title("Statistics"),
+        html! {
+            h1 { "Statistics" }
+            table {
+                tbody {
+                    tr { td { "Invites sent" } td class="number" { (stats.total) } }
+                    tr { td { "Last 7 days" } td class="number" { (stats.last_week) } }
+                    tr { td { "Last 30 days" } td class="number" { (stats.last_month) } }
+                    @if state.track_joins {
+                        tr { td { "Invites accepted" } td class="number" { (stats.accepted) " (" (percent(stats.accepted, stats.total)) ")" } }
+                    }
+                    tr { td { "Failed invites" } td class="number" { (stats.failures) } }
+                    @if stats.dry_runs > 0 {
+                        tr { td { "Dry runs, not sent" } td class="number" { (stats.dry_runs) } }
+                    }
+                }
+            }
+            (counts("Invites per room", &stats.rooms))
+            (counts("Denials", &stats.denials))
+            p {
+                a href=(state.absolute_link("")) { "Back to the invite form" }
+            }
+        },
+    ))
+}
@@ -0,0 +1,520 @@
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
+
+use anyhow::Context;
+use chrono::{DateTime, Utc};
+use ruma::{OwnedEventId, OwnedRoomId, OwnedUserId, RoomId, UserId};
+use tokio::{io::AsyncWriteExt, sync::Mutex};
+
+use crate::{
+    approval, email_hash, links, logging, mail, queue,
+    webhook::{self, EventKind},
+    AppState,
+};
+
+const PRUNE_INTERVAL: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Why an invite was denied, recorded next to the free text reason so that it can be told
+/// apart in any language.
+#[derive(
+    Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, serde::Serialize, serde::Deserialize,
+)]
+#[serde(rename_all = "snake_case")]
+pub enum Denial {
+    RoomBan,
+    PolicyList,
+    GithubAccountAge,
+    GithubMembership,
+    Localpart,
+    Homeserver,
+    /// Over `--max-invitees-per-account`.
+    InviteQuota,
+    Other,
+}
+
+impl Denial {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Denial::RoomBan => "room_ban",
+            Denial::PolicyList => "policy_list",
+            Denial::GithubAccountAge => "github_account_age",
+            Denial::GithubMembership => "github_membership",
+            Denial::Localpart => "localpart",
+            Denial::Homeserver => "homeserver",
+            Denial::InviteQuota => "invite_quota",
+            Denial::Other => "other",
+        }
+    }
+
+    /// Shown on the statistics page and in the digest.
+    pub fn label(self) -> &'static str {
+        match self {
+            Denial::RoomBan => "banned from the room",
+            Denial::PolicyList => "banned by a policy list",
+            Denial::GithubAccountAge => "GitHub account too young",
+            Denial::GithubMembership => "not in the required GitHub organization or team",
+            Denial::Localpart => "Matrix ID not matching the GitHub login",
+            Denial::Homeserver => "Matrix account on another homeserver",
+            Denial::InviteQuota => "over the daily invite limit of the GitHub account",
+            Denial::Other => "other",
+        }
+    }
+}
+
+/// One invite attempt, as recorded in the audit store.
+#[derive(Clone, serde::Serialize, serde::Deserialize)]
+pub struct Entry {
+    pub timestamp: DateTime<Utc>,
+    pub event: EventKind,
+    /// Absent for email invites, which record the hash of the address instead.
+    pub user_id: Option<OwnedUserId>,
+    #[serde(default)]
+    pub email_sha256: Option<String>,
+    pub room_id: OwnedRoomId,
+    pub github_login: String,
+    pub reason: Option<String>,
+    /// When the invited user joined, tracked with `--track-joins`.
+    #[serde(default)]
+    pub accepted: Option<DateTime<Utc>>,
+    /// When the invite was rescinded by `--invite-expiry`.
+    #[serde(default)]
+    pub expired: Option<DateTime<Utc>>,
+    /// Terms of service URL the requester agreed to, with `--tos-url`.
+    #[serde(default)]
+    pub terms: Option<String>,
+    /// Admin who sent the invite through the admin API, leaving `github_login` empty.
+    #[serde(default)]
+    pub initiated_by: Option<String>,
+    /// Why the invite was denied, written with every denial by [`deny`] and [`deny_email`].
+    pub reason_code: Option<Denial>,
+}
+
+/// Everything the audit store keeps besides the entries, in a snapshot rewritten on every
+/// change.
+#[derive(Default, serde::Serialize, serde::Deserialize)]
+#[serde(default)]
+pub struct Data {
+    /// When the last digest was posted to the admin room.
+    pub last_digest: Option<DateTime<Utc>>,
+    /// Successful invites per room, restored on startup.
+    pub invite_counts: HashMap<OwnedRoomId, u64>,
+    /// Where the membership sync loop continues after a restart.
+    pub join_sync_token: Option<String>,
+    /// Outstanding invite links by token, restored on startup.
+    pub links: HashMap<String, links::Link>,
+    /// Invites of `--queue-invites` by job id, sent and finished ones included.
+    pub jobs: HashMap<String, queue::Job>,
+    /// Invites awaiting moderator approval by the admin room message asking for them, restored
+    /// on startup.
+    pub approvals: HashMap<OwnedEventId, approval::Approval>,
+}
+
+/// One line of the entry log.
+#[derive(serde::Serialize, serde::Deserialize)]
+#[serde(untagged)]
+enum Line {
+    /// Replaces the entry at `at`, counted from the start of the log.
+    Amended {
+        at: usize,
+        entry: Entry,
+    },
+    Added(Entry),
+}
+
+struct Entries {
+    list: Vec<Entry>,
+    /// Lines of the log superseded by a later [`Line::Amended`], dropped by [`Store::prune`].
+    superseded: usize,
+}
+
+/// Audit store kept in memory. The entries are appended to a JSON lines log next to the
+/// snapshot file, so recording an invite does not rewrite the history, and entries older than
+/// `--audit-retention` are dropped.
+pub struct Store {
+    path: PathBuf,
+    data: Mutex<Data>,
+    log: PathBuf,
+    entries: Mutex<Entries>,
+    retention: Option<Duration>,
+}
+
+/// The entry log of the snapshot at `path`, e.g. `audit.jsonl` for `audit.json`.
+pub fn log_path(path: &Path) -> PathBuf {
+    path.with_extension("jsonl")
+}
+
+fn read_log(path: &Path) -> anyhow::Result<Vec<Entry>> {
+    let text = match std::fs::read_to_string(path) {
+        Ok(text) => text,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(vec![]),
+        Err(err) => {
+            return Err(err).with_context(|| format!("failed to read audit log {}", path.display()))
+        }
+    };
+    let mut lines = text.split_terminator('\n').collect::<Vec<_>>();
+    // A line cut short by a crash while appending.
+    if !text.ends_with('\n') && lines.pop().is_some() {
+        log::warn!(
+            "dropping the incomplete last line of audit log {}",
+            path.display()
+        );
+    }
+    let mut entries = vec![];
+    for (number, line) in lines.into_iter().enumerate() {
+        let line = serde_json::from_str(line).with_context(|| {
+            format!(
+                "failed to parse line {} of audit log {}",
+                number + 1,
+                path.display()
+            )
+        })?;
+        match line {
+            Line::Added(entry) => entries.push(entry),
+            Line::Amended { at, entry } => {
+                if let Some(amended) = entries.get_mut(at) {
+                    *amended = entry;
+                }
+            }
+        }
+    }
+    Ok(entries)
+}
+
+fn to_lines(lines: &[Line]) -> anyhow::Result<Vec<u8>> {
+    let mut bytes = vec![];
+    for line in lines {
+        serde_json::to_writer(&mut bytes, &line)?;
+        bytes.push(b'\n');
+    }
+    Ok(bytes)
+}
+
+fn added(entries: &[Entry]) -> Vec<Line> {
+    entries.iter().cloned().map(Line::Added).collect()
+}
+
+impl Store {
+    pub fn open(path: &Path, retention: Option<Duration>) -> anyhow::Result<Store> {
+        let data = match std::fs::read(path) {
+            Ok(data) => serde_json::from_slice(&data)
+                .with_context(|| format!("failed to parse audit store {}", path.display()))?,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => Data::default(),
+            Err(err) => {
+                return Err(err)
+                    .with_context(|| format!("failed to read audit store {}", path.display()))
+            }
+        };
+        let log = log_path(path);
+        let mut list = read_log(&log)?;
+        if let Some(cutoff) = cutoff(retention) {
+            list.retain(|entry| entry.timestamp >= cutoff);
+        }
+        // Written back without the superseded and expired lines.
+        let tmp = log.with_extension("jsonl.tmp");
+        std::fs::write(&tmp, to_lines(&added(&list))?)
+            .and_then(|()| std::fs::rename(&tmp, &log))
+            .with_context(|| format!("failed to write audit log {}", log.display()))?;
+        Ok(Store {
+            path: path.to_path_buf(),
+            data: Mutex::new(data),
+            log,
+            entries: Mutex::new(Entries {
+                list,
+                superseded: 0,
+            }),
+            retention,
+        })
+    }
+
+    pub async fn read<R>(&self, f: impl FnOnce(&Data) -> R) -> R {
+        f(&*self.data.lock().await)
+    }
+
+    /// Change the stored data and write it out. Write failures are only logged, the change is
+    /// kept in memory.
+    pub async fn update<R>(&self, f: impl FnOnce(&mut Data) -> R) -> R {
+        let mut data = self.data.lock().await;
+        let result = f(&mut data);
+        if let Err(err) = self.persist(&data).await {
+            log::error!(
+                "failed to write audit store {}: {:#}",
+                self.path.display(),
+                err
+            );
+        }
+        result
+    }
+
+    async fn persist(&self, data: &Data) -> anyhow::Result<()> {
+        let tmp = self.path.with_extension("tmp");
+        tokio::fs::write(&tmp, serde_json::to_vec(data)?).await?;
+        tokio::fs::rename(&tmp, &self.path).await?;
+        Ok(())
+    }
+
+    /// Read the entries, oldest first.
+    pub async fn entries<R>(&self, f: impl FnOnce(&[Entry]) -> R) -> R {
+        f(&self.entries.lock().await.list)
+    }
+
+    /// Add an entry at the end of the log.
+    pub async fn append(&self, entry: Entry) {
+        let mut entries = self.entries.lock().await;
+        self.write(&[Line::Added(entry.clone())]).await;
+        entries.list.push(entry);
+    }
+
+    /// Change entries in place, newest first, where `f` returns whether it changed one. Only
+    /// the changed entries are written, returning how many there were.
+    pub async fn amend(&self, mut f: impl FnMut(&mut Entry) -> bool) -> usize {
+        let mut entries = self.entries.lock().await;
+        let mut lines = vec![];
+        for (at, entry) in entries.list.iter_mut().enumerate().rev() {
+            if f(entry) {
+                lines.push(Line::Amended {
+                    at,
+                    entry: entry.clone(),
+                });
+            }
+        }
+        if !lines.is_empty() {
+            self.write(&lines).await;
+            entries.superseded += lines.len();
+        }
+        lines.len()
+    }
+
+    /// Drop the entries older than `--audit-retention` and rewrite the log when that or an
+    /// earlier [`Store::amend`] left lines to drop.
+    pub async fn prune(&self) {
+        let mut entries = self.entries.lock().await;
+        let kept = match cutoff(self.retention) {
+            Some(cutoff) => entries
+                .list
+                .iter()
+                .filter(|entry| entry.timestamp >= cutoff)
+                .cloned()
+                .collect(),
+            None => entries.list.clone(),
+        };
+        let pruned = entries.list.len() - kept.len();
+        if pruned == 0 && entries.superseded == 0 {
+            return;
+        }
+        // The positions of later amendments count from the rewritten log, so the entries in
+        // memory only change along with it.
+        let result = async {
+            let tmp = self.log.with_extension("jsonl.tmp");
+            tokio::fs::write(&tmp, to_lines(&added(&kept))?).await?;
+            tokio::fs::rename(&tmp, &self.log).await?;
+            anyhow::Ok(())
+        }
+        .await;
+        if let Err(err) = result {
+            log::error!(
+                "failed to write audit log {}: {:#}",
+                self.log.display(),
+                err
+            );
+            return;
+        }
+        *entries = Entries {
+            list: kept,
+            superseded: 0,
+        };
+        if pruned > 0 {
+            log::warn!("dropped {} audit entries past the retention", pruned);
+        }
+    }
+
+    /// Append to the log. Write failures are only logged, the entries are kept in memory.
+    async fn write(&self, lines: &[Line]) {
+        let result = async {
+            let bytes = to_lines(lines)?;
+            let mut file = tokio::fs::OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(&self.log)
+                .await?;
+            file.write_all(&bytes).await?;
+            file.flush().await?;
+            anyhow::Ok(())
+        }
+        .await;
+        if let Err(err) = result {
+            log::error!(
+                "failed to write audit log {}: {:#}",
+                self.log.display(),
+                err
+            );
+        }
+    }
+}
+
+fn cutoff(retention: Option<Duration>) -> Option<DateTime<Utc>> {
+    Some(Utc::now() - chrono::Duration::from_std(retention?).ok()?)
+}
+
+/// Prune the audit store once a day, see [`Store::prune`].
+pub fn spawn(state: Arc<AppState>) {
+    if state.store.is_none() {
+        return;
+    }
+    tokio::spawn(async move {
+        loop {
+            tokio::time::sleep(PRUNE_INTERVAL).await;
+            if let Some(store) = &state.store {
+                store.prune().await;
+            }
+        }
+    });
+}
+
+/// Record the outcome of an invite attempt in the audit store and send it to the webhook.
+pub async fn record(
+    state: &AppState,
+    event: EventKind,
+    user_id: &UserId,
+    room_id: &RoomId,
+    github_login: &str,
+    reason: Option<String>,
+) {
+    let event = webhook::Event::new(event, user_id, room_id, github_login, reason);
+    save(state, event, Some(user_id.to_owned()), room_id, None).await;
+}
+
+/// Record a denied invite, with the English `reason` shown to admins.
+pub async fn deny(
+    state: &AppState,
+    user_id: &UserId,
+    room_id: &RoomId,
+    github_login: &str,
+    denial: Denial,
+    reason: String,
+) {
+    let event = webhook::Event::new(
+        EventKind::InviteDenied,
+        user_id,
+        room_id,
+        github_login,
+        Some(reason),
+    );
+    save(
+        state,
+        event,
+        Some(user_id.to_owned()),
+        room_id,
+        Some(denial),
+    )
+    .await;
+}
+
+/// Record the outcome of an invite an admin sent without a GitHub login.
+pub async fn record_admin(
+    state: &AppState,
+    event: EventKind,
+    user_id: &UserId,
+    room_id: &RoomId,
+    actor: &str,
+    reason: Option<String>,
+) {
+    let mut event = webhook::Event::new(event, user_id, room_id, "", reason);
+    event.initiated_by = Some(actor.to_string());
+    save(state, event, Some(user_id.to_owned()), room_id, None).await;
+}
+
+/// Record the outcome of an email invite, keyed on the hash of the address.
+pub async fn record_email(
+    state: &AppState,
+    event: EventKind,
+    email: &str,
+    room_id: &RoomId,
+    github_login: &str,
+    reason: Option<String>,
+) {
+    let event = webhook::Event::email(event, email_hash(email), room_id, github_login, reason);
+    save(state, event, None, room_id, None).await;
+}
+
+/// [`deny`] for an email invite.
+pub async fn deny_email(
+    state: &AppState,
+    email: &str,
+    room_id: &RoomId,
+    github_login: &str,
+    denial: Denial,
+    reason: String,
+) {
+    let event = webhook::Event::email(
+        EventKind::InviteDenied,
+        email_hash(email),
+        room_id,
+        github_login,
+        Some(reason),
+    );
+    save(state, event, None, room_id, Some(denial)).await;
+}
+
+/// Note the terms agreed to on the latest invite sent to a user or email address.
+pub async fn agreed(
+    state: &AppState,
+    user_id: Option<&UserId>,
+    email: Option<&str>,
+    room_id: &RoomId,
+    terms: &str,
+) {
+    let Some(store) = &state.store else {
+        return;
+    };
+    let email_sha256 = email.map(email_hash);
+    let mut found = false;
+    store
+        .amend(|entry| {
+            if found
+                || entry.event != EventKind::InviteSent
+                || entry.terms.is_some()
+                || *entry.room_id != *room_id
+                || entry.user_id.as_deref() != user_id
+                || entry.email_sha256 != email_sha256
+            {
+                return false;
+            }
+            found = true;
+            entry.terms = Some(terms.to_string());
+            true
+        })
+        .await;
+}
+
+async fn save(
+    state: &AppState,
+    event: webhook::Event,
+    user_id: Option<OwnedUserId>,
+    room_id: &RoomId,
+    denial: Option<Denial>,
+) {
+    if let Some(store) = &state.store {
+        let entry = Entry {
+            timestamp: event.timestamp,
+            event: event.event,
+            user_id,
+            email_sha256: event.email_sha256.clone(),
+            room_id: room_id.to_owned(),
+            github_login: event.github_login.clone(),
+            reason: event.reason.clone(),
+            accepted: None,
+            expired: None,
+            terms: None,
+            initiated_by: event.initiated_by.clone(),
+            reason_code: denial,
+        };
+        store.append(entry).await;
+    }
+    logging::invite(&event);
+    mail::notify(state, &event);
+    webhook::notify(state, event);
+}
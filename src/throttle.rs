@@ -0,0 +1,153 @@
+//! Cap on concurrent requests to the homeserver, see `--max-homeserver-concurrency`.
+//!
+//! Every request of the Matrix client takes a permit in [`token::RefreshingClient`], so page
+//! handlers, room discovery and background loops all share the cap without doing anything.
+//! Requests that wait longer than `--homeserver-wait-timeout` fail instead of queueing, and
+//! [`busy_page`] answers the page that ran into one with a 503.
+//!
+//! [`token::RefreshingClient`]: crate::token::RefreshingClient
+
+use std::{
+    cell::Cell,
+    fmt,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+use axum::{
+    extract::{Request, State},
+    http::StatusCode,
+    middleware::Next,
+    response::Response,
+};
+use tokio::sync::{Semaphore, SemaphorePermit};
+
+use crate::{fallback, i18n::t, page, AppState};
+
+pub const DEFAULT_CONCURRENCY: usize = 4;
+pub const DEFAULT_WAIT: Duration = Duration::from_secs(5);
+
+tokio::task_local! {
+    /// Set when a homeserver request of the current page ran into the wait timeout.
+    static BUSY: Cell<bool>;
+}
+
+pub struct Throttle {
+    permits: Semaphore,
+    concurrency: usize,
+    wait: Duration,
+    requests: AtomicU64,
+    waited_micros: AtomicU64,
+    timeouts: AtomicU64,
+}
+
+/// A homeserver request that got no permit within the wait timeout.
+#[derive(Debug)]
+pub struct Busy(Duration);
+
+impl fmt::Display for Busy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "no homeserver request slot became free within {:?}",
+            self.0
+        )
+    }
+}
+
+impl std::error::Error for Busy {}
+
+/// Numbers shown on the admin dashboard.
+pub struct ThrottleStats {
+    pub concurrency: usize,
+    pub in_flight: usize,
+    pub requests: u64,
+    /// Time all requests spent waiting for a permit.
+    pub waited: Duration,
+    pub timeouts: u64,
+}
+
+impl Default for Throttle {
+    fn default() -> Self {
+        Throttle::new(DEFAULT_CONCURRENCY, DEFAULT_WAIT)
+    }
+}
+
+impl Throttle {
+    pub fn new(concurrency: usize, wait: Duration) -> Throttle {
+        Throttle {
+            permits: Semaphore::new(concurrency),
+            concurrency,
+            wait,
+            requests: AtomicU64::new(0),
+            waited_micros: AtomicU64::new(0),
+            timeouts: AtomicU64::new(0),
+        }
+    }
+
+    /// Wait for a permit to send one homeserver request.
+    pub async fn acquire(&self) -> Result<SemaphorePermit<'_>, Busy> {
+        let started = Instant::now();
+        let permit = tokio::time::timeout(self.wait, self.permits.acquire()).await;
+        self.requests.fetch_add(1, Ordering::Relaxed);
+        self.waited_micros
+            .fetch_add(started.elapsed().as_micros() as u64, Ordering::Relaxed);
+        match permit {
+            Ok(Ok(permit)) => Ok(permit),
+            // The semaphore is never closed, so only the timeout gets here.
+            _ => {
+                let timeouts = self.timeouts.fetch_add(1, Ordering::Relaxed) + 1;
+                log::warn!(
+                    "a homeserver request waited {:?} for one of {} slots, giving up ({} since startup)",
+                    self.wait,
+                    self.concurrency,
+                    timeouts
+                );
+                let _ = BUSY.try_with(|busy| busy.set(true));
+                Err(Busy(self.wait))
+            }
+        }
+    }
+
+    pub fn stats(&self) -> ThrottleStats {
+        ThrottleStats {
+            concurrency: self.concurrency,
+            in_flight: self.concurrency - self.permits.available_permits(),
+            requests: self.requests.load(Ordering::Relaxed),
+            waited: Duration::from_micros(self.waited_micros.load(Ordering::Relaxed)),
+            timeouts: self.timeouts.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Answer a request with a 503 asking to retry when one of its homeserver requests gave up
+/// waiting, whatever the handler made of the failure.
+pub async fn busy_page(
+    State(state): State<Arc<AppState>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let nonce = page::nonce(request.extensions());
+    let json = fallback::wants_json(request.headers());
+    let path = request.uri().path().to_string();
+    let (response, busy) = BUSY
+        .scope(Cell::new(false), async {
+            let response = next.run(request).await;
+            (response, BUSY.with(Cell::get))
+        })
+        .await;
+    if !busy {
+        return response;
+    }
+    log::warn!("answered {} with a 503, the homeserver is busy", path);
+    fallback::error(
+        &state,
+        &nonce,
+        json,
+        StatusCode::SERVICE_UNAVAILABLE,
+        &t("The homeserver is busy right now, please try again in a moment."),
+    )
+}
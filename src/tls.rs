@@ -0,0 +1,123 @@
+use std::{
+    fs::File,
+    io::BufReader,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
+
+use anyhow::Context;
+use axum_server::tls_rustls::RustlsConfig;
+use rustls::{
+    crypto::aws_lc_rs,
+    pki_types::{CertificateDer, PrivateKeyDer},
+    sign::CertifiedKey,
+    ServerConfig,
+};
+use tokio::{
+    signal::unix::{signal, SignalKind},
+    time::MissedTickBehavior,
+};
+
+fn load_server_config(cert_path: &Path, key_path: &Path) -> anyhow::Result<ServerConfig> {
+    let certs = rustls_pemfile::certs(&mut BufReader::new(
+        File::open(cert_path)
+            .with_context(|| format!("failed to open tls certificate {}", cert_path.display()))?,
+    ))
+    .collect::<Result<Vec<CertificateDer<'static>>, _>>()
+    .with_context(|| format!("failed to parse tls certificate {}", cert_path.display()))?;
+    if certs.is_empty() {
+        anyhow::bail!("no certificate found in {}", cert_path.display());
+    }
+
+    let key: PrivateKeyDer<'static> = rustls_pemfile::private_key(&mut BufReader::new(
+        File::open(key_path)
+            .with_context(|| format!("failed to open tls key {}", key_path.display()))?,
+    ))
+    .with_context(|| format!("failed to parse tls key {}", key_path.display()))?
+    .with_context(|| format!("no private key found in {}", key_path.display()))?;
+
+    let provider = aws_lc_rs::default_provider();
+    let signing_key = provider
+        .key_provider
+        .load_private_key(key.clone_key())
+        .context("unsupported tls private key")?;
+    CertifiedKey::new(certs.clone(), signing_key)
+        .keys_match()
+        .with_context(|| {
+            format!(
+                "tls key {} does not match certificate {}",
+                key_path.display(),
+                cert_path.display()
+            )
+        })?;
+
+    let mut config = ServerConfig::builder_with_provider(Arc::new(provider))
+        .with_safe_default_protocol_versions()?
+        .with_no_client_auth()
+        .with_single_cert(certs, key)?;
+    config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+    Ok(config)
+}
+
+pub fn load(cert: &Path, key: &Path) -> anyhow::Result<RustlsConfig> {
+    Ok(RustlsConfig::from_config(Arc::new(load_server_config(
+        cert, key,
+    )?)))
+}
+
+/// How often [`watch`] checks the certificate and key files for changes in production.
+pub const POLL_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Modification times of the certificate and key, following symlinks as certbot's live
+/// directory uses them.
+fn modified(cert: &Path, key: &Path) -> [Option<SystemTime>; 2] {
+    [cert, key].map(|path| {
+        std::fs::metadata(path)
+            .and_then(|meta| meta.modified())
+            .ok()
+    })
+}
+
+/// Reload the certificate and key on SIGHUP, or once either file changed as checked every
+/// `poll`, so renewals need no manual step. The old pair is kept if the new one is invalid,
+/// such as while only one of the files has been replaced.
+pub fn watch(
+    config: RustlsConfig,
+    cert: PathBuf,
+    key: PathBuf,
+    poll: Duration,
+) -> anyhow::Result<()> {
+    let mut hangup = signal(SignalKind::hangup())?;
+    let mut seen = modified(&cert, &key);
+    let mut ticks = tokio::time::interval(poll);
+    ticks.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    tokio::spawn(async move {
+        loop {
+            tokio::select! {
+                signal = hangup.recv() => {
+                    if signal.is_none() {
+                        return;
+                    }
+                }
+                _ = ticks.tick() => {
+                    let current = modified(&cert, &key);
+                    if current == seen {
+                        continue;
+                    }
+                    seen = current;
+                }
+            }
+            match load_server_config(&cert, &key) {
+                Ok(server_config) => {
+                    config.reload_from_config(Arc::new(server_config));
+                    log::warn!("reloaded tls certificate {}", cert.display());
+                }
+                Err(err) => {
+                    log::error!("failed to reload tls certificate: {:#}", err);
+                }
+            }
+        }
+    });
+    Ok(())
+}
@@ -0,0 +1,301 @@
+//! Authentication of the Matrix client: access tokens that expire, refreshed with
+//! `--refresh-token` as specified by MSC2918, or the token of an appservice.
+
+use std::{
+    fmt,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use anyhow::Context;
+use axum::http::{self, header::AUTHORIZATION, StatusCode};
+use ruma::OwnedUserId;
+use tokio::{fs::OpenOptions, io::AsyncWriteExt, sync::Mutex};
+
+use crate::{
+    hex,
+    secret::{redact, Secret},
+    throttle::{Busy, Throttle},
+};
+
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+pub struct Tokens {
+    pub access_token: Secret<String>,
+    pub refresh_token: Option<Secret<String>>,
+    /// Hash of the configured access token the refreshes started from, kept in
+    /// `--token-state-file` to tell when the operator configures a new one.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub origin: Option<String>,
+}
+
+/// How the bot authenticates with the homeserver.
+#[derive(Debug)]
+pub struct Credentials {
+    /// The access token, or the `as_token` of an appservice.
+    pub tokens: Tokens,
+    pub state_file: Option<PathBuf>,
+    /// User an appservice acts as, through the `user_id` query parameter.
+    pub appservice_sender: Option<OwnedUserId>,
+}
+
+/// Tokens rotated by refreshes, read from `--token-state-file` when it exists so that a restart
+/// continues the chain. A state file started from another access token than the configured one
+/// is ignored.
+pub fn load(tokens: Tokens, file: Option<&Path>) -> anyhow::Result<Tokens> {
+    let origin = Some(hex(ring::digest::digest(
+        &ring::digest::SHA256,
+        tokens.access_token.expose().as_bytes(),
+    )
+    .as_ref()));
+    let Some(file) = file else {
+        if tokens.refresh_token.is_some() {
+            log::warn!("Refreshed tokens are lost on restart without --token-state-file");
+        }
+        return Ok(tokens);
+    };
+    let saved: Tokens = match std::fs::read(file) {
+        Ok(data) => serde_json::from_slice(&data)
+            .with_context(|| format!("invalid token_state_file {}", file.display()))?,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => {
+            return Ok(Tokens { origin, ..tokens })
+        }
+        Err(err) => {
+            return Err(err)
+                .with_context(|| format!("failed to read token_state_file {}", file.display()))
+        }
+    };
+    match &saved.origin {
+        Some(saved_origin) if Some(saved_origin) != origin.as_ref() => {
+            log::warn!(
+                "The access token was reconfigured since {} was written, using the configured one",
+                file.display()
+            );
+            Ok(Tokens { origin, ..tokens })
+        }
+        Some(_) => {
+            log::warn!("Using the access token from {}", file.display());
+            Ok(saved)
+        }
+        None => {
+            log::warn!(
+                "Using the access token from {} instead of the configured one, remove the file if the configured one is newer",
+                file.display()
+            );
+            Ok(Tokens { origin, ..saved })
+        }
+    }
+}
+
+/// Replace the state file with one only the owner can read.
+async fn save(file: &Path, tokens: &Tokens) -> anyhow::Result<()> {
+    let tmp = file.with_extension("tmp");
+    // A leftover temporary file would keep its permissions.
+    let _ = tokio::fs::remove_file(&tmp).await;
+    let mut out = OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(&tmp)
+        .await?;
+    out.write_all(&serde_json::to_vec(tokens)?).await?;
+    out.sync_all().await?;
+    tokio::fs::rename(&tmp, file).await?;
+    Ok(())
+}
+
+#[derive(serde::Deserialize)]
+struct Refreshed {
+    access_token: Secret<String>,
+    refresh_token: Option<Secret<String>>,
+}
+
+#[derive(serde::Deserialize)]
+struct ErrorBody {
+    errcode: String,
+    #[serde(default)]
+    soft_logout: bool,
+}
+
+struct Session {
+    homeserver_url: String,
+    tokens: Mutex<Tokens>,
+    file: Option<PathBuf>,
+    appservice_sender: Option<OwnedUserId>,
+}
+
+/// HTTP client of the Matrix client, sending requests with the current access token and
+/// refreshing it once a request is answered with a soft logout. Appservices impersonate their
+/// sender instead. Every request waits for a permit of the [`Throttle`].
+#[derive(Clone)]
+pub struct RefreshingClient {
+    http: reqwest::Client,
+    session: Arc<Session>,
+    throttle: Arc<Throttle>,
+}
+
+/// Failure of a homeserver request before any answer.
+#[derive(Debug)]
+pub enum ClientError {
+    Http(reqwest::Error),
+    Busy(Busy),
+}
+
+impl fmt::Display for ClientError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ClientError::Http(err) => err.fmt(f),
+            ClientError::Busy(err) => err.fmt(f),
+        }
+    }
+}
+
+impl std::error::Error for ClientError {}
+
+impl From<reqwest::Error> for ClientError {
+    fn from(err: reqwest::Error) -> Self {
+        ClientError::Http(err)
+    }
+}
+
+impl RefreshingClient {
+    pub fn new(
+        http: reqwest::Client,
+        homeserver_url: String,
+        credentials: Credentials,
+        throttle: Arc<Throttle>,
+    ) -> Self {
+        RefreshingClient {
+            throttle,
+            http,
+            session: Arc::new(Session {
+                homeserver_url,
+                tokens: Mutex::new(credentials.tokens),
+                file: credentials.state_file,
+                appservice_sender: credentials.appservice_sender,
+            }),
+        }
+    }
+
+    async fn send(
+        &self,
+        request: &http::Request<Vec<u8>>,
+        access_token: Option<&str>,
+    ) -> reqwest::Result<http::Response<Vec<u8>>> {
+        let mut headers = request.headers().clone();
+        headers.remove(AUTHORIZATION);
+        let mut url = request.uri().to_string();
+        if let Some(sender) = &self.session.appservice_sender {
+            url = impersonate(&url, sender);
+        }
+        let mut builder = self
+            .http
+            .request(request.method().clone(), url)
+            .headers(headers)
+            .body(request.body().clone());
+        if let Some(access_token) = access_token {
+            builder = builder.bearer_auth(access_token);
+        }
+        let response = builder.send().await?;
+        let status = response.status();
+        let headers = response.headers().clone();
+        let mut http_response = http::Response::new(response.bytes().await?.to_vec());
+        *http_response.status_mut() = status;
+        *http_response.headers_mut() = headers;
+        Ok(http_response)
+    }
+
+    /// A new access token replacing `expired`. Requests failing together wait for the first of
+    /// them to refresh, and then reuse its token.
+    async fn refresh(&self, expired: &str) -> Option<String> {
+        let mut tokens = self.session.tokens.lock().await;
+        if tokens.access_token.expose() != expired {
+            return Some(tokens.access_token.expose().clone());
+        }
+        let refresh_token = tokens.refresh_token.clone()?;
+        let refreshed = self
+            .http
+            .post(format!(
+                "{}/_matrix/client/v3/refresh",
+                self.session.homeserver_url.trim_end_matches('/')
+            ))
+            .json(&serde_json::json!({ "refresh_token": refresh_token.expose() }))
+            .send()
+            .await
+            .and_then(|response| response.error_for_status());
+        let refreshed: Refreshed = match refreshed {
+            Ok(response) => response.json().await,
+            Err(err) => Err(err),
+        }
+        .map_err(|err| log::error!("failed to refresh the access token: {}", redact(err)))
+        .ok()?;
+        tokens.access_token = refreshed.access_token;
+        if refreshed.refresh_token.is_some() {
+            tokens.refresh_token = refreshed.refresh_token;
+        }
+        log::warn!("Refreshed the access token of the bot");
+        let saved = tokens.clone();
+        drop(tokens);
+        if let Some(file) = &self.session.file {
+            if let Err(err) = save(file, &saved).await {
+                log::error!(
+                    "failed to save the refreshed tokens to {}: {:#}",
+                    file.display(),
+                    err
+                );
+            }
+        }
+        Some(saved.access_token.expose().clone())
+    }
+}
+
+/// Add the `user_id` query parameter through which an appservice acts as `sender`.
+fn impersonate(url: &str, sender: &ruma::UserId) -> String {
+    match url::Url::parse(url) {
+        Ok(mut url) => {
+            url.query_pairs_mut()
+                .append_pair("user_id", sender.as_str());
+            url.into()
+        }
+        Err(_) => url.to_string(),
+    }
+}
+
+/// Whether the homeserver rejected an expired access token that a refresh may replace.
+fn soft_logout(response: &http::Response<Vec<u8>>) -> bool {
+    response.status() == StatusCode::UNAUTHORIZED
+        && serde_json::from_slice::<ErrorBody>(response.body())
+            .is_ok_and(|body| body.errcode == "M_UNKNOWN_TOKEN" && body.soft_logout)
+}
+
+impl ruma::client::HttpClient for RefreshingClient {
+    type RequestBody = Vec<u8>;
+    type ResponseBody = Vec<u8>;
+    type Error = ClientError;
+
+    async fn send_http_request(
+        &self,
+        request: http::Request<Vec<u8>>,
+    ) -> Result<http::Response<Vec<u8>>, ClientError> {
+        let _permit = self.throttle.acquire().await.map_err(ClientError::Busy)?;
+        // Only requests ruma authenticates carry the token, and then always the current one.
+        if !request.headers().contains_key(AUTHORIZATION) {
+            return Ok(self.send(&request, None).await?);
+        }
+        let access_token = self
+            .session
+            .tokens
+            .lock()
+            .await
+            .access_token
+            .expose()
+            .clone();
+        let response = self.send(&request, Some(&access_token)).await?;
+        if !soft_logout(&response) {
+            return Ok(response);
+        }
+        match self.refresh(&access_token).await {
+            Some(access_token) => Ok(self.send(&request, Some(&access_token)).await?),
+            None => Ok(response),
+        }
+    }
+}
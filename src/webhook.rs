@@ -0,0 +1,168 @@
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+use ring::hmac;
+use ruma::{RoomId, UserId};
+
+use crate::{
+    hex,
+    secret::{redact, Secret},
+    AppState,
+};
+
+/// Bumped whenever a field of [`Event`] changes meaning or goes away.
+pub const SCHEMA_VERSION: u32 = 2;
+
+const TIMEOUT: Duration = Duration::from_secs(5);
+const ATTEMPTS: u32 = 3;
+const SIGNATURE_HEADER: &str = "X-Bouncer-Signature";
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum EventKind {
+    InviteSent,
+    InviteDenied,
+    InviteFailed,
+    /// Invite that passed every check but was not sent, with `--dry-run`.
+    InviteDryRun,
+    /// Synthetic event sent through the admin api to check delivery.
+    Test,
+}
+
+impl EventKind {
+    pub fn label(&self) -> &'static str {
+        match self {
+            EventKind::InviteSent => "invited",
+            EventKind::InviteDenied => "denied",
+            EventKind::InviteFailed => "failed",
+            EventKind::InviteDryRun => "dry run",
+            EventKind::Test => "test",
+        }
+    }
+}
+
+#[derive(serde::Serialize)]
+pub struct Event {
+    pub version: u32,
+    pub event: EventKind,
+    /// Null for email invites, which carry `email_sha256` instead.
+    pub user_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub email_sha256: Option<String>,
+    pub room_id: String,
+    pub github_login: String,
+    pub reason: Option<String>,
+    pub timestamp: DateTime<Utc>,
+    /// Admin who sent the invite through the admin API, for invites without a GitHub login.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub initiated_by: Option<String>,
+}
+
+impl Event {
+    pub fn new(
+        event: EventKind,
+        user_id: &UserId,
+        room_id: &RoomId,
+        github_login: &str,
+        reason: Option<String>,
+    ) -> Self {
+        Event {
+            version: SCHEMA_VERSION,
+            event,
+            user_id: Some(user_id.to_string()),
+            email_sha256: None,
+            room_id: room_id.to_string(),
+            github_login: github_login.to_string(),
+            reason,
+            timestamp: Utc::now(),
+            initiated_by: None,
+        }
+    }
+
+    /// Event of an email invite, identified by the hash of the address.
+    pub fn email(
+        event: EventKind,
+        email_sha256: String,
+        room_id: &RoomId,
+        github_login: &str,
+        reason: Option<String>,
+    ) -> Self {
+        Event {
+            version: SCHEMA_VERSION,
+            event,
+            user_id: None,
+            email_sha256: Some(email_sha256),
+            room_id: room_id.to_string(),
+            github_login: github_login.to_string(),
+            reason,
+            timestamp: Utc::now(),
+            initiated_by: None,
+        }
+    }
+}
+
+/// Endpoint receiving invite events as JSON, signed with HMAC-SHA256 when a secret is set.
+pub struct Webhook {
+    url: url::Url,
+    key: Option<hmac::Key>,
+    client: reqwest::Client,
+}
+
+impl Webhook {
+    pub fn new(url: url::Url, secret: Option<Secret<String>>) -> anyhow::Result<Self> {
+        Ok(Webhook {
+            url,
+            key: secret.map(|secret| hmac::Key::new(hmac::HMAC_SHA256, secret.expose().as_bytes())),
+            client: reqwest::Client::builder()
+                .user_agent("Matrix Bouncer")
+                .timeout(TIMEOUT)
+                .build()?,
+        })
+    }
+
+    /// Post an event, retrying on server errors and network failures.
+    pub async fn deliver(&self, event: &Event) -> Result<(), String> {
+        let body = serde_json::to_vec(event).map_err(|err| err.to_string())?;
+        let signature = self
+            .key
+            .as_ref()
+            .map(|key| format!("sha256={}", hex(hmac::sign(key, &body).as_ref())));
+
+        let mut error = String::new();
+        for attempt in 1..=ATTEMPTS {
+            let mut request = self
+                .client
+                .post(self.url.clone())
+                .header(reqwest::header::CONTENT_TYPE, "application/json")
+                .body(body.clone());
+            if let Some(signature) = &signature {
+                request = request.header(SIGNATURE_HEADER, signature);
+            }
+            match request.send().await {
+                Ok(response) if response.status().is_success() => return Ok(()),
+                Ok(response) if !response.status().is_server_error() => {
+                    return Err(format!("webhook answered {}", response.status()));
+                }
+                Ok(response) => error = format!("webhook answered {}", response.status()),
+                Err(err) => error = err.to_string(),
+            }
+            log::warn!("webhook delivery attempt {} failed: {}", attempt, error);
+            if attempt < ATTEMPTS {
+                tokio::time::sleep(Duration::from_secs(attempt.into())).await;
+            }
+        }
+        Err(error)
+    }
+}
+
+/// Deliver an event in the background, if a webhook is configured.
+pub fn notify(state: &AppState, event: Event) {
+    let Some(webhook) = state.webhook.clone() else {
+        return;
+    };
+    tokio::spawn(async move {
+        if let Err(err) = webhook.deliver(&event).await {
+            log::error!("failed to deliver webhook event: {}", redact(&err));
+        }
+    });
+}
@@ -0,0 +1,126 @@
+//! The client address behind trusted reverse proxies.
+
+use std::net::IpAddr;
+
+use axum::http::{HeaderMap, HeaderValue};
+use bouncer::client_ip::{client_ip, Cidr};
+
+fn ip(address: &str) -> IpAddr {
+    address.parse().unwrap()
+}
+
+fn headers(pairs: &[(&'static str, &str)]) -> HeaderMap {
+    let mut headers = HeaderMap::new();
+    for (name, value) in pairs {
+        headers.append(*name, HeaderValue::from_str(value).unwrap());
+    }
+    headers
+}
+
+fn trusted(ranges: &[&str]) -> Vec<Cidr> {
+    ranges.iter().map(|range| range.parse().unwrap()).collect()
+}
+
+#[test]
+fn ignores_headers_without_trusted_proxies() {
+    let headers = headers(&[("x-forwarded-for", "198.51.100.7")]);
+    assert_eq!(
+        client_ip(ip("203.0.113.1"), &headers, &[]),
+        ip("203.0.113.1")
+    );
+}
+
+#[test]
+fn ignores_headers_from_untrusted_peers() {
+    let headers = headers(&[
+        ("x-forwarded-for", "198.51.100.7"),
+        ("forwarded", "for=198.51.100.7"),
+    ]);
+    assert_eq!(
+        client_ip(ip("203.0.113.1"), &headers, &trusted(&["10.0.0.0/8"])),
+        ip("203.0.113.1")
+    );
+}
+
+#[test]
+fn walks_trusted_hops_from_the_right() {
+    // The client spoofed the first entry; only the hops appended by trusted proxies count.
+    let headers = headers(&[("x-forwarded-for", "192.0.2.66, 198.51.100.7, 10.0.0.2")]);
+    assert_eq!(
+        client_ip(ip("10.0.0.1"), &headers, &trusted(&["10.0.0.0/8"])),
+        ip("198.51.100.7")
+    );
+}
+
+#[test]
+fn joins_repeated_headers() {
+    let headers = headers(&[
+        ("x-forwarded-for", "192.0.2.66"),
+        ("x-forwarded-for", "198.51.100.7"),
+    ]);
+    assert_eq!(
+        client_ip(ip("10.0.0.1"), &headers, &trusted(&["10.0.0.1"])),
+        ip("198.51.100.7")
+    );
+}
+
+#[test]
+fn handles_ipv6() {
+    let headers = headers(&[("x-forwarded-for", "2001:db8::7, [2001:db8:1::2]:443")]);
+    assert_eq!(
+        client_ip(ip("::1"), &headers, &trusted(&["::1", "2001:db8:1::/48"])),
+        ip("2001:db8::7")
+    );
+    // IPv4 peers of a dual-stack listener arrive mapped into IPv6.
+    let headers = self::headers(&[("x-forwarded-for", "198.51.100.7")]);
+    assert_eq!(
+        client_ip(ip("::ffff:127.0.0.1"), &headers, &trusted(&["127.0.0.1"])),
+        ip("198.51.100.7")
+    );
+}
+
+#[test]
+fn prefers_forwarded() {
+    let headers = headers(&[
+        ("x-forwarded-for", "192.0.2.66"),
+        (
+            "forwarded",
+            r#"for=192.0.2.43, for="[2001:db8:cafe::17]:4711";proto=https"#,
+        ),
+    ]);
+    assert_eq!(
+        client_ip(ip("10.0.0.1"), &headers, &trusted(&["10.0.0.1"])),
+        ip("2001:db8:cafe::17")
+    );
+}
+
+#[test]
+fn stops_at_malformed_hops() {
+    let trusted = trusted(&["10.0.0.0/8"]);
+    let headers = self::headers(&[("x-forwarded-for", "198.51.100.7, not-an-ip, 10.0.0.2")]);
+    assert_eq!(
+        client_ip(ip("10.0.0.1"), &headers, &trusted),
+        ip("10.0.0.2")
+    );
+    let headers = self::headers(&[("forwarded", "for=198.51.100.7, for=_hidden")]);
+    assert_eq!(
+        client_ip(ip("10.0.0.1"), &headers, &trusted),
+        ip("10.0.0.1")
+    );
+    let headers = self::headers(&[("x-forwarded-for", "")]);
+    assert_eq!(
+        client_ip(ip("10.0.0.1"), &headers, &trusted),
+        ip("10.0.0.1")
+    );
+}
+
+#[test]
+fn parses_ranges() {
+    assert!("10.0.0.0/8".parse::<Cidr>().is_ok());
+    assert!("2001:db8::/32".parse::<Cidr>().is_ok());
+    assert!("10.0.0.0/33".parse::<Cidr>().is_err());
+    assert!("example.com".parse::<Cidr>().is_err());
+    let everything: Cidr = "0.0.0.0/0".parse().unwrap();
+    assert!(everything.contains(ip("203.0.113.1")));
+    assert!(!everything.contains(ip("2001:db8::1")));
+}
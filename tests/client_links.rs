@@ -0,0 +1,106 @@
+//! Links opening an invited room in a Matrix client.
+
+use bouncer::{
+    client_links::{element, matrix_to, room_reference},
+    RoomInfo,
+};
+use ruma::{server_name, space::SpaceRoomJoinRule};
+
+fn room(alias: Option<&str>) -> RoomInfo {
+    RoomInfo {
+        room_id: "!abc123:example.com".try_into().unwrap(),
+        canonical_alias: alias.map(|alias| alias.try_into().unwrap()),
+        name: Some("Example".to_string()),
+        join_rule: SpaceRoomJoinRule::Invite,
+        suggested: false,
+        parent: None,
+        members: None,
+        topic: None,
+        avatar_url: None,
+        predecessor: None,
+        replacement: None,
+    }
+}
+
+#[test]
+fn encodes_aliases() {
+    let room = room(Some("#rust-lang_café:example.com"));
+    let via = server_name!("matrix.example.com");
+    assert_eq!(
+        room_reference(&room, via),
+        "%23rust-lang_caf%C3%A9%3Aexample.com"
+    );
+    assert_eq!(
+        matrix_to(&room, via),
+        "https://matrix.to/#/%23rust-lang_caf%C3%A9%3Aexample.com"
+    );
+}
+
+#[test]
+fn joins_room_ids_via_the_bot_server() {
+    let room = room(None);
+    assert_eq!(
+        matrix_to(&room, server_name!("matrix.example.com:8448")),
+        "https://matrix.to/#/%21abc123%3Aexample.com?via=matrix.example.com%3A8448"
+    );
+}
+
+#[test]
+fn builds_element_links() {
+    let room = room(Some("#general:example.com"));
+    let via = server_name!("example.com");
+    assert_eq!(
+        element("https://chat.example.com/", &room, via),
+        "https://chat.example.com/#/room/%23general%3Aexample.com"
+    );
+    assert_eq!(
+        element("element://vector/webapp", &room, via),
+        "element://vector/webapp/#/room/%23general%3Aexample.com"
+    );
+}
+
+/// Rasterize the rectangles of the SVG path, one pixel per unit.
+fn rasterize(svg: &str) -> (usize, Vec<bool>) {
+    let size = regex::Regex::new(r#"width="(\d+)""#)
+        .unwrap()
+        .captures(svg)
+        .unwrap()[1]
+        .parse::<usize>()
+        .unwrap();
+    let mut pixels = vec![false; size * size];
+    let rects = regex::Regex::new(r"M(\d+) (\d+)h(\d+)v(\d+)").unwrap();
+    for rect in rects.captures_iter(svg) {
+        let [left, top, width, height] = [1, 2, 3, 4].map(|i| rect[i].parse::<usize>().unwrap());
+        for y in top..top + height {
+            for x in left..left + width {
+                pixels[y * size + x] = true;
+            }
+        }
+    }
+    (size, pixels)
+}
+
+#[test]
+fn qr_code_encodes_the_link() {
+    let url = matrix_to(&room(None), server_name!("matrix.example.com"));
+    let svg = bouncer::client_links::qr_code(&url).unwrap();
+    assert!(svg.starts_with("<svg"), "{}", svg);
+
+    let (size, pixels) = rasterize(&svg);
+    assert!(size >= 240, "{}", size);
+    let code = qrcode::QrCode::new(&url).unwrap();
+    let width = code.width();
+    // The code is drawn with a quiet zone of four modules on every side.
+    let module = size / (width + 8);
+    assert_eq!(module * (width + 8), size);
+    for (index, color) in code.to_colors().into_iter().enumerate() {
+        let (x, y) = (index % width + 4, index / width + 4);
+        let center = (y * module + module / 2) * size + x * module + module / 2;
+        assert_eq!(
+            pixels[center],
+            color == qrcode::Color::Dark,
+            "module {}",
+            index
+        );
+    }
+}
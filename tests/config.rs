@@ -0,0 +1,57 @@
+//! Validation of settings at startup.
+
+use bouncer::config::{Args, Config};
+use clap::Parser;
+
+fn config(flags: &[&str]) -> anyhow::Result<Config> {
+    let mut args = vec![
+        "bouncer",
+        "--homeserver-url",
+        "https://matrix.example.com",
+        "--access-token",
+        "syt_accesstoken",
+        "--github-client-id",
+        "client",
+        "--github-client-secret",
+        "clientsecret",
+        "--turnstile-secret-key",
+        "turnstilesecret",
+        "--listen-address",
+        "127.0.0.1:8080",
+    ];
+    args.extend(flags);
+    Config::from_args(Args::try_parse_from(args).unwrap())
+}
+
+#[test]
+fn accepts_the_callback_under_the_base_path() {
+    config(&[
+        "--github-redirect-url",
+        "https://bouncer.example.com/callback",
+    ])
+    .unwrap();
+    config(&[
+        "--base-path",
+        "/bouncer/",
+        "--github-redirect-url",
+        "https://example.com/bouncer/callback",
+    ])
+    .unwrap();
+}
+
+#[test]
+fn refuses_redirect_urls_missing_the_callback() {
+    let err = config(&[
+        "--base-path",
+        "/bouncer",
+        "--github-redirect-url",
+        "https://example.com/callback",
+    ])
+    .unwrap_err();
+    assert!(
+        err.to_string()
+            .contains("does not point at /bouncer/callback"),
+        "{}",
+        err
+    );
+}
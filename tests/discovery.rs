@@ -0,0 +1,321 @@
+//! Room discovery against a mock homeserver.
+
+use std::{collections::HashSet, sync::Arc, time::Duration};
+
+use bouncer::{
+    discovery::{discover, RoomFilter, SkipReason},
+    secret::Secret,
+    throttle::Throttle,
+    token::{Credentials, Tokens},
+    MatrixClient,
+};
+use ruma::{room_id, OwnedUserId};
+use serde_json::json;
+use wiremock::{
+    matchers::{path, path_regex},
+    Mock, MockServer, ResponseTemplate,
+};
+
+const BOT: &str = "@bouncer:localhost";
+
+/// A homeserver knowing the bot and nothing else; tests mount the rooms they need.
+async fn homeserver() -> MockServer {
+    let homeserver = MockServer::start().await;
+    Mock::given(path("/_matrix/client/versions"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+            "versions": ["v1.1", "v1.2", "v1.3", "v1.4", "v1.5", "v1.6", "v1.7", "v1.8", "v1.9", "v1.10", "v1.11"],
+        })))
+        .mount(&homeserver)
+        .await;
+    Mock::given(path_regex(r"/account/whoami$"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(json!({ "user_id": BOT })))
+        .mount(&homeserver)
+        .await;
+    Mock::given(path_regex(".*"))
+        .respond_with(ResponseTemplate::new(404).set_body_json(json!({
+            "errcode": "M_NOT_FOUND",
+            "error": "Not found",
+        })))
+        .with_priority(u8::MAX)
+        .mount(&homeserver)
+        .await;
+    homeserver
+}
+
+async fn connect(homeserver: &MockServer) -> (MatrixClient, OwnedUserId) {
+    bouncer::connect(
+        homeserver.uri(),
+        Credentials {
+            tokens: Tokens {
+                access_token: Secret::new("syt_test".to_string()),
+                refresh_token: None,
+                origin: None,
+            },
+            state_file: None,
+            appservice_sender: None,
+        },
+        None,
+        Arc::new(Throttle::new(8, Duration::from_secs(10))),
+    )
+    .await
+    .unwrap()
+}
+
+async fn joined_rooms(homeserver: &MockServer, rooms: &[&str]) {
+    Mock::given(path_regex(r"/joined_rooms$"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(json!({ "joined_rooms": rooms })))
+        .mount(homeserver)
+        .await;
+}
+
+/// Power levels letting the bot invite in every room.
+async fn invite_permission(homeserver: &MockServer) {
+    Mock::given(path_regex(r"/state/m\.room\.power_levels/?$"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+            "users": { BOT: 100 },
+            "invite": 50,
+        })))
+        .mount(homeserver)
+        .await;
+}
+
+/// Power levels of the rooms whose id contains `localpart`, taking precedence over
+/// [`invite_permission`].
+async fn power_levels(homeserver: &MockServer, localpart: &str, response: ResponseTemplate) {
+    Mock::given(path_regex(format!(
+        r"/rooms/[^/]*{}[^/]*/state/m\.room\.power_levels/?$",
+        localpart
+    )))
+    .respond_with(response)
+    .with_priority(1)
+    .mount(homeserver)
+    .await;
+}
+
+/// MSC3266 summary of a room. Every room that gets summarized needs one, as a 404 would switch
+/// this process over to reading room state.
+async fn summary(homeserver: &MockServer, room_id: &str, name: &str) {
+    let localpart = room_id.trim_start_matches('!').split(':').next().unwrap();
+    Mock::given(path_regex(format!(r"summary.*(!|%21){}(:|%3A)", localpart)))
+        .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+            "room_id": room_id,
+            "name": name,
+            "num_joined_members": 5,
+            "join_rule": "invite",
+            "guest_can_join": false,
+            "world_readable": false,
+            "membership": "join",
+        })))
+        .mount(homeserver)
+        .await;
+}
+
+fn chunk(room_id: &str, children: &[&str]) -> serde_json::Value {
+    json!({
+        "room_id": room_id,
+        "num_joined_members": 3,
+        "world_readable": false,
+        "guest_can_join": false,
+        "join_rule": "invite",
+        "room_type": if children.is_empty() { None } else { Some("m.space") },
+        "children_state": children.iter().map(|child| json!({
+            "type": "m.space.child",
+            "state_key": child,
+            "content": { "via": ["localhost"] },
+            "sender": BOT,
+            "origin_server_ts": 0,
+        })).collect::<Vec<_>>(),
+    })
+}
+
+#[tokio::test]
+async fn filters_space_children() {
+    let homeserver = homeserver().await;
+    joined_rooms(
+        &homeserver,
+        &["!space:localhost", "!a:localhost", "!b:localhost"],
+    )
+    .await;
+    invite_permission(&homeserver).await;
+    Mock::given(path_regex(r"/hierarchy$"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+            "rooms": [
+                chunk("!space:localhost", &["!a:localhost", "!b:localhost"]),
+                chunk("!a:localhost", &[]),
+                chunk("!b:localhost", &[]),
+            ],
+        })))
+        .mount(&homeserver)
+        .await;
+    let (client, user_id) = connect(&homeserver).await;
+
+    let filter = RoomFilter {
+        include: Some(HashSet::from([room_id!("!a:localhost").to_owned()])),
+        include_dm_rooms: true,
+        spaces: vec![room_id!("!space:localhost").to_owned()],
+        concurrency: 1,
+        ..Default::default()
+    };
+    let discovery = discover(&client, &user_id, &filter).await.unwrap();
+    assert_eq!(discovery.rooms.len(), 1);
+    assert!(discovery.rooms.contains_key(room_id!("!a:localhost")));
+    assert!(discovery
+        .skipped
+        .contains(&(room_id!("!b:localhost").to_owned(), SkipReason::Excluded)));
+
+    let filter = RoomFilter {
+        exclude: HashSet::from([room_id!("!a:localhost").to_owned()]),
+        include_dm_rooms: true,
+        spaces: vec![room_id!("!space:localhost").to_owned()],
+        concurrency: 1,
+        ..Default::default()
+    };
+    let discovery = discover(&client, &user_id, &filter).await.unwrap();
+    assert!(!discovery.rooms.contains_key(room_id!("!a:localhost")));
+    assert!(discovery.rooms.contains_key(room_id!("!b:localhost")));
+}
+
+#[tokio::test]
+async fn records_why_rooms_are_skipped() {
+    let homeserver = homeserver().await;
+    joined_rooms(
+        &homeserver,
+        &[
+            "!open:localhost",
+            "!locked:localhost",
+            "!excluded:localhost",
+            "!direct:localhost",
+        ],
+    )
+    .await;
+    invite_permission(&homeserver).await;
+    power_levels(
+        &homeserver,
+        "locked",
+        ResponseTemplate::new(200).set_body_json(json!({
+            "users": { BOT: 0 },
+            "invite": 50,
+        })),
+    )
+    .await;
+    Mock::given(path_regex(r"/account_data/m\.direct$"))
+        .respond_with(
+            ResponseTemplate::new(200)
+                .set_body_json(json!({ "@friend:localhost": ["!direct:localhost"] })),
+        )
+        .mount(&homeserver)
+        .await;
+    summary(&homeserver, "!open:localhost", "Open").await;
+    let (client, user_id) = connect(&homeserver).await;
+
+    let filter = RoomFilter {
+        exclude: HashSet::from([room_id!("!excluded:localhost").to_owned()]),
+        concurrency: 2,
+        ..Default::default()
+    };
+    let discovery = discover(&client, &user_id, &filter).await.unwrap();
+    assert_eq!(discovery.rooms.len(), 1);
+    assert_eq!(
+        discovery.rooms[room_id!("!open:localhost")].name.as_deref(),
+        Some("Open")
+    );
+    let mut skipped = discovery.skipped;
+    skipped.sort_by(|a, b| a.0.cmp(&b.0));
+    assert_eq!(
+        skipped,
+        [
+            (
+                room_id!("!direct:localhost").to_owned(),
+                SkipReason::DirectMessage
+            ),
+            (
+                room_id!("!excluded:localhost").to_owned(),
+                SkipReason::Excluded
+            ),
+            (
+                room_id!("!locked:localhost").to_owned(),
+                SkipReason::NoInvitePermission
+            ),
+        ]
+    );
+}
+
+#[tokio::test]
+async fn skips_configured_rooms_the_bot_is_not_in() {
+    let homeserver = homeserver().await;
+    joined_rooms(&homeserver, &["!open:localhost", "!other:localhost"]).await;
+    invite_permission(&homeserver).await;
+    summary(&homeserver, "!open:localhost", "Open").await;
+    let (client, user_id) = connect(&homeserver).await;
+
+    let filter = RoomFilter {
+        include: Some(HashSet::from([
+            room_id!("!open:localhost").to_owned(),
+            room_id!("!gone:localhost").to_owned(),
+        ])),
+        include_dm_rooms: true,
+        concurrency: 1,
+        ..Default::default()
+    };
+    let discovery = discover(&client, &user_id, &filter).await.unwrap();
+    assert_eq!(discovery.rooms.len(), 1);
+    assert!(discovery.rooms.contains_key(room_id!("!open:localhost")));
+    assert!(discovery.skipped.contains(&(
+        room_id!("!gone:localhost").to_owned(),
+        SkipReason::NotJoined
+    )));
+    assert!(discovery.skipped.contains(&(
+        room_id!("!other:localhost").to_owned(),
+        SkipReason::Excluded
+    )));
+}
+
+#[tokio::test]
+async fn assumes_default_power_levels_when_unusable() {
+    let homeserver = homeserver().await;
+    joined_rooms(
+        &homeserver,
+        &[
+            "!missing:localhost",
+            "!malformed:localhost",
+            "!broken:localhost",
+        ],
+    )
+    .await;
+    // The catch-all answers M_NOT_FOUND for the power levels of the first room.
+    power_levels(
+        &homeserver,
+        "malformed",
+        ResponseTemplate::new(200).set_body_json(json!({ "users": "everyone", "invite": [] })),
+    )
+    .await;
+    power_levels(
+        &homeserver,
+        "broken",
+        ResponseTemplate::new(500).set_body_json(json!({
+            "errcode": "M_UNKNOWN",
+            "error": "Internal server error",
+        })),
+    )
+    .await;
+    summary(&homeserver, "!missing:localhost", "Missing").await;
+    summary(&homeserver, "!malformed:localhost", "Malformed").await;
+    let (client, user_id) = connect(&homeserver).await;
+
+    let filter = RoomFilter {
+        include_dm_rooms: true,
+        concurrency: 1,
+        ..Default::default()
+    };
+    let discovery = discover(&client, &user_id, &filter).await.unwrap();
+    assert_eq!(discovery.rooms.len(), 2);
+    assert!(discovery.rooms.contains_key(room_id!("!missing:localhost")));
+    assert!(discovery
+        .rooms
+        .contains_key(room_id!("!malformed:localhost")));
+    // Other errors skip only the room.
+    assert_eq!(
+        discovery.skipped,
+        [(room_id!("!broken:localhost").to_owned(), SkipReason::Failed)]
+    );
+}
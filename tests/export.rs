@@ -0,0 +1,110 @@
+//! CSV export of the audit store, read back the way a spreadsheet would.
+
+use bouncer::{
+    export::{entry_row, row, ExportQuery, HEADER},
+    store::{Denial, Entry},
+    webhook::EventKind,
+};
+
+/// Split RFC 4180 text into rows of fields.
+fn parse(csv: &str) -> Vec<Vec<String>> {
+    let mut rows = vec![];
+    let mut fields = vec![];
+    let mut field = String::new();
+    let mut quoted = false;
+    let mut chars = csv.chars().peekable();
+    while let Some(c) = chars.next() {
+        match (quoted, c) {
+            (true, '"') if chars.peek() == Some(&'"') => {
+                chars.next();
+                field.push('"');
+            }
+            (true, '"') => quoted = false,
+            (true, c) => field.push(c),
+            (false, '"') => quoted = true,
+            (false, ',') => fields.push(std::mem::take(&mut field)),
+            (false, '\r') => assert_eq!(chars.next(), Some('\n'), "bare CR in {:?}", csv),
+            (false, '\n') => panic!("bare LF in {:?}", csv),
+            (false, c) => field.push(c),
+        }
+        if !quoted && chars.peek().is_none() || (!quoted && c == '\r') {
+            fields.push(std::mem::take(&mut field));
+            rows.push(std::mem::take(&mut fields));
+        }
+    }
+    rows
+}
+
+fn entry(user_id: &str, github_login: &str, reason: &str) -> Entry {
+    Entry {
+        timestamp: "2024-05-01T12:30:00Z".parse().unwrap(),
+        event: EventKind::InviteDenied,
+        user_id: Some(user_id.try_into().unwrap()),
+        email_sha256: None,
+        room_id: "!room:example.com".try_into().unwrap(),
+        github_login: github_login.to_string(),
+        reason: Some(reason.to_string()),
+        accepted: None,
+        expired: None,
+        terms: None,
+        initiated_by: None,
+        reason_code: Some(Denial::GithubAccountAge),
+    }
+}
+
+#[test]
+fn round_trips_awkward_values() {
+    let values = [
+        "plain",
+        "comma, separated",
+        "\"quoted\" twice \"\"",
+        "line\r\nbreak and\nnewline",
+        "@ünïcødé:例え.jp",
+        "",
+    ];
+    let csv = row(values);
+    assert_eq!(parse(&csv), vec![values.to_vec()]);
+}
+
+#[test]
+fn writes_header_and_entries() {
+    let entries = [
+        entry(
+            "@alice:example.com",
+            "octocat",
+            "needs a GitHub account, older",
+        ),
+        entry("@bob:example.org", "hubot", "said \"hi\"\nand left"),
+    ];
+    let mut csv = row(HEADER.iter().copied());
+    for entry in &entries {
+        csv.push_str(&entry_row(entry));
+    }
+    let rows = parse(&csv);
+    assert_eq!(rows.len(), 3, "{:?}", rows);
+    assert_eq!(rows[0], HEADER);
+    for (row, entry) in rows[1..].iter().zip(&entries) {
+        assert_eq!(row.len(), HEADER.len());
+        assert_eq!(row[0], "2024-05-01T12:30:00Z");
+        assert_eq!(row[1], "invite_denied");
+        assert_eq!(row[2], entry.user_id.as_ref().unwrap().as_str());
+        assert_eq!(row[4], "!room:example.com");
+        assert_eq!(row[5], entry.github_login);
+        assert_eq!(row[6], *entry.reason.as_ref().unwrap());
+        assert_eq!(row[7], "github_account_age");
+        assert_eq!(row[8], "");
+    }
+}
+
+#[test]
+fn filters_entries() {
+    let entry = entry("@alice:example.com", "OctoCat", "");
+    let query = |query: &str| serde_html_form::from_str::<ExportQuery>(query).unwrap();
+    assert!(query("").matches(&entry));
+    assert!(query("github_login=octocat").matches(&entry));
+    assert!(!query("github_login=hubot").matches(&entry));
+    assert!(query("room_id=%21room%3Aexample.com").matches(&entry));
+    assert!(!query("room_id=%21other%3Aexample.com").matches(&entry));
+    assert!(query("since=2024-05-01T00:00:00Z&until=2024-05-02T00:00:00Z").matches(&entry));
+    assert!(!query("until=2024-05-01T12:30:00Z").matches(&entry));
+}
@@ -0,0 +1,34 @@
+//! Country restrictions, failing open for clients the database does not locate.
+
+use std::collections::HashSet;
+
+use bouncer::geoip::GeoIp;
+
+fn codes(codes: &[&str]) -> HashSet<String> {
+    codes.iter().map(|code| code.to_string()).collect()
+}
+
+#[test]
+fn blocks_listed_countries() {
+    let geoip = GeoIp::new(None, codes(&["AQ"]), codes(&[])).unwrap();
+    assert!(geoip.blocks(Some("AQ")));
+    assert!(!geoip.blocks(Some("DE")));
+    assert!(!geoip.blocks(None));
+}
+
+#[test]
+fn allows_only_listed_countries() {
+    let geoip = GeoIp::new(None, codes(&["FR"]), codes(&["DE", "FR"])).unwrap();
+    assert!(!geoip.blocks(Some("DE")));
+    assert!(geoip.blocks(Some("US")));
+    // Blocking wins over allowing.
+    assert!(geoip.blocks(Some("FR")));
+    assert!(!geoip.blocks(None));
+}
+
+#[test]
+fn refuses_missing_database() {
+    let path = std::env::temp_dir().join("bouncer-missing-geoip.mmdb");
+    let _ = std::fs::remove_file(&path);
+    assert!(GeoIp::new(Some(path), codes(&[]), codes(&[])).is_err());
+}
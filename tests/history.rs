@@ -0,0 +1,217 @@
+//! Invite history of the admin API, read from a seeded audit store.
+
+use std::collections::HashMap;
+
+use bouncer::{
+    history::{self, HistoryQuery, MAX_LIMIT},
+    store::{self, Store},
+};
+use serde_json::json;
+
+/// An audit store in a temporary file, holding one entry per
+/// `(minute, event, user, login, reason, reason code)`.
+fn seeded(name: &str, entries: &[(u32, &str, &str, &str, &str, Option<&str>)]) -> Store {
+    let path = std::env::temp_dir().join(format!("bouncer-history-{}.json", name));
+    let entries = entries
+        .iter()
+        .map(|(minute, event, user_id, login, reason, code)| {
+            json!({
+                "timestamp": format!("2024-05-01T12:{:02}:00Z", minute),
+                "event": event,
+                "user_id": user_id,
+                "room_id": "!room:example.com",
+                "github_login": login,
+                "reason": reason,
+                "reason_code": code,
+            })
+        })
+        .collect::<Vec<_>>();
+    let log = entries
+        .iter()
+        .map(|entry| format!("{}\n", entry))
+        .collect::<String>();
+    std::fs::write(store::log_path(&path), log).unwrap();
+    Store::open(&path, None).unwrap()
+}
+
+fn query(params: &[(&str, &str)]) -> Result<HistoryQuery, HashMap<String, String>> {
+    HistoryQuery::parse(
+        &params
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect(),
+    )
+}
+
+async fn page(store: &Store, params: &[(&str, &str)]) -> serde_json::Value {
+    let query = query(params).unwrap();
+    serde_json::to_value(
+        store
+            .entries(|entries| history::page(entries, &query))
+            .await,
+    )
+    .unwrap()
+}
+
+#[tokio::test]
+async fn pages_newest_first() {
+    let store = seeded(
+        "pages",
+        &[
+            (
+                0,
+                "invite_sent",
+                "@a:example.com",
+                "octocat",
+                "vouched",
+                None,
+            ),
+            (
+                1,
+                "invite_sent",
+                "@b:example.com",
+                "octocat",
+                "vouched",
+                None,
+            ),
+            (
+                2,
+                "invite_denied",
+                "@c:example.com",
+                "hubot",
+                "banned by a policy list",
+                Some("policy_list"),
+            ),
+            (
+                3,
+                "invite_sent",
+                "@d:example.com",
+                "octocat",
+                "vouched",
+                None,
+            ),
+            (
+                4,
+                "invite_sent",
+                "@e:example.com",
+                "octocat",
+                "vouched",
+                None,
+            ),
+        ],
+    );
+    let users = |page: &serde_json::Value| {
+        page["entries"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|entry| entry["user_id"].as_str().unwrap().to_string())
+            .collect::<Vec<_>>()
+    };
+
+    let first = page(&store, &[("github_login", "OctoCat"), ("limit", "2")]).await;
+    assert_eq!(users(&first), ["@e:example.com", "@d:example.com"]);
+    let next = first["next"].as_str().expect("no cursor on the first page");
+    let second = page(
+        &store,
+        &[
+            ("github_login", "octocat"),
+            ("limit", "2"),
+            ("cursor", next),
+        ],
+    )
+    .await;
+    assert_eq!(users(&second), ["@b:example.com", "@a:example.com"]);
+    assert!(second["next"].is_null(), "{}", second);
+
+    // Free text of the reasons stays out, only its category is given.
+    let denied = page(&store, &[("decision", "invite_denied")]).await;
+    assert_eq!(users(&denied), ["@c:example.com"]);
+    assert_eq!(denied["entries"][0]["reason_category"], "policy_list");
+    assert!(denied["entries"][0].get("reason").is_none(), "{}", denied);
+    assert!(!first.to_string().contains("vouched"), "{}", first);
+
+    let window = page(
+        &store,
+        &[
+            ("since", "2024-05-01T12:01:00Z"),
+            ("until", "2024-05-01T12:03:00Z"),
+            ("user_id", "@b:example.com"),
+        ],
+    )
+    .await;
+    assert_eq!(users(&window), ["@b:example.com"]);
+}
+
+#[test]
+fn reports_invalid_fields() {
+    let too_many = (MAX_LIMIT + 1).to_string();
+    let errors = query(&[
+        ("user_id", "alice"),
+        ("room_id", "#alias:example.com"),
+        ("decision", "maybe"),
+        ("limit", too_many.as_str()),
+        ("since", "yesterday"),
+        ("sort", "oldest"),
+    ])
+    .unwrap_err();
+    let mut fields = errors.keys().map(String::as_str).collect::<Vec<_>>();
+    fields.sort_unstable();
+    assert_eq!(
+        fields,
+        ["decision", "limit", "room_id", "since", "sort", "user_id"]
+    );
+    assert!(query(&[("limit", "0")]).is_err());
+    assert!(query(&[
+        ("since", "2024-05-02T00:00:00Z"),
+        ("until", "2024-05-01T00:00:00Z")
+    ])
+    .is_err());
+    assert_eq!(query(&[]).unwrap().limit, history::DEFAULT_LIMIT);
+}
+
+#[tokio::test]
+async fn categorizes_denials_by_their_code() {
+    // Denials keep the reason shown to the requester, in their language.
+    let store = seeded(
+        "codes",
+        &[
+            (
+                0,
+                "invite_denied",
+                "@a:example.com",
+                "octocat",
+                "erfordert ein GitHub-Konto, das älter als 30 Tage ist",
+                Some("github_account_age"),
+            ),
+            (
+                1,
+                "invite_denied",
+                "@b:example.com",
+                "octocat",
+                "über dem täglichen Einladungslimit des GitHub-Kontos",
+                Some("invite_quota"),
+            ),
+            (
+                2,
+                "invite_failed",
+                "@c:example.com",
+                "octocat",
+                "M_FORBIDDEN",
+                None,
+            ),
+        ],
+    );
+
+    let entries = page(&store, &[]).await;
+    let categories = entries["entries"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|entry| entry["reason_category"].as_str().unwrap())
+        .collect::<Vec<_>>();
+    assert_eq!(
+        categories,
+        ["homeserver_error", "invite_quota", "github_account_age"]
+    );
+}
@@ -0,0 +1,49 @@
+//! Catching typos in the homeserver of invitees before the GitHub login.
+
+use bouncer::homeserver::{suggestion, HomeserverCheck};
+use ruma::{server_name, OwnedUserId};
+
+#[test]
+fn suggests_popular_servers() {
+    assert_eq!(suggestion("matirx.org"), Some("matrix.org"));
+    assert_eq!(suggestion("matrx.org"), Some("matrix.org"));
+    assert_eq!(suggestion("Matrix.orgg"), Some("matrix.org"));
+    assert_eq!(suggestion("gitter.in"), Some("gitter.im"));
+    assert_eq!(suggestion("matrix.org"), None);
+    assert_eq!(suggestion("example.com"), None);
+}
+
+fn users(user_ids: &[&str]) -> Vec<OwnedUserId> {
+    user_ids
+        .iter()
+        .map(|user_id| user_id.parse().unwrap())
+        .collect()
+}
+
+#[tokio::test]
+async fn refuses_servers_that_do_not_answer() {
+    let client = reqwest::Client::new();
+    // Nothing listens on port 1.
+    let users = users(&["@alice:localhost:1"]);
+    let (status, message) = HomeserverCheck::new(true)
+        .check(&client, server_name!("example.com"), &users)
+        .await
+        .unwrap_err();
+    assert_eq!(status, 400);
+    assert!(message.contains("localhost:1"), "{}", message);
+
+    assert!(HomeserverCheck::new(false)
+        .check(&client, server_name!("example.com"), &users)
+        .await
+        .is_ok());
+}
+
+#[tokio::test]
+async fn skips_own_server_and_addresses() {
+    let client = reqwest::Client::new();
+    let users = users(&["@alice:localhost:1", "@bob:127.0.0.1:1"]);
+    assert!(HomeserverCheck::new(true)
+        .check(&client, server_name!("localhost:1"), &users)
+        .await
+        .is_ok());
+}
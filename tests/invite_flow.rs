@@ -0,0 +1,1737 @@
+//! End-to-end tests of the invite flow: the bouncer binary runs against mock servers standing
+//! in for the homeserver, GitHub and Turnstile, and is driven through real HTTP requests.
+
+use std::{
+    collections::HashMap,
+    process::{Child, Command, Stdio},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+use reqwest::{header, redirect, StatusCode};
+use serde_json::json;
+use tokio::{
+    io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader},
+    net::TcpListener,
+    task::JoinSet,
+};
+use wiremock::{
+    matchers::{
+        bearer_token, body_partial_json, body_string_contains, method, path, path_regex,
+        query_param,
+    },
+    Mock, MockServer, ResponseTemplate,
+};
+
+const ROOM_ID: &str = "!room:localhost";
+const BOT: &str = "@bouncer:localhost";
+const INVITEE: &str = "@alice:localhost";
+
+/// The bouncer binary, killed when dropped.
+struct Bouncer {
+    child: Child,
+    url: String,
+}
+
+impl Drop for Bouncer {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+struct Upstreams {
+    homeserver: MockServer,
+    github: MockServer,
+    turnstile: MockServer,
+    /// Where the bouncer reaches Turnstile, the mock unless a test puts something in between.
+    turnstile_url: String,
+}
+
+/// Mock servers answering every request the bouncer makes on the happy path.
+async fn upstreams(captcha_passes: bool) -> Upstreams {
+    let homeserver = MockServer::start().await;
+    Mock::given(path("/_matrix/client/versions"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+            "versions": ["v1.1", "v1.2", "v1.3", "v1.4", "v1.5", "v1.6", "v1.7", "v1.8", "v1.9", "v1.10", "v1.11"],
+        })))
+        .mount(&homeserver)
+        .await;
+    Mock::given(path_regex(r"/account/whoami$"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(json!({ "user_id": BOT })))
+        .mount(&homeserver)
+        .await;
+    Mock::given(path_regex(r"/joined_rooms$"))
+        .respond_with(
+            ResponseTemplate::new(200).set_body_json(json!({ "joined_rooms": [ROOM_ID] })),
+        )
+        .mount(&homeserver)
+        .await;
+    Mock::given(path_regex(r"/rooms/[^/]+/state/m\.room\.power_levels/?$"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+            "users": { BOT: 100 },
+            "users_default": 0,
+            "invite": 50,
+        })))
+        .mount(&homeserver)
+        .await;
+    Mock::given(path_regex(r"summary"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+            "room_id": ROOM_ID,
+            "name": "Test Room",
+            "num_joined_members": 5,
+            "join_rule": "invite",
+            "guest_can_join": false,
+            "world_readable": false,
+            "membership": "join",
+        })))
+        .mount(&homeserver)
+        .await;
+    Mock::given(path_regex(r"/profile/"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(json!({ "displayname": "Alice" })))
+        .mount(&homeserver)
+        .await;
+    // Everything else, such as the membership of the invitee, is unknown to the homeserver.
+    Mock::given(path_regex(".*"))
+        .respond_with(ResponseTemplate::new(404).set_body_json(json!({
+            "errcode": "M_NOT_FOUND",
+            "error": "Not found",
+        })))
+        .with_priority(u8::MAX)
+        .mount(&homeserver)
+        .await;
+
+    let github = MockServer::start().await;
+    Mock::given(method("POST"))
+        .and(path("/login/oauth/access_token"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+            "access_token": "gho_test",
+            "token_type": "bearer",
+            "scope": "",
+        })))
+        .mount(&github)
+        .await;
+    Mock::given(method("GET"))
+        .and(path("/user"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+            "login": "octocat",
+            "created_at": "2015-01-01T00:00:00Z",
+        })))
+        .mount(&github)
+        .await;
+
+    let turnstile = MockServer::start().await;
+    Mock::given(method("POST"))
+        .and(path("/turnstile/v0/siteverify"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+            "success": captcha_passes,
+            "error-codes": if captcha_passes { vec![] } else { vec!["invalid-input-response"] },
+        })))
+        .mount(&turnstile)
+        .await;
+
+    Upstreams {
+        homeserver,
+        github,
+        turnstile_url: turnstile.uri(),
+        turnstile,
+    }
+}
+
+/// Expect exactly `count` invites of the invitee, checked when the homeserver mock is dropped.
+async fn expect_invites(upstreams: &Upstreams, count: u64) {
+    Mock::given(method("POST"))
+        .and(path_regex(r"/rooms/[^/]+/invite$"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(json!({})))
+        .expect(count)
+        .mount(&upstreams.homeserver)
+        .await;
+}
+
+/// Start the bouncer on `port`, a fixed port per test, and wait until it is ready.
+async fn start(upstreams: &Upstreams, port: u16) -> Bouncer {
+    start_with(upstreams, port, &[]).await
+}
+
+/// Like [`start`], with additional flags.
+async fn start_with(upstreams: &Upstreams, port: u16, flags: &[&str]) -> Bouncer {
+    let url = format!("http://127.0.0.1:{}", port);
+    let child = Command::new(env!("CARGO_BIN_EXE_bouncer"))
+        .env_clear()
+        .env("RUST_LOG", "warn")
+        .args(["--homeserver-url", &upstreams.homeserver.uri()])
+        .args(["--access-token", "syt_test"])
+        .args(["--github-client-id", "client"])
+        .args(["--github-client-secret", "secret"])
+        .args([
+            "--github-redirect-url",
+            &format!("http://localhost:{}/callback", port),
+        ])
+        .args(["--github-url", &upstreams.github.uri()])
+        .args(["--github-api-url", &upstreams.github.uri()])
+        .args(["--turnstile-url", &upstreams.turnstile_url])
+        .args(["--turnstile-site-key", "site"])
+        .args(["--turnstile-secret-key", "secret"])
+        .args(["--listen-address", &format!("127.0.0.1:{}", port)])
+        .args(["--min-submit-time", "0s"])
+        .args(["--token-check-interval", "0"])
+        .arg("--skip-confirmation")
+        .args(flags)
+        .stdout(Stdio::null())
+        .spawn()
+        .expect("failed to start the bouncer");
+    let mut bouncer = Bouncer { child, url };
+
+    let client = reqwest::Client::new();
+    for _ in 0..300 {
+        if let Some(status) = bouncer.child.try_wait().unwrap() {
+            panic!("the bouncer exited during startup: {}", status);
+        }
+        if client
+            .get(format!("{}/readyz", bouncer.url))
+            .send()
+            .await
+            .is_ok_and(|response| response.status().is_success())
+        {
+            return bouncer;
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+    panic!("the bouncer did not become ready");
+}
+
+fn client() -> reqwest::Client {
+    reqwest::Client::builder()
+        .redirect(redirect::Policy::none())
+        .build()
+        .unwrap()
+}
+
+/// The signed render stamp of the invite form on the index page.
+async fn rendered_stamp(client: &reqwest::Client, bouncer: &Bouncer) -> String {
+    let page = client
+        .get(&bouncer.url)
+        .send()
+        .await
+        .unwrap()
+        .text()
+        .await
+        .unwrap();
+    assert!(page.contains("Test Room"), "room missing from the index");
+    let (_, rest) = page
+        .split_once(r#"name="rendered" value=""#)
+        .expect("render stamp missing from the index");
+    rest.split('"').next().unwrap().to_string()
+}
+
+async fn submit(client: &reqwest::Client, bouncer: &Bouncer) -> reqwest::Response {
+    let rendered = rendered_stamp(client, bouncer).await;
+    client
+        .post(format!("{}/invite", bouncer.url))
+        .form(&[
+            ("room_id", ROOM_ID),
+            ("user_id", INVITEE),
+            ("cf-turnstile-response", "token"),
+            ("website", ""),
+            ("rendered", &rendered),
+        ])
+        .send()
+        .await
+        .unwrap()
+}
+
+/// Cookies set by a response, as sent back in a Cookie header.
+fn cookies(response: &reqwest::Response) -> String {
+    response
+        .headers()
+        .get_all(header::SET_COOKIE)
+        .iter()
+        .filter_map(|cookie| cookie.to_str().ok()?.split(';').next())
+        .collect::<Vec<_>>()
+        .join("; ")
+}
+
+#[tokio::test]
+async fn invites_after_github_login() {
+    let upstreams = upstreams(true).await;
+    expect_invites(&upstreams, 1).await;
+    let bouncer = start(&upstreams, 38401).await;
+    let client = client();
+
+    let response = submit(&client, &bouncer).await;
+    assert_eq!(response.status(), StatusCode::SEE_OTHER);
+    let location = url::Url::parse(response.headers()[header::LOCATION].to_str().unwrap()).unwrap();
+    assert!(location
+        .as_str()
+        .starts_with(&format!("{}/login/oauth/authorize", upstreams.github.uri())));
+    let (_, csrf) = location
+        .query_pairs()
+        .find(|(key, _)| key == "state")
+        .expect("authorize url without state");
+
+    let response = client
+        .get(format!("{}/callback", bouncer.url))
+        .query(&[("code", "code"), ("state", &csrf)])
+        .header(header::COOKIE, cookies(&response))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let page = response.text().await.unwrap();
+    assert!(page.contains("Test Room: invited"), "{}", page);
+}
+
+#[tokio::test]
+async fn refuses_failed_captcha() {
+    let upstreams = upstreams(false).await;
+    expect_invites(&upstreams, 0).await;
+    let bouncer = start(&upstreams, 38402).await;
+
+    let response = submit(&client(), &bouncer).await;
+    assert_eq!(response.status(), StatusCode::FORBIDDEN);
+    let page = response.text().await.unwrap();
+    assert!(
+        page.contains("The captcha could not be verified"),
+        "{}",
+        page
+    );
+}
+
+#[tokio::test]
+async fn refuses_unknown_csrf_token() {
+    let upstreams = upstreams(true).await;
+    expect_invites(&upstreams, 0).await;
+    let bouncer = start(&upstreams, 38403).await;
+
+    let response = client()
+        .get(format!("{}/callback", bouncer.url))
+        .query(&[("code", "code"), ("state", "expired")])
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    let page = response.text().await.unwrap();
+    assert!(page.contains("invalid csrf token"), "{}", page);
+}
+
+#[tokio::test]
+async fn retries_rate_limited_discovery() {
+    let upstreams = upstreams(true).await;
+    Mock::given(path_regex(r"/joined_rooms$"))
+        .respond_with(ResponseTemplate::new(429).set_body_json(json!({
+            "errcode": "M_LIMIT_EXCEEDED",
+            "error": "Too many requests",
+            "retry_after_ms": 10,
+        })))
+        .up_to_n_times(1)
+        .with_priority(1)
+        .expect(1)
+        .mount(&upstreams.homeserver)
+        .await;
+    let bouncer = start(&upstreams, 38404).await;
+
+    // The room is served once the retried discovery went through.
+    rendered_stamp(&client(), &bouncer).await;
+}
+
+#[tokio::test]
+async fn refuses_oversized_form() {
+    let upstreams = upstreams(true).await;
+    expect_invites(&upstreams, 0).await;
+    let bouncer = start(&upstreams, 38405).await;
+
+    let response = client()
+        .post(format!("{}/invite", bouncer.url))
+        .form(&[
+            ("room_id", ROOM_ID),
+            ("user_id", INVITEE),
+            ("website", &"x".repeat(32 * 1024)),
+        ])
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::PAYLOAD_TOO_LARGE);
+    let page = response.text().await.unwrap();
+    assert!(
+        page.contains("The submitted request is too large"),
+        "{}",
+        page
+    );
+}
+
+#[tokio::test]
+async fn times_out_hung_captcha_check() {
+    let upstreams = upstreams(true).await;
+    Mock::given(method("POST"))
+        .and(path("/turnstile/v0/siteverify"))
+        .respond_with(
+            ResponseTemplate::new(200)
+                .set_body_json(json!({ "success": true }))
+                .set_delay(Duration::from_secs(30)),
+        )
+        .with_priority(1)
+        .mount(&upstreams.turnstile)
+        .await;
+    expect_invites(&upstreams, 0).await;
+    let bouncer = start_with(&upstreams, 38406, &["--request-timeout", "1s"]).await;
+
+    let response = submit(&client(), &bouncer).await;
+    assert_eq!(response.status(), StatusCode::GATEWAY_TIMEOUT);
+    let page = response.text().await.unwrap();
+    assert!(page.contains("The request took too long"), "{}", page);
+}
+
+#[tokio::test]
+async fn revalidates_index() {
+    let upstreams = upstreams(true).await;
+    let bouncer = start(&upstreams, 38407).await;
+    let client = client();
+    rendered_stamp(&client, &bouncer).await;
+
+    let response = client.get(&bouncer.url).send().await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    assert!(response.headers().contains_key(header::CACHE_CONTROL));
+    let etag = response.headers()[header::ETAG].clone();
+
+    let response = client
+        .get(&bouncer.url)
+        .header(header::IF_NONE_MATCH, etag.clone())
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::NOT_MODIFIED);
+    assert_eq!(response.headers()[header::ETAG], etag);
+
+    let response = client
+        .get(format!("{}?q=test", bouncer.url))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    assert!(!response.headers().contains_key(header::ETAG));
+}
+
+#[tokio::test]
+async fn revalidates_index_after_a_reload() {
+    let upstreams = upstreams(true).await;
+    let config = std::env::temp_dir().join("bouncer-reload-38437.toml");
+    std::fs::write(&config, "").unwrap();
+    let bouncer = start_with(&upstreams, 38437, &["--config", config.to_str().unwrap()]).await;
+    let client = client();
+    let response = client.get(&bouncer.url).send().await.unwrap();
+    let etag = response.headers()[header::ETAG].clone();
+    assert!(response.text().await.unwrap().contains("Test Room"));
+
+    std::fs::write(
+        &config,
+        format!("[room_config.\"{}\"]\nhidden = true\n", ROOM_ID),
+    )
+    .unwrap();
+    let status = Command::new("kill")
+        .args(["-HUP", &bouncer.child.id().to_string()])
+        .status()
+        .unwrap();
+    assert!(status.success());
+    tokio::time::sleep(Duration::from_secs(1)).await;
+
+    let response = client
+        .get(&bouncer.url)
+        .header(header::IF_NONE_MATCH, etag.clone())
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_ne!(response.headers()[header::ETAG], etag);
+    assert!(!response.text().await.unwrap().contains("Test Room"));
+}
+
+#[tokio::test]
+async fn serves_favicon_and_robots() {
+    let upstreams = upstreams(true).await;
+    let bouncer = start(&upstreams, 38408).await;
+    let client = client();
+
+    let response = client
+        .get(format!("{}/favicon.ico", bouncer.url))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(response.headers()[header::CONTENT_TYPE], "image/x-icon");
+    assert!(!response.bytes().await.unwrap().is_empty());
+
+    let robots = client
+        .get(format!("{}/robots.txt", bouncer.url))
+        .send()
+        .await
+        .unwrap()
+        .text()
+        .await
+        .unwrap();
+    assert_eq!(robots, "User-agent: *\nAllow: /$\nDisallow: /\n");
+
+    let page = client
+        .get(&bouncer.url)
+        .send()
+        .await
+        .unwrap()
+        .text()
+        .await
+        .unwrap();
+    assert!(page.contains(r#"rel="icon""#), "{}", page);
+}
+
+#[tokio::test]
+async fn styles_unknown_paths_and_methods() {
+    let upstreams = upstreams(true).await;
+    let bouncer = start(&upstreams, 38409).await;
+    let client = client();
+
+    for (request, status) in [
+        (
+            client.get(format!("{}/no-such-page", bouncer.url)),
+            StatusCode::NOT_FOUND,
+        ),
+        (
+            client.get(format!("{}/invite", bouncer.url)),
+            StatusCode::METHOD_NOT_ALLOWED,
+        ),
+    ] {
+        let response = request.send().await.unwrap();
+        assert_eq!(response.status(), status);
+        assert!(response.headers()[header::CONTENT_TYPE]
+            .to_str()
+            .unwrap()
+            .starts_with("text/html"));
+        let page = response.text().await.unwrap();
+        assert!(page.contains(r#"href="/""#), "{}", page);
+    }
+
+    let response = client
+        .get(format!("{}/no-such-page", bouncer.url))
+        .header(header::ACCEPT, "application/json")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    assert_eq!(response.headers()[header::CONTENT_TYPE], "application/json");
+    let body: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(body["status"], 404);
+    assert!(body["error"].is_string());
+}
+
+/// Run the invite flow into a room requiring the GitHub team example/maintainers, with further
+/// `room_config` settings, whose membership lookup answers `membership`, a state or else 404.
+async fn invite_team_member(
+    port: u16,
+    membership: Option<&str>,
+    room_config: &str,
+    invites: u64,
+) -> String {
+    let upstreams = upstreams(true).await;
+    expect_invites(&upstreams, invites).await;
+    let lookup = match membership {
+        Some(state) => ResponseTemplate::new(200).set_body_json(json!({
+            "state": state,
+            "role": "member",
+        })),
+        None => ResponseTemplate::new(404).set_body_json(json!({ "message": "Not Found" })),
+    };
+    Mock::given(method("GET"))
+        .and(path("/orgs/example/teams/maintainers/memberships/octocat"))
+        .and(bearer_token("ghp_server"))
+        .respond_with(lookup)
+        .expect(1)
+        .mount(&upstreams.github)
+        .await;
+    let config = std::env::temp_dir().join(format!("bouncer-team-{}.toml", port));
+    std::fs::write(
+        &config,
+        format!(
+            "[room_config.\"{}\"]\ngithub_org = \"example\"\ngithub_team = \"maintainers\"\n{}",
+            ROOM_ID, room_config
+        ),
+    )
+    .unwrap();
+    let bouncer = start_with(
+        &upstreams,
+        port,
+        &[
+            "--config",
+            config.to_str().unwrap(),
+            "--github-token",
+            "ghp_server",
+        ],
+    )
+    .await;
+    let client = client();
+
+    let response = submit(&client, &bouncer).await;
+    assert_eq!(response.status(), StatusCode::SEE_OTHER);
+    let location = url::Url::parse(response.headers()[header::LOCATION].to_str().unwrap()).unwrap();
+    let (_, csrf) = location
+        .query_pairs()
+        .find(|(key, _)| key == "state")
+        .expect("authorize url without state");
+    let response = client
+        .get(format!("{}/callback", bouncer.url))
+        .query(&[("code", "code"), ("state", &csrf)])
+        .header(header::COOKIE, cookies(&response))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    response.text().await.unwrap()
+}
+
+#[tokio::test]
+async fn invites_active_team_members() {
+    let page = invite_team_member(38410, Some("active"), "", 1).await;
+    assert!(page.contains("Test Room: invited"), "{}", page);
+}
+
+#[tokio::test]
+async fn refuses_pending_team_members() {
+    let page = invite_team_member(38411, Some("pending"), "", 0).await;
+    assert!(
+        page.contains("GitHub team example/maintainers, whose invitation is still pending"),
+        "{}",
+        page
+    );
+}
+
+#[tokio::test]
+async fn refuses_users_outside_the_team() {
+    let page = invite_team_member(38412, None, "", 0).await;
+    assert!(
+        page.contains("needs membership in the GitHub team example/maintainers"),
+        "{}",
+        page
+    );
+}
+
+#[tokio::test]
+async fn team_membership_composes_with_room_policies() {
+    let page = invite_team_member(38413, Some("active"), "min_github_age_days = 36500\n", 0).await;
+    assert!(
+        page.contains("needs a GitHub account older than 36500 days"),
+        "{}",
+        page
+    );
+}
+
+#[tokio::test]
+async fn limits_pending_logins_per_client() {
+    let upstreams = upstreams(true).await;
+    let bouncer = start_with(&upstreams, 38414, &["--max-pending-per-client", "2"]).await;
+    let client = client();
+
+    for _ in 0..2 {
+        assert_eq!(
+            submit(&client, &bouncer).await.status(),
+            StatusCode::SEE_OTHER
+        );
+    }
+    let response = submit(&client, &bouncer).await;
+    assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+    let page = response.text().await.unwrap();
+    assert!(
+        page.contains("2 of your invites are already waiting for a GitHub login"),
+        "{}",
+        page
+    );
+}
+
+#[tokio::test]
+async fn retries_queued_invites() {
+    let upstreams = upstreams(true).await;
+    Mock::given(method("POST"))
+        .and(path_regex(r"/rooms/[^/]+/invite$"))
+        .respond_with(ResponseTemplate::new(502))
+        .up_to_n_times(1)
+        .with_priority(1)
+        .expect(1)
+        .mount(&upstreams.homeserver)
+        .await;
+    expect_invites(&upstreams, 1).await;
+    let store = std::env::temp_dir().join("bouncer-queue-38415.json");
+    let _ = std::fs::remove_file(&store);
+    let _ = std::fs::remove_file(bouncer::store::log_path(&store));
+    let bouncer = start_with(
+        &upstreams,
+        38415,
+        &["--queue-invites", "--audit-store", store.to_str().unwrap()],
+    )
+    .await;
+    let client = client();
+
+    let response = submit(&client, &bouncer).await;
+    let location = url::Url::parse(response.headers()[header::LOCATION].to_str().unwrap()).unwrap();
+    let (_, csrf) = location
+        .query_pairs()
+        .find(|(key, _)| key == "state")
+        .expect("authorize url without state");
+    let page = client
+        .get(format!("{}/callback", bouncer.url))
+        .query(&[("code", "code"), ("state", &csrf)])
+        .header(header::COOKIE, cookies(&response))
+        .send()
+        .await
+        .unwrap()
+        .text()
+        .await
+        .unwrap();
+    assert!(page.contains("Test Room: being processed"), "{}", page);
+    let (_, rest) = page
+        .split_once(r#"data-status=""#)
+        .expect("job status missing from the outcome");
+    let status_url = format!("{}{}", bouncer.url, rest.split('"').next().unwrap());
+
+    // The first attempt fails, the retry after five seconds goes through.
+    let mut status = json!(null);
+    for _ in 0..150 {
+        status = client
+            .get(&status_url)
+            .send()
+            .await
+            .unwrap()
+            .json::<serde_json::Value>()
+            .await
+            .unwrap();
+        if status["status"] != "queued" {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+    assert_eq!(status["status"], "invited", "{}", status);
+    assert_eq!(status["attempts"], 2, "{}", status);
+
+    let response = client
+        .get(format!("{}/status/unknown", bouncer.url))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+async fn refreshes_stale_rooms_in_the_background() {
+    let upstreams = upstreams(true).await;
+    // Once at startup, once more after the cache went stale.
+    Mock::given(path_regex(r"/joined_rooms$"))
+        .respond_with(
+            ResponseTemplate::new(200).set_body_json(json!({ "joined_rooms": [ROOM_ID] })),
+        )
+        .with_priority(1)
+        .expect(2)
+        .mount(&upstreams.homeserver)
+        .await;
+    let bouncer = start_with(&upstreams, 38416, &["--room-cache-ttl", "1s"]).await;
+    let client = client();
+
+    rendered_stamp(&client, &bouncer).await;
+    tokio::time::sleep(Duration::from_millis(1500)).await;
+    // Concurrent stale pages start a single refresh, and are served the stale rooms.
+    let (first, second) = tokio::join!(
+        rendered_stamp(&client, &bouncer),
+        rendered_stamp(&client, &bouncer)
+    );
+    assert!(!first.is_empty() && !second.is_empty());
+    tokio::time::sleep(Duration::from_millis(500)).await;
+}
+
+#[tokio::test]
+async fn answers_cors_requests_of_allowed_origins() {
+    let upstreams = upstreams(true).await;
+    let bouncer = start_with(
+        &upstreams,
+        38417,
+        &["--cors-allowed-origins", "https://app.example.com"],
+    )
+    .await;
+    let client = client();
+    let rooms = format!("{}/api/rooms", bouncer.url);
+
+    let response = client
+        .get(&rooms)
+        .header(header::ORIGIN, "https://app.example.com")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(
+        response.headers()[header::ACCESS_CONTROL_ALLOW_ORIGIN],
+        "https://app.example.com"
+    );
+
+    let response = client
+        .get(&rooms)
+        .header(header::ORIGIN, "https://evil.example.com")
+        .send()
+        .await
+        .unwrap();
+    assert!(!response
+        .headers()
+        .contains_key(header::ACCESS_CONTROL_ALLOW_ORIGIN));
+
+    let response = client
+        .request(
+            reqwest::Method::OPTIONS,
+            format!("{}/api/invite", bouncer.url),
+        )
+        .header(header::ORIGIN, "https://app.example.com")
+        .header(header::ACCESS_CONTROL_REQUEST_METHOD, "POST")
+        .header(header::ACCESS_CONTROL_REQUEST_HEADERS, "content-type")
+        .send()
+        .await
+        .unwrap();
+    assert!(response.status().is_success(), "{}", response.status());
+    let headers = response.headers();
+    assert_eq!(
+        headers[header::ACCESS_CONTROL_ALLOW_ORIGIN],
+        "https://app.example.com"
+    );
+    assert!(headers[header::ACCESS_CONTROL_ALLOW_METHODS]
+        .to_str()
+        .unwrap()
+        .contains("POST"));
+    assert!(headers[header::ACCESS_CONTROL_ALLOW_HEADERS]
+        .to_str()
+        .unwrap()
+        .contains("content-type"));
+}
+
+/// Assert a response is the HTML error page for a bad request, linking back to the form.
+async fn assert_bad_request(response: reqwest::Response, expected: &str) {
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    assert!(response.headers()[header::CONTENT_TYPE]
+        .to_str()
+        .unwrap()
+        .starts_with("text/html"));
+    let page = response.text().await.unwrap();
+    assert!(page.contains(expected), "{}", page);
+    assert!(
+        page.contains(r#"<a href="/">Back to the invite form</a>"#),
+        "{}",
+        page
+    );
+}
+
+#[tokio::test]
+async fn explains_malformed_requests() {
+    let upstreams = upstreams(true).await;
+    expect_invites(&upstreams, 0).await;
+    let bouncer = start(&upstreams, 38418).await;
+    let client = client();
+    let invite = format!("{}/invite", bouncer.url);
+
+    let response = client
+        .post(&invite)
+        .form(&[("room_id", ROOM_ID), ("user_id", INVITEE)])
+        .send()
+        .await
+        .unwrap();
+    assert_bad_request(response, "Complete the captcha before submitting.").await;
+
+    let response = client
+        .post(&invite)
+        .form(&[
+            ("room_id", ROOM_ID),
+            ("user_id", INVITEE),
+            ("cf-turnstile-response", "token"),
+            ("tos", "maybe"),
+        ])
+        .send()
+        .await
+        .unwrap();
+    assert_bad_request(response, "The submitted request is invalid").await;
+
+    let response = client
+        .post(&invite)
+        .json(&json!({ "room_id": ROOM_ID, "user_id": INVITEE }))
+        .send()
+        .await
+        .unwrap();
+    assert_bad_request(response, "The submitted request is invalid").await;
+
+    let response = client
+        .get(format!("{}/callback", bouncer.url))
+        .query(&[("state", "state")])
+        .send()
+        .await
+        .unwrap();
+    assert_bad_request(
+        response,
+        "The GitHub login did not complete, please start the invite again.",
+    )
+    .await;
+}
+
+/// Submit a batch of Matrix IDs for the test room.
+async fn submit_batch(
+    client: &reqwest::Client,
+    bouncer: &Bouncer,
+    user_ids: &str,
+) -> reqwest::Response {
+    let rendered = rendered_stamp(client, bouncer).await;
+    client
+        .post(format!("{}/invite", bouncer.url))
+        .form(&[
+            ("room_id", ROOM_ID),
+            ("user_ids", user_ids),
+            ("cf-turnstile-response", "token"),
+            ("website", ""),
+            ("rendered", &rendered),
+        ])
+        .send()
+        .await
+        .unwrap()
+}
+
+/// Answer the membership lookup of a user in the test room.
+async fn member(upstreams: &Upstreams, localpart: &str, membership: &str) {
+    Mock::given(path_regex(format!(
+        r"/state/m\.room\.member/[^/]*{}",
+        localpart
+    )))
+    .respond_with(ResponseTemplate::new(200).set_body_json(json!({ "membership": membership })))
+    .mount(&upstreams.homeserver)
+    .await;
+}
+
+/// Complete the GitHub login a submission redirected to, returning the outcome page.
+async fn log_in(
+    client: &reqwest::Client,
+    bouncer: &Bouncer,
+    submitted: reqwest::Response,
+) -> String {
+    assert_eq!(submitted.status(), StatusCode::SEE_OTHER);
+    let location =
+        url::Url::parse(submitted.headers()[header::LOCATION].to_str().unwrap()).unwrap();
+    let (_, csrf) = location
+        .query_pairs()
+        .find(|(key, _)| key == "state")
+        .expect("authorize url without state");
+    let response = client
+        .get(format!("{}/callback", bouncer.url))
+        .query(&[("code", "code"), ("state", &csrf)])
+        .header(header::COOKIE, cookies(&submitted))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    response.text().await.unwrap()
+}
+
+#[tokio::test]
+async fn refuses_batches_with_a_banned_user() {
+    let upstreams = upstreams(true).await;
+    expect_invites(&upstreams, 0).await;
+    member(&upstreams, "mallory", "ban").await;
+    let bouncer = start(&upstreams, 38419).await;
+
+    let response = submit_batch(&client(), &bouncer, "@alice:localhost\n@mallory:localhost").await;
+    assert_eq!(response.status(), StatusCode::FORBIDDEN);
+    let page = response.text().await.unwrap();
+    assert!(
+        page.contains("@mallory:localhost cannot be invited"),
+        "{}",
+        page
+    );
+}
+
+#[tokio::test]
+async fn skips_batches_of_existing_members() {
+    let upstreams = upstreams(true).await;
+    expect_invites(&upstreams, 0).await;
+    member(&upstreams, "alice", "join").await;
+    member(&upstreams, "bob", "invite").await;
+    let bouncer = start(&upstreams, 38420).await;
+
+    let response = submit_batch(&client(), &bouncer, "@alice:localhost\n@bob:localhost").await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let page = response.text().await.unwrap();
+    assert!(
+        page.contains("@alice:localhost is already a member of"),
+        "{}",
+        page
+    );
+    assert!(
+        page.contains("An invite is already pending for @bob:localhost to"),
+        "{}",
+        page
+    );
+}
+
+#[tokio::test]
+async fn limits_invitees_per_github_account() {
+    let upstreams = upstreams(true).await;
+    expect_invites(&upstreams, 1).await;
+    let bouncer = start_with(&upstreams, 38421, &["--max-invitees-per-account", "1"]).await;
+    let client = client();
+
+    let response = submit_batch(&client, &bouncer, "@alice:localhost\n@bob:localhost").await;
+    let page = log_in(&client, &bouncer, response).await;
+    assert!(page.contains("Test Room: invited"), "{}", page);
+    assert!(
+        page.contains("over the daily invite limit of the GitHub account"),
+        "{}",
+        page
+    );
+}
+
+#[tokio::test]
+async fn bulk_invites_an_uploaded_csv() {
+    let upstreams = upstreams(true).await;
+    expect_invites(&upstreams, 1).await;
+    let store = std::env::temp_dir().join("bouncer-bulk-38422.json");
+    let _ = std::fs::remove_file(&store);
+    let _ = std::fs::remove_file(bouncer::store::log_path(&store));
+    let bouncer = start_with(
+        &upstreams,
+        38422,
+        &[
+            "--admin-token",
+            "admintoken",
+            "--audit-store",
+            store.to_str().unwrap(),
+        ],
+    )
+    .await;
+    let client = client();
+
+    let body = format!(
+        "--boundary\r\n\
+         Content-Disposition: form-data; name=\"file\"; filename=\"invites.csv\"\r\n\
+         Content-Type: text/csv\r\n\
+         \r\n\
+         user_id,room,reason\r\n\
+         {},{},welcome\r\n\
+         --boundary--\r\n",
+        INVITEE, ROOM_ID
+    );
+    let response = client
+        .post(format!("{}/admin/bulk-invite", bouncer.url))
+        .bearer_auth("admintoken")
+        .header(
+            header::CONTENT_TYPE,
+            "multipart/form-data; boundary=boundary",
+        )
+        .body(body)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let outcomes: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(outcomes[0]["row"], 2);
+    assert_eq!(outcomes[0]["error"], serde_json::Value::Null);
+
+    let history: serde_json::Value = client
+        .get(format!("{}/admin/audit", bouncer.url))
+        .bearer_auth("admintoken")
+        .send()
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    let entry = &history["entries"][0];
+    assert_eq!(entry["decision"], "invite_sent");
+    assert_eq!(entry["user_id"], INVITEE);
+    assert_eq!(entry["github_login"], "");
+    assert_eq!(entry["initiated_by"], "bearer token");
+}
+
+#[tokio::test]
+async fn sends_the_invite_reason() {
+    let upstreams = upstreams(true).await;
+    expect_invites(&upstreams, 0).await;
+    Mock::given(method("POST"))
+        .and(path_regex(r"/rooms/[^/]+/invite$"))
+        .and(body_partial_json(json!({
+            "user_id": INVITEE,
+            "reason": "Vouched by octocat for @alice:localhost",
+        })))
+        .respond_with(ResponseTemplate::new(200).set_body_json(json!({})))
+        .with_priority(1)
+        .expect(1)
+        .mount(&upstreams.homeserver)
+        .await;
+    let bouncer = start_with(
+        &upstreams,
+        38423,
+        &[
+            "--invite-reason-template",
+            "Vouched by {github_login} for {matrix_user}",
+        ],
+    )
+    .await;
+    let client = client();
+
+    let response = submit(&client, &bouncer).await;
+    let page = log_in(&client, &bouncer, response).await;
+    assert!(page.contains("Test Room: invited"), "{}", page);
+}
+
+#[tokio::test]
+async fn leaves_out_the_reason_when_disabled() {
+    let upstreams = upstreams(true).await;
+    expect_invites(&upstreams, 0).await;
+    Mock::given(method("POST"))
+        .and(path_regex(r"/rooms/[^/]+/invite$"))
+        .and(|request: &wiremock::Request| {
+            serde_json::from_slice::<serde_json::Value>(&request.body)
+                .is_ok_and(|body| body.get("reason").is_none())
+        })
+        .respond_with(ResponseTemplate::new(200).set_body_json(json!({})))
+        .with_priority(1)
+        .expect(1)
+        .mount(&upstreams.homeserver)
+        .await;
+    let bouncer = start_with(&upstreams, 38424, &["--no-invite-reason"]).await;
+    let client = client();
+
+    let response = submit(&client, &bouncer).await;
+    let page = log_in(&client, &bouncer, response).await;
+    assert!(page.contains("Test Room: invited"), "{}", page);
+}
+
+const ADMIN_ROOM: &str = "!admin:localhost";
+const MODERATOR: &str = "@mod:localhost";
+
+/// An admin room where [`MODERATOR`] may run commands. Its syncs stay empty until
+/// [`sync_commands`] delivers messages.
+async fn admin_room(upstreams: &Upstreams) {
+    Mock::given(method("GET"))
+        .and(path_regex(r"/sync$"))
+        .respond_with(
+            ResponseTemplate::new(200)
+                .set_body_json(json!({ "next_batch": "s0" }))
+                .set_delay(Duration::from_millis(200)),
+        )
+        .mount(&upstreams.homeserver)
+        .await;
+    Mock::given(path_regex(
+        r"/rooms/[^/]*admin[^/]*/state/m\.room\.power_levels/?$",
+    ))
+    .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+        "users": { BOT: 100, MODERATOR: 50 },
+        "users_default": 0,
+    })))
+    .with_priority(1)
+    .mount(&upstreams.homeserver)
+    .await;
+    Mock::given(method("PUT"))
+        .and(path_regex(r"/rooms/[^/]*admin[^/]*/send/m\.room\.message/"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(json!({ "event_id": "$reply" })))
+        .mount(&upstreams.homeserver)
+        .await;
+}
+
+/// Deliver messages of `(sender, body)` to the admin room with the next sync.
+async fn sync_commands(upstreams: &Upstreams, messages: &[(&str, &str)]) {
+    let events = messages
+        .iter()
+        .enumerate()
+        .map(|(i, (sender, body))| {
+            json!({
+                "type": "m.room.message",
+                "event_id": format!("$command{}", i),
+                "sender": sender,
+                "origin_server_ts": 0,
+                "content": { "msgtype": "m.text", "body": body },
+            })
+        })
+        .collect::<Vec<_>>();
+    Mock::given(method("GET"))
+        .and(path_regex(r"/sync$"))
+        .and(query_param("since", "s0"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+            "next_batch": "s1",
+            "rooms": { "join": { ADMIN_ROOM: { "timeline": { "events": events } } } },
+        })))
+        .up_to_n_times(1)
+        .with_priority(1)
+        .mount(&upstreams.homeserver)
+        .await;
+}
+
+/// Bodies of the notices posted to the admin room, once there are at least `count`.
+async fn admin_replies(upstreams: &Upstreams, count: usize) -> Vec<String> {
+    for _ in 0..100 {
+        let replies = upstreams
+            .homeserver
+            .received_requests()
+            .await
+            .unwrap()
+            .iter()
+            .filter(|request| {
+                request.method == "PUT" && request.url.path().contains("/send/m.room.message/")
+            })
+            .map(|request| {
+                request.body_json::<serde_json::Value>().unwrap()["body"]
+                    .as_str()
+                    .unwrap()
+                    .to_string()
+            })
+            .collect::<Vec<_>>();
+        if replies.len() >= count {
+            return replies;
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+    panic!("fewer than {} admin room notices", count);
+}
+
+#[tokio::test]
+async fn lists_rooms_on_command() {
+    let upstreams = upstreams(true).await;
+    admin_room(&upstreams).await;
+    sync_commands(&upstreams, &[(MODERATOR, "!bouncer rooms")]).await;
+    let _bouncer = start_with(&upstreams, 38425, &["--admin-room", ADMIN_ROOM]).await;
+
+    assert_eq!(
+        admin_replies(&upstreams, 1).await,
+        ["1 served rooms:\nTest Room (!room:localhost): 0 invites"]
+    );
+}
+
+#[tokio::test]
+async fn refreshes_rooms_on_command() {
+    let upstreams = upstreams(true).await;
+    admin_room(&upstreams).await;
+    sync_commands(&upstreams, &[(MODERATOR, "!bouncer refresh")]).await;
+    let _bouncer = start_with(&upstreams, 38426, &["--admin-room", ADMIN_ROOM]).await;
+
+    assert_eq!(
+        admin_replies(&upstreams, 1).await,
+        ["Rooms refreshed: 1 served, added 0, removed 0"]
+    );
+}
+
+#[tokio::test]
+async fn invites_on_command() {
+    let upstreams = upstreams(true).await;
+    expect_invites(&upstreams, 0).await;
+    Mock::given(method("POST"))
+        .and(path_regex(r"/rooms/[^/]+/invite$"))
+        .and(body_partial_json(json!({
+            "user_id": INVITEE,
+            "reason": "Invited by @mod:localhost",
+        })))
+        .respond_with(ResponseTemplate::new(200).set_body_json(json!({})))
+        .with_priority(1)
+        .expect(1)
+        .mount(&upstreams.homeserver)
+        .await;
+    admin_room(&upstreams).await;
+    sync_commands(
+        &upstreams,
+        &[(
+            MODERATOR,
+            "!bouncer invite @alice:localhost !room:localhost",
+        )],
+    )
+    .await;
+    let _bouncer = start_with(&upstreams, 38427, &["--admin-room", ADMIN_ROOM]).await;
+
+    assert_eq!(
+        admin_replies(&upstreams, 1).await,
+        ["Invited @alice:localhost to !room:localhost"]
+    );
+}
+
+#[tokio::test]
+async fn lists_pending_logins_on_command() {
+    let upstreams = upstreams(true).await;
+    admin_room(&upstreams).await;
+    let bouncer = start_with(&upstreams, 38428, &["--admin-room", ADMIN_ROOM]).await;
+
+    let response = submit(&client(), &bouncer).await;
+    assert_eq!(response.status(), StatusCode::SEE_OTHER);
+    sync_commands(&upstreams, &[(MODERATOR, "!bouncer pending")]).await;
+    let replies = admin_replies(&upstreams, 1).await;
+    let (header, pending) = replies[0].split_once('\n').unwrap();
+    assert_eq!(header, "1 pending GitHub logins:");
+    assert!(
+        pending.contains(": @alice:localhost to !room:localhost, "),
+        "{}",
+        pending
+    );
+}
+
+#[tokio::test]
+async fn explains_unknown_commands() {
+    let upstreams = upstreams(true).await;
+    admin_room(&upstreams).await;
+    sync_commands(&upstreams, &[(MODERATOR, "!bouncer frobnicate")]).await;
+    let _bouncer = start_with(&upstreams, 38429, &["--admin-room", ADMIN_ROOM]).await;
+
+    let replies = admin_replies(&upstreams, 1).await;
+    assert!(
+        replies[0].starts_with("Usage: !bouncer rooms"),
+        "{}",
+        replies[0]
+    );
+}
+
+#[tokio::test]
+async fn ignores_commands_below_the_power_level() {
+    let upstreams = upstreams(true).await;
+    expect_invites(&upstreams, 0).await;
+    admin_room(&upstreams).await;
+    // Messages are handled in order, so a reply to the first would come before the second.
+    sync_commands(
+        &upstreams,
+        &[
+            (
+                "@stranger:localhost",
+                "!bouncer invite @alice:localhost !room:localhost",
+            ),
+            (MODERATOR, "!bouncer frobnicate"),
+        ],
+    )
+    .await;
+    let _bouncer = start_with(&upstreams, 38430, &["--admin-room", ADMIN_ROOM]).await;
+
+    let replies = admin_replies(&upstreams, 1).await;
+    assert_eq!(replies.len(), 1, "{:?}", replies);
+    assert!(replies[0].starts_with("Usage: "), "{}", replies[0]);
+}
+
+/// Keys of a Redis standing in for `--redis-url`. Expiry is not modelled.
+#[derive(Default)]
+struct RedisData {
+    strings: HashMap<String, String>,
+    sorted_sets: HashMap<String, Vec<(i64, String)>>,
+}
+
+enum Reply {
+    Ok,
+    Int(i64),
+    Bulk(Option<String>),
+    Array(Vec<Reply>),
+}
+
+impl Reply {
+    fn encode(&self, out: &mut Vec<u8>) {
+        match self {
+            Reply::Ok => out.extend(b"+OK\r\n"),
+            Reply::Int(n) => out.extend(format!(":{}\r\n", n).as_bytes()),
+            Reply::Bulk(None) => out.extend(b"$-1\r\n"),
+            Reply::Bulk(Some(value)) => {
+                out.extend(format!("${}\r\n{}\r\n", value.len(), value).as_bytes())
+            }
+            Reply::Array(items) => {
+                out.extend(format!("*{}\r\n", items.len()).as_bytes());
+                for item in items {
+                    item.encode(out);
+                }
+            }
+        }
+    }
+}
+
+/// An in-memory Redis answering the commands of the session store until [`MockRedis::outage`].
+struct MockRedis {
+    url: String,
+    data: Arc<Mutex<RedisData>>,
+    server: JoinSet<()>,
+}
+
+impl MockRedis {
+    async fn start() -> MockRedis {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("redis://{}", listener.local_addr().unwrap());
+        let data = Arc::new(Mutex::new(RedisData::default()));
+        let mut server = JoinSet::new();
+        let shared = data.clone();
+        server.spawn(async move {
+            // Connections are aborted along with the listener when the set is dropped.
+            let mut connections = JoinSet::new();
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                connections.spawn(serve_redis(stream, shared.clone()));
+            }
+        });
+        MockRedis { url, data, server }
+    }
+
+    /// Close the port and every open connection.
+    async fn outage(&mut self) {
+        self.server.shutdown().await;
+    }
+
+    fn keys(&self, prefix: &str) -> usize {
+        let data = self.data.lock().unwrap();
+        data.strings
+            .keys()
+            .filter(|key| key.starts_with(prefix))
+            .count()
+    }
+}
+
+async fn serve_redis(stream: tokio::net::TcpStream, data: Arc<Mutex<RedisData>>) {
+    let (reader, mut writer) = stream.into_split();
+    let mut reader = BufReader::new(reader);
+    while let Some(command) = read_command(&mut reader).await {
+        let mut out = vec![];
+        redis_command(&mut data.lock().unwrap(), &command).encode(&mut out);
+        if writer.write_all(&out).await.is_err() {
+            return;
+        }
+    }
+}
+
+async fn read_command(
+    reader: &mut BufReader<tokio::net::tcp::OwnedReadHalf>,
+) -> Option<Vec<String>> {
+    let mut line = String::new();
+    reader.read_line(&mut line).await.ok()?;
+    let count = line.trim_end().strip_prefix('*')?.parse::<usize>().ok()?;
+    let mut command = vec![];
+    for _ in 0..count {
+        line.clear();
+        reader.read_line(&mut line).await.ok()?;
+        let len = line.trim_end().strip_prefix('$')?.parse::<usize>().ok()?;
+        let mut arg = vec![0; len + 2];
+        reader.read_exact(&mut arg).await.ok()?;
+        arg.truncate(len);
+        command.push(String::from_utf8(arg).ok()?);
+    }
+    Some(command)
+}
+
+fn redis_command(data: &mut RedisData, command: &[String]) -> Reply {
+    fn score(arg: &str) -> i64 {
+        match arg {
+            "-inf" => i64::MIN,
+            "+inf" => i64::MAX,
+            arg => arg.parse().unwrap(),
+        }
+    }
+    match command
+        .iter()
+        .map(String::as_str)
+        .collect::<Vec<_>>()
+        .as_slice()
+    {
+        ["GET", key] => Reply::Bulk(data.strings.get(*key).cloned()),
+        ["GETDEL", key] => Reply::Bulk(data.strings.remove(*key)),
+        ["SET", key, value, ..] => {
+            data.strings.insert(key.to_string(), value.to_string());
+            Reply::Ok
+        }
+        ["MGET", keys @ ..] => Reply::Array(
+            keys.iter()
+                .map(|key| Reply::Bulk(data.strings.get(*key).cloned()))
+                .collect(),
+        ),
+        ["SCAN", _, "MATCH", pattern] => {
+            let prefix = pattern.trim_end_matches('*');
+            Reply::Array(vec![
+                Reply::Bulk(Some("0".to_string())),
+                Reply::Array(
+                    data.strings
+                        .keys()
+                        .filter(|key| key.starts_with(prefix))
+                        .map(|key| Reply::Bulk(Some(key.clone())))
+                        .collect(),
+                ),
+            ])
+        }
+        ["INCR", key] => {
+            let value = data
+                .strings
+                .entry(key.to_string())
+                .or_insert_with(|| "0".to_string());
+            let hits = value.parse::<i64>().unwrap() + 1;
+            *value = hits.to_string();
+            Reply::Int(hits)
+        }
+        ["PEXPIRE", ..] => Reply::Int(1),
+        ["ZADD", key, score_arg, member] => {
+            let set = data.sorted_sets.entry(key.to_string()).or_default();
+            let added = !set.iter().any(|(_, other)| other == *member);
+            set.retain(|(_, other)| other != *member);
+            set.push((score(score_arg), member.to_string()));
+            set.sort();
+            Reply::Int(added.into())
+        }
+        ["ZREM", key, member] => {
+            let set = data.sorted_sets.entry(key.to_string()).or_default();
+            let before = set.len();
+            set.retain(|(_, other)| other != *member);
+            Reply::Int((before - set.len()) as i64)
+        }
+        ["ZREMRANGEBYSCORE", key, min, max] => {
+            let (min, max) = (score(min), score(max));
+            let set = data.sorted_sets.entry(key.to_string()).or_default();
+            let before = set.len();
+            set.retain(|(score, _)| *score < min || *score > max);
+            Reply::Int((before - set.len()) as i64)
+        }
+        ["ZCARD", key] => Reply::Int(data.sorted_sets.get(*key).map_or(0, Vec::len) as i64),
+        ["ZRANGE", key, "0", "-1"] => Reply::Array(
+            data.sorted_sets
+                .get(*key)
+                .into_iter()
+                .flatten()
+                .map(|(_, member)| Reply::Bulk(Some(member.clone())))
+                .collect(),
+        ),
+        // Connection setup, such as CLIENT SETINFO.
+        _ => Reply::Ok,
+    }
+}
+
+#[tokio::test]
+async fn limits_pending_logins_per_client_in_redis() {
+    let upstreams = upstreams(true).await;
+    let redis = MockRedis::start().await;
+    let bouncer = start_with(
+        &upstreams,
+        38431,
+        &["--redis-url", &redis.url, "--max-pending-per-client", "2"],
+    )
+    .await;
+    let client = client();
+
+    for _ in 0..2 {
+        assert_eq!(
+            submit(&client, &bouncer).await.status(),
+            StatusCode::SEE_OTHER
+        );
+    }
+    let response = submit(&client, &bouncer).await;
+    assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+    let page = response.text().await.unwrap();
+    assert!(
+        page.contains("2 of your invites are already waiting for a GitHub login"),
+        "{}",
+        page
+    );
+    assert_eq!(redis.keys("bouncer:pending:"), 2);
+}
+
+#[tokio::test]
+async fn answers_unavailable_during_a_redis_outage() {
+    let upstreams = upstreams(true).await;
+    let mut redis = MockRedis::start().await;
+    let bouncer = start_with(&upstreams, 38432, &["--redis-url", &redis.url]).await;
+    let client = client();
+
+    assert_eq!(
+        submit(&client, &bouncer).await.status(),
+        StatusCode::SEE_OTHER
+    );
+    redis.outage().await;
+    let response = submit(&client, &bouncer).await;
+    assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+    let page = response.text().await.unwrap();
+    assert!(page.contains("temporarily unavailable"), "{}", page);
+}
+
+#[tokio::test]
+async fn binds_the_github_login_to_a_pkce_challenge() {
+    let upstreams = upstreams(true).await;
+    expect_invites(&upstreams, 1).await;
+    Mock::given(method("POST"))
+        .and(path("/login/oauth/access_token"))
+        .and(body_string_contains("code_verifier="))
+        .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+            "access_token": "gho_test",
+            "token_type": "bearer",
+            "scope": "",
+        })))
+        .with_priority(1)
+        .expect(1)
+        .mount(&upstreams.github)
+        .await;
+    let bouncer = start(&upstreams, 38433).await;
+    let client = client();
+
+    let response = submit(&client, &bouncer).await;
+    assert_eq!(response.status(), StatusCode::SEE_OTHER);
+    let location = url::Url::parse(response.headers()[header::LOCATION].to_str().unwrap()).unwrap();
+    let query = location.query_pairs().collect::<HashMap<_, _>>();
+    assert_eq!(query["code_challenge_method"], "S256");
+    let challenge = query["code_challenge"].to_string();
+    let csrf = query["state"].to_string();
+
+    let callback = || {
+        client
+            .get(format!("{}/callback", bouncer.url))
+            .query(&[("code", "code"), ("state", &csrf)])
+            .header(header::COOKIE, cookies(&response))
+            .send()
+    };
+    let page = callback().await.unwrap().text().await.unwrap();
+    assert!(page.contains("Test Room: invited"), "{}", page);
+    // The verifier went with the invite, a replayed callback has none to send.
+    assert_eq!(callback().await.unwrap().status(), StatusCode::BAD_REQUEST);
+
+    let exchange = upstreams
+        .github
+        .received_requests()
+        .await
+        .unwrap()
+        .into_iter()
+        .find(|request| request.url.path() == "/login/oauth/access_token")
+        .unwrap();
+    let verifier = url::form_urlencoded::parse(&exchange.body)
+        .find(|(key, _)| key == "code_verifier")
+        .map(|(_, verifier)| verifier.into_owned())
+        .unwrap();
+    let digest = ring::digest::digest(&ring::digest::SHA256, verifier.as_bytes());
+    assert_eq!(
+        base64::Engine::encode(&base64::engine::general_purpose::URL_SAFE_NO_PAD, digest),
+        challenge
+    );
+}
+
+/// State event of the rooms with the given localpart, overriding the catch-all 404.
+async fn room_state(
+    upstreams: &Upstreams,
+    localpart: &str,
+    event_type: &str,
+    content: serde_json::Value,
+) {
+    Mock::given(path_regex(format!(
+        r"/rooms/(!|%21){}(:|%3A)[^/]*/state/{}/?$",
+        localpart, event_type
+    )))
+    .respond_with(ResponseTemplate::new(200).set_body_json(content))
+    .mount(&upstreams.homeserver)
+    .await;
+}
+
+#[tokio::test]
+async fn summarizes_rooms_from_state_without_msc3266() {
+    let upstreams = upstreams(true).await;
+    Mock::given(path_regex(r"/joined_rooms$"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+            "joined_rooms": [ROOM_ID, "!bare:localhost"],
+        })))
+        .with_priority(1)
+        .mount(&upstreams.homeserver)
+        .await;
+    Mock::given(path_regex(r"summary"))
+        .respond_with(ResponseTemplate::new(400).set_body_json(json!({
+            "errcode": "M_UNRECOGNIZED",
+            "error": "Unrecognized request",
+        })))
+        .with_priority(1)
+        .mount(&upstreams.homeserver)
+        .await;
+    room_state(
+        &upstreams,
+        "room",
+        r"m\.room\.name",
+        json!({ "name": "Test Room" }),
+    )
+    .await;
+    room_state(
+        &upstreams,
+        "room",
+        r"m\.room\.canonical_alias",
+        json!({ "alias": "#test:localhost" }),
+    )
+    .await;
+    room_state(
+        &upstreams,
+        "room",
+        r"m\.room\.join_rules",
+        json!({ "join_rule": "public" }),
+    )
+    .await;
+    // The second room has none of these events.
+    let bouncer = start(&upstreams, 38434).await;
+
+    let rooms = client()
+        .get(format!("{}/api/rooms", bouncer.url))
+        .send()
+        .await
+        .unwrap()
+        .json::<Vec<serde_json::Value>>()
+        .await
+        .unwrap();
+    let room = |room_id: &str| {
+        rooms
+            .iter()
+            .find(|room| room["room_id"] == room_id)
+            .unwrap_or_else(|| panic!("{} missing from {:?}", room_id, rooms))
+    };
+    assert_eq!(room(ROOM_ID)["name"], "Test Room");
+    assert_eq!(room(ROOM_ID)["canonical_alias"], "#test:localhost");
+    assert_eq!(room(ROOM_ID)["join_rule"], "public");
+    assert_eq!(room("!bare:localhost")["name"], serde_json::Value::Null);
+    assert_eq!(
+        room("!bare:localhost")["canonical_alias"],
+        serde_json::Value::Null
+    );
+    assert_eq!(room("!bare:localhost")["join_rule"], "invite");
+}
+
+/// A TCP proxy in front of `upstream`, counting the connections made through it.
+async fn counting_proxy(upstream: &MockServer) -> (String, Arc<AtomicUsize>) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let url = format!("http://{}", listener.local_addr().unwrap());
+    let upstream = *upstream.address();
+    let connections = Arc::new(AtomicUsize::new(0));
+    let counter = connections.clone();
+    tokio::spawn(async move {
+        loop {
+            let (mut inbound, _) = listener.accept().await.unwrap();
+            counter.fetch_add(1, Ordering::SeqCst);
+            tokio::spawn(async move {
+                let mut outbound = tokio::net::TcpStream::connect(upstream).await.unwrap();
+                let _ = tokio::io::copy_bidirectional(&mut inbound, &mut outbound).await;
+            });
+        }
+    });
+    (url, connections)
+}
+
+#[tokio::test]
+async fn reuses_connections_to_turnstile() {
+    let mut upstreams = upstreams(true).await;
+    let (url, connections) = counting_proxy(&upstreams.turnstile).await;
+    upstreams.turnstile_url = url;
+    let bouncer = start(&upstreams, 38435).await;
+    let client = client();
+
+    for _ in 0..3 {
+        assert_eq!(
+            submit(&client, &bouncer).await.status(),
+            StatusCode::SEE_OTHER
+        );
+    }
+    let verified = upstreams.turnstile.received_requests().await.unwrap().len();
+    assert!(verified >= 3, "{} captcha checks", verified);
+    // The startup check and every submission share one pooled connection.
+    assert_eq!(connections.load(Ordering::SeqCst), 1);
+}
+
+#[tokio::test]
+async fn lets_the_form_reach_the_configured_github() {
+    let upstreams = upstreams(true).await;
+    let bouncer = start(&upstreams, 38436).await;
+
+    let response = client().get(&bouncer.url).send().await.unwrap();
+    let csp = response.headers()[header::CONTENT_SECURITY_POLICY]
+        .to_str()
+        .unwrap()
+        .to_string();
+    let form_action = csp
+        .split("; ")
+        .find(|directive| directive.starts_with("form-action "))
+        .unwrap_or_else(|| panic!("no form-action in {}", csp));
+    assert_eq!(
+        form_action,
+        format!("form-action 'self' {}", upstreams.github.uri())
+    );
+}
@@ -0,0 +1,91 @@
+//! Refusing invitees by their localpart, and matching it to GitHub logins.
+
+use bouncer::localpart::{LocalpartRules, LoginMatch};
+use ruma::UserId;
+
+fn rules(patterns: &[&str]) -> LocalpartRules {
+    let patterns = patterns
+        .iter()
+        .map(|pattern| pattern.to_string())
+        .collect::<Vec<_>>();
+    LocalpartRules::new(&patterns, None, None).unwrap()
+}
+
+#[test]
+fn matches_patterns_anywhere() {
+    let rules = rules(&["crypto", r"^[a-z0-9]{24,}$"]);
+    assert!(rules.violation("freecrypto_420").is_some());
+    assert!(rules.violation("q8x0v2k9m3z7r1t5w4y6u0p2").is_some());
+    assert!(rules.violation("alice").is_none());
+}
+
+#[test]
+fn ignores_case_unless_turned_back_on() {
+    let rules = self::rules(&["crypto"]);
+    assert!(rules.violation("FreeCrypto").is_some());
+    let rules = self::rules(&["(?-i)Crypto"]);
+    assert!(rules.violation("freecrypto").is_none());
+    assert!(rules.violation("FreeCrypto").is_some());
+}
+
+#[test]
+fn handles_unicode_localparts() {
+    let rules = self::rules(&["ё", r"^\p{Cyrillic}+$"]);
+    assert!(rules.violation("пётр").is_some());
+    assert!(rules.violation("ЁЖИК").is_some());
+    assert!(rules.violation("zoë").is_none());
+
+    // Lengths count characters rather than bytes.
+    let rules = LocalpartRules::new(&[], Some(3), Some(4)).unwrap();
+    assert!(rules.violation("zoë").is_none());
+    assert!(rules.violation("élan").is_none());
+    assert!(rules.violation("zö").is_some());
+    assert!(rules.violation("ölkanne").is_some());
+}
+
+#[test]
+fn names_invalid_patterns() {
+    let err = LocalpartRules::new(&["(unclosed".to_string()], None, None).unwrap_err();
+    assert!(format!("{:#}", err).contains("(unclosed"), "{:#}", err);
+    assert!(LocalpartRules::new(&[], Some(5), Some(2)).is_err());
+}
+
+fn user(user_id: &str) -> &UserId {
+    <&UserId>::try_from(user_id).unwrap()
+}
+
+fn login_match(mapping: &[&str], exceptions: &[&str]) -> LoginMatch {
+    let strings = |values: &[&str]| {
+        values
+            .iter()
+            .map(|value| value.to_string())
+            .collect::<Vec<_>>()
+    };
+    LoginMatch::new(&strings(mapping), &strings(exceptions)).unwrap()
+}
+
+#[test]
+fn matches_logins_ignoring_case() {
+    let login_match = login_match(&[], &[]);
+    assert!(login_match.matches("Octocat", user("@octocat:example.com")));
+    assert!(login_match.matches("jane-doe", user("@jane-doe:example.com")));
+    assert!(!login_match.matches("jane-doe", user("@jane_doe:example.com")));
+    assert!(!login_match.matches("octocat", user("@octocat2:example.com")));
+}
+
+#[test]
+fn maps_dashes_to_underscores() {
+    let login_match = login_match(&["-=_"], &[]);
+    assert!(login_match.matches("Jane-Doe", user("@jane_doe:example.com")));
+    assert!(login_match.matches("Jane-Doe", user("@jane-doe:example.com")));
+    assert!(!login_match.matches("Jane-Doe", user("@jane.doe:example.com")));
+    assert!(LoginMatch::new(&["-_".to_string()], &[]).is_err());
+}
+
+#[test]
+fn skips_exceptions() {
+    let login_match = login_match(&[], &["Release-Bot", "@ops:example.com"]);
+    assert!(login_match.matches("release-bot", user("@builds:example.com")));
+    assert!(login_match.matches("alice", user("@ops:example.com")));
+    assert!(!login_match.matches("alice", user("@ops:other.example")));
+}
@@ -0,0 +1,60 @@
+//! JSON log lines, whose audit fields are an interface of log pipelines.
+
+use bouncer::logging::{json_line, LogFormat, AUDIT_TARGET};
+use log::{kv::Value, Level, Record};
+use serde_json::json;
+
+#[test]
+fn serializes_audit_fields() {
+    let fields = [
+        ("event", Value::from("github_login")),
+        ("matrix_user", Value::from("@alice:example.com")),
+        ("github_login", Value::from("octocat")),
+        ("github_age_seconds", Value::from(86400i64)),
+    ];
+    let mut line = json_line(
+        &Record::builder()
+            .level(Level::Warn)
+            .target(AUDIT_TARGET)
+            .args(format_args!(
+                "matrix user @alice:example.com is GitHub user octocat"
+            ))
+            .key_values(&fields[..])
+            .build(),
+    );
+    let timestamp = line.as_object_mut().unwrap().remove("timestamp").unwrap();
+    assert!(chrono::DateTime::parse_from_rfc3339(timestamp.as_str().unwrap()).is_ok());
+    assert_eq!(
+        line,
+        json!({
+            "level": "WARN",
+            "target": "bouncer::audit",
+            "message": "matrix user @alice:example.com is GitHub user octocat",
+            "event": "github_login",
+            "matrix_user": "@alice:example.com",
+            "github_login": "octocat",
+            "github_age_seconds": 86400,
+        })
+    );
+}
+
+#[test]
+fn plain_lines_have_no_extra_fields() {
+    let line = json_line(
+        &Record::builder()
+            .level(Level::Error)
+            .target("bouncer::reload")
+            .args(format_args!("room discovery failed"))
+            .build(),
+    );
+    let keys = line.as_object().unwrap().keys().collect::<Vec<_>>();
+    assert_eq!(keys.len(), 4, "{}", line);
+    assert_eq!(line["message"], "room discovery failed");
+}
+
+#[test]
+fn parses_log_format() {
+    assert_eq!("text".parse::<LogFormat>().unwrap(), LogFormat::Text);
+    assert_eq!("json".parse::<LogFormat>().unwrap(), LogFormat::Json);
+    assert!("yaml".parse::<LogFormat>().is_err());
+}
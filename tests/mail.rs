@@ -0,0 +1,103 @@
+//! Notification emails, sent to a minimal SMTP server recording what it receives.
+
+use std::time::Duration;
+
+use bouncer::{
+    mail::{Mailer, SmtpSettings},
+    secret::Secret,
+    webhook::EventKind,
+};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+    net::TcpListener,
+};
+
+/// Accept one SMTP session and return the commands and message it carried.
+async fn smtp_server() -> (u16, tokio::task::JoinHandle<String>) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let session = tokio::spawn(async move {
+        let (stream, _) = listener.accept().await.unwrap();
+        let (read, mut write) = stream.into_split();
+        let mut lines = BufReader::new(read).lines();
+        let mut transcript = String::new();
+        write.write_all(b"220 localhost ESMTP\r\n").await.unwrap();
+        let mut data = false;
+        while let Some(line) = lines.next_line().await.unwrap() {
+            transcript.push_str(&line);
+            transcript.push('\n');
+            let reply: &[u8] = if data {
+                if line != "." {
+                    continue;
+                }
+                data = false;
+                b"250 queued\r\n"
+            } else if line.starts_with("DATA") {
+                data = true;
+                b"354 go ahead\r\n"
+            } else if line.starts_with("QUIT") {
+                write.write_all(b"221 bye\r\n").await.unwrap();
+                break;
+            } else {
+                b"250 ok\r\n"
+            };
+            write.write_all(reply).await.unwrap();
+        }
+        transcript
+    });
+    (port, session)
+}
+
+#[tokio::test]
+async fn mails_every_recipient() {
+    let (port, session) = smtp_server().await;
+    let mailer = Mailer::new(SmtpSettings {
+        url: Secret::new(format!("smtp://127.0.0.1:{}", port)),
+        username: None,
+        password: None,
+        from: "Bouncer <bouncer@example.com>".parse().unwrap(),
+        to: vec![
+            "alice@example.com".parse().unwrap(),
+            "bob@example.com".parse().unwrap(),
+        ],
+        events: vec![EventKind::InviteSent],
+        interval: Duration::from_secs(60),
+    })
+    .unwrap();
+
+    mailer
+        .send("@alice:example.com invited", "to !room:example.com".into())
+        .await
+        .unwrap();
+    drop(mailer);
+
+    let transcript = tokio::time::timeout(Duration::from_secs(5), session)
+        .await
+        .expect("the SMTP session did not end")
+        .unwrap();
+    assert!(
+        transcript.contains("MAIL FROM:<bouncer@example.com>"),
+        "{}",
+        transcript
+    );
+    assert!(
+        transcript.contains("RCPT TO:<alice@example.com>"),
+        "{}",
+        transcript
+    );
+    assert!(
+        transcript.contains("RCPT TO:<bob@example.com>"),
+        "{}",
+        transcript
+    );
+    assert!(
+        transcript.contains("Subject: @alice:example.com invited"),
+        "{}",
+        transcript
+    );
+    assert!(
+        transcript.contains("to !room:example.com"),
+        "{}",
+        transcript
+    );
+}
@@ -0,0 +1,132 @@
+//! Ordering of the room list, see `--room-order`.
+
+use bouncer::{order::RoomOrder, RoomInfo};
+use ruma::space::SpaceRoomJoinRule;
+
+fn room(room_id: &str, alias: Option<&str>, name: Option<&str>, members: Option<u64>) -> RoomInfo {
+    RoomInfo {
+        room_id: room_id.try_into().unwrap(),
+        canonical_alias: alias.map(|alias| alias.try_into().unwrap()),
+        name: name.map(str::to_string),
+        join_rule: SpaceRoomJoinRule::Invite,
+        suggested: false,
+        parent: None,
+        members,
+        topic: None,
+        avatar_url: None,
+        predecessor: None,
+        replacement: None,
+    }
+}
+
+fn sorted(order: &str, rooms: &[RoomInfo]) -> Vec<String> {
+    let mut rooms = rooms.iter().collect::<Vec<_>>();
+    order.parse::<RoomOrder>().unwrap().sort(&mut rooms);
+    rooms.iter().map(|room| room.room_id.to_string()).collect()
+}
+
+#[test]
+fn orders_by_name_falling_back_to_alias_and_room_id() {
+    let rooms = [
+        room("!d:example.com", None, None, None),
+        room("!c:example.com", Some("#Beta:example.com"), None, None),
+        room(
+            "!b:example.com",
+            Some("#zeta:example.com"),
+            Some("alpha"),
+            None,
+        ),
+        room("!a:example.com", None, Some("Gamma"), None),
+    ];
+    // Names and aliases compare ignoring case; "!d" sorts before "#beta" and "alpha".
+    assert_eq!(
+        sorted("name", &rooms),
+        [
+            "!d:example.com",
+            "!c:example.com",
+            "!b:example.com",
+            "!a:example.com"
+        ]
+    );
+}
+
+#[test]
+fn orders_by_alias_falling_back_to_room_id() {
+    let rooms = [
+        room("!a:example.com", Some("#b:example.com"), Some("A"), None),
+        room("!z:example.com", Some("#A:example.com"), Some("B"), None),
+        room("!c:example.com", None, Some("C"), None),
+    ];
+    assert_eq!(
+        sorted("alias", &rooms),
+        ["!c:example.com", "!z:example.com", "!a:example.com"]
+    );
+}
+
+#[test]
+fn orders_by_members_with_unknown_counts_last() {
+    let rooms = [
+        room("!a:example.com", None, Some("a"), None),
+        room("!b:example.com", None, Some("b"), Some(3)),
+        room("!c:example.com", None, Some("c"), Some(10)),
+        room("!d:example.com", None, Some("d"), Some(3)),
+    ];
+    assert_eq!(
+        sorted("members", &rooms),
+        [
+            "!c:example.com",
+            "!b:example.com",
+            "!d:example.com",
+            "!a:example.com"
+        ]
+    );
+}
+
+#[test]
+fn pins_listed_rooms_first() {
+    let rooms = [
+        room("!a:example.com", None, Some("a"), None),
+        room("!b:example.com", None, Some("b"), None),
+        room("!c:example.com", None, Some("c"), None),
+        room("!d:example.com", None, None, None),
+    ];
+    assert_eq!(
+        sorted("!c:example.com, !b:example.com", &rooms),
+        [
+            "!c:example.com",
+            "!b:example.com",
+            "!d:example.com",
+            "!a:example.com"
+        ]
+    );
+}
+
+#[test]
+fn breaks_ties_by_room_id() {
+    let rooms = [
+        room("!b:example.com", None, Some("Same"), Some(1)),
+        room("!a:example.com", None, Some("same"), Some(1)),
+    ];
+    for order in ["name", "members", "!c:example.com"] {
+        assert_eq!(
+            sorted(order, &rooms),
+            ["!a:example.com", "!b:example.com"],
+            "{}",
+            order
+        );
+    }
+    let rooms = [
+        room("!b:example.com", Some("#same:example.com"), None, None),
+        room("!a:example.com", Some("#same:example.com"), None, None),
+    ];
+    assert_eq!(
+        sorted("alias", &rooms),
+        ["!a:example.com", "!b:example.com"]
+    );
+}
+
+#[test]
+fn refuses_invalid_orders() {
+    let err = "name,members".parse::<RoomOrder>().unwrap_err();
+    assert!(err.to_string().contains("name,members"), "{}", err);
+}
@@ -0,0 +1,16 @@
+//! Links between the pages of the index.
+
+use bouncer::page_link;
+
+#[test]
+fn keeps_the_preselected_room() {
+    assert_eq!(
+        page_link("", Some("#room:example.com"), 2, 25, false),
+        "?room=%23room%3Aexample.com&page=2"
+    );
+    assert_eq!(
+        page_link("rust lang", Some("!room:example.com"), 1, 10, true),
+        "?q=rust+lang&room=%21room%3Aexample.com&page=1&per_page=10&batch=true"
+    );
+    assert_eq!(page_link("", None, 3, 25, false), "?page=3");
+}
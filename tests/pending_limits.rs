@@ -0,0 +1,184 @@
+//! Caps on invites waiting for a GitHub login, checked against the in-memory session store.
+
+use std::sync::Arc;
+
+use bouncer::{
+    sessions::{MemoryStore, PendingLimits, SessionStore, Stashed},
+    Invite,
+};
+use chrono::{Duration, Utc};
+
+/// An invite for `user_id` from `client_ip`, submitted `age` seconds ago.
+fn invite(user_id: &str, client_ip: &str, age: i64) -> Invite {
+    Invite {
+        created: Utc::now() - Duration::seconds(age),
+        room_ids: vec!["!room:localhost".try_into().unwrap()],
+        user_ids: vec![user_id.try_into().unwrap()],
+        emails: vec![],
+        malformed: vec![],
+        existing: vec![],
+        pkce_verifier: None,
+        browser_nonce: None,
+        github_user: None,
+        terms: None,
+        client_ip: Some(client_ip.to_string()),
+    }
+}
+
+async fn tokens(store: &MemoryStore) -> Vec<String> {
+    let mut tokens = store
+        .pending()
+        .await
+        .unwrap()
+        .into_iter()
+        .map(|(token, _)| token)
+        .collect::<Vec<_>>();
+    tokens.sort();
+    tokens
+}
+
+#[tokio::test]
+async fn evicts_oldest_invite_of_the_same_user() {
+    let store = MemoryStore::default();
+    let limits = PendingLimits {
+        total: 100,
+        per_user: 2,
+        per_client: 3,
+    };
+    for (token, ip, age) in [
+        ("a", "192.0.2.1", 30),
+        ("b", "192.0.2.2", 20),
+        ("c", "192.0.2.3", 10),
+    ] {
+        let invite = invite("@alice:localhost", ip, age);
+        let stashed = store.insert_pending(token, &invite, limits).await.unwrap();
+        assert_eq!(stashed, Stashed::Stored);
+    }
+    assert_eq!(tokens(&store).await, ["b", "c"]);
+}
+
+#[tokio::test]
+async fn refuses_invites_beyond_the_client_limit() {
+    let store = MemoryStore::default();
+    let limits = PendingLimits {
+        total: 100,
+        per_user: 5,
+        per_client: 2,
+    };
+    let first = invite("@a:localhost", "192.0.2.1", 0);
+    let second = invite("@b:localhost", "192.0.2.1", 0);
+    let third = invite("@c:localhost", "192.0.2.1", 0);
+    assert_eq!(
+        store.insert_pending("a", &first, limits).await.unwrap(),
+        Stashed::Stored
+    );
+    assert_eq!(
+        store.insert_pending("b", &second, limits).await.unwrap(),
+        Stashed::Stored
+    );
+    assert_eq!(
+        store.insert_pending("c", &third, limits).await.unwrap(),
+        Stashed::ClientBusy
+    );
+    // Other addresses are not affected.
+    let elsewhere = invite("@c:localhost", "192.0.2.2", 0);
+    assert_eq!(
+        store.insert_pending("d", &elsewhere, limits).await.unwrap(),
+        Stashed::Stored
+    );
+    assert_eq!(tokens(&store).await, ["a", "b", "d"]);
+
+    // Completing a login frees its slot.
+    store.take_pending("a").await.unwrap();
+    assert_eq!(
+        store.insert_pending("c", &third, limits).await.unwrap(),
+        Stashed::Stored
+    );
+    assert_eq!(tokens(&store).await, ["b", "c", "d"]);
+}
+
+#[tokio::test]
+async fn expired_invites_free_client_slots() {
+    let store = MemoryStore::default();
+    let limits = PendingLimits {
+        total: 100,
+        per_user: 5,
+        per_client: 1,
+    };
+    let expired = invite("@a:localhost", "192.0.2.1", 31 * 60);
+    let fresh = invite("@b:localhost", "192.0.2.1", 0);
+    assert_eq!(
+        store.insert_pending("a", &expired, limits).await.unwrap(),
+        Stashed::Stored
+    );
+    assert_eq!(
+        store.insert_pending("b", &fresh, limits).await.unwrap(),
+        Stashed::Stored
+    );
+    assert_eq!(tokens(&store).await, ["b"]);
+}
+
+#[tokio::test]
+async fn concurrent_submissions_share_the_client_limit() {
+    let store = Arc::new(MemoryStore::default());
+    let limits = PendingLimits {
+        total: 100,
+        per_user: 5,
+        per_client: 3,
+    };
+    let mut submissions = tokio::task::JoinSet::new();
+    for i in 0..10 {
+        let store = store.clone();
+        submissions.spawn(async move {
+            let invite = invite(&format!("@user{}:localhost", i), "192.0.2.1", 0);
+            store
+                .insert_pending(&i.to_string(), &invite, limits)
+                .await
+                .unwrap()
+        });
+    }
+    let mut stored = 0;
+    while let Some(stashed) = submissions.join_next().await {
+        match stashed.unwrap() {
+            Stashed::Stored => stored += 1,
+            Stashed::ClientBusy => {}
+            Stashed::Full => panic!("the store is not full"),
+        }
+    }
+    assert_eq!(stored, 3);
+    assert_eq!(tokens(&store).await.len(), 3);
+}
+
+#[tokio::test]
+async fn refuses_invites_when_full() {
+    let store = MemoryStore::default();
+    let limits = PendingLimits {
+        total: 2,
+        per_user: 5,
+        per_client: 5,
+    };
+    let first = invite("@a:localhost", "192.0.2.1", 0);
+    let second = invite("@b:localhost", "192.0.2.2", 0);
+    let third = invite("@c:localhost", "192.0.2.3", 0);
+    assert_eq!(
+        store.insert_pending("a", &first, limits).await.unwrap(),
+        Stashed::Stored
+    );
+    assert_eq!(
+        store.insert_pending("b", &second, limits).await.unwrap(),
+        Stashed::Stored
+    );
+    assert_eq!(
+        store.insert_pending("c", &third, limits).await.unwrap(),
+        Stashed::Full
+    );
+    assert_eq!(tokens(&store).await, ["a", "b"]);
+
+    // Completing a login frees its slot.
+    store.take_pending("a").await.unwrap();
+    assert_eq!(
+        store.insert_pending("c", &third, limits).await.unwrap(),
+        Stashed::Stored
+    );
+    assert_eq!(tokens(&store).await, ["b", "c"]);
+}
@@ -0,0 +1,32 @@
+//! Globs of policy list rules, see `--policy-room`.
+
+use bouncer::policy::glob_matches;
+
+#[test]
+fn matches_any_run_with_a_star() {
+    assert!(glob_matches("*", ""));
+    assert!(glob_matches("*", "@spam:example.com"));
+    assert!(glob_matches("@*:example.com", "@spam:example.com"));
+    assert!(glob_matches("@*:example.com", "@:example.com"));
+    assert!(glob_matches("*.example.com", "evil.example.com"));
+    assert!(glob_matches("*.example.com", "a.b.example.com"));
+    assert!(!glob_matches("*.example.com", "example.com"));
+    assert!(glob_matches("@spam*:*", "@spambot:example.com"));
+    assert!(!glob_matches("@spam*:*", "@alice:example.com"));
+}
+
+#[test]
+fn matches_one_character_with_a_question_mark() {
+    assert!(glob_matches("@bot?:example.com", "@bot1:example.com"));
+    assert!(!glob_matches("@bot?:example.com", "@bot:example.com"));
+    assert!(!glob_matches("@bot?:example.com", "@bot12:example.com"));
+    assert!(glob_matches("@b?t:example.com", "@böt:example.com"));
+}
+
+#[test]
+fn matches_dots_literally() {
+    assert!(glob_matches("example.com", "example.com"));
+    assert!(!glob_matches("example.com", "examplexcom"));
+    assert!(!glob_matches("@a.b:example.com", "@axb:example.com"));
+    assert!(!glob_matches("evil.example.com", "example.com"));
+}
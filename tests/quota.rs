@@ -0,0 +1,20 @@
+//! Invitees per GitHub account, see `--max-invitees-per-account`.
+
+use bouncer::quota::Quota;
+
+#[tokio::test]
+async fn counts_every_invitee_against_the_account() {
+    let quota = Quota::new(Some(3));
+    assert_eq!(quota.take("octocat", 2).await, 2);
+    // A batch gets the rest of the quota, logins compare ignoring case.
+    assert_eq!(quota.take("OctoCat", 5).await, 1);
+    assert_eq!(quota.take("octocat", 1).await, 0);
+    assert_eq!(quota.take("hubot", 3).await, 3);
+}
+
+#[tokio::test]
+async fn grants_everything_without_a_limit() {
+    let quota = Quota::new(None);
+    assert_eq!(quota.take("octocat", 100).await, 100);
+    assert_eq!(quota.take("octocat", 100).await, 100);
+}
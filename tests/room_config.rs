@@ -0,0 +1,121 @@
+//! Per-room settings from the config file.
+
+use std::collections::HashMap;
+
+use bouncer::{
+    config::{Args, Config, RoomConfig},
+    room_policy, RoomInfo,
+};
+use clap::Parser;
+use ruma::{space::SpaceRoomJoinRule, OwnedRoomId};
+
+fn args(room_config: &str) -> Args {
+    let mut args = Args::try_parse_from([
+        "bouncer",
+        "--homeserver-url",
+        "https://matrix.example.com",
+        "--access-token",
+        "syt_accesstoken",
+        "--github-client-id",
+        "client",
+        "--github-client-secret",
+        "clientsecret",
+        "--github-redirect-url",
+        "https://bouncer.example.com/callback",
+        "--turnstile-secret-key",
+        "turnstilesecret",
+        "--listen-address",
+        "127.0.0.1:8080",
+    ])
+    .unwrap();
+    let file: Args = toml::from_str(room_config).unwrap();
+    args.room_config = file.room_config;
+    args
+}
+
+#[test]
+fn reads_room_tables() {
+    let config = Config::from_args(args(
+        r##"
+        [room_config."#finance:example.com"]
+        min_github_age_days = 365
+        github_org = "example"
+        github_team = "finance"
+        homeservers = ["example.com"]
+
+        [room_config."!general:example.com"]
+        hidden = true
+        display_name = "General"
+        "##,
+    ))
+    .unwrap();
+    assert_eq!(
+        config.rules.room_config["#finance:example.com"],
+        RoomConfig {
+            min_github_age_days: Some(365),
+            github_org: Some("example".to_string()),
+            github_team: Some("finance".to_string()),
+            homeservers: vec!["example.com".to_string()],
+            ..RoomConfig::default()
+        }
+    );
+    assert!(config.rules.room_config["!general:example.com"].hidden);
+    assert_eq!(
+        config.rules.room_config["!general:example.com"]
+            .display_name
+            .as_deref(),
+        Some("General")
+    );
+}
+
+#[test]
+fn overrides_name_and_topic() {
+    let room_id: OwnedRoomId = "!ops:example.com".try_into().unwrap();
+    let mut room = RoomInfo {
+        room_id: room_id.clone(),
+        canonical_alias: Some("#ops-3-migrated:example.com".try_into().unwrap()),
+        name: Some("ops-#3-migrated".to_string()),
+        join_rule: SpaceRoomJoinRule::Invite,
+        suggested: false,
+        parent: None,
+        members: Some(3),
+        topic: Some("see runbook v2 (old)".to_string()),
+        avatar_url: None,
+        predecessor: None,
+        replacement: None,
+    };
+    let configs = HashMap::from([(
+        room_id.clone(),
+        RoomConfig {
+            display_name: Some("Operations".to_string()),
+            description: Some("Keeping the lights on.".to_string()),
+            ..RoomConfig::default()
+        },
+    )]);
+    room_policy::display(&configs, &mut room);
+    assert_eq!(room.name.as_deref(), Some("Operations"));
+    assert_eq!(room.topic.as_deref(), Some("Keeping the lights on."));
+    assert_eq!(room.room_id, room_id);
+    assert_eq!(
+        room.canonical_alias.unwrap().as_str(),
+        "#ops-3-migrated:example.com"
+    );
+}
+
+#[test]
+fn rejects_inconsistent_rooms() {
+    for room_config in [
+        r#"room_config."!a:example.com" = { github_team = "finance" }"#,
+        r#"room_config."!a:example.com" = { require_approval = true }"#,
+        r#"room_config."!a:example.com" = { homeservers = ["not a server"] }"#,
+    ] {
+        assert!(
+            Config::from_args(args(room_config)).is_err(),
+            "{}",
+            room_config
+        );
+    }
+    let unknown: Result<Args, _> =
+        toml::from_str(r#"room_config."!a:example.com" = { min_age = 1 }"#);
+    assert!(unknown.is_err());
+}
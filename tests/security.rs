@@ -0,0 +1,36 @@
+//! Content-Security-Policy of the pages.
+
+use bouncer::security::content_security_policy;
+
+fn directive<'a>(csp: &'a str, name: &str) -> &'a str {
+    csp.split("; ")
+        .find(|directive| directive.split(' ').next() == Some(name))
+        .unwrap_or_else(|| panic!("no {} in {}", name, csp))
+}
+
+#[test]
+fn allows_the_configured_captcha_and_github() {
+    let csp = content_security_policy(
+        &["https://captcha.example.com".to_string()],
+        "https://github.example.com",
+        "abc",
+        &["img-src https://cdn.example.com".to_string()],
+    );
+    assert_eq!(
+        directive(&csp, "script-src"),
+        "script-src https://captcha.example.com 'nonce-abc'"
+    );
+    assert_eq!(
+        directive(&csp, "frame-src"),
+        "frame-src https://captcha.example.com"
+    );
+    assert_eq!(
+        directive(&csp, "form-action"),
+        "form-action 'self' https://github.example.com"
+    );
+    assert_eq!(
+        directive(&csp, "img-src"),
+        "img-src 'self' https://cdn.example.com"
+    );
+    assert!(!csp.contains("challenges.cloudflare.com"), "{}", csp);
+}
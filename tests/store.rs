@@ -0,0 +1,115 @@
+//! The audit store's entry log, read back after a restart.
+
+use std::time::Duration;
+
+use bouncer::{
+    store::{self, Entry, Store},
+    webhook::EventKind,
+};
+use chrono::Utc;
+
+fn path(name: &str) -> std::path::PathBuf {
+    let path = std::env::temp_dir().join(format!("bouncer-store-{}.json", name));
+    let _ = std::fs::remove_file(&path);
+    let _ = std::fs::remove_file(store::log_path(&path));
+    path
+}
+
+fn entry(user_id: &str, days_ago: i64) -> Entry {
+    Entry {
+        timestamp: Utc::now() - chrono::Duration::days(days_ago),
+        event: EventKind::InviteSent,
+        user_id: Some(user_id.try_into().unwrap()),
+        email_sha256: None,
+        room_id: "!room:example.com".try_into().unwrap(),
+        github_login: "octocat".to_string(),
+        reason: None,
+        accepted: None,
+        expired: None,
+        terms: None,
+        initiated_by: None,
+        reason_code: None,
+    }
+}
+
+async fn users(store: &Store) -> Vec<String> {
+    store
+        .entries(|entries| {
+            entries
+                .iter()
+                .map(|entry| entry.user_id.as_ref().unwrap().to_string())
+                .collect()
+        })
+        .await
+}
+
+#[tokio::test]
+async fn appends_entries_and_amendments() {
+    let path = path("append");
+    let store = Store::open(&path, None).unwrap();
+    store.append(entry("@alice:example.com", 0)).await;
+    store.append(entry("@bob:example.com", 0)).await;
+    let accepted = store
+        .amend(|entry| {
+            let matches = entry.user_id.as_ref().unwrap().as_str() == "@alice:example.com";
+            if matches {
+                entry.accepted = Some(Utc::now());
+            }
+            matches
+        })
+        .await;
+    assert_eq!(accepted, 1);
+    // Nothing but the log holds the entries.
+    store.update(|data| data.last_digest = None).await;
+    assert!(!std::fs::read_to_string(&path).unwrap().contains("alice"));
+    let log = std::fs::read_to_string(store::log_path(&path)).unwrap();
+    assert_eq!(log.lines().count(), 3);
+    drop(store);
+
+    let store = Store::open(&path, None).unwrap();
+    assert_eq!(
+        users(&store).await,
+        ["@alice:example.com", "@bob:example.com"]
+    );
+    let accepted = store
+        .entries(|entries| {
+            entries
+                .iter()
+                .map(|entry| entry.accepted.is_some())
+                .collect::<Vec<_>>()
+        })
+        .await;
+    assert_eq!(accepted, [true, false]);
+    // Reopening folded the amendment into the entry.
+    let log = std::fs::read_to_string(store::log_path(&path)).unwrap();
+    assert_eq!(log.lines().count(), 2);
+}
+
+#[tokio::test]
+async fn drops_entries_past_the_retention() {
+    let path = path("retention");
+    let store = Store::open(&path, None).unwrap();
+    store.append(entry("@old:example.com", 40)).await;
+    store.append(entry("@new:example.com", 1)).await;
+    drop(store);
+
+    let retention = Some(Duration::from_secs(30 * 24 * 60 * 60));
+    let store = Store::open(&path, retention).unwrap();
+    assert_eq!(users(&store).await, ["@new:example.com"]);
+    let log = std::fs::read_to_string(store::log_path(&path)).unwrap();
+    assert!(!log.contains("@old:example.com"));
+}
+
+#[tokio::test]
+async fn drops_a_line_cut_short() {
+    let path = path("cut");
+    let store = Store::open(&path, None).unwrap();
+    store.append(entry("@alice:example.com", 0)).await;
+    drop(store);
+    let mut log = std::fs::read_to_string(store::log_path(&path)).unwrap();
+    log.push_str("{\"timestamp\":");
+    std::fs::write(store::log_path(&path), log).unwrap();
+
+    let store = Store::open(&path, None).unwrap();
+    assert_eq!(users(&store).await, ["@alice:example.com"]);
+}
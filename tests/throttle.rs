@@ -0,0 +1,41 @@
+//! Cap on concurrent homeserver requests.
+
+use std::time::Duration;
+
+use bouncer::throttle::Throttle;
+
+#[tokio::test]
+async fn gives_up_waiting_after_the_timeout() {
+    let throttle = Throttle::new(2, Duration::from_millis(50));
+    let first = throttle.acquire().await.unwrap();
+    let _second = throttle.acquire().await.unwrap();
+    assert_eq!(throttle.stats().in_flight, 2);
+
+    let err = throttle.acquire().await.unwrap_err();
+    assert!(err.to_string().contains("50ms"), "{}", err);
+    let stats = throttle.stats();
+    assert_eq!(stats.requests, 3);
+    assert_eq!(stats.timeouts, 1);
+    assert!(stats.waited >= Duration::from_millis(50));
+
+    drop(first);
+    assert!(throttle.acquire().await.is_ok());
+    assert_eq!(throttle.stats().timeouts, 1);
+}
+
+#[tokio::test]
+async fn waiting_requests_get_freed_slots() {
+    let throttle = Throttle::new(1, Duration::from_secs(5));
+    let permit = throttle.acquire().await.unwrap();
+    let waiting = async {
+        throttle.acquire().await.map(|_| ()).unwrap();
+    };
+    let release = async {
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        drop(permit);
+    };
+    tokio::join!(waiting, release);
+    let stats = throttle.stats();
+    assert_eq!(stats.in_flight, 0);
+    assert_eq!(stats.timeouts, 0);
+}
@@ -0,0 +1,110 @@
+//! HTTPS serving with the self-signed certificate for localhost in tests/fixtures.
+
+use std::{net::SocketAddr, path::PathBuf, time::Duration};
+
+use axum::{routing::get, Router};
+use reqwest::{Certificate, StatusCode};
+use tokio::net::TcpListener;
+
+fn fixture(name: &str) -> PathBuf {
+    PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+        .join("tests/fixtures")
+        .join(name)
+}
+
+#[tokio::test]
+async fn completes_a_tls_handshake() {
+    let config = bouncer::tls::load(&fixture("localhost.crt"), &fixture("localhost.key")).unwrap();
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap();
+    let router = Router::new().route("/", get(|| async { "hello" }));
+    tokio::spawn(bouncer::serve::serve(
+        vec![(listener, router)],
+        Some(config),
+    ));
+
+    let certificate =
+        Certificate::from_pem(&std::fs::read(fixture("localhost.crt")).unwrap()).unwrap();
+    let client = reqwest::Client::builder()
+        .add_root_certificate(certificate)
+        .resolve("localhost", address)
+        .build()
+        .unwrap();
+    let response = client
+        .get(format!("https://localhost:{}/", address.port()))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(response.text().await.unwrap(), "hello");
+
+    // Plain HTTP is not answered on a TLS listener.
+    assert!(reqwest::Client::new()
+        .get(format!("http://{}/", address))
+        .send()
+        .await
+        .is_err());
+}
+
+async fn handshake(address: SocketAddr, trusted: &std::path::Path) -> reqwest::Result<String> {
+    let certificate = Certificate::from_pem(&std::fs::read(trusted).unwrap()).unwrap();
+    reqwest::Client::builder()
+        .add_root_certificate(certificate)
+        .resolve("localhost", address)
+        .build()
+        .unwrap()
+        .get(format!("https://localhost:{}/", address.port()))
+        .send()
+        .await?
+        .text()
+        .await
+}
+
+#[tokio::test]
+async fn reloads_the_certificate_when_its_files_change() {
+    let dir = std::env::temp_dir().join(format!("bouncer-tls-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let (cert, key) = (dir.join("tls.crt"), dir.join("tls.key"));
+    std::fs::copy(fixture("localhost.crt"), &cert).unwrap();
+    std::fs::copy(fixture("localhost.key"), &key).unwrap();
+
+    let config = bouncer::tls::load(&cert, &key).unwrap();
+    bouncer::tls::watch(
+        config.clone(),
+        cert.clone(),
+        key.clone(),
+        Duration::from_millis(50),
+    )
+    .unwrap();
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap();
+    let router = Router::new().route("/", get(|| async { "hello" }));
+    tokio::spawn(bouncer::serve::serve(
+        vec![(listener, router)],
+        Some(config),
+    ));
+    assert_eq!(
+        handshake(address, &fixture("localhost.crt")).await.unwrap(),
+        "hello"
+    );
+
+    // A broken certificate keeps the old pair in place.
+    std::fs::write(&cert, "not a certificate").unwrap();
+    tokio::time::sleep(Duration::from_millis(200)).await;
+    assert_eq!(
+        handshake(address, &fixture("localhost.crt")).await.unwrap(),
+        "hello"
+    );
+
+    // A renewed pair is picked up without a signal.
+    std::fs::copy(fixture("renewed.crt"), &cert).unwrap();
+    std::fs::copy(fixture("renewed.key"), &key).unwrap();
+    tokio::time::sleep(Duration::from_millis(200)).await;
+    assert_eq!(
+        handshake(address, &fixture("renewed.crt")).await.unwrap(),
+        "hello"
+    );
+    assert!(handshake(address, &fixture("localhost.crt")).await.is_err());
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
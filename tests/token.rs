@@ -0,0 +1,187 @@
+//! Refreshed access tokens kept in `--token-state-file`.
+
+use std::{os::unix::fs::PermissionsExt, path::PathBuf, sync::Arc, time::Duration};
+
+use bouncer::{
+    secret::Secret,
+    throttle::Throttle,
+    token::{self, Credentials, Tokens},
+};
+use ruma::{
+    api::client::{membership::invite_user, state::get_state_events_for_key, sync::sync_events},
+    events::StateEventType,
+    room_id, user_id,
+};
+use serde_json::json;
+use wiremock::{
+    matchers::{bearer_token, method, path, path_regex, query_param},
+    Mock, MockServer, ResponseTemplate,
+};
+
+fn state_file(name: &str) -> PathBuf {
+    let path = std::env::temp_dir().join(format!("bouncer-tokens-{}.json", name));
+    let _ = std::fs::remove_file(&path);
+    path
+}
+
+fn tokens(access_token: &str) -> Tokens {
+    Tokens {
+        access_token: Secret::new(access_token.to_string()),
+        refresh_token: Some(Secret::new("syr_old".to_string())),
+        origin: None,
+    }
+}
+
+#[tokio::test]
+async fn saves_refreshed_tokens_for_the_owner_only() {
+    let homeserver = MockServer::start().await;
+    Mock::given(path("/_matrix/client/versions"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+            "versions": ["v1.1", "v1.2", "v1.3", "v1.4", "v1.5", "v1.6", "v1.7", "v1.8", "v1.9", "v1.10", "v1.11"],
+        })))
+        .mount(&homeserver)
+        .await;
+    Mock::given(path_regex(r"/account/whoami$"))
+        .and(bearer_token("syt_old"))
+        .respond_with(ResponseTemplate::new(401).set_body_json(json!({
+            "errcode": "M_UNKNOWN_TOKEN",
+            "error": "Access token has expired",
+            "soft_logout": true,
+        })))
+        .mount(&homeserver)
+        .await;
+    Mock::given(path_regex(r"/account/whoami$"))
+        .and(bearer_token("syt_new"))
+        .respond_with(
+            ResponseTemplate::new(200).set_body_json(json!({ "user_id": "@bouncer:localhost" })),
+        )
+        .mount(&homeserver)
+        .await;
+    Mock::given(method("POST"))
+        .and(path("/_matrix/client/v3/refresh"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+            "access_token": "syt_new",
+            "refresh_token": "syr_new",
+        })))
+        .expect(1)
+        .mount(&homeserver)
+        .await;
+    let file = state_file("refresh");
+
+    bouncer::connect(
+        homeserver.uri(),
+        Credentials {
+            tokens: tokens("syt_old"),
+            state_file: Some(file.clone()),
+            appservice_sender: None,
+        },
+        None,
+        Arc::new(Throttle::new(8, Duration::from_secs(10))),
+    )
+    .await
+    .unwrap();
+
+    let mode = std::fs::metadata(&file).unwrap().permissions().mode();
+    assert_eq!(mode & 0o777, 0o600);
+    let saved =
+        serde_json::from_slice::<serde_json::Value>(&std::fs::read(&file).unwrap()).unwrap();
+    assert_eq!(saved["access_token"], "syt_new");
+    assert_eq!(saved["refresh_token"], "syr_new");
+
+    // A restart continues with the refreshed tokens.
+    let loaded = token::load(tokens("syt_old"), Some(&file)).unwrap();
+    assert_eq!(loaded.access_token.expose(), "syt_new");
+}
+
+#[test]
+fn prefers_a_newly_configured_access_token() {
+    let file = state_file("reconfigured");
+    let started = token::load(tokens("syt_old"), Some(&file)).unwrap();
+    let refreshed = Tokens {
+        access_token: Secret::new("syt_refreshed".to_string()),
+        ..started
+    };
+    std::fs::write(&file, serde_json::to_vec(&refreshed).unwrap()).unwrap();
+
+    let loaded = token::load(tokens("syt_old"), Some(&file)).unwrap();
+    assert_eq!(loaded.access_token.expose(), "syt_refreshed");
+    let loaded = token::load(tokens("syt_other"), Some(&file)).unwrap();
+    assert_eq!(loaded.access_token.expose(), "syt_other");
+}
+
+#[tokio::test]
+async fn impersonates_the_appservice_sender() {
+    const SENDER: &str = "@bouncer:localhost";
+    let homeserver = MockServer::start().await;
+    Mock::given(path("/_matrix/client/versions"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+            "versions": ["v1.1", "v1.2", "v1.3", "v1.4", "v1.5", "v1.6", "v1.7", "v1.8", "v1.9", "v1.10", "v1.11"],
+        })))
+        .mount(&homeserver)
+        .await;
+    Mock::given(path_regex(r"/account/whoami$"))
+        .and(query_param("user_id", SENDER))
+        .respond_with(ResponseTemplate::new(200).set_body_json(json!({ "user_id": SENDER })))
+        .expect(1)
+        .mount(&homeserver)
+        .await;
+    Mock::given(path_regex(r"/state/m\.room\.power_levels/?$"))
+        .and(query_param("user_id", SENDER))
+        .respond_with(ResponseTemplate::new(200).set_body_json(json!({ "users": { SENDER: 100 } })))
+        .expect(1)
+        .mount(&homeserver)
+        .await;
+    Mock::given(method("POST"))
+        .and(path_regex(r"/invite$"))
+        .and(query_param("user_id", SENDER))
+        .respond_with(ResponseTemplate::new(200).set_body_json(json!({})))
+        .expect(1)
+        .mount(&homeserver)
+        .await;
+    Mock::given(path_regex(r"/sync$"))
+        .and(query_param("since", "s1"))
+        .and(query_param("user_id", SENDER))
+        .respond_with(ResponseTemplate::new(200).set_body_json(json!({ "next_batch": "s2" })))
+        .expect(1)
+        .mount(&homeserver)
+        .await;
+
+    let (client, user_id) = bouncer::connect(
+        homeserver.uri(),
+        Credentials {
+            tokens: Tokens {
+                access_token: Secret::new("as_token".to_string()),
+                refresh_token: None,
+                origin: None,
+            },
+            state_file: None,
+            appservice_sender: Some(SENDER.try_into().unwrap()),
+        },
+        None,
+        Arc::new(Throttle::new(8, Duration::from_secs(10))),
+    )
+    .await
+    .unwrap();
+    assert_eq!(user_id.as_str(), SENDER);
+
+    client
+        .send_request(get_state_events_for_key::v3::Request::new(
+            room_id!("!room:localhost").to_owned(),
+            StateEventType::RoomPowerLevels,
+            String::new(),
+        ))
+        .await
+        .unwrap();
+    client
+        .send_request(invite_user::v3::Request::new(
+            room_id!("!room:localhost").to_owned(),
+            invite_user::v3::InvitationRecipient::UserId {
+                user_id: user_id!("@alice:localhost").to_owned(),
+            },
+        ))
+        .await
+        .unwrap();
+    let mut sync = sync_events::v3::Request::new();
+    sync.since = Some("s1".to_string());
+    assert_eq!(client.send_request(sync).await.unwrap().next_batch, "s2");
+}